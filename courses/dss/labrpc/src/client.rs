@@ -1,26 +1,42 @@
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use futures::channel::mpsc::UnboundedSender;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
 use futures::channel::oneshot;
 use futures::executor::ThreadPool;
-use futures::future::{self, FutureExt};
+use futures::future::{self, Either, FutureExt};
+use futures::stream::{self, StreamExt};
 
+use crate::clock::Clock;
 use crate::error::{Error, Result};
-use crate::server::RpcFuture;
+use crate::server::{RpcFuture, RpcStream};
 
 pub struct Rpc {
     pub(crate) client_name: String,
     pub(crate) fq_name: &'static str,
     pub(crate) req: Option<Vec<u8>>,
     pub(crate) resp: Option<oneshot::Sender<Result<Vec<u8>>>>,
+    // the replies of a streaming call, instead of resp
+    pub(crate) items: Option<UnboundedSender<Result<Vec<u8>>>>,
     pub(crate) hooks: Arc<Mutex<Option<Arc<dyn RpcHooks>>>>,
+    // in the time of the network's clock
+    pub(crate) deadline: Option<Duration>,
+    // set once the caller gave up
+    pub(crate) cancel: Arc<AtomicBool>,
 }
 
 impl Rpc {
     pub(crate) fn take_resp_sender(&mut self) -> Option<oneshot::Sender<Result<Vec<u8>>>> {
         self.resp.take()
     }
+
+    /// Whether nobody waits for the reply anymore, it being `now`.
+    pub(crate) fn expired(&self, now: Duration) -> bool {
+        self.cancel.load(Ordering::Acquire)
+            || matches!(self.deadline, Some(deadline) if now >= deadline)
+    }
 }
 
 impl fmt::Debug for Rpc {
@@ -44,6 +60,9 @@ pub struct Client {
     // copy of Network.sender
     pub(crate) sender: UnboundedSender<Rpc>,
     pub(crate) hooks: Arc<Mutex<Option<Arc<dyn RpcHooks>>>>,
+    pub(crate) deadline: Option<Duration>,
+    // copy of Network's clock
+    pub(crate) clock: Arc<dyn Clock>,
 
     pub worker: ThreadPool,
 }
@@ -60,12 +79,16 @@ impl Client {
         }
 
         let (tx, rx) = oneshot::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
         let rpc = Rpc {
             client_name: self.name.clone(),
             fq_name,
             req: Some(buf),
             resp: Some(tx),
+            items: None,
             hooks: self.hooks.clone(),
+            deadline: self.deadline.map(|timeout| self.clock.now() + timeout),
+            cancel: cancel.clone(),
         };
 
         // Sends requests and waits responses.
@@ -73,13 +96,67 @@ impl Client {
             return Box::pin(future::err(Error::Stopped));
         }
 
-        Box::pin(rx.then(|res| async move {
+        let resp = Box::pin(rx.then(|res| async move {
             match res {
                 Ok(Ok(resp)) => labcodec::decode(&resp).map_err(Error::Decode),
                 Ok(Err(e)) => Err(e),
                 Err(e) => Err(Error::Recv(e)),
             }
-        }))
+        }));
+        match self.deadline {
+            None => resp,
+            Some(timeout) => {
+                let timeout = self.clock.sleep(timeout);
+                Box::pin(future::select(resp, timeout).map(move |res| match res {
+                    Either::Left((res, _)) => res,
+                    Either::Right(_) => {
+                        cancel.store(true, Ordering::Release);
+                        Err(Error::Timeout)
+                    }
+                }))
+            }
+        }
+    }
+
+    /// Calls a server-streaming RPC. The stream ends after an error: the
+    /// network may cut it like it drops a reply.
+    pub fn call_stream<Req, Rsp>(&self, fq_name: &'static str, req: &Req) -> RpcStream<Result<Rsp>>
+    where
+        Req: labcodec::Message,
+        Rsp: labcodec::Message + 'static,
+    {
+        let mut buf = vec![];
+        if let Err(e) = labcodec::encode(req, &mut buf) {
+            return stream::once(future::err(Error::Encode(e))).boxed();
+        }
+
+        let (tx, rx) = unbounded();
+        let rpc = Rpc {
+            client_name: self.name.clone(),
+            fq_name,
+            req: Some(buf),
+            resp: None,
+            items: Some(tx),
+            hooks: self.hooks.clone(),
+            deadline: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+        };
+        if self.sender.unbounded_send(rpc).is_err() {
+            return stream::once(future::err(Error::Stopped)).boxed();
+        }
+
+        rx.map(|item| item.and_then(|resp| labcodec::decode(&resp).map_err(Error::Decode)))
+            .boxed()
+    }
+
+    /// A copy of this client end whose calls fail with `Error::Timeout`
+    /// after `timeout`. The network then stops delivering them, and their
+    /// handlers see `is_cancelled`.
+    pub fn with_deadline(&self, timeout: Duration) -> Client {
+        Client {
+            deadline: Some(timeout),
+            ..self.clone()
+        }
     }
 
     pub fn set_hooks(&self, hooks: Arc<dyn RpcHooks>) {
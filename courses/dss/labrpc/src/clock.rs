@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::future::FutureExt;
+use futures_timer::Delay;
+
+use crate::server::RpcFuture;
+
+/// The time a network runs on, see `Network::with_clock`: its delays, the
+/// timeouts of its clients, and whatever else shares the clock, e.g. the
+/// ticks of raft peers.
+pub trait Clock: Send + Sync + 'static {
+    /// The time since the clock started.
+    fn now(&self) -> Duration;
+
+    /// Resolves once `d` has passed.
+    fn sleep(&self, d: Duration) -> RpcFuture<()>;
+}
+
+/// The wall clock, the default.
+#[derive(Clone, Debug)]
+pub struct RealClock {
+    start: Instant,
+}
+
+impl RealClock {
+    pub fn new() -> RealClock {
+        RealClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for RealClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, d: Duration) -> RpcFuture<()> {
+        Delay::new(d).boxed()
+    }
+}
+
+/// A clock that only moves when told to. Sleeps on it end when `advance`
+/// passes their time, however long that takes in real time, so a test that
+/// advances it as soon as all its threads wait runs as fast as it can, and
+/// the same way every time.
+#[derive(Clone, Debug, Default)]
+pub struct SimClock {
+    inner: Arc<Mutex<SimState>>,
+}
+
+#[derive(Debug, Default)]
+struct SimState {
+    now: Duration,
+    next_id: u64,
+    // the sleeps waiting, by when they end, then by when they started.
+    timers: BTreeMap<(Duration, u64), Option<Waker>>,
+}
+
+impl SimClock {
+    pub fn new() -> SimClock {
+        SimClock::default()
+    }
+
+    /// Moves the clock `d` forward, ending the sleeps due by then.
+    pub fn advance(&self, d: Duration) {
+        let now = self.inner.lock().unwrap().now + d;
+        self.advance_to(now);
+    }
+
+    /// Moves the clock to the end of the first sleep, and ends it, along
+    /// with any other due then. Returns false, not moving, if nothing
+    /// sleeps.
+    pub fn advance_to_next(&self) -> bool {
+        match self.next_timer() {
+            Some(at) => {
+                self.advance_to(at);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// When the first sleep ends, if any.
+    pub fn next_timer(&self) -> Option<Duration> {
+        let state = self.inner.lock().unwrap();
+        state.timers.keys().next().map(|(at, _)| *at)
+    }
+
+    fn advance_to(&self, at: Duration) {
+        let mut due = vec![];
+        {
+            let mut state = self.inner.lock().unwrap();
+            state.now = state.now.max(at);
+            let now = state.now;
+            let later = state.timers.split_off(&(now, u64::MAX));
+            due.extend(std::mem::replace(&mut state.timers, later).into_values());
+        }
+        // wake outside the lock, a waker may poll the sleep right away.
+        for waker in due.into_iter().flatten() {
+            waker.wake();
+        }
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Duration {
+        self.inner.lock().unwrap().now
+    }
+
+    fn sleep(&self, d: Duration) -> RpcFuture<()> {
+        if d == Duration::from_secs(0) {
+            return Box::pin(futures::future::ready(()));
+        }
+        let mut state = self.inner.lock().unwrap();
+        let key = (state.now + d, state.next_id);
+        state.next_id += 1;
+        state.timers.insert(key, None);
+        Box::pin(SimSleep {
+            clock: self.clone(),
+            key,
+        })
+    }
+}
+
+struct SimSleep {
+    clock: SimClock,
+    key: (Duration, u64),
+}
+
+impl Future for SimSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.clock.inner.lock().unwrap();
+        match state.timers.get_mut(&self.key) {
+            Some(waker) => {
+                *waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            None => Poll::Ready(()),
+        }
+    }
+}
+
+impl Drop for SimSleep {
+    fn drop(&mut self) {
+        self.clock.inner.lock().unwrap().timers.remove(&self.key);
+    }
+}
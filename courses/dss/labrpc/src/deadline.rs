@@ -0,0 +1,45 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+thread_local! {
+    // the cancellation flag of the call whose handler is being polled.
+    static CURRENT: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+}
+
+/// Whether the caller of the RPC being handled gave up on it, its deadline
+/// having passed, see `Client::with_deadline`. A long handler may check it
+/// between steps and stop early, nobody waits for its reply anymore.
+///
+/// Only true in the future of the handler itself, not in the futures and
+/// threads it spawns.
+pub fn is_cancelled() -> bool {
+    CURRENT.with(|c| matches!(&*c.borrow(), Some(flag) if flag.load(Ordering::Acquire)))
+}
+
+/// A handler future that sees `flag` through `is_cancelled`.
+pub(crate) struct WithCancel<F> {
+    inner: F,
+    flag: Arc<AtomicBool>,
+}
+
+impl<F> WithCancel<F> {
+    pub(crate) fn new(inner: F, flag: Arc<AtomicBool>) -> WithCancel<F> {
+        WithCancel { inner, flag }
+    }
+}
+
+impl<F: Future + Unpin> Future for WithCancel<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let prev = CURRENT.with(|c| c.replace(Some(this.flag.clone())));
+        let res = Pin::new(&mut this.inner).poll(cx);
+        CURRENT.with(|c| *c.borrow_mut() = prev);
+        res
+    }
+}
@@ -12,6 +12,17 @@ pub enum Error {
     Recv(Canceled),
     Timeout,
     Stopped,
+    /// The server had too many calls in flight, see
+    /// `ServerBuilder::max_in_flight`.
+    Overloaded,
+    /// A request or reply of `size` bytes, over the limit of the network,
+    /// see `Network::set_max_message_size`.
+    TooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The handler panicked, with this message.
+    Panicked(String),
     Other(String),
 }
 
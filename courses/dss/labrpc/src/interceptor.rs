@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::server::RpcFuture;
+
+/// The call an interceptor sees.
+#[derive(Clone, Debug)]
+pub struct Call {
+    /// The client end the call came from.
+    pub client_name: String,
+    /// The server it is delivered to.
+    pub server_name: String,
+    /// `service.method`.
+    pub fq_name: &'static str,
+}
+
+/// The rest of the chain: the next interceptor, and the handler in the end.
+pub type Next = Box<dyn FnOnce(Vec<u8>) -> RpcFuture<Result<Vec<u8>>> + Send>;
+
+/// Wraps every unary call the network delivers, see
+/// `Network::add_interceptor`. An interceptor may look at or change the
+/// request before passing it on to `next`, and the reply after, or not call
+/// `next` at all and fail the call, e.g. to inject a fault on the calls of a
+/// method only.
+///
+/// Interceptors run on the server side, once a request made it through the
+/// network, in the order they were added.
+pub trait Interceptor: Send + Sync + 'static {
+    fn intercept(&self, call: &Call, req: Vec<u8>, next: Next) -> RpcFuture<Result<Vec<u8>>>;
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(&Call, Vec<u8>, Next) -> RpcFuture<Result<Vec<u8>>> + Send + Sync + 'static,
+{
+    fn intercept(&self, call: &Call, req: Vec<u8>, next: Next) -> RpcFuture<Result<Vec<u8>>> {
+        self(call, req, next)
+    }
+}
+
+/// Chains `interceptors` in front of `handler`.
+pub(crate) fn chain(interceptors: Vec<Arc<dyn Interceptor>>, call: Call, handler: Next) -> Next {
+    interceptors
+        .into_iter()
+        .rev()
+        .fold(handler, |next, interceptor| {
+            let call = call.clone();
+            Box::new(move |req| interceptor.intercept(&call, req, next))
+        })
+}
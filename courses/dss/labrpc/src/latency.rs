@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// The delay of a link, see `Network::set_latency`.
+///
+/// The global unreliable switch delays every RPC alike, by up to 27ms.
+/// Links with a latency of their own can model servers that are far apart,
+/// e.g. two data centers, or a slow path one way only: the link from `a` to
+/// `b` is another client end than the one from `b` to `a`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Latency {
+    /// Always the same delay.
+    Fixed(Duration),
+    /// Any delay in `[min, max]`, evenly.
+    Uniform { min: Duration, max: Duration },
+    /// Log-normal, as network delays mostly are: half the delays are below
+    /// `median`, and the larger `sigma`, the longer the tail above it.
+    /// A `sigma` of 0.5 makes about one delay in 12 more than twice the
+    /// median.
+    LogNormal { median: Duration, sigma: f64 },
+}
+
+impl Latency {
+    /// Draws the delay of an RPC.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Duration {
+        match *self {
+            Latency::Fixed(d) => d,
+            Latency::Uniform { min, max } => {
+                if max <= min {
+                    return min;
+                }
+                let (min, max) = (min.as_micros() as u64, max.as_micros() as u64);
+                Duration::from_micros(rng.gen_range(min, max + 1))
+            }
+            Latency::LogNormal { median, sigma } => {
+                // Box-Muller, the normal distribution isn't in rand itself.
+                let u1 = 1.0 - rng.gen::<f64>();
+                let u2 = rng.gen::<f64>();
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                Duration::from_secs_f64(median.as_secs_f64() * (sigma * z).exp())
+            }
+        }
+    }
+}
@@ -1,16 +1,30 @@
 #![allow(clippy::new_without_default)]
 
 mod client;
+mod clock;
+mod deadline;
 mod error;
+mod interceptor;
+mod latency;
 #[macro_use]
 mod macros;
 mod network;
 mod server;
+mod stats;
+mod trace;
 
 pub use self::client::{Client, Rpc, RpcHooks};
+pub use self::clock::{Clock, RealClock, SimClock};
+pub use self::deadline::is_cancelled;
 pub use self::error::{Error, Result};
+pub use self::interceptor::{Call, Interceptor, Next};
+pub use self::latency::Latency;
 pub use self::network::Network;
-pub use self::server::{Handler, HandlerFactory, RpcFuture, Server, ServerBuilder};
+pub use self::server::{
+    Handler, HandlerFactory, Overflow, RpcFuture, RpcStream, Server, ServerBuilder, StreamHandler,
+};
+pub use self::stats::{Histogram, MethodStats, Stats};
+pub use self::trace::{read_trace, Record};
 
 #[cfg(test)]
 pub mod tests {
@@ -21,6 +35,7 @@ pub mod tests {
 
     use futures::channel::oneshot::Canceled;
     use futures::executor::{block_on, ThreadPool};
+    use futures::future::FutureExt;
     use futures::stream::StreamExt;
     use futures_timer::Delay;
     use prost_derive::Message;
@@ -34,6 +49,10 @@ pub mod tests {
             rpc handler2(JunkArgs) returns (JunkReply);
             rpc handler3(JunkArgs) returns (JunkReply);
             rpc handler4(JunkArgs) returns (JunkReply);
+            rpc handler5(JunkArgs) returns (JunkReply);
+            rpc handler6(JunkArgs) returns (JunkReply);
+            /// Counts down from x.
+            rpc countdown(JunkArgs) returns (stream JunkReply);
         }
     }
     use junk::{add_service, Client as JunkClient, Service as Junk};
@@ -53,6 +72,7 @@ pub mod tests {
     #[derive(Default)]
     struct JunkInner {
         log2: Vec<i64>,
+        cancelled: Vec<i64>,
     }
     #[derive(Clone)]
     struct JunkService {
@@ -84,6 +104,26 @@ pub mod tests {
                 x: "pointer".to_owned(),
             })
         }
+        async fn handler5(&self, args: JunkArgs) -> Result<JunkReply> {
+            // runs until its caller gives up.
+            while !is_cancelled() {
+                Delay::new(Duration::from_millis(10)).await;
+            }
+            self.inner.lock().unwrap().cancelled.push(args.x);
+            Err(Error::Timeout)
+        }
+        async fn handler6(&self, args: JunkArgs) -> Result<JunkReply> {
+            panic!("handler6-{}", args.x)
+        }
+        async fn countdown(&self, args: JunkArgs) -> RpcStream<Result<JunkReply>> {
+            if args.x < 0 {
+                return futures::stream::once(async { Err(Error::Other("negative".to_owned())) })
+                    .boxed();
+            }
+            futures::stream::iter((0..=args.x).rev())
+                .map(|x| Ok(JunkReply { x: x.to_string() }))
+                .boxed()
+        }
     }
 
     fn init_logger() {
@@ -536,4 +576,532 @@ pub mod tests {
         block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
         assert_eq!(reply.x, format!("handler2-{}", i));
     }
+
+    #[test]
+    fn test_latency() {
+        init_logger();
+
+        let mut rng = rand::thread_rng();
+        let ms = Duration::from_millis;
+        assert_eq!(Latency::Fixed(ms(5)).sample(&mut rng), ms(5));
+        let uniform = Latency::Uniform {
+            min: ms(10),
+            max: ms(20),
+        };
+        let log_normal = Latency::LogNormal {
+            median: ms(10),
+            sigma: 0.5,
+        };
+        let mut below = 0;
+        for _ in 0..1000 {
+            let d = uniform.sample(&mut rng);
+            assert!(ms(10) <= d && d <= ms(20), "{:?}", d);
+            if log_normal.sample(&mut rng) < ms(10) {
+                below += 1;
+            }
+        }
+        assert!(400 < below && below < 600, "{} below the median", below);
+
+        // only the link with a latency is slow.
+        let (net, _, _) = junk_suit();
+        let slow = JunkClient::new(net.create_client("slow".to_owned()));
+        let fast = JunkClient::new(net.create_client("fast".to_owned()));
+        for name in &["slow", "fast"] {
+            net.connect(name, "test_server");
+            net.enable(name, true);
+        }
+        net.set_latency("slow", Latency::Fixed(ms(200)));
+        let call = |client: &JunkClient| {
+            let start = Instant::now();
+            block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+            start.elapsed()
+        };
+        assert!(call(&slow) >= ms(200));
+        assert!(call(&fast) < ms(200));
+        net.clear_latency("slow");
+        assert!(call(&slow) < ms(200));
+    }
+
+    #[test]
+    fn test_drop_rate() {
+        init_logger();
+
+        let (net, _, junk) = junk_suit();
+        let lossy = JunkClient::new(net.create_client("lossy".to_owned()));
+        let fine = JunkClient::new(net.create_client("fine".to_owned()));
+        for name in &["lossy", "fine"] {
+            net.connect(name, "test_server");
+            net.enable(name, true);
+        }
+        net.set_drop_rate("lossy", 0.5);
+
+        let mut failed = 0;
+        for i in 0..100 {
+            if block_on(async { lossy.handler2(&JunkArgs { x: i }).await }).is_err() {
+                failed += 1;
+            }
+            block_on(async { fine.handler2(&JunkArgs { x: -i }).await.unwrap() });
+        }
+        // a call is lost with its request or its reply, 3 in 4.
+        assert!(50 < failed && failed < 95, "{} of 100 failed", failed);
+        // lost replies were handled anyway.
+        let handled = junk.inner.lock().unwrap().log2.len();
+        assert!(handled > 100 + (100 - failed), "{} handled", handled);
+
+        net.set_drop_rate("lossy", 1.0);
+        block_on(async { lossy.handler4(&JunkArgs::default()).await.unwrap_err() });
+        net.set_drop_rate("lossy", 0.0);
+        block_on(async { lossy.handler4(&JunkArgs::default()).await.unwrap() });
+    }
+
+    #[test]
+    fn test_deadline() {
+        init_logger();
+
+        let (net, _, junk) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        let deadline = Duration::from_millis(100);
+        let call = |x| {
+            let start = Instant::now();
+            let res = block_on(async {
+                client
+                    .with_deadline(deadline)
+                    .handler5(&JunkArgs { x })
+                    .await
+            });
+            (res, start.elapsed())
+        };
+
+        // a call to a dead server doesn't park for seconds.
+        net.set_long_delays(true);
+        let (res, took) = call(1);
+        assert_eq!(res, Err(Error::Timeout));
+        assert!(took < Duration::from_secs(1), "{:?}", took);
+
+        // a handler sees its caller gave up.
+        net.enable("test_client", true);
+        let (res, took) = call(2);
+        assert_eq!(res, Err(Error::Timeout));
+        assert!(took < Duration::from_secs(1), "{:?}", took);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(junk.inner.lock().unwrap().cancelled, vec![2]);
+
+        // calls without a deadline are left alone.
+        let reply = block_on(async { client.handler2(&JunkArgs { x: 3 }).await.unwrap() });
+        assert_eq!(reply.x, "handler2-3");
+    }
+
+    #[test]
+    fn test_stream() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let replies = block_on(client.countdown(&JunkArgs { x: 3 }).collect::<Vec<_>>());
+        let xs: Vec<_> = replies.into_iter().map(|r| r.unwrap().x).collect();
+        assert_eq!(xs, vec!["3", "2", "1", "0"]);
+        let replies = block_on(client.countdown(&JunkArgs { x: -1 }).collect::<Vec<_>>());
+        assert_eq!(replies, vec![Err(Error::Other("negative".to_owned()))]);
+        assert_eq!(server.count(), 2);
+
+        // streams and calls don't mix.
+        let replies = block_on(
+            server
+                .dispatch_stream("junk.handler4", &[])
+                .collect::<Vec<_>>(),
+        );
+        assert!(matches!(replies[..], [Err(Error::Unimplemented(_))]));
+        block_on(server.dispatch("junk.countdown", &[])).unwrap_err();
+
+        // a stream over a broken link ends with an error.
+        net.enable("test_client", false);
+        let replies = block_on(client.countdown(&JunkArgs { x: 3 }).collect::<Vec<_>>());
+        assert_eq!(replies, vec![Err(Error::Timeout)]);
+        net.enable("test_client", true);
+        net.set_drop_rate("test_client", 1.0);
+        let replies = block_on(client.countdown(&JunkArgs { x: 3 }).collect::<Vec<_>>());
+        assert_eq!(replies, vec![Err(Error::Timeout)]);
+    }
+
+    #[test]
+    fn test_interceptors() {
+        init_logger();
+
+        let (net, _, junk) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        // records the calls, and fails the handler2 of 13 without running it.
+        let calls = Arc::new(Mutex::new(vec![]));
+        let calls_ = calls.clone();
+        net.add_interceptor(move |call: &Call, req: Vec<u8>, next: Next| {
+            calls_
+                .lock()
+                .unwrap()
+                .push((call.client_name.clone(), call.fq_name));
+            let args: JunkArgs = labcodec::decode(&req).unwrap();
+            if call.fq_name == "junk.handler2" && args.x == 13 {
+                return futures::future::err::<Vec<u8>, _>(Error::Other("unlucky".to_owned()))
+                    .boxed();
+            }
+            next(req)
+        });
+        // runs after the first one, and changes the replies.
+        net.add_interceptor(|_: &Call, req: Vec<u8>, next: Next| {
+            next(req)
+                .map(|rsp| {
+                    let mut reply: JunkReply = labcodec::decode(&rsp?).unwrap();
+                    reply.x += "!";
+                    let mut rsp = vec![];
+                    labcodec::encode(&reply, &mut rsp).unwrap();
+                    Ok(rsp)
+                })
+                .boxed()
+        });
+
+        let reply = block_on(client.handler2(&JunkArgs { x: 12 })).unwrap();
+        assert_eq!(reply.x, "handler2-12!");
+        let err = block_on(client.handler2(&JunkArgs { x: 13 })).unwrap_err();
+        assert_eq!(err, Error::Other("unlucky".to_owned()));
+        let reply = block_on(client.handler4(&JunkArgs::default())).unwrap();
+        assert_eq!(reply.x, "pointer!");
+        assert_eq!(junk.inner.lock().unwrap().log2, vec![12]);
+        let client_name = "test_client".to_owned();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                (client_name.clone(), "junk.handler2"),
+                (client_name.clone(), "junk.handler2"),
+                (client_name, "junk.handler4"),
+            ]
+        );
+
+        net.clear_interceptors();
+        let reply = block_on(client.handler4(&JunkArgs::default())).unwrap();
+        assert_eq!(reply.x, "pointer");
+    }
+
+    #[test]
+    fn test_seed() {
+        init_logger();
+
+        let outcomes = |seed| {
+            let net = Network::with_seed(seed);
+            assert_eq!(net.seed(), seed);
+            let mut builder = ServerBuilder::new("test_server".to_owned());
+            add_service(JunkService::new(), &mut builder).unwrap();
+            net.add_server(builder.build());
+            let client = JunkClient::new(net.create_client("test_client".to_owned()));
+            net.connect("test_client", "test_server");
+            net.enable("test_client", true);
+            net.set_drop_rate("test_client", 0.5);
+            (0..20)
+                .map(|i| block_on(async { client.handler2(&JunkArgs { x: i }).await.is_ok() }))
+                .collect::<Vec<_>>()
+        };
+        // the same drops, one call after another.
+        assert_eq!(outcomes(7), outcomes(7));
+        assert_ne!(outcomes(7), outcomes(8));
+    }
+
+    #[test]
+    fn test_duplicate_rate() {
+        init_logger();
+
+        let (net, _, junk) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        net.set_duplicate_rate(1.0);
+
+        for i in 0..10 {
+            let reply = block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
+            assert_eq!(reply.x, format!("handler2-{}", i));
+        }
+        thread::sleep(Duration::from_millis(200));
+        let mut log2 = junk.inner.lock().unwrap().log2.clone();
+        log2.sort();
+        let twice: Vec<_> = (0..10).flat_map(|i| vec![i, i]).collect();
+        assert_eq!(log2, twice);
+        assert_eq!(net.count("test_server"), 20);
+
+        net.set_duplicate_rate(0.0);
+        block_on(async { client.handler2(&JunkArgs { x: 10 }).await.unwrap() });
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(junk.inner.lock().unwrap().log2.len(), 21);
+    }
+
+    // requests of a client whose replies are disabled are still handled.
+    #[test]
+    fn test_enable_replies() {
+        init_logger();
+
+        let (net, _, junk) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        net.enable_replies("test_client", false);
+        let err = block_on(async { client.handler2(&JunkArgs { x: 1 }).await.unwrap_err() });
+        assert_eq!(err, Error::Timeout);
+        assert_eq!(junk.inner.lock().unwrap().log2, vec![1]);
+
+        net.enable_replies("test_client", true);
+        let reply = block_on(async { client.handler2(&JunkArgs { x: 2 }).await.unwrap() });
+        assert_eq!(reply.x, "handler2-2");
+        assert_eq!(junk.inner.lock().unwrap().log2, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_stats() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        for i in 0..3 {
+            block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
+        }
+        block_on(async { client.handler4(&JunkArgs { x: 0 }).await.unwrap() });
+        net.enable("test_client", false);
+        block_on(async { client.handler4(&JunkArgs { x: 0 }).await.unwrap_err() });
+
+        let stats = net.stats();
+        let handler2 = &stats.methods["junk.handler2"];
+        assert_eq!((handler2.calls, handler2.errors), (3, 0));
+        assert_eq!(handler2.reply_bytes, 3 * "handler2-0".len() as u64 + 3 * 2);
+        assert_eq!(handler2.latency.count(), 3);
+        let handler4 = &stats.methods["junk.handler4"];
+        assert_eq!((handler4.calls, handler4.errors), (2, 1));
+        assert_eq!(stats.service("junk").calls, 5);
+        assert_eq!(stats.service("other").calls, 0);
+        assert_eq!(stats.total().errors, 1);
+
+        net.reset_stats();
+        assert_eq!(net.stats(), Stats::default());
+    }
+
+    #[test]
+    fn test_capture() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let path = std::env::temp_dir().join(format!("labrpc-capture-{}", std::process::id()));
+        net.capture(&path).unwrap();
+        block_on(async { client.handler2(&JunkArgs { x: 7 }).await.unwrap() });
+        net.enable("test_client", false);
+        block_on(async { client.handler2(&JunkArgs { x: 8 }).await.unwrap_err() });
+        net.stop_capture();
+        net.enable("test_client", true);
+        block_on(async { client.handler2(&JunkArgs { x: 9 }).await.unwrap() });
+
+        let records = read_trace(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        let (ok, err) = (&records[0], &records[1]);
+        assert_eq!(ok.client_name, "test_client");
+        assert_eq!(ok.server_name.as_deref(), Some("test_server"));
+        assert_eq!(ok.fq_name, "junk.handler2");
+        let args: JunkArgs = labcodec::decode(&ok.request).unwrap();
+        assert_eq!(args.x, 7);
+        let reply: JunkReply = labcodec::decode(ok.outcome.as_ref().unwrap()).unwrap();
+        assert_eq!(reply.x, "handler2-7");
+        assert_eq!(err.outcome, Err(Error::Timeout.to_string()));
+        assert!(err.at >= ok.at + ok.latency);
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        init_logger();
+
+        for &overflow in &[Overflow::Reject, Overflow::Queue] {
+            let net = Network::new();
+            let mut builder = ServerBuilder::new("test_server".to_owned());
+            builder.max_in_flight(1, overflow);
+            add_service(JunkService::new(), &mut builder).unwrap();
+            let server = builder.build();
+            net.add_server(server.clone());
+            let client = JunkClient::new(net.create_client("test_client".to_owned()));
+            net.connect("test_client", "test_server");
+            net.enable("test_client", true);
+
+            // handler5 takes the only slot until its caller gives up.
+            let slow = client.with_deadline(Duration::from_millis(300));
+            let slow = thread::spawn(move || block_on(slow.handler5(&JunkArgs { x: 1 })));
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(server.in_flight(), 1);
+
+            let start = Instant::now();
+            let res = block_on(client.handler4(&JunkArgs::default()));
+            match overflow {
+                Overflow::Reject => assert_eq!(res, Err(Error::Overloaded)),
+                Overflow::Queue => {
+                    assert!(res.is_ok(), "{:?}", res);
+                    assert!(start.elapsed() >= Duration::from_millis(150));
+                }
+            }
+            assert_eq!(slow.join().unwrap(), Err(Error::Timeout));
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(server.in_flight(), 0);
+            block_on(client.handler4(&JunkArgs::default())).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sim_clock() {
+        init_logger();
+
+        let clock = SimClock::new();
+        let net = Network::with_clock(1, clock.clone());
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        net.add_server(builder.build());
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+
+        // runs call on a thread, moving the clock whenever all sleep.
+        let run = |call: RpcFuture<Result<JunkReply>>| {
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || tx.send(block_on(call)).unwrap());
+            loop {
+                if let Ok(res) = rx.try_recv() {
+                    return res;
+                }
+                if !clock.advance_to_next() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        };
+
+        // a disabled client times out after a while, in simulated time.
+        let res = run(client.handler2(&JunkArgs { x: 1 }));
+        assert_eq!(res, Err(Error::Timeout));
+        assert!(clock.now() < Duration::from_millis(100));
+
+        // a deadline of a minute passes in no time.
+        net.enable("test_client", true);
+        let start = Instant::now();
+        let before = clock.now();
+        let slow = client.with_deadline(Duration::from_secs(60));
+        let res = run(slow.handler5(&JunkArgs { x: 2 }));
+        assert_eq!(res, Err(Error::Timeout));
+        assert!(clock.now() >= before + Duration::from_secs(60));
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "{:?}",
+            start.elapsed()
+        );
+
+        let res = run(client.handler2(&JunkArgs { x: 3 }));
+        assert_eq!(res.unwrap().x, "handler2-3");
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        init_logger();
+
+        let (net, _, junk) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        // "handler2-1" and its header.
+        net.set_max_message_size(Some(8));
+        let err = block_on(client.handler2(&JunkArgs { x: 1 })).unwrap_err();
+        assert_eq!(err, Error::TooLarge { size: 12, max: 8 });
+        assert_eq!(junk.inner.lock().unwrap().log2, vec![1]);
+
+        // a reply of 2 fragments, one of them lost half the time.
+        net.set_fragmentation(Some(0.5));
+        let ok = (0..40)
+            .filter(|&x| block_on(client.handler2(&JunkArgs { x })).is_ok())
+            .count();
+        assert!(ok > 0 && ok < 30, "{} of 40 went through", ok);
+
+        net.set_fragmentation(Some(0.0));
+        block_on(client.handler2(&JunkArgs { x: 1 })).unwrap();
+        net.set_max_message_size(None);
+        net.set_fragmentation(Some(1.0));
+        block_on(client.handler2(&JunkArgs { x: 1 })).unwrap();
+    }
+
+    #[test]
+    fn test_corruption_rate() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        // every reply is corrupt, it's lost or decodes to something else.
+        net.set_corruption_rate(1.0);
+        for x in 0..20 {
+            let res = block_on(client.handler2(&JunkArgs { x }));
+            let good = JunkReply {
+                x: format!("handler2-{}", x),
+            };
+            assert_ne!(res, Ok(good));
+        }
+
+        net.set_corruption_rate(0.0);
+        let reply = block_on(client.handler2(&JunkArgs { x: 1 })).unwrap();
+        assert_eq!(reply.x, "handler2-1");
+    }
+
+    #[test]
+    fn test_handler_panic() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        // more panics than the network has threads.
+        for x in 0..5 {
+            let err = block_on(client.handler6(&JunkArgs { x })).unwrap_err();
+            assert_eq!(err, Error::Panicked(format!("handler6-{}", x)));
+        }
+        assert_eq!(server.panics(), 5);
+        let stats = net.stats();
+        assert_eq!(stats.methods["junk.handler6"].panics, 5);
+        assert_eq!(stats.methods["junk.handler6"].errors, 5);
+
+        let reply = block_on(client.handler2(&JunkArgs { x: 1 })).unwrap();
+        assert_eq!(reply.x, "handler2-1");
+    }
+
+    #[test]
+    fn test_drop_method() {
+        init_logger();
+
+        let (net, _, junk) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        net.drop_method("junk.handler2", 1.0);
+        for x in 0..5 {
+            let err = block_on(client.handler2(&JunkArgs { x })).unwrap_err();
+            assert_eq!(err, Error::Timeout);
+            block_on(client.handler4(&JunkArgs { x })).unwrap();
+        }
+        assert!(junk.inner.lock().unwrap().log2.is_empty());
+
+        net.drop_method("junk.handler2", 0.0);
+        block_on(client.handler2(&JunkArgs { x: 1 })).unwrap();
+        assert_eq!(junk.inner.lock().unwrap().log2, vec![1]);
+    }
 }
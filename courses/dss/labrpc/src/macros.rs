@@ -8,7 +8,7 @@ macro_rules! service {
         service $svc_name:ident {
             $(
                 $(#[$method_attr:meta])*
-                rpc $method_name:ident($input:ty) returns ($output:ty);
+                rpc $method_name:ident($input:ty) returns ($($output:tt)+);
             )*
         }
     ) => {
@@ -25,7 +25,7 @@ macro_rules! service {
             pub trait Service: Clone + Send + 'static {
                 $(
                     $(#[$method_attr])*
-                    async fn $method_name(&self, req: $input) -> $crate::Result<$output>;
+                    async fn $method_name(&self, req: $input) -> $crate::__rpc_output!($($output)+);
                 )*
             }
 
@@ -38,15 +38,20 @@ macro_rules! service {
                     Client { client }
                 }
 
+                /// A copy of this client whose calls time out after `timeout`.
+                pub fn with_deadline(&self, timeout: ::std::time::Duration) -> Client {
+                    Client { client: self.client.with_deadline(timeout) }
+                }
+
                 pub fn spawn<F>(&self, f: F)
                 where F: __futures::Future<Output = ()> + Send + 'static
                 {
                     self.client.worker.spawn_ok(f);
                 }
 
-                $(pub fn $method_name(&self, args: &$input) -> $crate::RpcFuture<$crate::Result<$output>> {
+                $(pub fn $method_name(&self, args: &$input) -> $crate::__rpc_call!(@type $($output)+) {
                     let fq_name = concat!(stringify!($svc_name), ".", stringify!($method_name));
-                    self.client.call(fq_name, args)
+                    $crate::__rpc_call!(self.client, fq_name, args, $($output)+)
                 })*
             }
 
@@ -56,29 +61,13 @@ macro_rules! service {
                     svc: Mutex<S>,
                 }
                 impl<S: Service> $crate::HandlerFactory for Factory<S> {
+                    #[allow(unused_variables)]
                     fn handler(&self, name: &'static str) -> Box<$crate::Handler> {
                         let s = self.svc.lock().unwrap().clone();
                         Box::new(move |req| {
                             match name {
                                 $(stringify!($method_name) => {
-                                    let request = match labcodec::decode(req) {
-                                        Ok(req) => req,
-                                        Err(e) => return Box::pin(__futures::future::err(
-                                            $crate::Error::Decode(e)
-                                        )),
-                                    };
-                                    Box::pin(async move {
-                                        let f = s.$method_name(request);
-                                        let resp = f.await;
-                                        match resp {
-                                            Ok(resp) => {
-                                                let mut rsp = vec![];
-                                                labcodec::encode(&resp, &mut rsp).map_err($crate::Error::Encode)?;
-                                                Ok(rsp)
-                                            }
-                                            Err(e) => Err(e),
-                                        }
-                                    })
+                                    $crate::__rpc_handler!(unary, s, req, $method_name, $($output)+)
                                 })*
                                 other => {
                                     Box::pin(__futures::future::err(
@@ -90,6 +79,21 @@ macro_rules! service {
                             }
                         })
                     }
+
+                    #[allow(unused_variables)]
+                    fn stream_handler(&self, name: &'static str) -> Box<$crate::StreamHandler> {
+                        let s = self.svc.lock().unwrap().clone();
+                        Box::new(move |req| {
+                            match name {
+                                $(stringify!($method_name) => {
+                                    $crate::__rpc_handler!(stream, s, req, $method_name, $($output)+)
+                                })*
+                                other => {
+                                    $crate::__rpc_handler!(@unknown stream, other, stringify!($svc_name))
+                                }
+                            }
+                        })
+                    }
                 }
 
                 let fact = Factory {
@@ -101,3 +105,87 @@ macro_rules! service {
         }
     };
 }
+
+// A method `returns (stream T)` is server-streaming: its handler returns a
+// stream of replies, and its client method a stream of results.
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __rpc_output {
+    (stream $output:ty) => { $crate::RpcStream<$crate::Result<$output>> };
+    ($output:ty) => { $crate::Result<$output> };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __rpc_call {
+    (@type stream $output:ty) => { $crate::RpcStream<$crate::Result<$output>> };
+    (@type $output:ty) => { $crate::RpcFuture<$crate::Result<$output>> };
+    ($client:expr, $fq_name:expr, $args:expr, stream $output:ty) => {
+        $client.call_stream($fq_name, $args)
+    };
+    ($client:expr, $fq_name:expr, $args:expr, $output:ty) => {
+        $client.call($fq_name, $args)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __rpc_handler {
+    (@unknown stream, $name:expr, $svc_name:expr) => {
+        __futures::StreamExt::boxed(__futures::stream::once(__futures::future::err(
+            $crate::Error::Unimplemented(format!("unknown {} in {}", $name, $svc_name)),
+        )))
+    };
+    (unary, $s:ident, $req:ident, $method_name:ident, stream $output:ty) => {
+        Box::pin(__futures::future::err($crate::Error::Unimplemented(
+            format!("{} is a streaming rpc", stringify!($method_name)),
+        )))
+    };
+    (unary, $s:ident, $req:ident, $method_name:ident, $output:ty) => {{
+        let request = match labcodec::decode($req) {
+            Ok(req) => req,
+            Err(e) => return Box::pin(__futures::future::err($crate::Error::Decode(e))),
+        };
+        Box::pin(async move {
+            let f = $s.$method_name(request);
+            let resp = f.await;
+            match resp {
+                Ok(resp) => {
+                    let mut rsp = vec![];
+                    labcodec::encode(&resp, &mut rsp).map_err($crate::Error::Encode)?;
+                    Ok(rsp)
+                }
+                Err(e) => Err(e),
+            }
+        })
+    }};
+    (stream, $s:ident, $req:ident, $method_name:ident, stream $output:ty) => {{
+        let request = match labcodec::decode($req) {
+            Ok(req) => req,
+            Err(e) => {
+                return __futures::StreamExt::boxed(__futures::stream::once(
+                    __futures::future::err($crate::Error::Decode(e)),
+                ))
+            }
+        };
+        let replies = async move { $s.$method_name(request).await };
+        let replies = __futures::FutureExt::flatten_stream(Box::pin(replies));
+        __futures::StreamExt::boxed(__futures::StreamExt::map(
+            replies,
+            |resp| -> $crate::Result<Vec<u8>> {
+                let mut rsp = vec![];
+                labcodec::encode(&resp?, &mut rsp).map_err($crate::Error::Encode)?;
+                Ok(rsp)
+            },
+        ))
+    }};
+    (stream, $s:ident, $req:ident, $method_name:ident, $output:ty) => {
+        __futures::StreamExt::boxed(__futures::stream::once(__futures::future::err(
+            $crate::Error::Unimplemented(format!(
+                "{} is not a streaming rpc",
+                stringify!($method_name)
+            )),
+        )))
+    };
+}
@@ -1,7 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
 use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
@@ -9,29 +11,55 @@ use futures::executor::ThreadPool;
 use futures::future::FutureExt;
 use futures::select;
 use futures::stream::StreamExt;
-use futures_timer::Delay;
 use log::{debug, error};
-use rand::{thread_rng, Rng};
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
 
 use crate::client::{Client, Rpc};
+use crate::clock::{Clock, RealClock};
+use crate::deadline::WithCancel;
 use crate::error::{Error, Result};
-use crate::server::Server;
+use crate::interceptor::{self, Call, Interceptor, Next};
+use crate::latency::Latency;
+use crate::server::{RpcFuture, Server};
+use crate::stats::Stats;
+use crate::trace;
 
 #[derive(Debug)]
 struct EndInfo {
     enabled: bool,
+    replies: bool,
     reliable: bool,
     long_reordering: bool,
+    latency: Option<Latency>,
+    drop_rate: f64,
     server: Option<Server>,
 }
 
+// the size limit of messages.
+#[derive(Clone, Copy, Debug, Default)]
+struct Mtu {
+    max: Option<usize>,
+    // if set, larger messages are split instead of failed, and each
+    // fragment is lost with this chance.
+    fragment_loss: Option<f64>,
+}
+
 struct Endpoints {
     // by client name
     enabled: HashMap<String, bool>,
+    // client names whose replies are lost
+    deaf: HashSet<String>,
     // servers, by name
     servers: HashMap<String, Option<Server>>,
     // client_name -> server_name
     connections: HashMap<String, Option<String>>,
+    // client_name -> the latency of its link, if it has one
+    latency: HashMap<String, Latency>,
+    // client_name -> the chance its link loses a request or a reply
+    drop_rate: HashMap<String, f64>,
+    // fq_name -> the chance a request of the method is lost
+    method_drop_rate: HashMap<String, f64>,
 }
 
 struct NetworkCore {
@@ -40,8 +68,23 @@ struct NetworkCore {
     long_delays: AtomicBool,
     // sometimes delay replies a long time
     long_reordering: AtomicBool,
+    // the chance a request is delivered twice, as f64 bits
+    duplicate_rate: AtomicU64,
+    // the chance a message has a byte flipped, as f64 bits
+    corruption_rate: AtomicU64,
     endpoints: Mutex<Endpoints>,
+    interceptors: Mutex<Vec<Arc<dyn Interceptor>>>,
+    mtu: Mutex<Mtu>,
+    // every delay and timeout is on clock
+    clock: Arc<dyn Clock>,
+    // every random decision is drawn from rng, seeded with seed
+    seed: u64,
+    rng: Mutex<StdRng>,
     count: AtomicUsize,
+    // unary calls, by method
+    stats: Mutex<Stats>,
+    // where calls are recorded, if anywhere
+    capture: Mutex<Option<trace::Writer>>,
     sender: UnboundedSender<Rpc>,
     poller: ThreadPool,
     worker: ThreadPool,
@@ -54,24 +97,54 @@ pub struct Network {
 
 impl Network {
     pub fn new() -> Network {
-        let (net, incoming) = Network::create();
+        Network::with_seed(thread_rng().gen())
+    }
+
+    /// A network whose drops, delays and reorderings are drawn from `seed`.
+    /// They're the same every run as long as the RPCs come in the same
+    /// order, which is up to the threads sending them.
+    pub fn with_seed(seed: u64) -> Network {
+        Network::with_clock(seed, RealClock::new())
+    }
+
+    /// Like `with_seed`, but the network, and its clients, wait on `clock`
+    /// instead of the wall clock, e.g. a `SimClock`.
+    pub fn with_clock<C: Clock>(seed: u64, clock: C) -> Network {
+        let (net, incoming) = Network::create_with(seed, Arc::new(clock));
         net.start(incoming);
         net
     }
 
     pub fn create() -> (Network, UnboundedReceiver<Rpc>) {
+        Network::create_with(thread_rng().gen(), Arc::new(RealClock::new()))
+    }
+
+    fn create_with(seed: u64, clock: Arc<dyn Clock>) -> (Network, UnboundedReceiver<Rpc>) {
         let (sender, incoming) = unbounded();
         let net = Network {
             core: Arc::new(NetworkCore {
                 reliable: AtomicBool::new(true),
                 long_delays: AtomicBool::new(false),
                 long_reordering: AtomicBool::new(false),
+                duplicate_rate: AtomicU64::new(0f64.to_bits()),
+                corruption_rate: AtomicU64::new(0f64.to_bits()),
                 endpoints: Mutex::new(Endpoints {
                     enabled: HashMap::new(),
+                    deaf: HashSet::new(),
                     servers: HashMap::new(),
                     connections: HashMap::new(),
+                    latency: HashMap::new(),
+                    drop_rate: HashMap::new(),
+                    method_drop_rate: HashMap::new(),
                 }),
+                interceptors: Mutex::new(vec![]),
+                mtu: Mutex::new(Mtu::default()),
+                clock,
+                seed,
+                rng: Mutex::new(StdRng::seed_from_u64(seed)),
                 count: AtomicUsize::new(0),
+                stats: Mutex::new(Stats::default()),
+                capture: Mutex::new(None),
                 poller: ThreadPool::builder().pool_size(2).create().unwrap(),
                 worker: ThreadPool::new().unwrap(),
                 sender,
@@ -85,10 +158,29 @@ impl Network {
         let network = self.clone();
         self.core.poller.spawn_ok(async move {
             while let Some(mut rpc) = incoming.next().await {
-                let resp = rpc.take_resp_sender().unwrap();
                 let net = network.clone();
+                if let Some(items) = rpc.items.take() {
+                    network
+                        .core
+                        .poller
+                        .spawn_ok(async move { net.process_stream(rpc, items).await });
+                    continue;
+                }
+                let resp = rpc.take_resp_sender().unwrap();
                 network.core.poller.spawn_ok(async move {
+                    let fq_name = rpc.fq_name;
+                    let sent = rpc.req.as_ref().map_or(0, Vec::len);
+                    let start = net.now();
+                    let captured = net.captured(&rpc);
                     let res = net.process_rpc(rpc).await;
+                    net.core
+                        .stats
+                        .lock()
+                        .unwrap()
+                        .observe(fq_name, sent, &res, net.now() - start);
+                    if let Some(sent) = captured {
+                        net.record(&sent, &res);
+                    }
                     if let Err(e) = resp.send(res) {
                         error!("fail to send resp: {:?}", e);
                     }
@@ -119,6 +211,8 @@ impl Network {
             sender,
             worker: self.core.worker.clone(),
             hooks: Arc::new(Mutex::new(None)),
+            deadline: None,
+            clock: self.core.clock.clone(),
         }
     }
 
@@ -141,6 +235,136 @@ impl Network {
         eps.enabled.insert(client_name.to_owned(), enabled);
     }
 
+    /// Enable/disable the replies to a Client, but not its requests: with
+    /// replies disabled, its RPCs reach the server and are handled, and the
+    /// Client times out anyway. So a link that only works from server a to
+    /// b is a's Client of b enabled with its replies disabled, and b's
+    /// Client of a disabled.
+    pub fn enable_replies(&self, client_name: &str, enabled: bool) {
+        debug!(
+            "replies to client {} are {}",
+            client_name,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        let mut eps = self.core.endpoints.lock().unwrap();
+        if enabled {
+            eps.deaf.remove(client_name);
+        } else {
+            eps.deaf.insert(client_name.to_owned());
+        }
+    }
+
+    /// Wraps every call delivered from now on in `interceptor`, after the
+    /// ones added before.
+    pub fn add_interceptor<I: Interceptor>(&self, interceptor: I) {
+        self.core
+            .interceptors
+            .lock()
+            .unwrap()
+            .push(Arc::new(interceptor));
+    }
+
+    pub fn clear_interceptors(&self) {
+        self.core.interceptors.lock().unwrap().clear();
+    }
+
+    // the handler of a call to server, behind the interceptors.
+    fn intercepted(&self, client_name: &str, server: &Server, fq_name: &'static str) -> Next {
+        let call = Call {
+            client_name: client_name.to_owned(),
+            server_name: server.core.name.clone(),
+            fq_name,
+        };
+        let server = server.clone();
+        let handler: Next = Box::new(move |req| server.dispatch(fq_name, &req));
+        let interceptors = self.core.interceptors.lock().unwrap().clone();
+        interceptor::chain(interceptors, call, handler)
+    }
+
+    /// Loses the requests of the method `fq_name`, e.g.
+    /// `"raft.install_snapshot"`, with probability `rate`, whoever sends
+    /// them, and leaves other methods be. 0 undoes it.
+    pub fn drop_method(&self, fq_name: &str, rate: f64) {
+        assert!(
+            (0.0..=1.0).contains(&rate),
+            "drop rate {} not in [0, 1]",
+            rate
+        );
+        let mut eps = self.core.endpoints.lock().unwrap();
+        if rate > 0.0 {
+            eps.method_drop_rate.insert(fq_name.to_owned(), rate);
+        } else {
+            eps.method_drop_rate.remove(fq_name);
+        }
+    }
+
+    /// Fails requests and replies of more than `max` bytes with
+    /// `Error::TooLarge`, or splits them, see `set_fragmentation`. No limit
+    /// with None, the default.
+    pub fn set_max_message_size(&self, max: Option<usize>) {
+        assert_ne!(max, Some(0), "messages can't be empty");
+        self.core.mtu.lock().unwrap().max = max;
+    }
+
+    /// Splits messages over the size limit into fragments of the limit
+    /// instead of failing them, and loses each fragment with chance `loss`.
+    /// A message arrives only if all of its fragments do, so the larger, the
+    /// likelier it's lost. Turned off with None.
+    pub fn set_fragmentation(&self, loss: Option<f64>) {
+        if let Some(p) = loss {
+            assert!(
+                (0.0..=1.0).contains(&p),
+                "fragment loss {} not in [0, 1]",
+                p
+            );
+        }
+        self.core.mtu.lock().unwrap().fragment_loss = loss;
+    }
+
+    // whether a message of len bytes gets through whole, it may be too
+    // large, or lose a fragment.
+    fn transmit(&self, len: usize) -> Result<bool> {
+        let mtu = *self.core.mtu.lock().unwrap();
+        let max = match mtu.max {
+            Some(max) if len > max => max,
+            _ => return Ok(true),
+        };
+        match mtu.fragment_loss {
+            None => Err(Error::TooLarge { size: len, max }),
+            Some(loss) => {
+                let fragments = len.div_ceil(max);
+                let mut rng = self.rng();
+                Ok((0..fragments).all(|_| !rng.gen_bool(loss)))
+            }
+        }
+    }
+
+    /// Delays every RPC a Client sends by a sample of `latency`, on top of
+    /// the short delays of an unreliable network.
+    pub fn set_latency(&self, client_name: &str, latency: Latency) {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        eps.latency.insert(client_name.to_owned(), latency);
+    }
+
+    /// Takes the latency of a Client away.
+    pub fn clear_latency(&self, client_name: &str) {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        eps.latency.remove(client_name);
+    }
+
+    /// Makes a Client lose a request, and a reply, with probability `p`
+    /// each, whether the network is reliable or not. A `p` of 0 makes its
+    /// link as reliable as the network again.
+    pub fn set_drop_rate(&self, client_name: &str, p: f64) {
+        assert!((0.0..=1.0).contains(&p), "drop rate {} not in [0, 1]", p);
+        let mut eps = self.core.endpoints.lock().unwrap();
+        if p > 0.0 {
+            eps.drop_rate.insert(client_name.to_owned(), p);
+        } else {
+            eps.drop_rate.remove(client_name);
+        }
+    }
+
     pub fn set_reliable(&self, yes: bool) {
         self.core.reliable.store(yes, Ordering::Release);
     }
@@ -149,6 +373,47 @@ impl Network {
         self.core.long_reordering.store(yes, Ordering::Release);
     }
 
+    /// Delivers a request twice with probability `p`: the server handles a
+    /// copy of it too, up to 100ms later, and the reply to the copy is lost.
+    pub fn set_duplicate_rate(&self, p: f64) {
+        assert!(
+            (0.0..=1.0).contains(&p),
+            "duplicate rate {} not in [0, 1]",
+            p
+        );
+        self.core
+            .duplicate_rate
+            .store(p.to_bits(), Ordering::Release);
+    }
+
+    /// Flips a byte in a delivered request or reply with probability `p`,
+    /// so it fails to decode, or decodes to something else. Only fields
+    /// under a checksum tell, see `raft::checksum`, faulty terms or indexes
+    /// pass for good ones.
+    pub fn set_corruption_rate(&self, p: f64) {
+        assert!(
+            (0.0..=1.0).contains(&p),
+            "corruption rate {} not in [0, 1]",
+            p
+        );
+        self.core
+            .corruption_rate
+            .store(p.to_bits(), Ordering::Release);
+    }
+
+    // flips a random byte of data, as often as the corruption rate says.
+    fn corrupt(&self, data: &mut [u8]) {
+        let rate = f64::from_bits(self.core.corruption_rate.load(Ordering::Acquire));
+        if data.is_empty() || rate <= 0.0 {
+            return;
+        }
+        let mut rng = self.rng();
+        if rng.gen_bool(rate) {
+            let i = rng.gen_range(0, data.len());
+            data[i] ^= rng.gen_range(1, 256) as u8;
+        }
+    }
+
     pub fn set_long_delays(&self, yes: bool) {
         self.core.long_delays.store(yes, Ordering::Release);
     }
@@ -158,10 +423,76 @@ impl Network {
         eps.servers[server_name].as_ref().unwrap().count()
     }
 
+    /// The clock of the network, to wait on the same time as it does.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.core.clock.clone()
+    }
+
+    fn now(&self) -> Duration {
+        self.core.clock.now()
+    }
+
+    fn sleep(&self, d: Duration) -> RpcFuture<()> {
+        self.core.clock.sleep(d)
+    }
+
+    /// The seed of the network, to make another with the same draws.
+    pub fn seed(&self) -> u64 {
+        self.core.seed
+    }
+
+    fn rng(&self) -> MutexGuard<'_, StdRng> {
+        self.core.rng.lock().unwrap()
+    }
+
     pub fn total_count(&self) -> usize {
         self.core.count.load(Ordering::Relaxed)
     }
 
+    /// Counts, bytes, errors and latencies of the unary calls so far, by
+    /// method. Streaming calls are only in `total_count`.
+    pub fn stats(&self) -> Stats {
+        self.core.stats.lock().unwrap().clone()
+    }
+
+    pub fn reset_stats(&self) {
+        *self.core.stats.lock().unwrap() = Stats::default();
+    }
+
+    /// Records every unary call from now on to the file at `path`, its
+    /// request, reply or error, and timing, see `read_trace`. Replaces the
+    /// file, and any capture going on.
+    pub fn capture<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let writer = trace::Writer::create(path, self.now())?;
+        *self.core.capture.lock().unwrap() = Some(writer);
+        Ok(())
+    }
+
+    pub fn stop_capture(&self) {
+        *self.core.capture.lock().unwrap() = None;
+    }
+
+    // what a capture records of rpc as it is sent, if one is going on.
+    fn captured(&self, rpc: &Rpc) -> Option<trace::Sent> {
+        self.core.capture.lock().unwrap().as_ref()?;
+        let eps = self.core.endpoints.lock().unwrap();
+        Some(trace::Sent {
+            at: self.now(),
+            client_name: rpc.client_name.clone(),
+            server_name: eps.connections.get(&rpc.client_name).cloned().flatten(),
+            fq_name: rpc.fq_name,
+            request: rpc.req.clone()?,
+        })
+    }
+
+    fn record(&self, sent: &trace::Sent, res: &Result<Vec<u8>>) {
+        if let Some(writer) = self.core.capture.lock().unwrap().as_mut() {
+            if let Err(e) = writer.write(sent, res, self.now()) {
+                error!("fail to capture rpc: {:?}", e);
+            }
+        }
+    }
+
     fn end_info(&self, client_name: &str) -> EndInfo {
         let eps = self.core.endpoints.lock().unwrap();
         let mut server = None;
@@ -170,8 +501,11 @@ impl Network {
         }
         EndInfo {
             enabled: eps.enabled[client_name],
+            replies: !eps.deaf.contains(client_name),
             reliable: self.core.reliable.load(Ordering::Acquire),
             long_reordering: self.core.long_reordering.load(Ordering::Acquire),
+            latency: eps.latency.get(client_name).copied(),
+            drop_rate: eps.drop_rate.get(client_name).copied().unwrap_or(0.0),
             server,
         }
     }
@@ -184,74 +518,217 @@ impl Network {
             })
     }
 
-    async fn process_rpc(&self, rpc: Rpc) -> Result<Vec<u8>> {
+    async fn process_rpc(&self, mut rpc: Rpc) -> Result<Vec<u8>> {
         self.core.count.fetch_add(1, Ordering::Relaxed);
         let network = self.clone();
         let end_info = self.end_info(&rpc.client_name);
         debug!("{:?} process with {:?}", rpc, end_info);
         let EndInfo {
             enabled,
+            replies,
             reliable,
             long_reordering,
+            latency,
+            drop_rate,
             server,
         } = end_info;
 
         match (enabled, server) {
             (true, Some(server)) => {
-                let short_delay = if !reliable {
-                    // short delay
-                    let ms = thread_rng().gen::<u64>() % 27;
-                    Some(ms)
-                } else {
-                    None
+                // a request too large fails right away, like one that
+                // can't be encoded.
+                let arrives = self.transmit(rpc.req.as_ref().map_or(0, Vec::len))?;
+                if let Some(req) = rpc.req.as_mut() {
+                    self.corrupt(req);
+                }
+                let duplicate_rate =
+                    f64::from_bits(self.core.duplicate_rate.load(Ordering::Acquire));
+                let method_drop_rate = {
+                    let eps = self.core.endpoints.lock().unwrap();
+                    eps.method_drop_rate.get(rpc.fq_name).copied()
+                };
+                // every draw at once, the generator can't be held across an await.
+                let (short_delay, delay, drop_req, drop_link, drop_reply, reordering, dup) = {
+                    let mut rng = self.rng();
+                    let short_delay = if !reliable {
+                        // short delay
+                        let ms = rng.gen::<u64>() % 27;
+                        Some(ms)
+                    } else {
+                        None
+                    };
+                    let delay = match latency {
+                        Some(latency) => {
+                            let ms = latency.sample(&mut *rng).as_millis() as u64;
+                            Some(short_delay.unwrap_or(0) + ms)
+                        }
+                        None => short_delay,
+                    };
+                    let drop_req = !reliable && (rng.gen::<u64>() % 1000) < 100;
+                    let drop_link = rng.gen_bool(drop_rate)
+                        || !arrives
+                        || matches!(method_drop_rate, Some(p) if rng.gen_bool(p));
+                    let drop_reply = !replies
+                        || (!reliable && rng.gen::<u64>() % 1000 < 100)
+                        || rng.gen_bool(drop_rate);
+                    let reordering = if long_reordering && rng.gen_range(0, 900) < 600i32 {
+                        // delay the response for a while
+                        let upper_bound: u64 = 1 + rng.gen_range(0, 2000);
+                        Some(200 + rng.gen_range(0, upper_bound))
+                    } else {
+                        None
+                    };
+                    let dup = rng.gen_bool(duplicate_rate);
+                    (
+                        short_delay,
+                        delay,
+                        drop_req,
+                        drop_link,
+                        drop_reply,
+                        reordering,
+                        dup,
+                    )
                 };
 
-                if !reliable && (thread_rng().gen::<u64>() % 1000) < 100 {
+                if drop_req {
                     // drop the request, return as if timeout
-                    Delay::new(Duration::from_secs(short_delay.unwrap())).await;
+                    self.sleep(Duration::from_secs(short_delay.unwrap())).await;
                     return Err(Error::Timeout);
                 }
-
-                let drop_reply = !reliable && thread_rng().gen::<u64>() % 1000 < 100;
-                let long_reordering = if long_reordering && thread_rng().gen_range(0, 900) < 600i32
-                {
-                    // delay the response for a while
-                    let upper_bound: u64 = 1 + thread_rng().gen_range(0, 2000);
-                    Some(200 + thread_rng().gen_range(0, upper_bound))
-                } else {
-                    None
-                };
+                if drop_link {
+                    // the link lost the request
+                    self.sleep(Duration::from_millis(delay.unwrap_or(0))).await;
+                    return Err(Error::Timeout);
+                }
+                if dup {
+                    self.duplicate(&rpc, server.clone());
+                }
 
                 // Dispatch
-                process_rpc(
-                    short_delay,
-                    drop_reply,
-                    long_reordering,
-                    rpc,
-                    network,
-                    server,
-                )
-                .await
+                process_rpc(delay, drop_reply, reordering, rpc, network, server).await
             }
             _ => {
                 // simulate no reply and eventual timeout.
                 let ms = if self.core.long_delays.load(Ordering::Acquire) {
                     // let Raft tests check that leader doesn't send
                     // RPCs synchronously.
-                    thread_rng().gen::<u64>() % 7000
+                    self.rng().gen::<u64>() % 7000
                 } else {
                     // many kv tests require the client to try each
                     // server in fairly rapid succession.
-                    thread_rng().gen::<u64>() % 100
+                    self.rng().gen::<u64>() % 100
                 };
 
                 debug!("{:?} delay {}ms then timeout", rpc, ms);
-                Delay::new(Duration::from_millis(ms)).await;
+                let mut wait = Duration::from_millis(ms);
+                if let Some(deadline) = rpc.deadline {
+                    wait = wait.min(deadline.saturating_sub(self.now()));
+                }
+                self.sleep(wait).await;
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    // delivers the replies of a streaming rpc to items, one by one. Like a
+    // dropped reply, a fault cuts the stream with an error.
+    async fn process_stream(&self, mut rpc: Rpc, items: UnboundedSender<Result<Vec<u8>>>) {
+        self.core.count.fetch_add(1, Ordering::Relaxed);
+        let end_info = self.end_info(&rpc.client_name);
+        debug!("{:?} stream with {:?}", rpc, end_info);
+        let EndInfo {
+            enabled,
+            reliable,
+            latency,
+            drop_rate,
+            server,
+            ..
+        } = end_info;
+        // the delay of a message over the link, and whether it's lost.
+        let draw = |loss: u64| {
+            let mut rng = self.rng();
+            let mut ms = if !reliable { rng.gen::<u64>() % 27 } else { 0 };
+            if let Some(latency) = latency {
+                ms += latency.sample(&mut *rng).as_millis() as u64;
+            }
+            let lost = (!reliable && rng.gen::<u64>() % 1000 < loss) || rng.gen_bool(drop_rate);
+            (Duration::from_millis(ms), lost)
+        };
+
+        let server = match (enabled, server) {
+            (true, Some(server)) => server,
+            _ => {
+                let ms = self.rng().gen::<u64>() % 100;
+                self.sleep(Duration::from_millis(ms)).await;
+                let _ = items.unbounded_send(Err(Error::Timeout));
+                return;
+            }
+        };
+        let (delay, lost) = draw(100);
+        self.sleep(delay).await;
+        if lost {
+            let _ = items.unbounded_send(Err(Error::Timeout));
+            return;
+        }
+        let fq_name = rpc.fq_name;
+        let req = rpc.req.take().unwrap();
+        if let Some(hooks) = rpc.hooks.lock().unwrap().as_ref() {
+            if let Err(e) = hooks.before_dispatch(fq_name, &req) {
+                let _ = items.unbounded_send(Err(e));
+                return;
+            }
+        }
+
+        let mut replies = server.dispatch_stream(fq_name, &req);
+        loop {
+            let item = select! {
+                item = replies.next().fuse() => item,
+                _ = server_dead(
+                    Duration::from_millis(100),
+                    self.clone(),
+                    &rpc.client_name,
+                    &server.core.name,
+                    server.core.id,
+                ).fuse() => Some(Err(Error::Stopped)),
+            };
+            let item = match item {
+                Some(item) => item,
+                None => return,
+            };
+            // a stream is cut more rarely than a reply is dropped.
+            let (delay, lost) = draw(10);
+            self.sleep(delay).await;
+            let item = if self.is_server_dead(&rpc.client_name, &server.core.name, server.core.id) {
+                Err(Error::Stopped)
+            } else if lost {
                 Err(Error::Timeout)
+            } else {
+                item
+            };
+            let last = item.is_err();
+            if items.unbounded_send(item).is_err() || last {
+                // the caller dropped the stream, or it ended.
+                return;
             }
         }
     }
 
+    // delivers a copy of rpc to server a little later.
+    fn duplicate(&self, rpc: &Rpc, server: Server) {
+        let net = self.clone();
+        let client_name = rpc.client_name.clone();
+        let fq_name = rpc.fq_name;
+        let req = rpc.req.clone().unwrap();
+        let ms = self.rng().gen_range(0, 100);
+        debug!("{:?} duplicate in {}ms", rpc, ms);
+        self.core.poller.spawn_ok(async move {
+            net.sleep(Duration::from_millis(ms)).await;
+            if !net.is_server_dead(&client_name, &server.core.name, server.core.id) {
+                let _ = net.intercepted(&client_name, &server, fq_name)(req).await;
+            }
+        });
+    }
+
     /// Spawns a future to run on this net framework.
     pub fn spawn<F>(&self, f: F)
     where
@@ -279,17 +756,22 @@ async fn process_rpc(
 ) -> Result<Vec<u8>> {
     // Dispatch ===============================================================
     if let Some(delay) = delay {
-        Delay::new(Duration::from_millis(delay)).await;
+        network.sleep(Duration::from_millis(delay)).await;
     }
     // We has finished the delay, take it out to prevent polling
     // twice.
     delay.take();
+    if rpc.expired(network.now()) {
+        // the caller gave up before the request got there.
+        return Err(Error::Timeout);
+    }
 
     let fq_name = rpc.fq_name;
     let req = rpc.req.take().unwrap();
     if let Some(hooks) = rpc.hooks.lock().unwrap().as_ref() {
         hooks.before_dispatch(fq_name, &req)?;
     }
+    let handler = network.intercepted(&rpc.client_name, &server, fq_name);
 
     // Execute the request (call the RPC handler) in a separate thread so that
     // we can periodically check if the server has been killed and the RPC
@@ -300,7 +782,7 @@ async fn process_rpc(
     // to an Append, but the server persisted the update into the old Persister.
     // config.go is careful to call DeleteServer() before superseding the Persister.
     let resp = select! {
-        res = server.dispatch(fq_name, &req).fuse() => res,
+        res = WithCancel::new(handler(req), rpc.cancel.clone()).fuse() => res,
         _ = server_dead(
             Duration::from_millis(100),
             network.clone(),
@@ -310,7 +792,7 @@ async fn process_rpc(
         ).fuse() => Err(Error::Stopped),
     };
 
-    let resp = if let Some(hooks) = rpc.hooks.lock().unwrap().as_ref() {
+    let mut resp = if let Some(hooks) = rpc.hooks.lock().unwrap().as_ref() {
         hooks.after_dispatch(fq_name, resp)?
     } else {
         resp?
@@ -323,15 +805,21 @@ async fn process_rpc(
     if network.is_server_dead(client_name, server_name, server_id) {
         return Err(Error::Stopped);
     }
-    if drop_reply {
+    if drop_reply || !network.transmit(resp.len())? {
         // drop the reply, return as if timeout.
         return Err(Error::Timeout);
     }
 
+    if rpc.expired(network.now()) {
+        return Err(Error::Timeout);
+    }
+
+    network.corrupt(&mut resp);
+
     // Reordering =============================================================
     if let Some(reordering) = long_reordering {
         debug!("{:?} next long reordering {}ms", rpc, reordering);
-        Delay::new(Duration::from_millis(reordering)).await;
+        network.sleep(Duration::from_millis(reordering)).await;
         Ok(resp)
     } else {
         Ok(resp)
@@ -349,7 +837,7 @@ async fn server_dead(
     server_id: usize,
 ) {
     loop {
-        Delay::new(interval).await;
+        net.sleep(interval).await;
         if net.is_server_dead(&client_name, &server_name, server_id) {
             debug!("{:?} is dead", server_name);
             return;
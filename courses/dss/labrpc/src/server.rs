@@ -1,9 +1,17 @@
+use std::any::Any;
 use std::collections::hash_map::{Entry, HashMap};
 use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
 
-use futures::future::{self, BoxFuture};
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{self, BoxStream, StreamExt};
+
+use log::error;
 
 use crate::error::{Error, Result};
 
@@ -11,16 +19,39 @@ static ID_ALLOC: AtomicUsize = AtomicUsize::new(0);
 
 pub type RpcFuture<T> = BoxFuture<'static, T>;
 
+/// The replies of a server-streaming RPC.
+pub type RpcStream<T> = BoxStream<'static, T>;
+
 pub type Handler = dyn FnOnce(&[u8]) -> RpcFuture<Result<Vec<u8>>>;
 
+pub type StreamHandler = dyn FnOnce(&[u8]) -> RpcStream<Result<Vec<u8>>>;
+
 pub trait HandlerFactory: Sync + Send + 'static {
     fn handler(&self, name: &'static str) -> Box<Handler>;
+
+    fn stream_handler(&self, name: &'static str) -> Box<StreamHandler> {
+        Box::new(move |_| {
+            let err = Error::Unimplemented(format!("unknown stream {}", name));
+            stream::once(future::err(err)).boxed()
+        })
+    }
+}
+
+/// What a server does with a call beyond its limit, see
+/// `ServerBuilder::max_in_flight`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Waits for a call in flight to finish.
+    Queue,
+    /// Fails with `Error::Overloaded`.
+    Reject,
 }
 
 pub struct ServerBuilder {
     name: String,
     // Service name -> service methods
     pub(crate) services: HashMap<&'static str, Box<dyn HandlerFactory>>,
+    limit: Option<(usize, Overflow)>,
 }
 
 impl ServerBuilder {
@@ -28,9 +59,17 @@ impl ServerBuilder {
         ServerBuilder {
             name,
             services: HashMap::new(),
+            limit: None,
         }
     }
 
+    /// Runs at most `max` handlers at a time, unary and streaming alike, a
+    /// stream being in flight until it ends. Unlimited by default.
+    pub fn max_in_flight(&mut self, max: usize, overflow: Overflow) {
+        assert!(max > 0, "a server must run at least one handler");
+        self.limit = Some((max, overflow));
+    }
+
     pub fn add_service(
         &mut self,
         service_name: &'static str,
@@ -55,17 +94,87 @@ impl ServerBuilder {
                 services: self.services,
                 id: ID_ALLOC.fetch_add(1, Ordering::Relaxed),
                 count: AtomicUsize::new(0),
+                panics: AtomicUsize::new(0),
+                limit: self.limit.map(|(max, overflow)| Limit {
+                    max,
+                    overflow,
+                    state: Mutex::new(LimitState::default()),
+                }),
             }),
         }
     }
 }
 
+struct Limit {
+    max: usize,
+    overflow: Overflow,
+    state: Mutex<LimitState>,
+}
+
+#[derive(Default)]
+struct LimitState {
+    in_flight: usize,
+    // the calls queued
+    waiters: Vec<Waker>,
+}
+
+// a slot of the limit, free again once dropped.
+struct Permit(Arc<ServerCore>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.0.limit.as_ref().unwrap().state.lock().unwrap();
+        state.in_flight -= 1;
+        // a waiter may be gone, let them all try again.
+        for waker in state.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+// resolves to a permit once a slot is free.
+struct Acquire(Arc<ServerCore>);
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Permit> {
+        let limit = self.0.limit.as_ref().unwrap();
+        let mut state = limit.state.lock().unwrap();
+        if state.in_flight < limit.max {
+            state.in_flight += 1;
+            Poll::Ready(Permit(self.0.clone()))
+        } else {
+            state.waiters.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
 pub(crate) struct ServerCore {
     pub(crate) name: String,
     pub(crate) id: usize,
 
     pub(crate) services: HashMap<&'static str, Box<dyn HandlerFactory>>,
     pub(crate) count: AtomicUsize,
+    panics: AtomicUsize,
+    limit: Option<Limit>,
+}
+
+impl ServerCore {
+    // the error of a call to fq_name whose handler panicked with payload.
+    fn panicked(&self, fq_name: &str, payload: Box<dyn Any + Send>) -> Error {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+        let msg = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "unknown panic".to_owned()
+        };
+        error!("{} handler of {} panicked: {}", fq_name, self.name, msg);
+        Error::Panicked(msg)
+    }
 }
 
 #[derive(Clone)]
@@ -82,7 +191,48 @@ impl Server {
         &self.core.name
     }
 
+    /// Handlers that panicked. The server goes on, the callers get
+    /// `Error::Panicked`.
+    pub fn panics(&self) -> usize {
+        self.core.panics.load(Ordering::Relaxed)
+    }
+
+    /// Handlers running now, or streams not ended, queued calls aside.
+    pub fn in_flight(&self) -> usize {
+        match &self.core.limit {
+            Some(limit) => limit.state.lock().unwrap().in_flight,
+            None => 0,
+        }
+    }
+
+    // the slot of a call, once free, or None if the server has no limit.
+    fn acquire(&self) -> Option<Result<BoxFuture<'static, Permit>>> {
+        let limit = self.core.limit.as_ref()?;
+        let acquire = Acquire(self.core.clone());
+        Some(match limit.overflow {
+            Overflow::Queue => Ok(acquire.boxed()),
+            Overflow::Reject => match acquire.now_or_never() {
+                Some(permit) => Ok(future::ready(permit).boxed()),
+                None => Err(Error::Overloaded),
+            },
+        })
+    }
+
     pub(crate) fn dispatch(&self, fq_name: &'static str, req: &[u8]) -> RpcFuture<Result<Vec<u8>>> {
+        match self.acquire() {
+            None => self.handle(fq_name, req),
+            Some(Err(e)) => Box::pin(future::err(e)),
+            Some(Ok(acquire)) => {
+                let handler = self.handle(fq_name, req);
+                Box::pin(async move {
+                    let _permit = acquire.await;
+                    handler.await
+                })
+            }
+        }
+    }
+
+    fn handle(&self, fq_name: &'static str, req: &[u8]) -> RpcFuture<Result<Vec<u8>>> {
         self.core.count.fetch_add(1, Ordering::Relaxed);
         let mut names = fq_name.split('.');
         let service_name = match names.next() {
@@ -104,8 +254,15 @@ impl Server {
             }
         };
         if let Some(factory) = self.core.services.get(service_name) {
-            let handle = factory.handler(method_name);
-            handle(req)
+            // a handler may panic making its future, or polling it.
+            let core = self.core.clone();
+            match panic::catch_unwind(AssertUnwindSafe(|| factory.handler(method_name)(req))) {
+                Ok(handler) => AssertUnwindSafe(handler)
+                    .catch_unwind()
+                    .map(move |res| res.unwrap_or_else(|p| Err(core.panicked(fq_name, p))))
+                    .boxed(),
+                Err(p) => Box::pin(future::err(core.panicked(fq_name, p))),
+            }
         } else {
             Box::pin(future::err(Error::Unimplemented(format!(
                 "unknown {}",
@@ -113,6 +270,58 @@ impl Server {
             ))))
         }
     }
+
+    pub(crate) fn dispatch_stream(
+        &self,
+        fq_name: &'static str,
+        req: &[u8],
+    ) -> RpcStream<Result<Vec<u8>>> {
+        match self.acquire() {
+            None => self.handle_stream(fq_name, req),
+            Some(Err(e)) => stream::once(future::err(e)).boxed(),
+            Some(Ok(acquire)) => {
+                let replies = self.handle_stream(fq_name, req);
+                acquire
+                    .map(|permit| {
+                        // the slot is taken until the stream is dropped.
+                        replies.map(move |reply| {
+                            let _ = &permit;
+                            reply
+                        })
+                    })
+                    .flatten_stream()
+                    .boxed()
+            }
+        }
+    }
+
+    fn handle_stream(&self, fq_name: &'static str, req: &[u8]) -> RpcStream<Result<Vec<u8>>> {
+        self.core.count.fetch_add(1, Ordering::Relaxed);
+        let mut names = fq_name.split('.');
+        match (names.next(), names.next()) {
+            (Some(service_name), Some(method_name)) => match self.core.services.get(service_name) {
+                Some(factory) => {
+                    let core = self.core.clone();
+                    let replies = factory.stream_handler(method_name);
+                    match panic::catch_unwind(AssertUnwindSafe(|| replies(req))) {
+                        // the stream ends after a panic.
+                        Ok(replies) => AssertUnwindSafe(replies)
+                            .catch_unwind()
+                            .map(move |res| res.unwrap_or_else(|p| Err(core.panicked(fq_name, p))))
+                            .boxed(),
+                        Err(p) => stream::once(future::err(core.panicked(fq_name, p))).boxed(),
+                    }
+                }
+                None => unknown_stream(fq_name),
+            },
+            _ => unknown_stream(fq_name),
+        }
+    }
+}
+
+fn unknown_stream(fq_name: &str) -> RpcStream<Result<Vec<u8>>> {
+    let err = Error::Unimplemented(format!("unknown {}", fq_name));
+    stream::once(future::err(err)).boxed()
 }
 
 impl fmt::Debug for Server {
@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+
+/// Number of buckets of a [`Histogram`].
+const BUCKETS: usize = 24;
+
+/// A latency histogram with exponential buckets.
+///
+/// Bucket `i` counts observations below `2^i` microseconds, the last one
+/// counts everything else.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            sum: Duration::default(),
+            max: Duration::default(),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&mut self, d: Duration) {
+        let micros = d.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += d;
+        self.max = self.max.max(d);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::default();
+        }
+        self.sum / self.count as u32
+    }
+
+    /// Adds the observations of `other`.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (n, m) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *n += m;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+
+    /// An upper bound of the `q` quantile, `0 <= q <= 1`.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let target = (q * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target && seen > 0 {
+                if i == BUCKETS - 1 {
+                    return self.max;
+                }
+                return Duration::from_micros(1 << i).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// The calls of one method, or of several merged.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MethodStats {
+    pub calls: u64,
+    /// Calls that returned an error, timeouts included.
+    pub errors: u64,
+    /// Calls whose handler panicked, among the errors.
+    pub panics: u64,
+    pub request_bytes: u64,
+    pub reply_bytes: u64,
+    /// From sending the request to the reply, or the error, as the caller
+    /// saw it.
+    pub latency: Histogram,
+}
+
+impl MethodStats {
+    pub(crate) fn observe(
+        &mut self,
+        request_bytes: usize,
+        res: &Result<Vec<u8>>,
+        latency: Duration,
+    ) {
+        self.calls += 1;
+        self.request_bytes += request_bytes as u64;
+        match res {
+            Ok(reply) => self.reply_bytes += reply.len() as u64,
+            Err(e) => {
+                self.errors += 1;
+                if let Error::Panicked(_) = e {
+                    self.panics += 1;
+                }
+            }
+        }
+        self.latency.observe(latency);
+    }
+
+    /// Adds the calls of `other`.
+    pub fn merge(&mut self, other: &MethodStats) {
+        self.calls += other.calls;
+        self.errors += other.errors;
+        self.panics += other.panics;
+        self.request_bytes += other.request_bytes;
+        self.reply_bytes += other.reply_bytes;
+        self.latency.merge(&other.latency);
+    }
+}
+
+/// The unary calls a network carried, by method, see `Network::stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// By `service.method`.
+    pub methods: BTreeMap<&'static str, MethodStats>,
+}
+
+impl Stats {
+    pub(crate) fn observe(
+        &mut self,
+        fq_name: &'static str,
+        request_bytes: usize,
+        res: &Result<Vec<u8>>,
+        latency: Duration,
+    ) {
+        self.methods
+            .entry(fq_name)
+            .or_default()
+            .observe(request_bytes, res, latency);
+    }
+
+    /// The calls of all methods of `service`.
+    pub fn service(&self, service: &str) -> MethodStats {
+        let mut stats = MethodStats::default();
+        for (fq_name, m) in &self.methods {
+            if fq_name.split('.').next() == Some(service) {
+                stats.merge(m);
+            }
+        }
+        stats
+    }
+
+    /// The calls of all methods.
+    pub fn total(&self) -> MethodStats {
+        let mut stats = MethodStats::default();
+        for m in self.methods.values() {
+            stats.merge(m);
+        }
+        stats
+    }
+}
@@ -0,0 +1,171 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::Result;
+
+/// A unary call the network carried, see `Network::capture`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// When the call was sent, from the start of the capture.
+    pub at: Duration,
+    /// How long until its reply, or its error.
+    pub latency: Duration,
+    pub client_name: String,
+    /// The server the client was connected to, if any.
+    pub server_name: Option<String>,
+    /// `service.method`.
+    pub fq_name: String,
+    /// The encoded request.
+    pub request: Vec<u8>,
+    /// The encoded reply, or the error, formatted.
+    pub outcome: std::result::Result<Vec<u8>, String>,
+}
+
+// A trace file is a sequence of records, each one written at once as:
+//
+//   u32 length of the rest of the record
+//   u64 at, u64 latency, in microseconds
+//   client_name, server_name (empty for none), fq_name, request
+//   u8 0 then reply, or u8 1 then error
+//
+// where strings and bytes are a u32 length then the bytes, all integers
+// little endian.
+
+/// A call as it was sent.
+pub(crate) struct Sent {
+    pub(crate) at: Duration,
+    pub(crate) client_name: String,
+    pub(crate) server_name: Option<String>,
+    pub(crate) fq_name: &'static str,
+    pub(crate) request: Vec<u8>,
+}
+
+/// Appends the calls of a network to a file.
+pub(crate) struct Writer {
+    file: File,
+    // the time of the network's clock it was created
+    start: Duration,
+}
+
+impl Writer {
+    pub(crate) fn create<P: AsRef<Path>>(path: P, now: Duration) -> io::Result<Writer> {
+        Ok(Writer {
+            file: File::create(path)?,
+            start: now,
+        })
+    }
+
+    /// Appends `sent`, which returned `res` at `now`.
+    pub(crate) fn write(
+        &mut self,
+        sent: &Sent,
+        res: &Result<Vec<u8>>,
+        now: Duration,
+    ) -> io::Result<()> {
+        let mut buf = vec![0; 4];
+        let at = sent.at.saturating_sub(self.start);
+        buf.extend_from_slice(&(at.as_micros() as u64).to_le_bytes());
+        let latency = now.saturating_sub(sent.at);
+        buf.extend_from_slice(&(latency.as_micros() as u64).to_le_bytes());
+        put_bytes(&mut buf, sent.client_name.as_bytes());
+        let server_name = sent.server_name.as_deref().unwrap_or("");
+        put_bytes(&mut buf, server_name.as_bytes());
+        put_bytes(&mut buf, sent.fq_name.as_bytes());
+        put_bytes(&mut buf, &sent.request);
+        match res {
+            Ok(reply) => {
+                buf.push(0);
+                put_bytes(&mut buf, reply);
+            }
+            Err(e) => {
+                buf.push(1);
+                put_bytes(&mut buf, e.to_string().as_bytes());
+            }
+        }
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_le_bytes());
+        // a whole record at a time, a test may panic at any point.
+        self.file.write_all(&buf)
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Reads the records of a trace written by `Network::capture`, in the order
+/// the calls returned. A record cut short at the end of the file, e.g. by a
+/// crash, is left out.
+pub fn read_trace<P: AsRef<Path>>(path: P) -> io::Result<Vec<Record>> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    let mut records = vec![];
+    let mut rest = &data[..];
+    while rest.len() >= 4 {
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        if rest.len() < 4 + len {
+            break;
+        }
+        let mut r = Cursor(&rest[4..4 + len]);
+        records.push(
+            r.record().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "malformed trace record")
+            })?,
+        );
+        rest = &rest[4 + len..];
+    }
+    Ok(records)
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        Some(self.take(len as usize)?.to_vec())
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?).ok()
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let at = Duration::from_micros(self.u64()?);
+        let latency = Duration::from_micros(self.u64()?);
+        let client_name = self.string()?;
+        let server_name = Some(self.string()?).filter(|s| !s.is_empty());
+        let fq_name = self.string()?;
+        let request = self.bytes()?;
+        let outcome = match self.take(1)?[0] {
+            0 => Ok(self.bytes()?),
+            1 => Err(self.string()?),
+            _ => return None,
+        };
+        Some(Record {
+            at,
+            latency,
+            client_name,
+            server_name,
+            fq_name,
+            request,
+            outcome,
+        })
+    }
+}
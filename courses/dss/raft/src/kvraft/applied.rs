@@ -0,0 +1,85 @@
+//! Waiting for the applied index to reach an index.
+//!
+//! Reads served by a server on its own, follower reads at a read index or
+//! reads of a session that must see its own writes, can only be served once
+//! the server applied up to some index. `AppliedIndex` is advanced by the
+//! apply loop, and RPC handlers wait on it without polling.
+
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+
+#[derive(Default)]
+struct Inner {
+    applied: u64,
+    waiting: Vec<(u64, oneshot::Sender<()>)>,
+}
+
+/// The index applied so far. Clones share it.
+#[derive(Clone, Default)]
+pub struct AppliedIndex {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl AppliedIndex {
+    pub fn new() -> AppliedIndex {
+        AppliedIndex::default()
+    }
+
+    pub fn get(&self) -> u64 {
+        self.inner.lock().unwrap().applied
+    }
+
+    /// Everything up to `index` is applied, e.g. by an entry or a snapshot.
+    /// Wakes those waiting for it. Never goes back.
+    pub fn advance(&self, index: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if index <= inner.applied {
+            return;
+        }
+        inner.applied = index;
+        let (ready, waiting) = std::mem::take(&mut inner.waiting)
+            .into_iter()
+            .partition(|(i, _)| *i <= index);
+        inner.waiting = waiting;
+        drop(inner);
+        for (_, tx) in ready {
+            let _ = tx.send(());
+        }
+    }
+
+    /// Resolves once everything up to `index` is applied. Fails, with
+    /// `Canceled`, only if the index is dropped first.
+    pub fn wait_for(&self, index: u64) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.inner.lock().unwrap();
+        if index <= inner.applied {
+            let _ = tx.send(());
+        } else {
+            inner.waiting.push((index, tx));
+        }
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_applied_index() {
+        let applied = AppliedIndex::new();
+        let mut ready = applied.wait_for(0);
+        assert_eq!(ready.try_recv().unwrap(), Some(()));
+        let mut at3 = applied.wait_for(3);
+        let mut at5 = applied.clone().wait_for(5);
+        applied.advance(4);
+        assert_eq!(applied.get(), 4);
+        assert_eq!(at3.try_recv().unwrap(), Some(()));
+        assert_eq!(at5.try_recv().unwrap(), None);
+        applied.advance(2);
+        assert_eq!(applied.get(), 4);
+        applied.advance(5);
+        assert_eq!(at5.try_recv().unwrap(), Some(()));
+    }
+}
@@ -0,0 +1,125 @@
+//! Applying ops on disjoint keys in parallel.
+//!
+//! A single apply loop applies one op at a time, which bounds the throughput
+//! of a server with many clients. Ops on different keys commute, so only the
+//! ops on the same key need to be applied in log order. `ApplyPool` runs ops
+//! on a few workers, every key on the same worker, so ops on a key run in
+//! the order they're submitted and ops on other keys run alongside.
+//!
+//! Keep the keys of each worker in its own shard of the store, so workers
+//! don't contend. Anything else, an op on several keys, a snapshot, the
+//! revision counter or the sessions, must see every op before it applied:
+//! call `barrier` first, or do it in the apply loop before submitting.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Workers applying the ops on a key in order.
+pub struct ApplyPool {
+    workers: Vec<Sender<Job>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl ApplyPool {
+    /// Starts `workers` workers, at least 1.
+    pub fn new(workers: usize) -> ApplyPool {
+        let (workers, handles) = (0..workers.max(1))
+            .map(|i| {
+                let (tx, rx) = channel::<Job>();
+                let handle = thread::Builder::new()
+                    .name(format!("apply-{}", i))
+                    .spawn(move || rx.into_iter().for_each(|job| job()))
+                    .unwrap();
+                (tx, handle)
+            })
+            .unzip();
+        ApplyPool { workers, handles }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// The worker of `key`, the same on every server, so a store can be
+    /// sharded by it.
+    pub fn shard(&self, key: &str) -> usize {
+        // `DefaultHasher::new` always hashes the same.
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.workers.len() as u64) as usize
+    }
+
+    /// Runs `job`, an op on `key`, after the jobs submitted on the key
+    /// before.
+    pub fn execute<F>(&self, key: &str, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shard = self.shard(key);
+        self.workers[shard].send(Box::new(job)).unwrap();
+    }
+
+    /// Waits until every job submitted so far is done.
+    pub fn barrier(&self) {
+        let (tx, rx) = channel();
+        for worker in &self.workers {
+            let tx = tx.clone();
+            worker.send(Box::new(move || tx.send(()).unwrap())).unwrap();
+        }
+        for _ in &self.workers {
+            rx.recv().unwrap();
+        }
+    }
+}
+
+impl Drop for ApplyPool {
+    /// Finishes the jobs submitted and stops the workers.
+    fn drop(&mut self) {
+        self.workers.clear();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn test_apply_pool() {
+        let pool = ApplyPool::new(4);
+        assert_eq!(pool.shard("k"), pool.shard("k"));
+        let applied = Arc::new(Mutex::new(vec![]));
+        for i in 0..100 {
+            let key = format!("k{}", i % 10);
+            let (applied, k) = (applied.clone(), key.clone());
+            pool.execute(&key, move || applied.lock().unwrap().push((k, i)));
+        }
+        pool.barrier();
+        let applied = applied.lock().unwrap().clone();
+        assert_eq!(applied.len(), 100);
+        for k in 0..10 {
+            let key = format!("k{}", k);
+            let ops: Vec<_> = applied
+                .iter()
+                .filter(|(k, _)| *k == key)
+                .map(|&(_, i)| i)
+                .collect();
+            let want: Vec<_> = (0..100).filter(|i| i % 10 == k).collect();
+            assert_eq!(ops, want);
+        }
+
+        let done = Arc::new(Mutex::new(false));
+        let d = done.clone();
+        pool.execute("k", move || *d.lock().unwrap() = true);
+        drop(pool);
+        assert!(*done.lock().unwrap());
+    }
+}
@@ -0,0 +1,114 @@
+//! A log of the ops a server applied.
+//!
+//! When a test finds a history that isn't linearizable, what the clerks saw
+//! is only half of the story. `AuditLog` keeps the last ops a server applied,
+//! in apply order with the entry of each, so the history can be checked
+//! against what the servers did. Only the last `capacity` records are kept,
+//! older ones are dropped and counted.
+
+use std::collections::VecDeque;
+
+/// An op applied by a server.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub client: u64,
+    pub seq: u64,
+    /// "Put", "Append", "Delete", ...
+    pub op: String,
+    /// The key of the op, the first one for an op on several keys.
+    pub key: String,
+    /// The log entry of the op.
+    pub index: u64,
+    pub term: u64,
+}
+
+/// The last records, oldest first.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    capacity: usize,
+    records: VecDeque<AuditRecord>,
+    dropped: u64,
+}
+
+impl AuditLog {
+    /// Keeps the last `capacity` records, none if 0.
+    pub fn new(capacity: usize) -> AuditLog {
+        AuditLog {
+            capacity,
+            records: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Records an op, dropping the oldest record if it's full.
+    pub fn record(&mut self, record: AuditRecord) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+        self.records.push_back(record);
+    }
+
+    /// Up to `limit` records of entries after `index`, oldest first. A
+    /// `limit` of 0 returns them all.
+    pub fn after(&self, index: u64, limit: usize) -> impl Iterator<Item = &AuditRecord> {
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let start = self.records.partition_point(|r| r.index <= index);
+        self.records.range(start..).take(limit)
+    }
+
+    /// The number of records dropped to make room.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(index: u64) -> AuditRecord {
+        AuditRecord {
+            client: 1,
+            seq: index,
+            op: "Put".to_owned(),
+            key: format!("k{}", index),
+            index,
+            term: 1,
+        }
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let mut off = AuditLog::default();
+        off.record(put(1));
+        assert!(off.is_empty());
+
+        let mut log = AuditLog::new(3);
+        for index in 1..=5 {
+            log.record(put(index));
+        }
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.dropped(), 2);
+        let indexes: Vec<_> = log.after(0, 0).map(|r| r.index).collect();
+        assert_eq!(indexes, vec![3, 4, 5]);
+        let page: Vec<_> = log.after(3, 1).cloned().collect();
+        assert_eq!(page, vec![put(4)]);
+        assert_eq!(log.after(5, 0).count(), 0);
+    }
+}
@@ -0,0 +1,100 @@
+//! Exponential backoff with jitter for clerk retries.
+//!
+//! A clerk retrying at a fixed cadence sends as many RPCs to a cluster
+//! without a leader as to one that's busy electing, and many clerks that
+//! failed together retry together. `Backoff` waits twice as long after every
+//! failed try, up to a cap, and picks a random delay below that ceiling
+//! ("full jitter"), so retries of different clerks spread out.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// The delays between retries: doubling from `base` up to `cap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackoffPolicy {
+    pub base: Duration,
+    pub cap: Duration,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> BackoffPolicy {
+        BackoffPolicy {
+            base: Duration::from_millis(10),
+            cap: Duration::from_millis(500),
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// The backoff of a new call, before its first retry.
+    pub fn start(&self) -> Backoff {
+        Backoff {
+            policy: *self,
+            retries: 0,
+        }
+    }
+
+    /// The most a call waits before retry `retry`, counted from 0.
+    pub fn ceiling(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry.min(31)).unwrap_or(u32::MAX);
+        self.base
+            .checked_mul(factor)
+            .map_or(self.cap, |d| d.min(self.cap))
+    }
+}
+
+/// The retries of a call.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    policy: BackoffPolicy,
+    retries: u32,
+}
+
+impl Backoff {
+    /// How long to wait before the next retry, a random delay up to the
+    /// ceiling of the retry.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.policy.ceiling(self.retries).as_micros() as u64;
+        self.retries = self.retries.saturating_add(1);
+        Duration::from_micros(rand::thread_rng().gen_range(0, ceiling + 1))
+    }
+
+    /// The retries so far.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Starts over from `base`, e.g. after a server answered.
+    pub fn reset(&mut self) {
+        self.retries = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let ms = Duration::from_millis;
+        let policy = BackoffPolicy {
+            base: ms(10),
+            cap: ms(100),
+        };
+        let ceilings: Vec<_> = (0..6).map(|r| policy.ceiling(r)).collect();
+        assert_eq!(
+            ceilings,
+            vec![ms(10), ms(20), ms(40), ms(80), ms(100), ms(100)]
+        );
+        assert_eq!(policy.ceiling(u32::MAX), ms(100));
+
+        let mut backoff = policy.start();
+        for retry in 0..10 {
+            assert!(backoff.next_delay() <= policy.ceiling(retry));
+        }
+        assert_eq!(backoff.retries(), 10);
+        backoff.reset();
+        assert!(backoff.next_delay() <= ms(10));
+    }
+}
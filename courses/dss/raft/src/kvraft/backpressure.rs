@@ -0,0 +1,92 @@
+//! A bound on the client operations a server waits on.
+//!
+//! During a partition a leader that can't commit keeps accepting requests,
+//! each parking a reply channel until it finds out it lost leadership. Take a
+//! permit from `Inflight` before proposing an op and hold it until the op is
+//! answered, and reply `Error::Busy` when there's none left, so clerks back
+//! off and retry instead of piling up.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::kvraft::errors::{Error, Result};
+
+/// Counts the operations in flight, up to a limit. Clones share the count.
+#[derive(Clone, Debug)]
+pub struct Inflight {
+    max: usize,
+    count: Arc<AtomicUsize>,
+}
+
+/// An operation in flight, counted until it's dropped.
+#[derive(Debug)]
+pub struct Permit {
+    count: Arc<AtomicUsize>,
+}
+
+impl Inflight {
+    pub fn new(max: usize) -> Inflight {
+        Inflight {
+            max,
+            count: Arc::default(),
+        }
+    }
+
+    /// Takes a permit, or fails with `Error::Busy` if `max` are taken.
+    ///
+    /// It doesn't lock, so it can be called from RPC handlers.
+    pub fn try_acquire(&self) -> Result<Permit> {
+        let mut count = self.count.load(Ordering::Relaxed);
+        loop {
+            if count >= self.max {
+                return Err(Error::Busy);
+            }
+            match self.count.compare_exchange_weak(
+                count,
+                count + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    return Ok(Permit {
+                        count: self.count.clone(),
+                    })
+                }
+                Err(actual) => count = actual,
+            }
+        }
+    }
+
+    /// The number of permits taken.
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inflight() {
+        let inflight = Inflight::new(2);
+        let a = inflight.try_acquire().unwrap();
+        let b = inflight.clone().try_acquire().unwrap();
+        assert_eq!(inflight.try_acquire().unwrap_err(), Error::Busy);
+        assert_eq!(inflight.len(), 2);
+        drop(a);
+        let _c = inflight.try_acquire().unwrap();
+        drop(b);
+        assert_eq!(inflight.len(), 1);
+    }
+}
@@ -0,0 +1,101 @@
+//! Cancelling clerk calls from another thread.
+//!
+//! A deadline gives up on a call at a time fixed when it starts. A
+//! `CancelToken` gives up on it whenever its owner decides to, e.g. when the
+//! caller that waited for it is gone. Clones of a token share it: cancelling
+//! one cancels the calls of all of them.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    // the tasks waiting in `Cancelled`.
+    wakers: Mutex<Vec<Waker>>,
+}
+
+/// Cancels the calls it's passed to, see `Clerk::with_cancel`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Cancels the calls of the token, running and future ones.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        for waker in self.inner.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the token is cancelled.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// The future of `CancelToken::cancelled`.
+#[derive(Debug)]
+pub struct Cancelled {
+    inner: Arc<Inner>,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.inner.cancelled.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        let mut wakers = self.inner.wakers.lock().unwrap();
+        // checked again under the lock, `cancel` may have drained the
+        // wakers in between.
+        if self.inner.cancelled.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_cancel_token() {
+        let token = CancelToken::new();
+        assert!(!token.is_cancelled());
+        let clone = token.clone();
+        let t = thread::spawn(move || block_on(clone.cancelled()));
+        thread::sleep(Duration::from_millis(10));
+        token.cancel();
+        t.join().unwrap();
+        assert!(token.is_cancelled());
+        // cancelled for good.
+        block_on(token.cancelled());
+        assert!(!CancelToken::new().is_cancelled());
+    }
+}
@@ -1,10 +1,513 @@
+use std::collections::VecDeque;
 use std::fmt;
+use std::future::Future;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use futures::executor::block_on;
+use futures::future::{self, Either};
+use futures::stream::{self, Stream};
+use futures_timer::Delay;
+
+use crate::kvraft::backoff::BackoffPolicy;
+use crate::kvraft::cancel::CancelToken;
+use crate::kvraft::coalesce::Coalescer;
+use crate::kvraft::errors::{Error, Result};
+use crate::kvraft::fence::FenceToken;
+use crate::kvraft::history::History;
+use crate::kvraft::leader::LeaderCache;
+use crate::kvraft::limits::Limits;
+use crate::kvraft::metrics::ClerkMetrics;
+use crate::kvraft::retry::RetryBudget;
+use crate::kvraft::snapshot::SnapshotPolicy;
 use crate::proto::kvraftpb::*;
 
+// read by `put_append_async`, where you turn it into a request.
+#[allow(dead_code)]
 enum Op {
     Put(String, String),
+    PutWithTtl(String, String, Duration),
     Append(String, String),
+    // an append replied with the value after it.
+    AppendAndGet(String, String),
+    Delete(String),
+    // a delete if the value is the second one.
+    DeleteIf(String, String),
+    // a delete of the keys in [start, end).
+    DeleteRange(String, String),
+}
+
+/// What a server replied to a write.
+#[derive(Default)]
+struct Written {
+    // the value after an `AppendAndGet`, "" after other ops.
+    value: String,
+    // for a write to a lock key.
+    fence: Option<FenceToken>,
+    // the keys removed by a delete.
+    deleted: u64,
+}
+
+/// A page of a range scan.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanPage {
+    /// key/value pairs in key order.
+    pub kvs: Vec<(String, String)>,
+    /// the start of the next page, `None` if this is the last one.
+    pub next: Option<String>,
+}
+
+/// How fresh a read must be, see `Clerk::get_with`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadConsistency {
+    /// sees every write completed before the read, and the leader confirms
+    /// it still leads with a round of heartbeats, without relying on clocks.
+    Linearizable,
+    /// like `Linearizable`, but a leader holding a lease serves it on its
+    /// own, as long as clocks run at about the same rate. what `get` does.
+    LeaseRead,
+    /// from the state any server applied so far, see `Clerk::get_stale`.
+    Stale,
+}
+
+/// What servers know a clerk by to detect duplicates, see `Clerk::session`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClerkSession {
+    pub client_id: u64,
+    /// the sequence number of the next request of the clerk.
+    pub next_seq: u64,
+}
+
+/// Writes applied atomically, built by `Clerk::batch`.
+pub struct Batch<'a> {
+    clerk: &'a Clerk,
+    ops: Vec<Op>,
+}
+
+impl Batch<'_> {
+    pub fn put(&mut self, key: String, value: String) -> &mut Self {
+        self.ops.push(Op::Put(key, value));
+        self
+    }
+
+    pub fn append(&mut self, key: String, value: String) -> &mut Self {
+        self.ops.push(Op::Append(key, value));
+        self
+    }
+
+    pub fn delete(&mut self, key: String) -> &mut Self {
+        self.ops.push(Op::Delete(key));
+        self
+    }
+
+    /// applies the writes in the order they were added, all or none of
+    /// them. a batch is applied once like a single put.
+    pub fn commit(&mut self) {
+        let ops = std::mem::take(&mut self.ops);
+        if !ops.is_empty() {
+            self.clerk.write_batch(ops)
+        }
+    }
+}
+
+/// Changes to the keys with a prefix, see `Clerk::watch`.
+pub struct Watch<'a> {
+    clerk: &'a Clerk,
+    key_prefix: String,
+    revision: u64,
+}
+
+impl Watch<'_> {
+    /// waits for the next changes, in revision order. returns an empty vec
+    /// if nothing changed for a while. no change is missed or returned
+    /// twice, even if the leader changes in between.
+    pub fn wait_changes(&mut self) -> Vec<KeyChange> {
+        let (changes, revision) = self.clerk.poll_watch(&self.key_prefix, self.revision);
+        self.revision = revision;
+        changes
+    }
+
+    /// the revision the next changes come after.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+/// The keys and values of the service as of a revision, in chunks in key
+/// order, see `Clerk::export`.
+pub struct Export<'a> {
+    clerk: &'a Clerk,
+    // None once the last chunk was returned.
+    after: Option<String>,
+    revision: u64,
+}
+
+impl Export<'_> {
+    /// the revision exported, 0 until the first chunk is fetched.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+impl Iterator for Export<'_> {
+    type Item = Vec<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let after = self.after.take()?;
+        let (kvs, next, revision) = self.clerk.export_chunk(&after, self.revision);
+        self.after = next;
+        self.revision = revision;
+        Some(kvs)
+    }
+}
+
+/// The key/value pairs of a range, fetched a page at a time, see
+/// `Clerk::scan_range`.
+pub struct ScanIter<'a> {
+    clerk: &'a Clerk,
+    end: String,
+    // the start of the page to fetch next, None after the last page.
+    next: Option<String>,
+    page: VecDeque<(String, String)>,
+}
+
+impl Iterator for ScanIter<'_> {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(kv) = self.page.pop_front() {
+                return Some(kv);
+            }
+            let start = self.next.take()?;
+            let page = self.clerk.scan(start, self.end.clone(), SCAN_PAGE);
+            self.page.extend(page.kvs);
+            self.next = page.next;
+        }
+    }
+}
+
+/// The keys of a namespace, see `Clerk::namespace`.
+pub struct Namespaced<'a> {
+    clerk: &'a Clerk,
+    namespace: String,
+}
+
+impl Namespaced<'_> {
+    /// like `Clerk::get`, in the namespace.
+    pub fn get(&self, key: String) -> String {
+        self.clerk.get_in(&self.namespace, key)
+    }
+
+    /// like `Clerk::put`, in the namespace. panics past the size limits of
+    /// the clerk, see `try_put`.
+    pub fn put(&self, key: String, value: String) {
+        self.put_within(key, value, RetryBudget::default()).unwrap()
+    }
+
+    /// like `Clerk::try_put`, in the namespace.
+    pub fn try_put(&self, key: String, value: String) -> Result<()> {
+        self.put_within(key, value, self.clerk.retry_budget())
+    }
+
+    fn put_within(&self, key: String, value: String, budget: RetryBudget) -> Result<()> {
+        self.clerk.limits().check(&key, &value)?;
+        self.clerk
+            .put_append(&self.namespace, Op::Put(key, value), budget)?;
+        Ok(())
+    }
+
+    /// like `Clerk::append`, in the namespace. panics like put, see
+    /// `try_append`.
+    pub fn append(&self, key: String, value: String) {
+        self.append_within(key, value, RetryBudget::default())
+            .unwrap()
+    }
+
+    /// like `Clerk::try_append`, in the namespace.
+    pub fn try_append(&self, key: String, value: String) -> Result<()> {
+        self.append_within(key, value, self.clerk.retry_budget())
+    }
+
+    fn append_within(&self, key: String, value: String, budget: RetryBudget) -> Result<()> {
+        self.clerk.limits().check(&key, &value)?;
+        self.clerk
+            .put_append(&self.namespace, Op::Append(key, value), budget)?;
+        Ok(())
+    }
+
+    /// like `Clerk::delete`, in the namespace. panics if the key is past
+    /// the size limits of the clerk, see `try_delete`.
+    pub fn delete(&self, key: String) {
+        self.delete_within(key, RetryBudget::default()).unwrap()
+    }
+
+    /// like delete, but fails with `Error::KeyTooLarge` if the key is past
+    /// the size limits of the clerk, and with `Error::Unavailable` once
+    /// its retry budget is spent.
+    pub fn try_delete(&self, key: String) -> Result<()> {
+        self.delete_within(key, self.clerk.retry_budget())
+    }
+
+    fn delete_within(&self, key: String, budget: RetryBudget) -> Result<()> {
+        self.clerk.limits().check_key(&key)?;
+        self.clerk
+            .put_append(&self.namespace, Op::Delete(key), budget)?;
+        Ok(())
+    }
+
+    /// the number of keys in the namespace and their size in bytes.
+    //
+    // you can send an RPC with code like this:
+    // let reply = self.clerk.servers[i].namespace_stats(args).unwrap();
+    pub fn stats(&self) -> (u64, u64) {
+        // You will have to modify this function.
+        crate::your_code_here(&self.namespace)
+    }
+
+    /// drops every key of the namespace at once, returns how many.
+    //
+    // you can send an RPC with code like this:
+    // let reply = self.clerk.servers[i].delete_namespace(args).unwrap();
+    pub fn delete_all(&self) -> u64 {
+        // You will have to modify this function.
+        crate::your_code_here(&self.namespace)
+    }
+}
+
+/// Clerk calls that give up at a deadline, see `Clerk::with_deadline`.
+pub struct Deadline<'a> {
+    clerk: &'a Clerk,
+    deadline: Instant,
+}
+
+impl Deadline<'_> {
+    /// like `Clerk::get`, but fails with `Error::Timeout` once the deadline
+    /// passes instead of retrying forever.
+    pub fn get(&self, key: String) -> Result<String> {
+        self.run(self.clerk.get_async(key))
+    }
+
+    /// like `Clerk::try_put`, but fails with `Error::Timeout` once the
+    /// deadline passes. the put may still be applied then.
+    pub fn put(&self, key: String, value: String) -> Result<()> {
+        self.clerk.limits().check(&key, &value)?;
+        let budget = self.clerk.retry_budget();
+        self.run(self.clerk.put_append_async("", Op::Put(key, value), budget))??;
+        Ok(())
+    }
+
+    /// like `Clerk::try_append`, see `put`.
+    pub fn append(&self, key: String, value: String) -> Result<()> {
+        self.clerk.limits().check(&key, &value)?;
+        let budget = self.clerk.retry_budget();
+        self.run(
+            self.clerk
+                .put_append_async("", Op::Append(key, value), budget),
+        )??;
+        Ok(())
+    }
+
+    /// like `Clerk::delete`, see `put`.
+    pub fn delete(&self, key: String) -> Result<()> {
+        self.clerk.limits().check_key(&key)?;
+        let budget = self.clerk.retry_budget();
+        self.run(self.clerk.put_append_async("", Op::Delete(key), budget))??;
+        Ok(())
+    }
+
+    // drives `call` until it's done or the deadline passes, dropping it
+    // then.
+    fn run<F: Future>(&self, call: F) -> Result<F::Output> {
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        match block_on(future::select(Box::pin(call), Delay::new(timeout))) {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Error::Timeout),
+        }
+    }
+}
+
+/// Clerk calls that stop when a token is cancelled, see
+/// `Clerk::with_cancel`.
+pub struct Cancellable<'a> {
+    clerk: &'a Clerk,
+    token: CancelToken,
+}
+
+impl Cancellable<'_> {
+    /// like `Clerk::get`, but fails with `Error::Cancelled` once the token
+    /// is cancelled instead of retrying forever.
+    pub fn get(&self, key: String) -> Result<String> {
+        self.run(self.clerk.get_async(key))
+    }
+
+    /// like `Clerk::try_put`, but fails with `Error::Cancelled` once the
+    /// token is cancelled. the put may still be applied then, but once at
+    /// most.
+    pub fn put(&self, key: String, value: String) -> Result<()> {
+        self.clerk.limits().check(&key, &value)?;
+        let budget = self.clerk.retry_budget();
+        self.run(self.clerk.put_append_async("", Op::Put(key, value), budget))??;
+        Ok(())
+    }
+
+    /// like `Clerk::try_append`, see `put`.
+    pub fn append(&self, key: String, value: String) -> Result<()> {
+        self.clerk.limits().check(&key, &value)?;
+        let budget = self.clerk.retry_budget();
+        self.run(
+            self.clerk
+                .put_append_async("", Op::Append(key, value), budget),
+        )??;
+        Ok(())
+    }
+
+    /// like `Clerk::delete`, see `put`.
+    pub fn delete(&self, key: String) -> Result<()> {
+        self.clerk.limits().check_key(&key)?;
+        let budget = self.clerk.retry_budget();
+        self.run(self.clerk.put_append_async("", Op::Delete(key), budget))??;
+        Ok(())
+    }
+
+    // drives `call` until it's done or the token is cancelled, dropping it
+    // then, with the RPC it waits for.
+    fn run<F: Future>(&self, call: F) -> Result<F::Output> {
+        if self.token.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        match block_on(future::select(Box::pin(call), self.token.cancelled())) {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Error::Cancelled),
+        }
+    }
+}
+
+/// pairs fetched at a time by `ScanIter`.
+const SCAN_PAGE: u32 = 100;
+
+/// how long a request may wait for its reply, sent as the `timeout_ms` of
+/// every request so servers give up on it too.
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Builds a clerk that retries differently, see `Clerk::builder`.
+#[derive(Clone, Debug)]
+pub struct ClerkBuilder {
+    rpc_timeout: Duration,
+    backoff: BackoffPolicy,
+    retry: RetryBudget,
+    hedge: Option<Duration>,
+    sink: Option<Arc<Mutex<ClerkMetrics>>>,
+    session: Option<ClerkSession>,
+    pipeline_depth: usize,
+    history: Option<History>,
+    limits: Limits,
+}
+
+impl Default for ClerkBuilder {
+    fn default() -> ClerkBuilder {
+        ClerkBuilder {
+            rpc_timeout: RPC_TIMEOUT,
+            backoff: BackoffPolicy::default(),
+            retry: RetryBudget::default(),
+            hedge: None,
+            sink: None,
+            session: None,
+            pipeline_depth: 1,
+            history: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl ClerkBuilder {
+    /// how long a request waits for its reply, 500ms by default.
+    pub fn rpc_timeout(mut self, timeout: Duration) -> Self {
+        self.rpc_timeout = timeout;
+        self
+    }
+
+    /// the delays between retries, `BackoffPolicy::default()` by default.
+    pub fn backoff(mut self, policy: BackoffPolicy) -> Self {
+        self.backoff = policy;
+        self
+    }
+
+    /// gives up after `rounds` rounds of every server failing, unlimited by
+    /// default. calls that return a `Result` fail with `Error::Unavailable`
+    /// then, the others keep trying.
+    pub fn retry_limit(mut self, rounds: u32) -> Self {
+        self.retry.rounds = Some(rounds);
+        self
+    }
+
+    /// gives up once `time` passed since the first try of a call, at the
+    /// end of a round, unlimited by default. like `retry_limit`, and the
+    /// call gives up at whichever comes first.
+    pub fn retry_time(mut self, time: Duration) -> Self {
+        self.retry.time = Some(time);
+        self
+    }
+
+    /// sends a Get to a second server too if the first hasn't replied
+    /// within `delay`, taking the first reply, see `kvraft::hedge`. gets
+    /// aren't hedged by default.
+    pub fn hedge_after(mut self, delay: Duration) -> Self {
+        self.hedge = Some(delay);
+        self
+    }
+
+    /// adds the metrics of every call to `sink` too, e.g. to sum up those
+    /// of many clerks.
+    pub fn metrics_sink(mut self, sink: Arc<Mutex<ClerkMetrics>>) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    /// resumes `session`, saved by a clerk before it went away, instead of
+    /// starting a new one. a request the clerk sent, but didn't see the
+    /// reply of, is retried with the same sequence number then, and servers
+    /// don't apply it twice.
+    pub fn resume(mut self, session: ClerkSession) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// lets up to `depth` async writes of the clerk be in flight at once,
+    /// see `kvraft::pipeline`. 1 by default, one write at a time.
+    pub fn pipeline_depth(mut self, depth: usize) -> Self {
+        self.pipeline_depth = depth;
+        self
+    }
+
+    /// records every get, put, append and delete of the clerk that returns
+    /// in `history`, see `kvraft::history`. a call that fails isn't
+    /// recorded, so keep calls that may give up out of checked histories.
+    pub fn history(mut self, history: History) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// the size limits the clerk checks writes against before sending them,
+    /// `Limits::default()` by default. set those of the servers.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn build(self, name: String, servers: Vec<KvClient>) -> Clerk {
+        // You'll have to add code here.
+        // Clerk { name, servers }
+        //
+        // track the failures of every write with `RetryBudget::start`, on
+        // the budget `put_append` is given.
+        //
+        // keep the servers in a `kvraft::endpoints::Endpoints`, so they can
+        // be added and removed later.
+        let leaders = LeaderCache::new(servers.len());
+        crate::your_code_here((name, servers, self, leaders))
+    }
 }
 
 pub struct Clerk {
@@ -21,36 +524,584 @@ impl fmt::Debug for Clerk {
 
 impl Clerk {
     pub fn new(name: String, servers: Vec<KvClient>) -> Clerk {
-        // You'll have to add code here.
-        // Clerk { name, servers }
-        crate::your_code_here((name, servers))
+        Clerk::builder().build(name, servers)
+    }
+
+    /// a clerk with other timeouts or retries than `new`.
+    pub fn builder() -> ClerkBuilder {
+        ClerkBuilder::default()
+    }
+
+    /// adds a server to the clerk, e.g. one that joined the cluster, and
+    /// returns its index for `remove_server`. calls in flight may try it
+    /// from their next try on.
+    //
+    // tell the `LeaderCache` with `add_server`.
+    pub fn add_server(&self, end: KvClient) -> usize {
+        // You will have to modify this function.
+        crate::your_code_here(end)
+    }
+
+    /// removes server `index` from the clerk, e.g. one that left the
+    /// cluster. calls in flight stop trying it from their next try on.
+    //
+    // tell the `LeaderCache` with `forget`, and skip the removed servers
+    // it may still return with `Endpoints::at_or_after`.
+    pub fn remove_server(&self, index: usize) {
+        // You will have to modify this function.
+        crate::your_code_here(index)
     }
 
     /// fetch the current value for a key.
     /// returns "" if the key does not exist.
     /// keeps trying forever in the face of all other errors.
+    /// concurrent gets of a key on the same clerk share their RPCs, see
+    /// `kvraft::coalesce`.
+    pub fn get(&self, key: String) -> String {
+        let call = Instant::now();
+        let fetch = || block_on(self.get_async(key.clone()));
+        let value = self.coalescer().get(&key, fetch);
+        if let Some(history) = self.history() {
+            history.get(&key, &value, call, Instant::now());
+        }
+        value
+    }
+
+    // the gets of the clerk in flight, by key.
+    fn coalescer(&self) -> &Coalescer<String> {
+        // You will have to modify this function.
+        crate::your_code_here(())
+    }
+
+    // the history the clerk records its calls in, from its builder.
+    fn history(&self) -> Option<&History> {
+        // You will have to modify this function.
+        crate::your_code_here(())
+    }
+
+    /// the size limits the clerk checks writes against, from its builder.
+    pub fn limits(&self) -> Limits {
+        // You will have to modify this function.
+        crate::your_code_here(())
+    }
+
+    // the retries of calls that return a `Result`, from its builder.
+    fn retry_budget(&self) -> RetryBudget {
+        // You will have to modify this function.
+        crate::your_code_here(())
+    }
+
+    /// like get, but resolves to the value instead of blocking, so one
+    /// thread can drive the requests of many clerks at once.
     //
     // you can send an RPC with code like this:
-    // if let Some(reply) = self.servers[i].get(args).wait() { /* do something */ }
-    pub fn get(&self, key: String) -> String {
+    // if let Ok(reply) = self.servers[i].get(&args).await { /* do something */ }
+    //
+    // start from `LeaderCache::leader`, and learn from every reply. after a
+    // wrong leader, try the server `LeaderCache::redirect` returns.
+    //
+    // wait between retries with `futures_timer::Delay`, not `thread::sleep`,
+    // for the `Backoff::next_delay` of the call. don't wait before trying
+    // the next server after a wrong leader, only after every server failed.
+    //
+    // with a hedging delay, send each try with `kvraft::hedge::hedged`,
+    // passing `Delay::new(delay)`. the hedge tries the other servers,
+    // following leader hints like a round of tries, with the same request.
+    pub async fn get_async(&self, key: String) -> String {
+        // You will have to modify this function.
+        crate::your_code_here(key)
+    }
+
+    /// like get, as fresh as `consistency` asks: a `Linearizable` read sets
+    /// `no_lease` and a `Stale` one `stale` in its request.
+    /// keeps trying forever in the face of all other errors.
+    pub fn get_with(&self, key: String, consistency: ReadConsistency) -> String {
+        // You will have to modify this function.
+        crate::your_code_here((key, consistency))
+    }
+
+    /// like get, in a namespace.
+    fn get_in(&self, namespace: &str, key: String) -> String {
+        // You will have to modify this function.
+        crate::your_code_here((namespace, key))
+    }
+
+    /// the client id of the clerk and the sequence number of its next
+    /// request. save it before every request to resume the session with
+    /// `ClerkBuilder::resume` after a crash.
+    pub fn session(&self) -> ClerkSession {
+        // You will have to modify this function.
+        crate::your_code_here(())
+    }
+
+    /// the latency of the calls of the clerk so far, by op, and how many
+    /// times they retried and were redirected to another leader.
+    //
+    // time every public call from its start to its result. count a retry
+    // for every try after the first, and a redirect for every wrong leader.
+    pub fn metrics(&self) -> ClerkMetrics {
+        // You will have to modify this function.
+        crate::your_code_here(())
+    }
+
+    /// calls that give up at `deadline` with `Error::Timeout`, instead of
+    /// retrying forever like those of the clerk. a call that gives up is
+    /// dropped while it waits for a reply, so leave the clerk ready for the
+    /// next call at any await point.
+    pub fn with_deadline(&self, deadline: Instant) -> Deadline<'_> {
+        Deadline {
+            clerk: self,
+            deadline,
+        }
+    }
+
+    /// calls that give up `timeout` from now, see `with_deadline`.
+    pub fn with_timeout(&self, timeout: Duration) -> Deadline<'_> {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// calls that give up with `Error::Cancelled` when `token` is
+    /// cancelled, from another thread. like those of `with_deadline`, a
+    /// call is dropped at an await point then.
+    pub fn with_cancel(&self, token: &CancelToken) -> Cancellable<'_> {
+        Cancellable {
+            clerk: self,
+            token: token.clone(),
+        }
+    }
+
+    /// the keys of a namespace, a keyspace of its own sharing the servers
+    /// with the others. the ops of the clerk itself are in the namespace "".
+    pub fn namespace(&self, namespace: String) -> Namespaced<'_> {
+        Namespaced {
+            clerk: self,
+            namespace,
+        }
+    }
+
+    /// fetch up to `limit` key/value pairs with keys in [start, end), in
+    /// key order. an empty `end` scans to the last key, a `limit` of 0
+    /// returns them all. pass `next` of the page as `start` to fetch the
+    /// page after it. like get, a page reflects every write completed
+    /// before the scan started.
+    /// keeps trying forever in the face of all other errors.
+    pub fn scan(&self, start: String, end: String, limit: u32) -> ScanPage {
+        // You will have to modify this function.
+        crate::your_code_here((start, end, limit))
+    }
+
+    /// iterates over the key/value pairs with keys in `range`, in key order,
+    /// fetching them a page at a time with `scan`. each page reflects every
+    /// write completed before it was fetched, but writes between pages may
+    /// show in later pages only.
+    /// keeps trying forever in the face of all other errors.
+    pub fn scan_range<R: RangeBounds<String>>(&self, range: R) -> ScanIter<'_> {
+        // the smallest key after `key`.
+        let after = |key: &String| format!("{}\0", key);
+        let start = match range.start_bound() {
+            Bound::Included(start) => start.clone(),
+            Bound::Excluded(start) => after(start),
+            Bound::Unbounded => String::new(),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => after(end),
+            Bound::Excluded(end) if end.is_empty() => return self.scan_empty(),
+            Bound::Excluded(end) => end.clone(),
+            Bound::Unbounded => String::new(),
+        };
+        ScanIter {
+            clerk: self,
+            end,
+            next: Some(start),
+            page: VecDeque::new(),
+        }
+    }
+
+    // nothing is below "", which `scan` takes as no end.
+    fn scan_empty(&self) -> ScanIter<'_> {
+        ScanIter {
+            clerk: self,
+            end: String::new(),
+            next: None,
+            page: VecDeque::new(),
+        }
+    }
+
+    /// fetch the value for a key from the state any server applied so far,
+    /// without waiting for a quorum. the value may be stale, it's returned
+    /// with the index of the last entry applied to that state, so a caller
+    /// can tell how stale. returns "" if the key does not exist.
+    /// keeps trying forever in the face of all other errors.
+    pub fn get_stale(&self, key: String) -> (String, u64) {
+        // You will have to modify this function.
+        crate::your_code_here(key)
+    }
+
+    /// fetch the value for a key like get_stale, from any server, but one
+    /// that applied every write of this clerk, so the clerk always sees its
+    /// own writes, and never a value older than one it read before. the
+    /// value may be stale with respect to writes of other clerks.
+    /// returns "" if the key does not exist.
+    /// keeps trying forever in the face of all other errors.
+    //
+    // remember the `index` replied to every write, and the applied index of
+    // every read, and send the largest as `min_index`.
+    pub fn get_session(&self, key: String) -> String {
         // You will have to modify this function.
         crate::your_code_here(key)
     }
 
-    /// shared by Put and Append.
+    /// fetch the value of a key as of `revision`, the revision of a write,
+    /// or the latest value if 0. fails with `Error::Compacted` if the
+    /// version has been compacted away, see `compact`.
+    /// keeps trying forever in the face of all other errors.
+    pub fn get_at(&self, key: String, revision: u64) -> Result<String> {
+        // You will have to modify this function.
+        crate::your_code_here((key, revision))
+    }
+
+    /// drops the versions of keys only needed to read below `revision`.
+    //
+    // you can send an RPC with code like this:
+    // let reply = self.servers[i].compact(args).unwrap();
+    pub fn compact(&self, revision: u64) {
+        // You will have to modify this function.
+        crate::your_code_here(revision)
+    }
+
+    /// shared by Put, Append and Delete, of the keys in `namespace`.
+    /// fails with the error a server replied for a write past the size
+    /// limits, see `kvraft::limits`, or with `Error::Unavailable` once
+    /// `budget` is spent. calls that don't return a `Result` pass
+    /// `RetryBudget::default()`, and keep trying.
+    fn put_append(&self, namespace: &str, op: Op, budget: RetryBudget) -> Result<Written> {
+        block_on(self.put_append_async(namespace, op, budget))
+    }
+
+    // you can send an RPC with code like this:
+    // let reply = self.servers[i].put_append(&args).await;
+    //
+    // take the sequence number of a write from `Pipeline::start`, polled
+    // as soon as the clerk is done with a write, and give it back with
+    // `Pipeline::finish` once it's replied, or the call is dropped.
+    async fn put_append_async(
+        &self,
+        namespace: &str,
+        op: Op,
+        budget: RetryBudget,
+    ) -> Result<Written> {
+        // You will have to modify this function.
+        crate::your_code_here((namespace, op, budget))
+    }
+
+    /// watches the keys starting with `key_prefix` for changes after
+    /// `after_revision`, 0 for all of them.
+    pub fn watch(&self, key_prefix: String, after_revision: u64) -> Watch<'_> {
+        Watch {
+            clerk: self,
+            key_prefix,
+            revision: after_revision,
+        }
+    }
+
+    /// like watch, but the changes come one at a time as a stream, which
+    /// never ends. a leader change doesn't lose or repeat a change, the
+    /// stream polls the next leader from the last revision it got.
+    pub fn watch_stream(
+        &self,
+        key_prefix: String,
+        after_revision: u64,
+    ) -> impl Stream<Item = KeyChange> + '_ {
+        let start = (key_prefix, after_revision, VecDeque::new());
+        stream::unfold(
+            start,
+            move |(key_prefix, mut revision, mut changes)| async move {
+                loop {
+                    if let Some(change) = changes.pop_front() {
+                        return Some((change, (key_prefix, revision, changes)));
+                    }
+                    let (polled, next) = self.poll_watch_async(&key_prefix, revision).await;
+                    changes.extend(polled);
+                    revision = next;
+                }
+            },
+        )
+    }
+
+    /// long-polls for the changes after `revision`, returns them and the
+    /// revision to poll after next.
+    fn poll_watch(&self, key_prefix: &str, revision: u64) -> (Vec<KeyChange>, u64) {
+        block_on(self.poll_watch_async(key_prefix, revision))
+    }
+
+    // you can send an RPC with code like this:
+    // let reply = self.servers[i].watch(&args).await;
+    //
+    // a server that's not the leader any more may not reply until the RPC
+    // times out. try the next one then, after the same revision.
+    async fn poll_watch_async(&self, key_prefix: &str, revision: u64) -> (Vec<KeyChange>, u64) {
+        // You will have to modify this function.
+        crate::your_code_here((key_prefix, revision))
+    }
+
+    /// adds `delta` to the integer value of a key atomically and returns the
+    /// new value. a missing key counts as 0.
+    /// keeps trying forever in the face of all other errors.
+    //
+    // you can send an RPC with code like this:
+    // let reply = self.servers[i].incr(args).unwrap();
+    pub fn incr(&self, key: String, delta: i64) -> i64 {
+        // You will have to modify this function.
+        crate::your_code_here((key, delta))
+    }
+
+    /// exports the keys and values as of the revision the export starts at,
+    /// in chunks of key/value pairs in key order. writes after that
+    /// revision aren't exported, so the chunks add up to a consistent state,
+    /// unless the revision is compacted meanwhile.
+    pub fn export(&self) -> Export<'_> {
+        Export {
+            clerk: self,
+            after: Some(String::new()),
+            revision: 0,
+        }
+    }
+
+    /// fetches the chunk after `after` as of `revision`, 0 for the first
+    /// chunk. returns it, the key to fetch the next chunk after if any, and
+    /// the revision it was read at.
+    //
+    // you can send an RPC with code like this:
+    // let reply = self.servers[i].export(args).unwrap();
+    fn export_chunk(
+        &self,
+        after: &str,
+        revision: u64,
+    ) -> (Vec<(String, String)>, Option<String>, u64) {
+        // You will have to modify this function.
+        crate::your_code_here((after, revision))
+    }
+
+    /// writes exported key/value pairs, meant for a fresh service that
+    /// restores a backup, and moves its revision up to `revision`, the
+    /// revision of the export. import every chunk of an export this way.
+    /// keeps trying forever in the face of all other errors.
+    //
+    // you can send an RPC with code like this:
+    // let reply = self.servers[i].import(args).unwrap();
+    pub fn import(&self, kvs: Vec<(String, String)>, revision: u64) {
+        // You will have to modify this function.
+        crate::your_code_here((kvs, revision))
+    }
+
+    /// declares index `name` over the keys starting with `key_prefix`, by
+    /// their values. does nothing if there's an index by that name.
+    //
+    // you can send an RPC with code like this:
+    // let reply = self.servers[i].create_index(args).unwrap();
+    pub fn create_index(&self, name: String, key_prefix: String) {
+        // You will have to modify this function.
+        crate::your_code_here((name, key_prefix))
+    }
+
+    /// fetch the keys of index `name` with values in [start, end), as
+    /// key/value pairs in value order, then key order. an empty `end` scans
+    /// to the last value. like scan, they reflect every write completed
+    /// before.
+    //
+    // fetch it in pages with code like this:
+    // let reply = self.servers[i].scan_index(args).unwrap();
+    pub fn scan_index(&self, name: String, start: String, end: String) -> Vec<(String, String)> {
+        // You will have to modify this function.
+        crate::your_code_here((name, start, end))
+    }
+
+    /// changes when every server snapshots, from the point it's applied on.
+    /// the policy is kept over restarts.
+    /// keeps trying forever in the face of all other errors.
+    //
+    // you can send an RPC with code like this:
+    // let reply = self.servers[i].set_snapshot_policy(args).unwrap();
+    pub fn set_snapshot_policy(&self, policy: SnapshotPolicy) {
+        // You will have to modify this function.
+        crate::your_code_here(policy)
+    }
+
+    /// starts a batch of writes to apply atomically.
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            clerk: self,
+            ops: vec![],
+        }
+    }
+
+    /// puts every key/value pair with a single request, applied all at once
+    /// like a batch, e.g. for bulk loads. panics like put if a pair is past
+    /// the size limits, before sending any.
+    pub fn put_many(&self, pairs: Vec<(String, String)>) {
+        self.write_many(pairs, Op::Put)
+    }
+
+    /// appends every value to its key with a single request, see
+    /// `put_many`.
+    pub fn append_many(&self, pairs: Vec<(String, String)>) {
+        self.write_many(pairs, Op::Append)
+    }
+
+    fn write_many(&self, pairs: Vec<(String, String)>, op: fn(String, String) -> Op) {
+        for (key, value) in &pairs {
+            self.limits().check(key, value).unwrap();
+        }
+        if !pairs.is_empty() {
+            let ops = pairs.into_iter().map(|(k, v)| op(k, v)).collect();
+            self.write_batch(ops)
+        }
+    }
+
+    // a batch is a single request of the clerk, with one sequence number.
     //
     // you can send an RPC with code like this:
-    // let reply = self.servers[i].put_append(args).unwrap();
-    fn put_append(&self, op: Op) {
+    // let reply = self.servers[i].write_batch(args).unwrap();
+    fn write_batch(&self, ops: Vec<Op>) {
         // You will have to modify this function.
-        crate::your_code_here(op)
+        crate::your_code_here(ops)
     }
 
+    /// panics if the key or the value is past the size limits, see
+    /// `try_put`. keeps trying forever, whatever the retry budget.
     pub fn put(&self, key: String, value: String) {
-        self.put_append(Op::Put(key, value))
+        self.put_within(key, value, RetryBudget::default()).unwrap()
     }
 
+    /// like put, but fails with `Error::KeyTooLarge` or
+    /// `Error::ValueTooLarge` if the key or the value is past the size
+    /// limits, checked before sending it, and with `Error::Unavailable`
+    /// once the retry budget is spent, see `ClerkBuilder::retry_limit`.
+    pub fn try_put(&self, key: String, value: String) -> Result<()> {
+        self.put_within(key, value, self.retry_budget())
+    }
+
+    fn put_within(&self, key: String, value: String, budget: RetryBudget) -> Result<()> {
+        self.limits().check(&key, &value)?;
+        let call = Instant::now();
+        self.put_append("", Op::Put(key.clone(), value.clone()), budget)?;
+        if let Some(history) = self.history() {
+            history.put(&key, &value, call, Instant::now());
+        }
+        Ok(())
+    }
+
+    /// like put, but the key reads as "" once `ttl` passed since it was
+    /// written, unless it's written again before. panics like put past the
+    /// size limits.
+    pub fn put_with_ttl(&self, key: String, value: String, ttl: Duration) {
+        self.limits().check(&key, &value).unwrap();
+        self.put_append("", Op::PutWithTtl(key, value, ttl), RetryBudget::default())
+            .unwrap();
+    }
+
+    /// panics if the key or the value after appending is past the size
+    /// limits, see `try_append`. keeps trying forever like put.
     pub fn append(&self, key: String, value: String) {
-        self.put_append(Op::Append(key, value))
+        self.append_within(key, value, RetryBudget::default())
+            .unwrap()
+    }
+
+    /// like append, but fails with `Error::KeyTooLarge` or
+    /// `Error::ValueTooLarge` if the key or the value after appending is
+    /// past the size limits. the value after appending is checked by the
+    /// server, the write isn't applied then. gives up like `try_put`.
+    pub fn try_append(&self, key: String, value: String) -> Result<()> {
+        self.append_within(key, value, self.retry_budget())
+    }
+
+    fn append_within(&self, key: String, value: String, budget: RetryBudget) -> Result<()> {
+        self.limits().check(&key, &value)?;
+        let call = Instant::now();
+        self.put_append("", Op::Append(key.clone(), value.clone()), budget)?;
+        if let Some(history) = self.history() {
+            history.append(&key, &value, call, Instant::now());
+        }
+        Ok(())
+    }
+
+    /// like append, but returns the value of the key after appending, the
+    /// same on a retry. panics like append past the size limits.
+    pub fn append_and_get(&self, key: String, value: String) -> String {
+        self.limits().check(&key, &value).unwrap();
+        self.put_append("", Op::AppendAndGet(key, value), RetryBudget::default())
+            .unwrap()
+            .value
+    }
+
+    /// like put, to a lock key, see `kvraft::fence`. returns the fencing
+    /// token of the write, larger than that of any write to a lock key
+    /// before it. panics if the key isn't a lock key, or like put past the
+    /// size limits.
+    pub fn put_fenced(&self, key: String, value: String) -> FenceToken {
+        self.limits().check(&key, &value).unwrap();
+        let written = self
+            .put_append("", Op::Put(key, value), RetryBudget::default())
+            .unwrap();
+        written.fence.expect("not a lock key")
+    }
+
+    /// removes a key, so it reads as "" again. Removing a key that
+    /// doesn't exist does nothing.
+    pub fn delete(&self, key: String) {
+        let call = Instant::now();
+        block_on(self.delete_async(key.clone()));
+        if let Some(history) = self.history() {
+            history.put(&key, "", call, Instant::now());
+        }
+    }
+
+    /// like put, resolving once it's done, see `get_async`. a clerk sends
+    /// one write at a time: await a write before starting the next one,
+    /// unless the clerk was built with a `pipeline_depth`. writes of a
+    /// pipelined clerk may be started together, those to a key are applied
+    /// in the order they were started.
+    pub async fn put_async(&self, key: String, value: String) {
+        self.limits().check(&key, &value).unwrap();
+        self.put_append_async("", Op::Put(key, value), RetryBudget::default())
+            .await
+            .unwrap();
+    }
+
+    /// like append, resolving once it's done, see `put_async`.
+    pub async fn append_async(&self, key: String, value: String) {
+        self.limits().check(&key, &value).unwrap();
+        self.put_append_async("", Op::Append(key, value), RetryBudget::default())
+            .await
+            .unwrap();
+    }
+
+    /// like delete, resolving once it's done, see `put_async`.
+    pub async fn delete_async(&self, key: String) {
+        self.limits().check_key(&key).unwrap();
+        self.put_append_async("", Op::Delete(key), RetryBudget::default())
+            .await
+            .unwrap();
+    }
+
+    /// removes a key if its value is `expected`, atomically. returns
+    /// whether it was removed. a retry of it replies the same, even if the
+    /// key was written again meanwhile.
+    pub fn delete_if(&self, key: String, expected: String) -> bool {
+        self.limits().check_key(&key).unwrap();
+        let written = self
+            .put_append("", Op::DeleteIf(key, expected), RetryBudget::default())
+            .unwrap();
+        written.deleted > 0
+    }
+
+    /// removes the keys in [start, end) with a single op, an empty `end`
+    /// removes them up to the last key. returns how many were removed.
+    pub fn delete_range(&self, start: String, end: String) -> u64 {
+        self.limits().check_key(&start).unwrap();
+        self.limits().check_key(&end).unwrap();
+        self.put_append("", Op::DeleteRange(start, end), RetryBudget::default())
+            .unwrap()
+            .deleted
     }
 }
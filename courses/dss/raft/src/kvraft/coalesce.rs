@@ -0,0 +1,200 @@
+//! Coalescing concurrent reads of a key.
+//!
+//! Threads sharing a clerk often read the same hot key at once, and every
+//! read costs a round trip to the leader, and a round of heartbeats there.
+//! A `Coalescer` lets concurrent reads of a key share one fetch.
+//!
+//! A read may only take a value fetched after it started, or it could miss
+//! a write that completed before it started. So a read that comes while a
+//! fetch of its key is in flight doesn't take that fetch's value, it waits
+//! for the next fetch, started once the one in flight is done, and shared by
+//! every read that came meanwhile.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+#[derive(Debug)]
+struct Slot<V> {
+    // fetches started and finished, each numbered from 1.
+    started: u64,
+    finished: u64,
+    running: bool,
+    // the value of the last fetch finished.
+    value: Option<V>,
+    // reads waiting for a fetch.
+    waiting: usize,
+}
+
+impl<V> Default for Slot<V> {
+    fn default() -> Slot<V> {
+        Slot {
+            started: 0,
+            finished: 0,
+            running: false,
+            value: None,
+            waiting: 0,
+        }
+    }
+}
+
+/// Shares fetches of a key among concurrent reads of it.
+#[derive(Debug)]
+pub struct Coalescer<V> {
+    slots: Mutex<HashMap<String, Slot<V>>>,
+    done: Condvar,
+}
+
+impl<V: Clone> Default for Coalescer<V> {
+    fn default() -> Coalescer<V> {
+        Coalescer {
+            slots: Mutex::new(HashMap::new()),
+            done: Condvar::new(),
+        }
+    }
+}
+
+impl<V: Clone> Coalescer<V> {
+    pub fn new() -> Coalescer<V> {
+        Coalescer::default()
+    }
+
+    /// Reads `key`, with `fetch` or with a fetch of another read started
+    /// after this one.
+    pub fn get<F: FnOnce() -> V>(&self, key: &str, fetch: F) -> V {
+        let mut slots = self.slots.lock().unwrap();
+        // the fetch whose value this read may take.
+        let mut target = None;
+        loop {
+            let slot = slots.entry(key.to_owned()).or_default();
+            if let Some(target) = target {
+                if slot.finished >= target {
+                    let value = slot.value.clone().unwrap();
+                    slot.waiting -= 1;
+                    self.clean(&mut slots, key);
+                    return value;
+                }
+            }
+            if !slot.running {
+                slot.running = true;
+                slot.started += 1;
+                let n = slot.started;
+                if target.is_some() {
+                    slot.waiting -= 1;
+                }
+                drop(slots);
+                return self.run(key, n, fetch);
+            }
+            if target.is_none() {
+                target = Some(slot.started + 1);
+                slot.waiting += 1;
+            }
+            slots = self.done.wait(slots).unwrap();
+        }
+    }
+
+    // runs fetch `n` of `key` and hands its value to the reads waiting.
+    fn run<F: FnOnce() -> V>(&self, key: &str, n: u64, fetch: F) -> V {
+        // lets a waiting read fetch instead if `fetch` panics.
+        struct Abort<'a, V: Clone>(&'a Coalescer<V>, &'a str);
+
+        impl<V: Clone> Drop for Abort<'_, V> {
+            fn drop(&mut self) {
+                let mut slots = self.0.slots.lock().unwrap();
+                if let Some(slot) = slots.get_mut(self.1) {
+                    slot.running = false;
+                }
+                self.0.clean(&mut slots, self.1);
+                self.0.done.notify_all();
+            }
+        }
+
+        let abort = Abort(self, key);
+        let value = fetch();
+        std::mem::forget(abort);
+        let mut slots = self.slots.lock().unwrap();
+        let slot = slots.get_mut(key).unwrap();
+        slot.running = false;
+        slot.finished = n;
+        slot.value = Some(value.clone());
+        self.clean(&mut slots, key);
+        self.done.notify_all();
+        value
+    }
+
+    // drops the slot of `key` once nothing is in flight or waiting.
+    fn clean(&self, slots: &mut HashMap<String, Slot<V>>, key: &str) {
+        if matches!(slots.get(key), Some(s) if !s.running && s.waiting == 0) {
+            slots.remove(key);
+        }
+    }
+
+    /// The keys with a fetch in flight or reads waiting.
+    pub fn len(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_coalescer() {
+        let c = Arc::new(Coalescer::new());
+        assert_eq!(c.get("k", || 1), 1);
+        assert!(c.is_empty());
+
+        // a fetch in flight, 8 reads come meanwhile and share the next one.
+        let fetches = Arc::new(AtomicU64::new(0));
+        let (started, wait) = mpsc::channel();
+        let (release, go) = mpsc::channel::<()>();
+        let first = {
+            let (c, fetches) = (c.clone(), fetches.clone());
+            thread::spawn(move || {
+                c.get("k", || {
+                    started.send(()).unwrap();
+                    go.recv().unwrap();
+                    fetches.fetch_add(1, Ordering::SeqCst)
+                })
+            })
+        };
+        wait.recv().unwrap();
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let (c, fetches) = (c.clone(), fetches.clone());
+                thread::spawn(move || {
+                    c.get("k", || {
+                        thread::sleep(Duration::from_millis(10));
+                        fetches.fetch_add(1, Ordering::SeqCst)
+                    })
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        release.send(()).unwrap();
+        assert_eq!(first.join().unwrap(), 0);
+        for r in readers {
+            // never the value of the fetch in flight when they came.
+            assert_eq!(r.join().unwrap(), 1);
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        assert!(c.is_empty());
+
+        // a fetch that panics leaves the key to the next read.
+        let c_ = c.clone();
+        assert!(thread::spawn(move || c_.get("k", || panic!("down")))
+            .join()
+            .is_err());
+        assert_eq!(c.get("k", || 7), 7);
+        assert!(c.is_empty());
+    }
+}
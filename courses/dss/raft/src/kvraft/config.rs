@@ -1,26 +1,31 @@
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 
 use rand::seq::SliceRandom;
 
+use crate::kvraft::audit::AuditRecord;
 use crate::kvraft::errors::{Error, Result};
+use crate::kvraft::history::History;
+use crate::kvraft::limits::Limits;
+use crate::kvraft::metrics::ClerkMetrics;
+use crate::kvraft::script::{Event, NetworkScript, ScriptRun};
+use crate::kvraft::snapshot::SnapshotPolicy;
 use crate::kvraft::{client, server};
 use crate::proto::kvraftpb::*;
 use crate::proto::raftpb::*;
-use crate::raft;
+use crate::raft::config::IdGen;
 use crate::raft::persister::*;
 
-static ID: AtomicUsize = AtomicUsize::new(300_000);
-
-fn uniqstring() -> String {
-    format!("{}", ID.fetch_add(1, Ordering::Relaxed))
-}
-
 struct Servers {
     kvservers: Vec<Option<server::Node>>,
     saved: Vec<Arc<SimplePersister>>,
+    // what the servers persist with, wrapping `saved`.
+    faulty: Vec<Arc<FaultyPersister<Arc<SimplePersister>>>>,
+    // stats of persisters replaced in `saved`.
+    retired_stats: Stats,
     endnames: Vec<Vec<String>>,
 }
 
@@ -35,8 +40,16 @@ pub struct Config {
     pub n: usize,
     servers: Mutex<Servers>,
     clerks: Mutex<HashMap<String, Vec<String>>>,
+    // generator of endnames and clerk names
+    ids: IdGen,
     next_client_id: AtomicUsize,
-    maxraftstate: Option<usize>,
+    snapshot_policy: SnapshotPolicy,
+    // whether servers started serve Gets under a leader lease.
+    leases: AtomicBool,
+    // the handlers servers started run at most at once.
+    server_limit: Mutex<Option<(usize, labrpc::Overflow)>>,
+    // the size limits of servers started and clerks made.
+    limits: Mutex<Limits>,
 
     // time at which the Config was created.
     start: Instant,
@@ -48,29 +61,92 @@ pub struct Config {
     rpcs0: AtomicUsize,
     // number of agreements
     ops: AtomicUsize,
+    // the calls of every clerk since begin().
+    clerk_metrics: Arc<Mutex<ClerkMetrics>>,
+    // the calls of every clerk since begin(), see `check_history`.
+    history: History,
 }
 
 impl Config {
     pub fn new(n: usize, unreliable: bool, maxraftstate: Option<usize>) -> Config {
+        Config::new_with_seed(n, unreliable, maxraftstate.into(), 300_000)
+    }
+
+    /// Like `new`, but servers snapshot by `snapshot_policy`.
+    pub fn with_snapshot_policy(
+        n: usize,
+        unreliable: bool,
+        snapshot_policy: SnapshotPolicy,
+    ) -> Config {
+        Config::new_with_seed(n, unreliable, snapshot_policy, 300_000)
+    }
+
+    /// Like `new` with an unreliable network, that also delays most replies
+    /// by up to 2.2s, so they arrive far out of order.
+    pub fn with_long_reordering(n: usize, maxraftstate: Option<usize>) -> Config {
+        let cfg = Config::new(n, true, maxraftstate);
+        cfg.net.set_long_reordering(true);
+        cfg
+    }
+
+    /// Like `new`, but the network draws its drops and delays from `seed`.
+    /// A failing test prints the seed of its network, so it can be run
+    /// again with the same draws.
+    pub fn with_seed(n: usize, unreliable: bool, maxraftstate: Option<usize>, seed: u64) -> Config {
+        let net = labrpc::Network::with_seed(seed);
+        Config::with_network(n, unreliable, maxraftstate.into(), 300_000, net)
+    }
+
+    /// Like `with_snapshot_policy`, but endnames are allocated starting from
+    /// `seed`.
+    pub fn new_with_seed(
+        n: usize,
+        unreliable: bool,
+        snapshot_policy: SnapshotPolicy,
+        seed: usize,
+    ) -> Config {
+        let net = labrpc::Network::new();
+        Config::with_network(n, unreliable, snapshot_policy, seed, net)
+    }
+
+    fn with_network(
+        n: usize,
+        unreliable: bool,
+        snapshot_policy: SnapshotPolicy,
+        seed: usize,
+        net: labrpc::Network,
+    ) -> Config {
         init_logger();
 
+        let saved: Vec<_> = (0..n).map(|_| Arc::new(SimplePersister::new())).collect();
         let servers = Servers {
             kvservers: vec![None; n],
-            saved: (0..n).map(|_| Arc::new(SimplePersister::new())).collect(),
+            faulty: saved
+                .iter()
+                .map(|p| Arc::new(FaultyPersister::new(p.clone())))
+                .collect(),
+            saved,
+            retired_stats: Stats::default(),
             endnames: vec![vec![String::new(); n]; n],
         };
         let cfg = Config {
             n,
-            net: labrpc::Network::new(),
+            net,
             servers: Mutex::new(servers),
             clerks: Mutex::new(HashMap::new()),
+            ids: IdGen::new(seed),
             // client ids start 1000 above the highest serverid,
             next_client_id: AtomicUsize::new(n + 1000),
-            maxraftstate,
+            snapshot_policy,
+            leases: AtomicBool::new(true),
+            server_limit: Mutex::new(None),
+            limits: Mutex::new(Limits::default()),
             start: Instant::now(),
             t0: Mutex::new(Instant::now()),
             rpcs0: AtomicUsize::new(0),
             ops: AtomicUsize::new(0),
+            clerk_metrics: Arc::default(),
+            history: History::new(),
         };
 
         // create a full set of KV servers.
@@ -105,7 +181,7 @@ impl Config {
         let servers = self.servers.lock().unwrap();
         let mut logsize = 0;
         for save in &servers.saved {
-            let n = save.raft_state().len();
+            let n = save.raft_state().unwrap().len();
             if n > logsize {
                 logsize = n;
             }
@@ -113,12 +189,24 @@ impl Config {
         logsize
     }
 
+    /// Stats of all persisters so far, and the bytes they hold now.
+    pub fn persist_stats(&self) -> (Stats, usize) {
+        let servers = self.servers.lock().unwrap();
+        let mut stats = servers.retired_stats.clone();
+        let mut live = 0;
+        for save in &servers.saved {
+            stats.merge(&save.stats());
+            live += save.raft_state().unwrap().len() + save.snapshot().unwrap().len();
+        }
+        (stats, live)
+    }
+
     /// Maximum snapshot size across all servers
     pub fn snapshot_size(&self) -> usize {
         let mut snapshotsize = 0;
         let servers = self.servers.lock().unwrap();
         for save in &servers.saved {
-            let n = save.snapshot().len();
+            let n = save.snapshot().unwrap().len();
             if n > snapshotsize {
                 snapshotsize = n;
             }
@@ -126,6 +214,19 @@ impl Config {
         snapshotsize
     }
 
+    /// Stats of kv server i, `None` if it's shut down.
+    pub fn kv_stats(&self, i: usize) -> Option<server::KvStats> {
+        let servers = self.servers.lock().unwrap();
+        servers.kvservers[i].as_ref().map(|kv| kv.stats())
+    }
+
+    /// The ops kv server i applied, as far back as its audit log goes.
+    /// `None` if it's shut down.
+    pub fn audit_log(&self, i: usize) -> Option<Vec<AuditRecord>> {
+        let servers = self.servers.lock().unwrap();
+        servers.kvservers[i].as_ref().map(|kv| kv.audit(0))
+    }
+
     /// Attach server i to servers listed in to
     fn connect(&self, i: usize, to: &[usize], servers: &Servers) {
         debug!("connect peer {} to {:?}", i, to);
@@ -133,12 +234,14 @@ impl Config {
         for j in to {
             let endname = &servers.endnames[i][*j];
             self.net.enable(endname, true);
+            self.net.enable_replies(endname, true);
         }
 
         // incoming socket files
         for j in to {
             let endname = &servers.endnames[*j][i];
             self.net.enable(endname, true);
+            self.net.enable_replies(endname, true);
         }
     }
 
@@ -187,30 +290,143 @@ impl Config {
         }
     }
 
+    /// Cuts the servers in `from` off from the servers in `to` one way only:
+    /// what servers in `from` send to servers in `to`, requests and replies,
+    /// is lost, what they send back arrives. Undone by `connect_all`.
+    pub fn partition_one_way(&self, from: &[usize], to: &[usize]) {
+        debug!("cut messages from {:?} to {:?}", from, to);
+        let servers = self.servers.lock().unwrap();
+        for &i in from {
+            for &j in to {
+                self.net.enable(&servers.endnames[i][j], false);
+                self.net.enable_replies(&servers.endnames[j][i], false);
+            }
+        }
+    }
+
+    /// Loses requests and replies on the link from server `from` to server
+    /// `to` with chance `rate`, until `from` restarts.
+    pub fn set_link_drop_rate(&self, from: usize, to: usize, rate: f64) {
+        let servers = self.servers.lock().unwrap();
+        self.net.set_drop_rate(&servers.endnames[from][to], rate);
+    }
+
+    /// Applies the events of `script`, each at its time from now on the
+    /// clock of the network, until the returned run is dropped.
+    pub fn run_script(self: &Arc<Self>, script: NetworkScript) -> ScriptRun {
+        let cfg = self.clone();
+        script.start(self.net.clock(), move |event| match event {
+            Event::Partition(p1, p2) => cfg.partition(p1, p2),
+            Event::Heal => cfg.connect_all(),
+            Event::DropRate { from, to, rate } => cfg.set_link_drop_rate(*from, *to, *rate),
+            Event::Unreliable(yes) => cfg.net.set_reliable(!yes),
+            Event::Crash(i) => cfg.shutdown_server(*i),
+            Event::Restart(i) => {
+                cfg.start_server(*i);
+                cfg.connect(*i, &cfg.all(), &cfg.servers.lock().unwrap());
+            }
+        })
+    }
+
+    /// Fails the next `n` calls of `fq_name`, e.g. `"kv.put_append"`, that
+    /// reach server `i`, as if they timed out. The returned counter tells
+    /// how many were failed so far.
+    pub fn fail_calls(&self, i: usize, fq_name: &'static str, n: usize) -> Arc<AtomicUsize> {
+        let failed = Arc::new(AtomicUsize::new(0));
+        let counter = failed.clone();
+        let server_name = format!("{}", i);
+        self.net.add_interceptor(
+            move |call: &labrpc::Call,
+                  req: Vec<u8>,
+                  next: labrpc::Next|
+                  -> labrpc::RpcFuture<labrpc::Result<Vec<u8>>> {
+                let fail = call.server_name == server_name
+                    && call.fq_name == fq_name
+                    && counter
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |c| {
+                            Some(c + 1).filter(|&c| c <= n)
+                        })
+                        .is_ok();
+                if fail {
+                    Box::pin(futures::future::err(labrpc::Error::Timeout))
+                } else {
+                    next(req)
+                }
+            },
+        );
+        failed
+    }
+
     // Create a clerk with clerk specific server names.
     // Give it connections to all of the servers, but for
     // now enable only connections to servers in to[].
     pub fn make_client(&self, to: &[usize]) -> client::Clerk {
+        self.make_client_with(to, client::Clerk::builder())
+    }
+
+    /// Like `make_client`, but builds the clerk with `builder`.
+    pub fn make_client_with(&self, to: &[usize], builder: client::ClerkBuilder) -> client::Clerk {
         // a fresh set of ClientEnds.
         let mut ends = Vec::with_capacity(self.n);
         let mut endnames = Vec::with_capacity(self.n);
         for j in 0..self.n {
-            let name = uniqstring();
-            endnames.push(name.clone());
-            let cli = self.net.create_client(name.clone());
-            ends.push(KvClient::new(cli));
-            self.net.connect(&name, &format!("{}", j));
+            let (name, end) = self.make_end(j);
+            endnames.push(name);
+            ends.push(end);
         }
 
         ends.shuffle(&mut rand::thread_rng());
-        let ck_name = uniqstring();
-        let ck = client::Clerk::new(ck_name.clone(), ends);
+        let ck_name = self.ids.uniqstring();
+        let ck = builder
+            .metrics_sink(self.clerk_metrics.clone())
+            .history(self.history.clone())
+            .limits(*self.limits.lock().unwrap())
+            .build(ck_name.clone(), ends);
         self.clerks.lock().unwrap().insert(ck_name, endnames);
         self.next_client_id.fetch_add(1, Ordering::Relaxed);
         self.connect_client(&ck, to);
         ck
     }
 
+    /// Like `make_client`, but the clerk only has servers `servers`, in
+    /// that order, all connected. See `add_client_server` for more.
+    pub fn make_client_of(&self, servers: &[usize]) -> client::Clerk {
+        let mut ends = Vec::with_capacity(servers.len());
+        let mut endnames = vec![String::new(); self.n];
+        for &j in servers {
+            let (name, end) = self.make_end(j);
+            endnames[j] = name;
+            ends.push(end);
+        }
+        let ck_name = self.ids.uniqstring();
+        let ck = client::Clerk::builder()
+            .metrics_sink(self.clerk_metrics.clone())
+            .history(self.history.clone())
+            .limits(*self.limits.lock().unwrap())
+            .build(ck_name.clone(), ends);
+        self.clerks.lock().unwrap().insert(ck_name, endnames);
+        self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        self.connect_client(&ck, servers);
+        ck
+    }
+
+    /// Adds server `i` to `ck` at runtime, connected, and returns its index
+    /// in the clerk.
+    pub fn add_client_server(&self, ck: &client::Clerk, i: usize) -> usize {
+        let (name, end) = self.make_end(i);
+        self.clerks.lock().unwrap().get_mut(&ck.name).unwrap()[i] = name;
+        self.connect_client(ck, &[i]);
+        ck.add_server(end)
+    }
+
+    // a new ClientEnd to server `j`, and its name.
+    fn make_end(&self, j: usize) -> (String, KvClient) {
+        let name = self.ids.uniqstring();
+        let cli = self.net.create_client(name.clone());
+        self.net.connect(&name, &format!("{}", j));
+        (name, KvClient::new(cli))
+    }
+
     pub fn delete_client(&self, ck: &client::Clerk) {
         self.clerks.lock().unwrap().remove(&ck.name);
     }
@@ -223,8 +439,8 @@ impl Config {
         debug!("connect_client {:?} to {:?}", ck_name, to);
         let clerks = self.clerks.lock().unwrap();
         let endnames = &clerks[ck_name];
-        for j in to {
-            let s = &endnames[*j];
+        // a clerk made by `make_client_of` has no end to some servers.
+        for s in to.iter().map(|&j| &endnames[j]).filter(|s| !s.is_empty()) {
             self.net.enable(s, true);
         }
     }
@@ -246,8 +462,9 @@ impl Config {
         // continues to update the Persister.
         // but copy old persister's content so that we always
         // pass Make() the last persisted state.
-        let p = raft::persister::SimplePersister::new();
-        p.save_state_and_snapshot(servers.saved[i].raft_state(), servers.saved[i].snapshot());
+        let p = servers.saved[i].fork();
+        let stats = servers.saved[i].stats();
+        servers.retired_stats.merge(&stats);
         servers.saved[i] = Arc::new(p);
 
         if let Some(kv) = servers.kvservers[i].take() {
@@ -257,10 +474,22 @@ impl Config {
 
     /// Start a server i.
     /// If restart servers, first call shutdown_server
+    /// Like `shutdown_server`, but lets server i drain first, see
+    /// `server::Node::drain`.
+    pub fn drain_server(&self, i: usize, timeout: Duration) {
+        // don't hold the lock while draining, the server still talks to the
+        // others.
+        let kv = self.servers.lock().unwrap().kvservers[i].clone();
+        if let Some(kv) = kv {
+            kv.drain(timeout);
+        }
+        self.shutdown_server(i);
+    }
+
     pub fn start_server(&self, i: usize) {
         // a fresh set of outgoing ClientEnd names.
         let mut servers = self.servers.lock().unwrap();
-        servers.endnames[i] = (0..self.n).map(|_| uniqstring()).collect();
+        servers.endnames[i] = (0..self.n).map(|_| self.ids.uniqstring()).collect();
 
         // a fresh set of ClientEnds.
         let mut ends = Vec::with_capacity(self.n);
@@ -275,23 +504,65 @@ impl Config {
         // give the fresh persister a copy of the old persister's
         // state, so that the spec is that we pass StartKVServer()
         // the last persisted state.
-        let sp = raft::persister::SimplePersister::new();
-        sp.save_state_and_snapshot(servers.saved[i].raft_state(), servers.saved[i].snapshot());
-        let p = Arc::new(sp);
+        let p = Arc::new(servers.saved[i].fork());
+        let stats = servers.saved[i].stats();
+        servers.retired_stats.merge(&stats);
         servers.saved[i] = p.clone();
-
-        let kv = server::KvServer::new(ends, i, Box::new(p), self.maxraftstate);
+        let p = Arc::new(FaultyPersister::new(p));
+        servers.faulty[i] = p.clone();
+
+        let mut kv = server::KvServer::with_clock(
+            ends,
+            i,
+            Box::new(p),
+            self.snapshot_policy.clone(),
+            self.net.clock(),
+        );
+        kv.set_leases(self.leases.load(Ordering::SeqCst));
+        kv.set_limits(*self.limits.lock().unwrap());
         let rf_node = kv.rf.clone();
         let kv_node = server::Node::new(kv);
         servers.kvservers[i] = Some(kv_node.clone());
 
         let mut builder = labrpc::ServerBuilder::new(format!("{}", i));
+        if let Some((max, overflow)) = *self.server_limit.lock().unwrap() {
+            builder.max_in_flight(max, overflow);
+        }
         add_raft_service(rf_node, &mut builder).unwrap();
         add_kv_service(kv_node, &mut builder).unwrap();
         let srv = builder.build();
         self.net.add_server(srv);
     }
 
+    /// Turns leader leases on or off, on by default, for servers started
+    /// from now on. Start them again with `shutdown_server` and
+    /// `start_server` to apply it to running servers.
+    pub fn set_leases(&self, enabled: bool) {
+        self.leases.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Makes servers started from now on run at most `max` RPC handlers at
+    /// once, raft and kv alike, see `labrpc::ServerBuilder::max_in_flight`.
+    pub fn limit_servers(&self, max: usize, overflow: labrpc::Overflow) {
+        *self.server_limit.lock().unwrap() = Some((max, overflow));
+    }
+
+    /// Sets the size limits of keys and values of servers started and
+    /// clerks made from now on, `Limits::default()` unless set.
+    pub fn set_limits(&self, limits: Limits) {
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    /// Injects `faults` into the saves of server i, until it restarts.
+    pub fn set_persist_faults(&self, i: usize, faults: Faults) {
+        self.servers.lock().unwrap().faulty[i].set_faults(faults);
+    }
+
+    /// Number of saves of server i failed since it started.
+    pub fn failed_saves(&self, i: usize) -> u64 {
+        self.servers.lock().unwrap().faulty[i].failed_saves()
+    }
+
     pub fn leader(&self) -> Result<usize> {
         let servers = self.servers.lock().unwrap();
         for (i, kv) in servers.kvservers.iter().enumerate() {
@@ -330,7 +601,19 @@ impl Config {
         info!("{} ...", description);
         *self.t0.lock().unwrap() = Instant::now();
         self.rpcs0.store(self.rpc_total(), Ordering::Relaxed);
+        self.net.reset_stats();
         self.ops.store(0, Ordering::Relaxed);
+        *self.clerk_metrics.lock().unwrap() = ClerkMetrics::default();
+        self.history.clear();
+    }
+
+    /// Checks that the gets, puts, appends and deletes of every clerk since
+    /// begin() are linearizable. Only for tests that use no other ops on the
+    /// keys they read.
+    pub fn check_history(&self, timeout: Duration) {
+        if !self.history.check(timeout) {
+            panic!("history is not linearizable");
+        }
     }
 
     /// End a Test -- the fact that we got here means there
@@ -351,11 +634,58 @@ impl Config {
 
         info!("  ... Passed --");
         info!("  {:?}  {} {} {}", t, npeers, nrpc, nops);
+
+        // which RPCs made up nrpc.
+        for (fq_name, m) in &self.net.stats().methods {
+            info!(
+                "  {} x{}: {} errors, {} bytes out, {} bytes back, p99 {:?}",
+                fq_name,
+                m.calls,
+                m.errors,
+                m.request_bytes,
+                m.reply_bytes,
+                m.latency.quantile(0.99),
+            );
+        }
+
+        // bytes persisted per byte of state the servers hold at the end.
+        let (stats, live) = self.persist_stats();
+        if stats.saves > 0 {
+            info!(
+                "  persisted {} bytes in {} saves, p99 {:?}, write amplification {:.1}",
+                stats.bytes_written,
+                stats.saves,
+                stats.save_latency.quantile(0.99),
+                stats.bytes_written as f64 / live.max(1) as f64,
+            );
+        }
+
+        // latency as the clerks saw it.
+        let clerks = self.clerk_metrics.lock().unwrap();
+        for (op, latency) in &clerks.latency {
+            info!(
+                "  {} x{}: mean {:?}, p99 {:?}, max {:?}",
+                op,
+                latency.count(),
+                latency.mean(),
+                latency.quantile(0.99),
+                latency.max(),
+            );
+        }
+        if clerks.calls() > 0 {
+            info!(
+                "  clerks retried {} times, redirected {} times",
+                clerks.retries, clerks.redirects
+            );
+        }
     }
 }
 
 impl Drop for Config {
     fn drop(&mut self) {
+        if thread::panicking() {
+            println!("network seed: {}", self.net.seed());
+        }
         let servers = self.servers.lock().unwrap();
         for s in &servers.kvservers {
             if let Some(s) = s {
@@ -0,0 +1,116 @@
+//! Draining a server before it stops.
+//!
+//! Killing a server drops the replies of the requests it's working on, and
+//! their clerks only find out by timing out. To drain, a server closes its
+//! `Gate` so new requests are turned away with `Error::ShuttingDown` and
+//! their clerks move on to another server right away, waits for the
+//! requests that passed the gate to be answered, hands raft leadership
+//! away, and only then stops.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::kvraft::errors::{Error, Result};
+
+#[derive(Debug, Default)]
+struct State {
+    closed: bool,
+    inside: usize,
+}
+
+/// Lets requests in until it's closed, and counts those inside. Clones
+/// share the gate.
+#[derive(Clone, Debug, Default)]
+pub struct Gate {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+/// A request let in by a `Gate`, inside until it's dropped.
+#[derive(Debug)]
+pub struct Pass {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl Gate {
+    pub fn new() -> Gate {
+        Gate::default()
+    }
+
+    /// Lets a request in, or fails with `Error::ShuttingDown` once closed.
+    pub fn enter(&self) -> Result<Pass> {
+        let mut state = self.state.0.lock().unwrap();
+        if state.closed {
+            return Err(Error::ShuttingDown);
+        }
+        state.inside += 1;
+        Ok(Pass {
+            state: self.state.clone(),
+        })
+    }
+
+    /// Turns away requests from now on.
+    pub fn close(&self) {
+        self.state.0.lock().unwrap().closed = true;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.0.lock().unwrap().closed
+    }
+
+    /// The number of requests inside.
+    pub fn inside(&self) -> usize {
+        self.state.0.lock().unwrap().inside
+    }
+
+    /// Waits up to `timeout` for the requests inside to leave. Returns
+    /// whether they all did.
+    pub fn wait_empty(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.inside > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = cvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+impl Drop for Pass {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().inside -= 1;
+        cvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_gate() {
+        let gate = Gate::new();
+        let a = gate.enter().unwrap();
+        let b = gate.clone().enter().unwrap();
+        assert_eq!(gate.inside(), 2);
+        gate.close();
+        assert!(gate.is_closed());
+        assert_eq!(gate.enter().unwrap_err(), Error::ShuttingDown);
+        drop(a);
+        assert!(!gate.wait_empty(Duration::from_millis(10)));
+
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(b);
+        });
+        assert!(gate.wait_empty(Duration::from_secs(5)));
+        assert_eq!(gate.inside(), 0);
+        t.join().unwrap();
+    }
+}
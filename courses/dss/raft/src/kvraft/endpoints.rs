@@ -0,0 +1,94 @@
+//! The servers of a clerk, as they change.
+//!
+//! A clerk built with a fixed list of servers can't follow a cluster whose
+//! members change. `Endpoints` is a list of servers that grows and shrinks
+//! while the clerk runs. A server keeps its index for as long as it's in
+//! the list, so indexes learned before, e.g. by `LeaderCache`, stay valid,
+//! and a removed server's index isn't reused.
+
+/// Servers of a clerk by index, `T` being the RPC client of a server.
+#[derive(Clone, Debug)]
+pub struct Endpoints<T> {
+    ends: Vec<Option<T>>,
+}
+
+impl<T> Endpoints<T> {
+    pub fn new(ends: Vec<T>) -> Endpoints<T> {
+        Endpoints {
+            ends: ends.into_iter().map(Some).collect(),
+        }
+    }
+
+    /// Adds a server, returns its index.
+    pub fn add(&mut self, end: T) -> usize {
+        self.ends.push(Some(end));
+        self.ends.len() - 1
+    }
+
+    /// Removes server `index`, returns it if it was there.
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        self.ends.get_mut(index)?.take()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.ends.get(index)?.as_ref()
+    }
+
+    /// The first server at or after `index`, wrapping around, `None` if
+    /// there's none left.
+    pub fn at_or_after(&self, index: usize) -> Option<usize> {
+        let n = self.ends.len();
+        (0..n)
+            .map(|i| (index + i) % n)
+            .find(|&i| self.ends[i].is_some())
+    }
+
+    /// The number of indexes handed out, removed servers included.
+    pub fn slots(&self) -> usize {
+        self.ends.len()
+    }
+
+    /// The servers left, by index.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.ends
+            .iter()
+            .enumerate()
+            .filter_map(|(i, end)| Some((i, end.as_ref()?)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoints() {
+        let mut e = Endpoints::new(vec!["a", "b"]);
+        assert_eq!(e.add("c"), 2);
+        assert_eq!(e.remove(1), Some("b"));
+        assert_eq!(e.remove(1), None);
+        assert_eq!(e.get(1), None);
+        assert_eq!(e.get(2), Some(&"c"));
+        assert_eq!(e.at_or_after(1), Some(2));
+        assert_eq!(e.at_or_after(3), Some(0));
+        assert_eq!(e.add("d"), 3);
+        assert_eq!(e.slots(), 4);
+        let left: Vec<_> = e.iter().collect();
+        assert_eq!(left, vec![(0, &"a"), (2, &"c"), (3, &"d")]);
+
+        for i in 0..4 {
+            e.remove(i);
+        }
+        assert!(e.is_empty());
+        assert_eq!(e.at_or_after(0), None);
+        assert_eq!(Endpoints::<()>::new(vec![]).at_or_after(0), None);
+    }
+}
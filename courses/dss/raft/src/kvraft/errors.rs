@@ -3,6 +3,48 @@ use std::{error, fmt, result};
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Error {
     NoLeader,
+    /// The server has too many operations in flight, retry later.
+    Busy,
+    /// The server is draining to shut down, try another one.
+    ShuttingDown,
+    /// The deadline of the request passed before its op was applied, or
+    /// the deadline of a clerk call before it got a reply. The op may still
+    /// be applied later.
+    Timeout,
+    /// A snapshot couldn't be decoded.
+    Corruption(String),
+    /// The key of a write is longer than the limit, see `kvraft::limits`.
+    KeyTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The value of a write, or after an append, is larger than the limit.
+    ValueTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// No index by the name was declared, see `kvraft::index`.
+    NoIndex(String),
+    /// The revision read has been compacted, revisions from this one on
+    /// are still there.
+    Compacted(u64),
+    /// Applying the op of the entry at `index` panicked. The op isn't
+    /// applied, and a retry of it fails the same way.
+    Poisoned {
+        index: u64,
+        message: String,
+    },
+    /// A clerk call spent its retry budget, see `kvraft::retry`. `tried`
+    /// servers failed it, with `last_errors`, the last error of each, over
+    /// `rounds` rounds. The op may still be applied later.
+    Unavailable {
+        tried: usize,
+        rounds: u32,
+        last_errors: Vec<String>,
+    },
+    /// The clerk call was cancelled, see `kvraft::cancel`. The op may still
+    /// be applied later.
+    Cancelled,
 }
 
 impl fmt::Display for Error {
@@ -14,7 +56,18 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            Error::NoLeader => None,
+            Error::NoLeader
+            | Error::Busy
+            | Error::Timeout
+            | Error::ShuttingDown
+            | Error::KeyTooLarge { .. }
+            | Error::ValueTooLarge { .. }
+            | Error::NoIndex(_)
+            | Error::Corruption(_)
+            | Error::Compacted(_)
+            | Error::Poisoned { .. }
+            | Error::Unavailable { .. }
+            | Error::Cancelled => None,
         }
     }
 }
@@ -0,0 +1,81 @@
+//! Fencing tokens.
+//!
+//! A clerk holding a lock key may pause, lose the lock to another clerk and
+//! wake up still believing it holds it. Writes to designated lock keys get a
+//! fencing token, the term and index of the entry that applied them, which
+//! only grows: an external resource that remembers the largest token it has
+//! seen can turn away a stale holder with a smaller one.
+
+/// Orders writes to lock keys, by term first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FenceToken {
+    pub term: u64,
+    pub index: u64,
+}
+
+/// Which keys are lock keys, by prefix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FencedKeys {
+    prefixes: Vec<String>,
+}
+
+impl Default for FencedKeys {
+    /// Keys starting with "lock/".
+    fn default() -> FencedKeys {
+        FencedKeys::new(vec!["lock/".to_owned()])
+    }
+}
+
+impl FencedKeys {
+    pub fn new(prefixes: Vec<String>) -> FencedKeys {
+        FencedKeys { prefixes }
+    }
+
+    /// Whether writes to `key` get a fencing token.
+    pub fn is_fenced(&self, key: &str) -> bool {
+        self.prefixes.iter().any(|p| key.starts_with(p.as_str()))
+    }
+}
+
+/// The guard of an external resource, the largest token it has seen.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fence {
+    largest: Option<FenceToken>,
+}
+
+impl Fence {
+    pub fn new() -> Fence {
+        Fence::default()
+    }
+
+    /// Admits a request with `token` if no larger token was seen before.
+    pub fn admit(&mut self, token: FenceToken) -> bool {
+        match self.largest {
+            Some(largest) if token < largest => false,
+            _ => {
+                self.largest = Some(token);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fence() {
+        let keys = FencedKeys::default();
+        assert!(keys.is_fenced("lock/a"));
+        assert!(!keys.is_fenced("a/lock/"));
+
+        let t = |term, index| FenceToken { term, index };
+        assert!(t(1, 9) < t(2, 3));
+        let mut fence = Fence::new();
+        assert!(fence.admit(t(1, 5)));
+        assert!(fence.admit(t(1, 5)));
+        assert!(fence.admit(t(2, 3)));
+        assert!(!fence.admit(t(1, 9)));
+    }
+}
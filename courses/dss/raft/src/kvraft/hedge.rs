@@ -0,0 +1,100 @@
+//! Hedged requests.
+//!
+//! A Get sent to a server that is slow, partitioned away or no longer the
+//! leader waits for its RPC timeout before the clerk tries another one. A
+//! hedged Get sends the same request to another server if the first hasn't
+//! replied within a short delay, and takes whichever reply comes back first.
+//! Both requests carry the same client and sequence number, so a server that
+//! gets both serves the op once.
+
+use std::future::Future;
+
+use futures::future::{self, Either};
+
+/// Awaits `first`, and if `delay` resolves before it, starts `hedge` too.
+/// Resolves to the first `Ok` of the two, or, if both fail, to the error of
+/// the one that failed last. A `first` that fails before the delay isn't
+/// hedged, its error is returned right away.
+pub async fn hedged<T, E, A, B, H, D>(first: A, hedge: H, delay: D) -> Result<T, E>
+where
+    A: Future<Output = Result<T, E>>,
+    B: Future<Output = Result<T, E>>,
+    H: FnOnce() -> B,
+    D: Future<Output = ()>,
+{
+    let first = match future::select(Box::pin(first), Box::pin(delay)).await {
+        Either::Left((result, _)) => return result,
+        Either::Right(((), first)) => first,
+    };
+    match future::select(first, Box::pin(hedge())).await {
+        Either::Left((Ok(v), _)) | Either::Right((Ok(v), _)) => Ok(v),
+        Either::Left((Err(_), hedge)) => hedge.await,
+        Either::Right((Err(_), first)) => first.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::executor::block_on;
+    use futures::future::{pending, ready};
+
+    use super::*;
+
+    // pending for `n` polls.
+    struct Yields(u32);
+
+    impl Future for Yields {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 == 0 {
+                return Poll::Ready(());
+            }
+            self.0 -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    async fn after<T>(polls: u32, v: T) -> T {
+        Yields(polls).await;
+        v
+    }
+
+    #[test]
+    fn test_hedged() {
+        let started = Cell::new(false);
+        let hedge = || {
+            started.set(true);
+            ready(Ok::<_, &str>(2))
+        };
+        let r = block_on(hedged(ready(Ok(1)), hedge, pending()));
+        assert_eq!(r, Ok(1));
+        assert!(!started.get(), "hedged a reply in time");
+        let r = block_on(hedged(ready(Err("down")), hedge, ready(())));
+        assert_eq!(r, Err("down"));
+        assert!(!started.get());
+
+        let r = block_on(hedged(pending(), hedge, ready(())));
+        assert_eq!(r, Ok(2));
+        assert!(started.get());
+
+        // the hedge fails, the first replies later.
+        let r = block_on(hedged(
+            after(2, Ok(1)),
+            || ready(Err("wrong leader")),
+            ready(()),
+        ));
+        assert_eq!(r, Ok(1));
+        let r = block_on(hedged(
+            after(2, Err::<u32, _>("timeout")),
+            || after(4, Err("wrong leader")),
+            after(1, ()),
+        ));
+        assert_eq!(r, Err("wrong leader"));
+    }
+}
@@ -0,0 +1,130 @@
+//! The history of the calls of clerks, for the linearizability checker.
+//!
+//! Checking a test's history means timing every Get, Put and Append around
+//! the call in the test body. A `History` does that in the clerk instead:
+//! clerks built with one record every call, with the time it was invoked
+//! and the time it returned, as operations of `linearizability::models`.
+//! Clones share the history, so the clerks of a test record into one.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use linearizability::check_operations_timeout;
+use linearizability::model::Operation;
+use linearizability::models::{KvInput, KvModel, KvOutput, Op};
+
+type KvOperation = Operation<KvInput, KvOutput>;
+
+/// Calls of clerks, timed from the creation of the history.
+#[derive(Clone, Debug)]
+pub struct History {
+    start: Instant,
+    operations: Arc<Mutex<Vec<KvOperation>>>,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new()
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            start: Instant::now(),
+            operations: Arc::default(),
+        }
+    }
+
+    /// A Get of `key` called at `call` returned `value` at `finish`.
+    pub fn get(&self, key: &str, value: &str, call: Instant, finish: Instant) {
+        self.record(Op::GET, key, "", value, call, finish);
+    }
+
+    /// A Put of `value` to `key` called at `call` returned at `finish`. A
+    /// Delete is a Put of "", it reads the same.
+    pub fn put(&self, key: &str, value: &str, call: Instant, finish: Instant) {
+        self.record(Op::PUT, key, value, "", call, finish);
+    }
+
+    /// An Append of `value` to `key` called at `call` returned at `finish`.
+    pub fn append(&self, key: &str, value: &str, call: Instant, finish: Instant) {
+        self.record(Op::APPEND, key, value, "", call, finish);
+    }
+
+    fn record(&self, op: Op, key: &str, value: &str, output: &str, call: Instant, finish: Instant) {
+        let operation = Operation {
+            input: KvInput {
+                op,
+                key: key.to_owned(),
+                value: value.to_owned(),
+            },
+            call: self.nanos(call),
+            output: KvOutput {
+                value: output.to_owned(),
+            },
+            finish: self.nanos(finish),
+        };
+        self.operations.lock().unwrap().push(operation);
+    }
+
+    fn nanos(&self, t: Instant) -> i64 {
+        t.saturating_duration_since(self.start).as_nanos() as i64
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets the calls recorded so far.
+    pub fn clear(&self) {
+        self.operations.lock().unwrap().clear();
+    }
+
+    /// Takes the calls recorded so far and checks they're linearizable.
+    /// Gives up, and takes them as linearizable, after `timeout`.
+    pub fn check(&self, timeout: Duration) -> bool {
+        let operations = std::mem::take(&mut *self.operations.lock().unwrap());
+        check_operations_timeout(KvModel {}, operations, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let timeout = Duration::from_secs(1);
+        let h = History::new();
+        let t = |ms| h.start + Duration::from_millis(ms);
+        h.put("k", "a", t(0), t(10));
+        let shared = h.clone();
+        shared.append("k", "b", t(20), t(30));
+        h.get("k", "ab", t(40), t(50));
+        assert_eq!(h.len(), 3);
+        assert!(h.check(timeout));
+        assert!(h.is_empty());
+        h.get("k", "x", t(0), t(1));
+        h.clear();
+        assert!(h.is_empty());
+
+        // a get after the append returned can't miss it.
+        h.put("k", "a", t(0), t(10));
+        h.append("k", "b", t(20), t(30));
+        h.get("k", "a", t(40), t(50));
+        assert!(!h.check(timeout));
+
+        // but a get overlapping it may.
+        h.put("k", "a", t(0), t(10));
+        h.append("k", "b", t(20), t(30));
+        h.get("k", "a", t(25), t(50));
+        h.put("k", "", t(60), t(70));
+        h.get("k", "", t(80), t(90));
+        assert!(h.check(timeout));
+    }
+}
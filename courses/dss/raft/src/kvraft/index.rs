@@ -0,0 +1,153 @@
+//! Secondary indexes.
+//!
+//! An index covers the keys with a prefix and orders them by value, so the
+//! keys with a value, or values in a range, can be found without a scan of
+//! every key. Indexes are updated in the same apply step as the write to the
+//! key, so they never disagree with the keys, on any replica.
+//!
+//! Indexes are declared through the raft log like writes, and filled from
+//! the keys already there when declared. Save the declarations in snapshots
+//! and rebuild the entries when restoring one.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+/// Indexes by name.
+#[derive(Debug, Default)]
+pub struct Indexes {
+    // index name to key prefix.
+    specs: BTreeMap<String, String>,
+    // (index name, value, key).
+    entries: BTreeSet<(String, String, String)>,
+}
+
+impl Indexes {
+    pub fn new() -> Indexes {
+        Indexes::default()
+    }
+
+    /// Declares index `name` over the keys starting with `key_prefix`,
+    /// filled from `keys`, the key/value pairs there are. Returns false if
+    /// there's an index by that name already, which is left as it is.
+    pub fn create<'a, I>(&mut self, name: &str, key_prefix: &str, keys: I) -> bool
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        if self.specs.contains_key(name) {
+            return false;
+        }
+        self.specs.insert(name.to_owned(), key_prefix.to_owned());
+        for (key, value) in keys {
+            if key.starts_with(key_prefix) {
+                self.insert(name, value, key);
+            }
+        }
+        true
+    }
+
+    /// Drops index `name`, returns whether there was one.
+    pub fn drop_index(&mut self, name: &str) -> bool {
+        if self.specs.remove(name).is_none() {
+            return false;
+        }
+        self.entries.retain(|(n, _, _)| n != name);
+        true
+    }
+
+    /// The indexes, with their key prefixes, to save in a snapshot.
+    pub fn specs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.specs.iter().map(|(n, p)| (n.as_str(), p.as_str()))
+    }
+
+    /// Updates the indexes covering `key` for a write from `old` to `new`,
+    /// `None` for a missing or deleted key.
+    pub fn update(&mut self, key: &str, old: Option<&str>, new: Option<&str>) {
+        let names: Vec<_> = self
+            .specs
+            .iter()
+            .filter(|(_, prefix)| key.starts_with(prefix.as_str()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            if let Some(old) = old {
+                self.entries
+                    .remove(&(name.clone(), old.to_owned(), key.to_owned()));
+            }
+            if let Some(new) = new {
+                self.insert(&name, new, key);
+            }
+        }
+    }
+
+    /// Up to `limit` (value, key) pairs of index `name` with values in
+    /// `[start, end)`, after the pair `after` if given, in order. An empty
+    /// `end` goes to the last value, a `limit` of 0 returns them all.
+    /// `None` if there's no such index.
+    pub fn scan(
+        &self,
+        name: &str,
+        start: &str,
+        end: &str,
+        after: Option<(&str, &str)>,
+        limit: usize,
+    ) -> Option<Vec<(&str, &str)>> {
+        self.specs.get(name)?;
+        let entry = |value: &str, key: &str| (name.to_owned(), value.to_owned(), key.to_owned());
+        let from = match after {
+            Some((value, key)) if value >= start => Bound::Excluded(entry(value, key)),
+            _ => Bound::Included(entry(start, "")),
+        };
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let found = self
+            .entries
+            .range((from, Bound::Unbounded))
+            .take_while(|(n, v, _)| n == name && (end.is_empty() || v.as_str() < end))
+            .take(limit)
+            .map(|(_, v, k)| (v.as_str(), k.as_str()))
+            .collect();
+        Some(found)
+    }
+
+    fn insert(&mut self, name: &str, value: &str, key: &str) {
+        self.entries
+            .insert((name.to_owned(), value.to_owned(), key.to_owned()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_indexes() {
+        let mut ix = Indexes::new();
+        let keys = vec![("user/1", "ann"), ("user/2", "bob"), ("team/1", "ann")];
+        assert!(ix.create("by-name", "user/", keys));
+        assert!(!ix.create("by-name", "team/", vec![]));
+        assert_eq!(
+            ix.scan("by-name", "ann", "b", None, 0).unwrap(),
+            vec![("ann", "user/1")]
+        );
+
+        ix.update("user/3", None, Some("ann"));
+        ix.update("user/2", Some("bob"), Some("al"));
+        ix.update("team/2", None, Some("ann"));
+        assert_eq!(
+            ix.scan("by-name", "", "", None, 0).unwrap(),
+            vec![("al", "user/2"), ("ann", "user/1"), ("ann", "user/3")]
+        );
+        // pages of one.
+        let page = ix.scan("by-name", "", "", Some(("ann", "user/1")), 1);
+        assert_eq!(page.unwrap(), vec![("ann", "user/3")]);
+
+        ix.update("user/1", Some("ann"), None);
+        assert_eq!(
+            ix.scan("by-name", "ann", "ann\0", None, 0).unwrap(),
+            vec![("ann", "user/3")]
+        );
+        assert_eq!(ix.specs().collect::<Vec<_>>(), vec![("by-name", "user/")]);
+        assert!(ix.drop_index("by-name"));
+        assert_eq!(ix.scan("by-name", "", "", None, 0), None);
+        assert!(ix.entries.is_empty());
+    }
+}
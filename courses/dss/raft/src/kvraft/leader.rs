@@ -0,0 +1,132 @@
+//! Finding the leader from the clerk.
+//!
+//! A clerk that probes the servers in turn for every op wastes a round trip
+//! on every follower before it. Servers reply to requests they can't serve
+//! with the index of the leader they believe in, and the clerk remembers the
+//! leader across ops. Clerks number their servers in their own order though,
+//! so every reply also carries the index of the server that sent it, and
+//! `LeaderCache` learns which of its servers is which raft peer from them.
+//!
+//! Indexes go in replies plus one, so 0, the default, means unknown.
+//!
+//! Servers added to a clerk, see `kvraft::endpoints`, get the next index,
+//! and a peer may be one the clerk didn't know yet.
+
+/// An index, or `None`, as sent in a `server` or `leader_hint` field.
+pub fn to_hint(index: Option<usize>) -> u32 {
+    index.map_or(0, |i| i as u32 + 1)
+}
+
+/// The index sent in a `server` or `leader_hint` field.
+pub fn from_hint(hint: u32) -> Option<usize> {
+    (hint as usize).checked_sub(1)
+}
+
+/// The servers of a clerk, by the order of the clerk, and which one leads.
+#[derive(Clone, Debug)]
+pub struct LeaderCache {
+    // the server to try first.
+    leader: usize,
+    // the server of the clerk of every raft peer, once learned.
+    by_peer: Vec<Option<usize>>,
+    // the number of servers of the clerk, removed ones included.
+    servers: usize,
+}
+
+impl LeaderCache {
+    pub fn new(servers: usize) -> LeaderCache {
+        LeaderCache {
+            leader: 0,
+            by_peer: vec![None; servers],
+            servers,
+        }
+    }
+
+    /// A server was added to the clerk, with the next index.
+    pub fn add_server(&mut self) {
+        self.servers += 1;
+    }
+
+    /// Server `server` was removed from the clerk, forgets what it was.
+    pub fn forget(&mut self, server: usize) {
+        for slot in &mut self.by_peer {
+            if *slot == Some(server) {
+                *slot = None;
+            }
+        }
+        if self.leader == server {
+            self.leader = (server + 1) % self.servers;
+        }
+    }
+
+    /// The server to send the next op to first.
+    pub fn leader(&self) -> usize {
+        self.leader
+    }
+
+    /// Server `server` replied, from raft peer `peer`, taken from the
+    /// `server` field of the reply.
+    pub fn learn(&mut self, server: usize, peer: Option<usize>) {
+        if let Some(peer) = peer {
+            if peer >= self.by_peer.len() {
+                self.by_peer.resize(peer + 1, None);
+            }
+            self.by_peer[peer] = Some(server);
+        }
+    }
+
+    /// Server `server` served an op, so it leads.
+    pub fn found(&mut self, server: usize) {
+        self.leader = server;
+    }
+
+    /// Server `server` doesn't lead and hinted at raft peer `hint`. Returns
+    /// the server to try next: the one of the hinted peer if the clerk knows
+    /// it, and it's not `server` itself, otherwise the next one in order,
+    /// which may have been removed since.
+    pub fn redirect(&mut self, server: usize, hint: Option<usize>) -> usize {
+        let hinted = hint.and_then(|p| *self.by_peer.get(p)?);
+        self.leader = match hinted {
+            Some(next) if next != server => next,
+            _ => (server + 1) % self.servers,
+        };
+        self.leader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leader_cache() {
+        assert_eq!(to_hint(None), 0);
+        assert_eq!(from_hint(to_hint(Some(2))), Some(2));
+        assert_eq!(from_hint(0), None);
+
+        // the clerk's servers 0, 1 and 2 are raft peers 2, 0 and 1.
+        let mut c = LeaderCache::new(3);
+        assert_eq!(c.leader(), 0);
+        c.learn(0, Some(2));
+        // peer 1 isn't known yet.
+        assert_eq!(c.redirect(0, Some(1)), 1);
+        c.learn(1, Some(0));
+        c.learn(2, Some(1));
+        c.learn(2, Some(4));
+        assert_eq!(c.redirect(1, Some(1)), 2);
+        assert_eq!(c.redirect(2, Some(1)), 0);
+        assert_eq!(c.redirect(0, None), 1);
+        c.found(2);
+        assert_eq!(c.leader(), 2);
+
+        // server 3 is added, it's peer 3.
+        c.add_server();
+        c.learn(3, Some(3));
+        assert_eq!(c.redirect(0, Some(3)), 3);
+        assert_eq!(c.redirect(3, None), 0);
+        c.found(3);
+        c.forget(3);
+        assert_eq!(c.leader(), 0);
+        assert_eq!(c.redirect(1, Some(3)), 2);
+    }
+}
@@ -0,0 +1,92 @@
+//! Size limits of keys and values.
+//!
+//! Every write lands in the raft log, and the value stays in every snapshot
+//! after it, so a huge key or value slows down the whole service. Servers
+//! reject writes past the limits, and clerks check them before sending, with
+//! `Error::KeyTooLarge` or `Error::ValueTooLarge`.
+
+use crate::kvraft::errors::{Error, Result};
+
+/// Max sizes in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_key: usize,
+    pub max_value: usize,
+}
+
+impl Default for Limits {
+    /// 1KiB keys and 1MiB values.
+    fn default() -> Limits {
+        Limits {
+            max_key: 1 << 10,
+            max_value: 1 << 20,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks a write of `value` to `key`.
+    pub fn check(&self, key: &str, value: &str) -> Result<()> {
+        self.check_key(key)?;
+        if value.len() > self.max_value {
+            return Err(Error::ValueTooLarge {
+                size: value.len(),
+                max: self.max_value,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks a key on its own, e.g. of an Incr or a Delete.
+    pub fn check_key(&self, key: &str) -> Result<()> {
+        if key.len() > self.max_key {
+            return Err(Error::KeyTooLarge {
+                size: key.len(),
+                max: self.max_key,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks the value `key` ends up with after appending `arg` to
+    /// `value`, the current one.
+    pub fn check_append(&self, key: &str, value: &str, arg: &str) -> Result<()> {
+        self.check_key(key)?;
+        let size = value.len() + arg.len();
+        if size > self.max_value {
+            return Err(Error::ValueTooLarge {
+                size,
+                max: self.max_value,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_key: 2,
+            max_value: 4,
+        };
+        assert_eq!(limits.check("ab", "abcd"), Ok(()));
+        assert_eq!(
+            limits.check("abc", ""),
+            Err(Error::KeyTooLarge { size: 3, max: 2 })
+        );
+        assert_eq!(
+            limits.check("a", "abcde"),
+            Err(Error::ValueTooLarge { size: 5, max: 4 })
+        );
+        assert_eq!(limits.check_append("a", "ab", "cd"), Ok(()));
+        assert_eq!(
+            limits.check_append("a", "abc", "de"),
+            Err(Error::ValueTooLarge { size: 5, max: 4 })
+        );
+        assert_eq!(Limits::default().check_key(&"k".repeat(1024)), Ok(()));
+    }
+}
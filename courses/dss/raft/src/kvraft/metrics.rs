@@ -0,0 +1,86 @@
+//! Latencies and retries of the calls of a clerk, see `Clerk::metrics`.
+//!
+//! RPC counts tell how hard the servers worked, not how long a client
+//! waited. These are measured by the clerk, from the start of a call to its
+//! result, retries and redirects included.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::raft::metrics::Histogram;
+
+/// Metrics of a clerk, or of several merged.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClerkMetrics {
+    /// The latency of calls, by op: "Get", "Put", "Append", ...
+    pub latency: BTreeMap<String, Histogram>,
+    /// Tries after the first one of a call, over all calls.
+    pub retries: u64,
+    /// Replies with `wrong_leader`.
+    pub redirects: u64,
+}
+
+impl ClerkMetrics {
+    /// A call of `op` returned after `latency`.
+    pub fn observe(&mut self, op: &str, latency: Duration) {
+        if let Some(h) = self.latency.get_mut(op) {
+            h.observe(latency);
+        } else {
+            let mut h = Histogram::default();
+            h.observe(latency);
+            self.latency.insert(op.to_owned(), h);
+        }
+    }
+
+    /// The number of calls returned.
+    pub fn calls(&self) -> u64 {
+        self.latency.values().map(Histogram::count).sum()
+    }
+
+    /// The latency of all calls, whatever their op.
+    pub fn total(&self) -> Histogram {
+        let mut total = Histogram::default();
+        for h in self.latency.values() {
+            total.merge(h);
+        }
+        total
+    }
+
+    /// Adds the calls of `other`.
+    pub fn merge(&mut self, other: &ClerkMetrics) {
+        for (op, h) in &other.latency {
+            self.latency.entry(op.clone()).or_default().merge(h);
+        }
+        self.retries += other.retries;
+        self.redirects += other.redirects;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clerk_metrics() {
+        let ms = Duration::from_millis;
+        let mut a = ClerkMetrics::default();
+        a.observe("Get", ms(1));
+        a.observe("Get", ms(3));
+        a.observe("Put", ms(10));
+        a.retries += 2;
+        assert_eq!(a.calls(), 3);
+        assert_eq!(a.latency["Get"].count(), 2);
+        assert_eq!(a.total().max(), ms(10));
+
+        let mut b = ClerkMetrics::default();
+        b.observe("Put", ms(20));
+        b.observe("Append", ms(5));
+        b.redirects += 1;
+        a.merge(&b);
+        assert_eq!(a.calls(), 5);
+        assert_eq!(a.latency["Put"].count(), 2);
+        assert_eq!(a.latency["Put"].max(), ms(20));
+        assert_eq!((a.retries, a.redirects), (2, 1));
+        assert_eq!(a.total().count(), 5);
+    }
+}
@@ -1,7 +1,33 @@
+pub mod applied;
+pub mod apply_pool;
+pub mod audit;
+pub mod backoff;
+pub mod backpressure;
+pub mod cancel;
 pub mod client;
+pub mod coalesce;
 #[cfg(test)]
 pub mod config;
+pub mod drain;
+pub mod endpoints;
 pub mod errors;
+pub mod fence;
+pub mod hedge;
+pub mod history;
+pub mod index;
+pub mod leader;
+pub mod limits;
+pub mod metrics;
+pub mod mvcc;
+pub mod namespace;
+pub mod pipeline;
+pub mod prefix;
+pub mod retry;
+pub mod script;
 pub mod server;
+pub mod session;
+pub mod snapshot;
+pub mod state_machine;
 #[cfg(test)]
 mod tests;
+pub mod waiters;
@@ -0,0 +1,173 @@
+//! Multi-version keys.
+//!
+//! Every applied write gets the next revision, starting from 1, the same on
+//! every replica. `Mvcc` keeps the last few versions of every key, so a key
+//! can be read as of an earlier revision, until the history below a revision
+//! is compacted. This is what the percolator lab builds transactions on.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::kvraft::errors::{Error, Result};
+
+struct Version<V> {
+    revision: u64,
+    // None if the key was deleted.
+    value: Option<V>,
+}
+
+struct History<V> {
+    versions: VecDeque<Version<V>>,
+    // whether older versions were dropped.
+    trimmed: bool,
+}
+
+/// Keys with their recent versions, in key order.
+pub struct Mvcc<V> {
+    keys: BTreeMap<String, History<V>>,
+    revision: u64,
+    compacted: u64,
+    max_versions: usize,
+}
+
+impl<V> Mvcc<V> {
+    /// Keeps up to `max_versions` versions of every key, at least 1.
+    pub fn new(max_versions: usize) -> Mvcc<V> {
+        Mvcc {
+            keys: BTreeMap::new(),
+            revision: 0,
+            compacted: 0,
+            max_versions: max_versions.max(1),
+        }
+    }
+
+    /// The revision of the last write.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Revisions below this one have been compacted.
+    pub fn compacted(&self) -> u64 {
+        self.compacted
+    }
+
+    /// Writes `value` to `key`, or deletes it if `None`, at the next
+    /// revision. Returns the revision.
+    pub fn write(&mut self, key: &str, value: Option<V>) -> u64 {
+        self.revision += 1;
+        let history = self.keys.entry(key.to_owned()).or_insert_with(|| History {
+            versions: VecDeque::new(),
+            trimmed: false,
+        });
+        history.versions.push_back(Version {
+            revision: self.revision,
+            value,
+        });
+        if history.versions.len() > self.max_versions {
+            history.versions.pop_front();
+            history.trimmed = true;
+        }
+        self.revision
+    }
+
+    /// The value of `key` as of `revision`, the latest one if 0 or past the
+    /// last write. Fails with `Error::Compacted` if the versions needed have
+    /// been dropped.
+    pub fn get(&self, key: &str, revision: u64) -> Result<Option<&V>> {
+        if revision == 0 || revision >= self.revision {
+            let latest = self.keys.get(key).and_then(|h| h.versions.back());
+            return Ok(latest.and_then(|v| v.value.as_ref()));
+        }
+        if revision < self.compacted {
+            return Err(Error::Compacted(self.compacted));
+        }
+        let history = match self.keys.get(key) {
+            Some(history) => history,
+            None => return Ok(None),
+        };
+        match history
+            .versions
+            .iter()
+            .rev()
+            .find(|v| v.revision <= revision)
+        {
+            Some(v) => Ok(v.value.as_ref()),
+            None if history.trimmed => Err(Error::Compacted(history.versions[0].revision)),
+            None => Ok(None),
+        }
+    }
+
+    /// Drops the versions only needed to read below `revision`, and keys
+    /// deleted by then.
+    pub fn compact(&mut self, revision: u64) {
+        let revision = revision.min(self.revision);
+        if revision <= self.compacted {
+            return;
+        }
+        self.compacted = revision;
+        self.keys.retain(|_, history| {
+            let versions = &mut history.versions;
+            // keep the version read at `revision` and the newer ones.
+            while versions.len() > 1 && versions[1].revision <= revision {
+                versions.pop_front();
+                history.trimmed = true;
+            }
+            let deleted = versions.len() == 1
+                && versions[0].revision <= revision
+                && versions[0].value.is_none();
+            !deleted
+        });
+    }
+
+    /// The latest values of the keys in `[start, end)`, all keys from
+    /// `start` if `end` is empty.
+    pub fn range<'a>(
+        &'a self,
+        start: &str,
+        end: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a V)> {
+        self.keys
+            .range(start.to_owned()..)
+            .take_while(move |(k, _)| end.is_empty() || k.as_str() < end)
+            .filter_map(|(k, h)| {
+                let value = h.versions.back()?.value.as_ref()?;
+                Some((k.as_str(), value))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mvcc() {
+        let mut m = Mvcc::new(3);
+        assert_eq!(m.write("a", Some(1)), 1);
+        assert_eq!(m.write("b", Some(10)), 2);
+        assert_eq!(m.write("a", Some(2)), 3);
+        assert_eq!(m.write("a", None), 4);
+        assert_eq!(m.get("a", 0).unwrap(), None);
+        assert_eq!(m.get("a", 1).unwrap(), Some(&1));
+        assert_eq!(m.get("a", 2).unwrap(), Some(&1));
+        assert_eq!(m.get("a", 3).unwrap(), Some(&2));
+        assert_eq!(m.get("b", 1).unwrap(), None);
+        assert_eq!(m.get("c", 1).unwrap(), None);
+        let range: Vec<_> = m.range("", "").collect();
+        assert_eq!(range, vec![("b", &10)]);
+
+        // only 3 versions are kept.
+        m.write("a", Some(5));
+        assert_eq!(m.get("a", 1), Err(Error::Compacted(3)));
+        assert_eq!(m.get("a", 3).unwrap(), Some(&2));
+
+        m.compact(4);
+        assert_eq!(m.compacted(), 4);
+        assert_eq!(m.get("a", 3), Err(Error::Compacted(4)));
+        assert_eq!(m.get("a", 4).unwrap(), None);
+        assert_eq!(m.get("b", 4).unwrap(), Some(&10));
+        m.write("b", None);
+        m.compact(m.revision());
+        assert_eq!(m.range("", "").count(), 1);
+        assert_eq!(m.keys.len(), 1);
+    }
+}
@@ -0,0 +1,147 @@
+//! Keyspaces sharing one raft group.
+//!
+//! Every op names a namespace, "" by default, and the same key in two
+//! namespaces is two keys. `Namespaces` keeps the keys of every namespace in
+//! its own ordered map with its stats, so a namespace can be dropped in one
+//! step, by a single raft entry, and its stats don't need a scan.
+
+use std::collections::BTreeMap;
+
+/// The keys in a namespace and their size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NamespaceStats {
+    pub keys: u64,
+    /// Of the keys and values, in bytes.
+    pub bytes: u64,
+}
+
+struct Namespace<V> {
+    keys: BTreeMap<String, V>,
+    stats: NamespaceStats,
+}
+
+/// Keys and values by namespace.
+pub struct Namespaces<V> {
+    namespaces: BTreeMap<String, Namespace<V>>,
+}
+
+impl<V> Default for Namespaces<V> {
+    fn default() -> Namespaces<V> {
+        Namespaces {
+            namespaces: BTreeMap::new(),
+        }
+    }
+}
+
+impl<V: AsRef<[u8]>> Namespaces<V> {
+    pub fn new() -> Namespaces<V> {
+        Namespaces::default()
+    }
+
+    pub fn get(&self, namespace: &str, key: &str) -> Option<&V> {
+        self.namespaces.get(namespace)?.keys.get(key)
+    }
+
+    /// Sets `key` in `namespace`, creating the namespace if it's new.
+    /// Returns the old value.
+    pub fn insert(&mut self, namespace: &str, key: String, value: V) -> Option<V> {
+        let ns = self
+            .namespaces
+            .entry(namespace.to_owned())
+            .or_insert_with(|| Namespace {
+                keys: BTreeMap::new(),
+                stats: NamespaceStats::default(),
+            });
+        ns.stats.bytes += size(&key, &value);
+        let old = ns.keys.insert(key.clone(), value);
+        match &old {
+            Some(old) => ns.stats.bytes -= size(&key, old),
+            None => ns.stats.keys += 1,
+        }
+        old
+    }
+
+    /// Removes `key` from `namespace`, and the namespace once it's empty.
+    pub fn remove(&mut self, namespace: &str, key: &str) -> Option<V> {
+        let ns = self.namespaces.get_mut(namespace)?;
+        let old = ns.keys.remove(key)?;
+        ns.stats.keys -= 1;
+        ns.stats.bytes -= size(key, &old);
+        if ns.keys.is_empty() {
+            self.namespaces.remove(namespace);
+        }
+        Some(old)
+    }
+
+    /// Drops `namespace` with all its keys, returns what it had.
+    pub fn delete_namespace(&mut self, namespace: &str) -> NamespaceStats {
+        self.namespaces
+            .remove(namespace)
+            .map(|ns| ns.stats)
+            .unwrap_or_default()
+    }
+
+    /// The stats of `namespace`, zeros if it has no keys.
+    pub fn stats(&self, namespace: &str) -> NamespaceStats {
+        self.namespaces
+            .get(namespace)
+            .map(|ns| ns.stats)
+            .unwrap_or_default()
+    }
+
+    /// The namespaces with keys, in order.
+    pub fn namespaces(&self) -> impl Iterator<Item = &str> {
+        self.namespaces.keys().map(String::as_str)
+    }
+
+    /// The keys of `namespace` from `start` on, in order.
+    pub fn range<'a>(
+        &'a self,
+        namespace: &str,
+        start: &str,
+    ) -> impl Iterator<Item = (&'a str, &'a V)> {
+        let start = start.to_owned();
+        self.namespaces
+            .get(namespace)
+            .into_iter()
+            .flat_map(move |ns| ns.keys.range(start.clone()..))
+            .map(|(k, v)| (k.as_str(), v))
+    }
+}
+
+fn size<V: AsRef<[u8]>>(key: &str, value: &V) -> u64 {
+    (key.len() + value.as_ref().len()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespaces() {
+        let mut n = Namespaces::new();
+        assert_eq!(n.insert("", "k".to_owned(), "v".to_owned()), None);
+        n.insert("a", "k".to_owned(), "va".to_owned());
+        n.insert("a", "l".to_owned(), "x".to_owned());
+        assert_eq!(
+            n.insert("a", "l".to_owned(), "xyz".to_owned()).unwrap(),
+            "x"
+        );
+        assert_eq!(n.get("", "k").unwrap(), "v");
+        assert_eq!(n.get("a", "k").unwrap(), "va");
+        assert_eq!(n.get("b", "k"), None);
+        assert_eq!(n.stats("a"), NamespaceStats { keys: 2, bytes: 7 });
+        let keys: Vec<_> = n.range("a", "l").map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["l"]);
+        assert_eq!(n.namespaces().collect::<Vec<_>>(), vec!["", "a"]);
+
+        assert_eq!(
+            n.delete_namespace("a"),
+            NamespaceStats { keys: 2, bytes: 7 }
+        );
+        assert_eq!(n.get("a", "k"), None);
+        assert_eq!(n.stats("a"), NamespaceStats::default());
+        assert_eq!(n.remove("", "k").unwrap(), "v");
+        assert_eq!(n.namespaces().count(), 0);
+    }
+}
@@ -0,0 +1,96 @@
+//! Pipelining the writes of a clerk.
+//!
+//! A clerk that waits for every write before sending the next one does a
+//! write per commit round at most. A pipelined clerk has up to `depth`
+//! writes in flight, each with its own sequence number, see
+//! `kvraft::session` for how servers detect duplicates of them.
+//!
+//! Writes in flight may be committed in any order, so two writes to the same
+//! key are never in flight together: a write waits for the one before it on
+//! its key. The first request of a clerk starts its session on the servers
+//! and goes alone too.
+
+use std::collections::BTreeMap;
+
+use crate::kvraft::session::MAX_PIPELINE;
+
+/// The writes of a clerk in flight.
+#[derive(Clone, Debug)]
+pub struct Pipeline {
+    depth: usize,
+    next_seq: u64,
+    // the key of every write in flight, by sequence number.
+    in_flight: BTreeMap<u64, String>,
+}
+
+impl Pipeline {
+    /// Up to `depth` writes in flight, at most `MAX_PIPELINE`, numbered from
+    /// `next_seq`.
+    pub fn new(depth: usize, next_seq: u64) -> Pipeline {
+        Pipeline {
+            depth: depth.clamp(1, MAX_PIPELINE as usize),
+            next_seq,
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    /// The sequence number of a write to `key` that may be sent now, `None`
+    /// if it must wait for a write in flight to finish.
+    pub fn start(&mut self, key: &str) -> Option<u64> {
+        let busy = self.in_flight.len() >= self.depth
+            || self.in_flight.contains_key(&1)
+            || self.in_flight.values().any(|k| k == key);
+        if busy {
+            return None;
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.in_flight.insert(seq, key.to_owned());
+        Some(seq)
+    }
+
+    /// Write `seq` got its reply.
+    pub fn finish(&mut self, seq: u64) {
+        self.in_flight.remove(&seq);
+    }
+
+    /// The sequence number of the next write.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline() {
+        let mut p = Pipeline::new(3, 1);
+        assert_eq!(p.start("a"), Some(1));
+        // the first request goes alone.
+        assert_eq!(p.start("b"), None);
+        p.finish(1);
+        assert_eq!(p.start("a"), Some(2));
+        assert_eq!(p.start("a"), None, "two writes to a key in flight");
+        assert_eq!(p.start("b"), Some(3));
+        assert_eq!(p.start("c"), Some(4));
+        assert_eq!(p.start("d"), None, "pipeline full");
+        assert_eq!(p.in_flight(), 3);
+        p.finish(3);
+        assert_eq!(p.start("d"), Some(5));
+        p.finish(2);
+        assert_eq!(p.start("a"), Some(6));
+        assert_eq!(p.next_seq(), 7);
+
+        let mut deep = Pipeline::new(1000, 10);
+        for i in 0..MAX_PIPELINE {
+            assert!(deep.start(&i.to_string()).is_some());
+        }
+        assert_eq!(deep.start("x"), None);
+    }
+}
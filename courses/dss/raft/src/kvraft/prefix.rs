@@ -0,0 +1,165 @@
+//! Prefix-compressed encoding of sorted keys and values.
+//!
+//! Keys of a store tend to share long prefixes, "user/1042/name" and
+//! "user/1042/email", and a snapshot that writes every key in full is mostly
+//! prefixes. Keys in order share the most with the key before, so each key
+//! is written as the length of the prefix it shares with the key before and
+//! the rest of it:
+//!
+//! ```text
+//! varint shared | varint suffix length | suffix | varint value length | value
+//! ```
+//!
+//! Lengths are LEB128 varints, a byte for lengths below 128.
+
+use crate::kvraft::errors::{Error, Result};
+
+/// Encodes keys, in increasing order, with their values.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    data: Vec<u8>,
+    last: String,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    /// Appends `key` with `value`. Panics unless `key` comes after the key
+    /// pushed before.
+    pub fn push(&mut self, key: &str, value: &[u8]) {
+        assert!(
+            self.data.is_empty() || key > self.last.as_str(),
+            "keys out of order: {:?} after {:?}",
+            key,
+            self.last
+        );
+        let shared = shared_prefix(&self.last, key);
+        put_varint(&mut self.data, shared as u64);
+        put_varint(&mut self.data, (key.len() - shared) as u64);
+        self.data.extend_from_slice(&key.as_bytes()[shared..]);
+        put_varint(&mut self.data, value.len() as u64);
+        self.data.extend_from_slice(value);
+        self.last.truncate(shared);
+        self.last.push_str(&key[shared..]);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Encodes `pairs`, whose keys must be in increasing order, e.g. those of a
+/// `BTreeMap`.
+pub fn encode<'a, I, V>(pairs: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a str, V)>,
+    V: AsRef<[u8]>,
+{
+    let mut encoder = Encoder::new();
+    for (key, value) in pairs {
+        encoder.push(key, value.as_ref());
+    }
+    encoder.finish()
+}
+
+/// Decodes what `encode` encoded, the keys in order.
+pub fn decode(mut data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut pairs: Vec<(String, Vec<u8>)> = vec![];
+    while !data.is_empty() {
+        let shared = take_varint(&mut data)? as usize;
+        let suffix_len = take_varint(&mut data)?;
+        let suffix = take(&mut data, suffix_len)?;
+        let last = pairs.last().map_or("", |(k, _)| k.as_str());
+        if shared > last.len() || !last.is_char_boundary(shared) {
+            return Err(corruption("bad shared prefix"));
+        }
+        let suffix = std::str::from_utf8(suffix).map_err(|_| corruption("key not utf-8"))?;
+        let key = [&last[..shared], suffix].concat();
+        let value_len = take_varint(&mut data)?;
+        let value = take(&mut data, value_len)?.to_vec();
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
+
+fn shared_prefix(a: &str, b: &str) -> usize {
+    let mut shared = a.bytes().zip(b.bytes()).take_while(|(x, y)| x == y).count();
+    // don't split a character, the suffix must be utf-8 on its own.
+    while !b.is_char_boundary(shared) {
+        shared -= 1;
+    }
+    shared
+}
+
+fn put_varint(data: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        data.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    data.push(n as u8);
+}
+
+fn take_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = take(data, 1)?[0];
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(corruption("varint too long"))
+}
+
+fn take<'a>(data: &mut &'a [u8], len: u64) -> Result<&'a [u8]> {
+    if (data.len() as u64) < len {
+        return Err(corruption("truncated snapshot"));
+    }
+    let (taken, rest) = data.split_at(len as usize);
+    *data = rest;
+    Ok(taken)
+}
+
+fn corruption(what: &str) -> Error {
+    Error::Corruption(what.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_prefix_encoding() {
+        let mut kvs = BTreeMap::new();
+        for i in 0..200 {
+            kvs.insert(format!("user/by-id/{:04}", i), vec![b'x'; i % 3]);
+        }
+        kvs.insert("".to_owned(), b"empty key".to_vec());
+        kvs.insert("usé".to_owned(), vec![0; 300]);
+        kvs.insert("usë".to_owned(), vec![]);
+        let data = encode(kvs.iter().map(|(k, v)| (k.as_str(), v)));
+        let naive: usize = kvs.iter().map(|(k, v)| k.len() + v.len()).sum();
+        assert!(
+            data.len() < naive / 2,
+            "{} bytes, {} naive",
+            data.len(),
+            naive
+        );
+        let decoded: BTreeMap<_, _> = decode(&data).unwrap().into_iter().collect();
+        assert_eq!(decoded, kvs);
+
+        assert_eq!(decode(&[]).unwrap(), vec![]);
+        assert_eq!(
+            decode(&data[..data.len() - 1]),
+            Err(Error::Corruption("truncated snapshot".to_owned()))
+        );
+        assert_eq!(
+            decode(&[3, 1, b'k', 0]),
+            Err(Error::Corruption("bad shared prefix".to_owned()))
+        );
+    }
+}
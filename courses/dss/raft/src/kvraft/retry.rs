@@ -0,0 +1,123 @@
+//! How long a clerk keeps retrying before it gives up.
+//!
+//! By default a clerk retries forever, which is what the tests want, but a
+//! caller in a full outage would rather hear that the service is down. A
+//! `RetryBudget` bounds the rounds of every server failing, the time spent,
+//! or both. `Retries` tracks a call against it and remembers the last error
+//! of every server, so the `Error::Unavailable` a call fails with tells what
+//! went wrong, not just that it did.
+
+use std::time::{Duration, Instant};
+
+use crate::kvraft::errors::Error;
+
+/// The retries a call may make, unlimited by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetryBudget {
+    /// The rounds of every server failing before giving up.
+    pub rounds: Option<u32>,
+    /// The time from the first try after which no round is started.
+    pub time: Option<Duration>,
+}
+
+impl RetryBudget {
+    pub fn is_unlimited(&self) -> bool {
+        self.rounds.is_none() && self.time.is_none()
+    }
+
+    /// The retries of a call started at `now`.
+    pub fn start(&self, servers: usize, now: Instant) -> Retries {
+        Retries {
+            budget: *self,
+            started: now,
+            rounds: 0,
+            last_errors: vec![None; servers],
+        }
+    }
+}
+
+/// The failed tries of a call.
+#[derive(Clone, Debug)]
+pub struct Retries {
+    budget: RetryBudget,
+    started: Instant,
+    rounds: u32,
+    // the last error of every server, `None` if it wasn't tried.
+    last_errors: Vec<Option<String>>,
+}
+
+impl Retries {
+    /// A try of `server` failed with `err`, an error replied or an RPC
+    /// error.
+    pub fn failed(&mut self, server: usize, err: impl ToString) {
+        if let Some(last) = self.last_errors.get_mut(server) {
+            *last = Some(err.to_string());
+        }
+    }
+
+    /// Every server was tried once more and none served the call.
+    pub fn round_failed(&mut self) {
+        self.rounds = self.rounds.saturating_add(1);
+    }
+
+    pub fn rounds(&self) -> u32 {
+        self.rounds
+    }
+
+    /// `Error::Unavailable` if the budget is spent at `now`, `None` if the
+    /// call may go on.
+    pub fn exhausted(&self, now: Instant) -> Option<Error> {
+        let spent = now.saturating_duration_since(self.started);
+        let out_of_rounds = matches!(self.budget.rounds, Some(r) if self.rounds >= r);
+        let out_of_time = matches!(self.budget.time, Some(t) if spent >= t);
+        if !out_of_rounds && !out_of_time {
+            return None;
+        }
+        let last_errors: Vec<_> = self.last_errors.iter().flatten().cloned().collect();
+        Some(Error::Unavailable {
+            tried: last_errors.len(),
+            rounds: self.rounds,
+            last_errors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_budget() {
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let unlimited = RetryBudget::default();
+        assert!(unlimited.is_unlimited());
+        let mut retries = unlimited.start(3, t0);
+        for _ in 0..100 {
+            retries.round_failed();
+        }
+        assert_eq!(retries.exhausted(t0 + ms(1_000_000)), None);
+
+        let budget = RetryBudget {
+            rounds: Some(2),
+            time: Some(ms(100)),
+        };
+        let mut retries = budget.start(3, t0);
+        retries.failed(0, "ErrWrongLeader");
+        retries.failed(2, Error::Busy);
+        retries.failed(5, "no such server");
+        retries.round_failed();
+        assert_eq!(retries.exhausted(t0 + ms(10)), None);
+        retries.failed(0, "Timeout");
+        assert_eq!(
+            retries.exhausted(t0 + ms(100)),
+            Some(Error::Unavailable {
+                tried: 2,
+                rounds: 1,
+                last_errors: vec!["Timeout".to_owned(), "Busy".to_owned()],
+            })
+        );
+        retries.round_failed();
+        assert!(retries.exhausted(t0).is_some());
+    }
+}
@@ -0,0 +1,196 @@
+//! Fault schedules for tests, see `Config::run_script`.
+//!
+//! Instead of a thread of its own that sleeps between partitions, a test can
+//! declare the faults it wants and when:
+//!
+//! ```ignore
+//! let script = NetworkScript::new()
+//!     .at(secs(2), Event::Partition(vec![0, 1], vec![2, 3, 4]))
+//!     .at(secs(5), Event::Heal)
+//!     .at(secs(6), Event::DropRate { from: 1, to: 3, rate: 0.3 });
+//! let run = cfg.run_script(script);
+//! // ... clerks at work ...
+//! run.wait();
+//! ```
+//!
+//! Times are from the start of the run, on the clock of the network, so a
+//! script follows the network onto simulated time.
+
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::executor::block_on;
+use futures::future::FutureExt;
+use futures::select;
+use labrpc::Clock;
+
+/// A change to the network between the servers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Servers talk only to those in the same partition.
+    Partition(Vec<usize>, Vec<usize>),
+    /// Connects every server to every other, undoing partitions.
+    Heal,
+    /// Loses requests and replies on the link from server `from` to server
+    /// `to` with chance `rate`, 0 for none.
+    DropRate { from: usize, to: usize, rate: f64 },
+    /// Turns the unreliable network on or off.
+    Unreliable(bool),
+    /// Crashes a server, see `Config::shutdown_server`.
+    Crash(usize),
+    /// Restarts a crashed server, see `Config::start_server`, connected to
+    /// every other.
+    Restart(usize),
+}
+
+/// A timeline of events.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NetworkScript {
+    // by time, events at the same time in the order they were added.
+    events: Vec<(Duration, Event)>,
+}
+
+impl NetworkScript {
+    pub fn new() -> NetworkScript {
+        NetworkScript::default()
+    }
+
+    /// Adds `event` at `t`.
+    pub fn at(mut self, t: Duration, event: Event) -> NetworkScript {
+        let i = self.events.partition_point(|(at, _)| *at <= t);
+        self.events.insert(i, (t, event));
+        self
+    }
+
+    pub fn events(&self) -> &[(Duration, Event)] {
+        &self.events
+    }
+
+    /// The time of the last event.
+    pub fn duration(&self) -> Duration {
+        self.events.last().map(|(t, _)| *t).unwrap_or_default()
+    }
+
+    /// Applies the events with `apply` on a thread of their own, each at its
+    /// time on `clock`.
+    pub fn start<F>(self, clock: Arc<dyn Clock>, apply: F) -> ScriptRun
+    where
+        F: Fn(&Event) + Send + 'static,
+    {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let start = clock.now();
+        let thread = thread::spawn(move || {
+            block_on(async {
+                // fires once the run is dropped.
+                let mut stopped = stopped.fuse();
+                for (t, event) in &self.events {
+                    let left = (start + *t).checked_sub(clock.now()).unwrap_or_default();
+                    select! {
+                        _ = clock.sleep(left).fuse() => (),
+                        _ = stopped => return,
+                    }
+                    debug!("script at {:?}: {:?}", t, event);
+                    apply(event);
+                }
+            })
+        });
+        ScriptRun {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+/// A script being applied. Dropping it stops the events not applied yet.
+#[derive(Debug)]
+pub struct ScriptRun {
+    // dropped to stop.
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ScriptRun {
+    /// Waits until every event is applied.
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+
+    /// Applies no more events.
+    pub fn stop(self) {}
+}
+
+impl Drop for ScriptRun {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            // a panic in apply was reported by the thread already.
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use labrpc::SimClock;
+
+    use super::*;
+
+    #[test]
+    fn test_network_script() {
+        let ms = Duration::from_millis;
+        let script = NetworkScript::new()
+            .at(ms(40), Event::Heal)
+            .at(ms(0), Event::Partition(vec![0], vec![1, 2]))
+            .at(ms(40), Event::Unreliable(true))
+            .at(ms(20), Event::Crash(1));
+        assert_eq!(script.duration(), ms(40));
+        let order: Vec<_> = script.events().iter().map(|(_, e)| e.clone()).collect();
+        assert_eq!(
+            order,
+            vec![
+                Event::Partition(vec![0], vec![1, 2]),
+                Event::Crash(1),
+                Event::Heal,
+                Event::Unreliable(true),
+            ]
+        );
+
+        // events wait for the clock, however long it takes to move.
+        let clock = SimClock::new();
+        clock.advance(ms(1000));
+        let applied = Arc::new(Mutex::new(vec![]));
+        let log = applied.clone();
+        let now = clock.clone();
+        let run = script.clone().start(Arc::new(clock.clone()), move |e| {
+            log.lock().unwrap().push((now.now() - ms(1000), e.clone()))
+        });
+        while applied.lock().unwrap().len() < 4 {
+            if !clock.advance_to_next() {
+                thread::sleep(ms(1));
+            }
+        }
+        run.wait();
+        let applied = applied.lock().unwrap();
+        for ((at, e), (t, event)) in applied.iter().zip(script.events()) {
+            assert_eq!(at, t, "{:?} applied at {:?}", e, at);
+            assert_eq!(e, event);
+        }
+
+        // stopped before its first event.
+        let applied = Arc::new(Mutex::new(vec![]));
+        let log = applied.clone();
+        let run = NetworkScript::new()
+            .at(Duration::from_secs(10), Event::Heal)
+            .start(Arc::new(labrpc::RealClock::new()), move |e| {
+                log.lock().unwrap().push(e.clone())
+            });
+        run.stop();
+        assert!(applied.lock().unwrap().is_empty());
+    }
+}
@@ -1,29 +1,258 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use futures::channel::mpsc::unbounded;
 
+use crate::kvraft::applied::AppliedIndex;
+use crate::kvraft::apply_pool::ApplyPool;
+use crate::kvraft::audit::{AuditLog, AuditRecord};
+use crate::kvraft::backpressure::Inflight;
+use crate::kvraft::drain::Gate;
+use crate::kvraft::errors::Result;
+use crate::kvraft::fence::FencedKeys;
+use crate::kvraft::limits::Limits;
+use crate::kvraft::snapshot::SnapshotPolicy;
+use crate::kvraft::state_machine::{Applied, Replica, StateMachine};
+use crate::kvraft::waiters::Waiters;
 use crate::proto::kvraftpb::*;
 use crate::raft;
 
+/// Clerks idle for this long are forgotten by duplicate detection.
+const SESSION_TTL: Duration = Duration::from_secs(600);
+
+/// Client operations waiting to be committed, see `kvraft::backpressure`.
+const MAX_PENDING: usize = 1024;
+
+/// Workers applying ops on disjoint keys in parallel, see
+/// `kvraft::apply_pool`.
+const APPLY_WORKERS: usize = 4;
+
+/// How long the leader serves Gets on its own after a majority acked a
+/// heartbeat, see `raft::lease`. Must be shorter than the minimum election
+/// timeout.
+const LEASE_DURATION: Duration = Duration::from_millis(100);
+
+/// Applied ops kept in the audit log, see `kvraft::audit`.
+const AUDIT_RECORDS: usize = 4096;
+
+/// The keys and values of a `KvServer`.
+#[derive(Default)]
+pub struct Store {
+    // Your definitions here. Keep the keys in an ordered map, e.g. a
+    // `BTreeMap` or a `kvraft::mvcc::Mvcc`, so scans can be served in key
+    // order. `Mvcc` also keeps the versions `get_at` reads,
+    // `kvraft::index::Indexes` the secondary indexes, and
+    // `kvraft::namespace::Namespaces` the keys of every namespace with their
+    // stats.
+    //
+    // To apply ops on the `ApplyPool` of the server, keep a shard of the
+    // keys for every worker, and take a `barrier` before an op that reads
+    // or writes more than one key.
+}
+
+impl Store {
+    /// The number of keys, and their size with their values in bytes, about.
+    pub fn size(&self) -> (u64, u64) {
+        // Your code here.
+        crate::your_code_here(())
+    }
+}
+
+impl StateMachine for Store {
+    fn apply(&mut self, op: &[u8]) -> Vec<u8> {
+        // Your code here.
+        crate::your_code_here(op)
+    }
+
+    // Write the keys in order with `kvraft::prefix::encode`, keys sharing
+    // prefixes take a fraction of the space then.
+    fn snapshot(&self) -> Vec<u8> {
+        // Your code here.
+        crate::your_code_here(())
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        // Your code here.
+        crate::your_code_here(snapshot)
+    }
+}
+
+/// What a `KvServer` holds, see `KvServer::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KvStats {
+    pub keys: u64,
+    /// Of the keys and values, about.
+    pub state_bytes: u64,
+    pub applied_index: u64,
+    /// Taken or installed.
+    pub snapshots: u64,
+    /// Proposals waiting to be committed.
+    pub pending: u64,
+    /// Clerks in the duplicate table.
+    pub sessions: u64,
+}
+
+impl From<KvStats> for StatsReply {
+    fn from(stats: KvStats) -> StatsReply {
+        StatsReply {
+            err: String::new(),
+            keys: stats.keys,
+            state_bytes: stats.state_bytes,
+            applied_index: stats.applied_index,
+            snapshots: stats.snapshots,
+            pending: stats.pending,
+            sessions: stats.sessions,
+        }
+    }
+}
+
+impl From<AuditRecord> for AuditEntry {
+    fn from(record: AuditRecord) -> AuditEntry {
+        AuditEntry {
+            client: record.client,
+            seq: record.seq,
+            op: record.op,
+            key: record.key,
+            index: record.index,
+            term: record.term,
+        }
+    }
+}
+
 pub struct KvServer {
     pub rf: raft::Node,
     me: usize,
-    // snapshot if log grows this big
-    maxraftstate: Option<usize>,
+    // the store with duplicate detection and the snapshot policy. apply
+    // committed entries through it, with the client, request number and
+    // time the leader stamped on each entry. reply `Error::Poisoned` to the
+    // waiter of an entry it reports `Applied::Poisoned`, and go on with the
+    // next entry. an entry that can't be decoded is poisoned too: catch the
+    // panic and skip it, don't let it stop the apply loop.
+    replica: Replica<Store>,
+    // take a permit for every operation proposed, and reply `Error::Busy`
+    // if there's none.
+    pending: Inflight,
+    // handlers waiting for their ops to be applied. expire them by the
+    // `timeout_ms` of their requests, and fail them all on losing leadership.
+    waiters: Waiters<Applied>,
+    // applies the ops on single keys, in order for each key.
+    apply_pool: ApplyPool,
+    // writes past them are rejected before they're proposed. appends that
+    // would grow a value past them are rejected when they're applied, the
+    // same on every replica.
+    limits: Limits,
+    // every client RPC passes it, and is turned away once the server
+    // drains, see `Node::drain`.
+    gate: Gate,
+    // writes to these keys reply with the term and index of the entry that
+    // applied them as a fencing token.
+    fenced_keys: FencedKeys,
+    // advance it as entries are applied, reads that must see an index
+    // wait on it.
+    applied: AppliedIndex,
+    // record every op applied in it, with the client, request number, index
+    // and term of its entry. duplicates aren't applied, don't record them.
+    audit: AuditLog,
     // Your definitions here.
 }
 
 impl KvServer {
-    pub fn new(
+    /// `snapshot_policy` may be a `maxraftstate`, an `Option<usize>`.
+    pub fn new<S: Into<SnapshotPolicy>>(
+        servers: Vec<crate::proto::raftpb::RaftClient>,
+        me: usize,
+        persister: Box<dyn raft::persister::Persister>,
+        snapshot_policy: S,
+    ) -> KvServer {
+        let clock = Arc::new(labrpc::RealClock::new());
+        KvServer::with_clock(servers, me, persister, snapshot_policy, clock)
+    }
+
+    /// Like `new`, but the raft ticks on `clock`, e.g. the clock of the
+    /// network, see `raft::Node::with_clock`.
+    pub fn with_clock<S: Into<SnapshotPolicy>>(
         servers: Vec<crate::proto::raftpb::RaftClient>,
         me: usize,
         persister: Box<dyn raft::persister::Persister>,
-        maxraftstate: Option<usize>,
+        snapshot_policy: S,
+        clock: Arc<dyn labrpc::Clock>,
     ) -> KvServer {
         // You may need initialization code here.
 
         let (tx, apply_ch) = unbounded();
-        let rf = raft::Raft::new(servers, me, persister, tx);
+        let mut rf = raft::Raft::new(servers, me, persister, tx);
+        rf.set_lease(Some(LEASE_DURATION));
+        let replica = Replica::new(Store::default(), SESSION_TTL, snapshot_policy.into());
 
-        crate::your_code_here((rf, maxraftstate, apply_ch))
+        let pending = Inflight::new(MAX_PENDING);
+        let waiters = Waiters::<Applied>::new();
+        let apply_pool = ApplyPool::new(APPLY_WORKERS);
+
+        let limits = Limits::default();
+        let gate = Gate::new();
+        let fenced_keys = FencedKeys::default();
+        let applied = AppliedIndex::new();
+        let audit = AuditLog::new(AUDIT_RECORDS);
+
+        // start the raft with
+        // `raft::Node::with_clock(rf, raft::DEFAULT_TICK_INTERVAL, clock)`.
+        crate::your_code_here((
+            rf,
+            clock,
+            replica,
+            pending,
+            waiters,
+            apply_pool,
+            limits,
+            gate,
+            fenced_keys,
+            applied,
+            audit,
+            apply_ch,
+        ))
+    }
+
+    pub fn stats(&self) -> KvStats {
+        let (keys, state_bytes) = self.replica.state().size();
+        KvStats {
+            keys,
+            state_bytes,
+            applied_index: self.replica.applied_index(),
+            snapshots: self.replica.snapshot_count(),
+            pending: self.pending.len() as u64,
+            sessions: self.replica.sessions().len() as u64,
+        }
+    }
+
+    /// Up to `limit` records of the ops applied after `index`, 0 for all of
+    /// them, and the number of records dropped from the audit log.
+    pub fn audit(&self, index: u64, limit: usize) -> (Vec<AuditRecord>, u64) {
+        let records = self.audit.after(index, limit).cloned().collect();
+        (records, self.audit.dropped())
+    }
+
+    /// Keeps the last `capacity` applied ops in the audit log, none if 0.
+    /// Records kept so far are dropped.
+    pub fn set_audit_capacity(&mut self, capacity: usize) {
+        self.audit = AuditLog::new(capacity);
+    }
+
+    /// Whether the leader serves Gets on its own while its lease holds,
+    /// on unless set.
+    pub fn set_leases(&self, enabled: bool) {
+        self.rf.set_lease(Some(LEASE_DURATION).filter(|_| enabled));
+    }
+
+    /// Sets the lock keys, "lock/..." unless set. Every server of a group
+    /// must have the same lock keys.
+    pub fn set_fenced_keys(&mut self, keys: FencedKeys) {
+        self.fenced_keys = keys;
+    }
+
+    /// Sets the size limits of keys and values, `Limits::default()` unless
+    /// set. Every server of a group must have the same limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
     }
 }
 
@@ -32,7 +261,15 @@ impl KvServer {
     #[doc(hidden)]
     pub fn __suppress_deadcode(&mut self) {
         let _ = &self.me;
-        let _ = &self.maxraftstate;
+        let _ = &self.replica;
+        let _ = &self.pending;
+        let _ = &self.waiters;
+        let _ = &self.apply_pool;
+        let _ = &self.limits;
+        let _ = &self.gate;
+        let _ = &self.fenced_keys;
+        let _ = &self.applied;
+        let _ = &self.audit;
     }
 }
 
@@ -74,6 +311,16 @@ impl Node {
         // Your code here, if desired.
     }
 
+    /// stops the server gracefully, for a restart that clients barely
+    /// notice: close the gate of the kv server, so clients are replied
+    /// `Error::ShuttingDown` and try another server, wait up to `timeout`
+    /// for the requests inside to be replied or fail them, hand leadership
+    /// away with `raft::Node::transfer_leadership`, then `kill`.
+    pub fn drain(&self, timeout: Duration) {
+        // Your code here.
+        crate::your_code_here(timeout)
+    }
+
     /// The current term of this peer.
     pub fn term(&self) -> u64 {
         self.get_state().term()
@@ -84,6 +331,18 @@ impl Node {
         self.get_state().is_leader()
     }
 
+    /// The stats of the kv server, see `KvServer::stats`.
+    pub fn stats(&self) -> KvStats {
+        // Your code here.
+        crate::your_code_here(())
+    }
+
+    /// The ops the kv server applied after `index`, see `KvServer::audit`.
+    pub fn audit(&self, index: u64) -> Vec<AuditRecord> {
+        // Your code here.
+        crate::your_code_here(index)
+    }
+
     pub fn get_state(&self) -> raft::State {
         // Your code here.
         raft::State {
@@ -94,15 +353,192 @@ impl Node {
 
 #[async_trait::async_trait]
 impl KvService for Node {
+    // A reply with `wrong_leader` tells where the leader is: fill `server`
+    // and `leader_hint` with `kvraft::leader::to_hint` of `me` and of
+    // `raft::State::leader`. Fill `server` in every other reply too.
+    //
+    // A `stale` Get is served from the applied state right away, on any
+    // server, and returns its applied index. With a `min_index`, wait for
+    // `AppliedIndex::wait_for` it first, so a session reads its own writes.
+    //
+    // Followers may serve a Get too: await `self.rf.read_index()`, wait
+    // until the state has applied up to it and read from the state.
+    //
+    // While `self.rf.lease_read()` gives a read index, the leader doesn't
+    // need to ask raft at all. Wait until it applied up to that index and
+    // read from the state. Unless the Get is `no_lease`: then take the read
+    // index path even under a lease.
+    //
+    // A hedged Get reaches two servers with the same client and sequence
+    // number, see `kvraft::hedge`. If Gets go through the log, both may be
+    // committed: answer the second from the session, don't apply it again.
+    //
     // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
     async fn get(&self, arg: GetRequest) -> labrpc::Result<GetReply> {
         // Your code here.
         crate::your_code_here(arg)
     }
 
+    // A Put with a `ttl_ms` expires. Replicas must agree on when, so the
+    // leader decides the expiry time and carries it in the log entry, and
+    // keys are purged when applying entries from after it, not by the
+    // local clock. Expired keys aren't saved in snapshots.
+    //
+    // Delete comes in here too. Like Put and Append, a duplicate Delete must
+    // not be applied twice.
+    //
+    // A DeleteIf compares and removes in the same apply step, and a
+    // DeleteRange removes every key in the range in one entry. Reply with
+    // the number of keys removed, and keep it as the result of the request
+    // for a retry: by then the keys may be back.
+    //
+    // An Append with `return_value` replies with the value after it. A retry
+    // of it must get that same value, not the current one, so keep it as the
+    // result of the request in `Replica::apply`.
+    //
+    // Reply `Error::KeyTooLarge` or `Error::ValueTooLarge` for a write past
+    // the limits, without proposing it, or once it's applied for an append
+    // that would grow a value past them. The append isn't applied then.
+    //
     // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
     async fn put_append(&self, arg: PutAppendRequest) -> labrpc::Result<PutAppendReply> {
         // Your code here.
         crate::your_code_here(arg)
     }
+
+    // An Incr is applied once like a Put, and a retry of it must get the
+    // value of the first try, which `Replica::apply` keeps for the last
+    // request of every client. What to reply for a value that isn't an
+    // integer is up to you, but every replica must do the same.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn incr(&self, arg: IncrRequest) -> labrpc::Result<IncrReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // Compaction goes through the raft log, so every replica drops the same
+    // versions.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn compact(&self, arg: CompactRequest) -> labrpc::Result<CompactReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // An export is read-only like Scan, every chunk at the revision of the
+    // first one, which fails like `get_at` once it's compacted.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn export(&self, arg: ExportRequest) -> labrpc::Result<ExportReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // An import goes into the raft log as a single entry like a batch. It
+    // must not move the revision back, revisions only grow.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn import(&self, arg: ImportRequest) -> labrpc::Result<ImportReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // Dropping a namespace is a single raft entry, applied once like a write.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn delete_namespace(
+        &self,
+        arg: DeleteNamespaceRequest,
+    ) -> labrpc::Result<DeleteNamespaceReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // Stats are read-only like Get.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn namespace_stats(
+        &self,
+        arg: NamespaceStatsRequest,
+    ) -> labrpc::Result<NamespaceStatsReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // Indexes are declared through the raft log. Update them with
+    // `Indexes::update` in the same apply step as every write, and fill a
+    // new one from the keys there are.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn create_index(&self, arg: CreateIndexRequest) -> labrpc::Result<CreateIndexReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // Index scans are read-only and linearizable like Scan. Reply
+    // `Error::NoIndex` for an index that wasn't declared.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn scan_index(&self, arg: ScanIndexRequest) -> labrpc::Result<ScanIndexReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // The policy goes through the raft log, and is set with
+    // `Replica::set_snapshot_policy` when it's applied, so every replica
+    // snapshots by the same policy from the same entry on.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn set_snapshot_policy(
+        &self,
+        arg: SetSnapshotPolicyRequest,
+    ) -> labrpc::Result<SetSnapshotPolicyReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // Any server replies with `self.stats()`, leader or not.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn stats(&self, arg: StatsRequest) -> labrpc::Result<StatsReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // Any server replies from its own audit log, see `KvServer::audit`.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn audit(&self, arg: AuditRequest) -> labrpc::Result<AuditReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // A batch goes into the raft log as a single entry, so it's applied
+    // all at once, and it's detected as a duplicate as a whole.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn write_batch(&self, arg: WriteBatchRequest) -> labrpc::Result<WriteBatchReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // Reply once there are changes after `after_revision`, or with none after
+    // a while so the client polls again, by `timeout_ms` at the latest. Keep enough history to resume a
+    // watch on any server, the revisions are the same on all of them.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn watch(&self, arg: WatchRequest) -> labrpc::Result<WatchReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    // Scans are read-only like Get, and must be linearizable too, either
+    // through the raft log or by the read-only optimization.
+    //
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn scan(&self, arg: ScanRequest) -> labrpc::Result<ScanReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
 }
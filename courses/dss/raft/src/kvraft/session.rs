@@ -0,0 +1,225 @@
+//! Clerk sessions for duplicate detection.
+//!
+//! The server remembers the last request applied for every clerk, so a
+//! request retried after a timeout or a leader change is applied once. The
+//! table would grow with every clerk ever seen, so sessions of clerks idle
+//! for longer than a ttl expire.
+//!
+//! Replicas must agree on which sessions expired, so time is never taken
+//! from the local clock: the leader stamps every log entry with its time,
+//! and the stamp of the entry being applied is the `now_ms` of the session
+//! table. Replicas apply the same entries, so they expire the same sessions.
+//! Save the table in snapshots, expired sessions are pruned by then.
+//!
+//! A clerk that pipelines its requests, see `kvraft::pipeline`, has several
+//! in flight, and they may be committed out of order. A session remembers
+//! the requests below the last one that weren't applied yet, up to
+//! `MAX_PIPELINE` back, so they're still applied when they come.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The most requests a clerk has in flight. A request this far below the
+/// last one of its clerk is known to be applied.
+pub const MAX_PIPELINE: u64 = 64;
+
+/// What to do with a request of a clerk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dedup {
+    /// The request is new, apply it.
+    Apply,
+    /// The request was applied already, reply with its result again.
+    Duplicate,
+    /// The session of the clerk expired, so whether the request was applied
+    /// can't be told. Fail it, the clerk must start over with a new id.
+    Expired,
+}
+
+/// The last request of a clerk.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Session {
+    /// Requests of a clerk are numbered from 1.
+    pub last_seq: u64,
+    /// The time stamped on the entry of the last request.
+    pub last_seen_ms: u64,
+    /// The result of the last request, replied again to a retry of it.
+    pub last_result: Vec<u8>,
+    /// The requests below the last one that weren't applied yet, in order.
+    pub skipped: Vec<u64>,
+}
+
+/// Sessions by clerk id.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sessions {
+    ttl_ms: u64,
+    sessions: HashMap<u64, Session>,
+}
+
+impl Sessions {
+    /// Expires sessions idle for `ttl`.
+    pub fn new(ttl: Duration) -> Sessions {
+        Sessions {
+            ttl_ms: ttl.as_millis() as u64,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Restores the sessions saved in a snapshot.
+    pub fn restore<I>(ttl: Duration, sessions: I) -> Sessions
+    where
+        I: IntoIterator<Item = (u64, Session)>,
+    {
+        Sessions {
+            ttl_ms: ttl.as_millis() as u64,
+            sessions: sessions.into_iter().collect(),
+        }
+    }
+
+    /// Checks request `seq` of `client`, stamped `now_ms`, and records it if
+    /// it's to be applied. Expires idle sessions first.
+    pub fn check(&mut self, client: u64, seq: u64, now_ms: u64) -> Dedup {
+        self.expire(now_ms);
+        let mut seen = Session {
+            last_seq: seq,
+            last_seen_ms: now_ms,
+            last_result: vec![],
+            skipped: vec![],
+        };
+        match self.sessions.get_mut(&client) {
+            Some(session) if seq <= session.last_seq => {
+                session.last_seen_ms = session.last_seen_ms.max(now_ms);
+                match session.skipped.binary_search(&seq) {
+                    Ok(i) => {
+                        session.skipped.remove(i);
+                        Dedup::Apply
+                    }
+                    Err(_) => Dedup::Duplicate,
+                }
+            }
+            Some(session) => {
+                let floor = seq.saturating_sub(MAX_PIPELINE);
+                seen.skipped = std::mem::take(&mut session.skipped);
+                seen.skipped.retain(|&s| s >= floor);
+                seen.skipped.extend(floor.max(session.last_seq + 1)..seq);
+                *session = seen;
+                Dedup::Apply
+            }
+            // only the first request of a clerk starts a session, a later one
+            // means the session is gone.
+            None if seq > 1 => Dedup::Expired,
+            None => {
+                self.sessions.insert(client, seen);
+                Dedup::Apply
+            }
+        }
+    }
+
+    /// Drops the sessions idle for the ttl at `now_ms`, returns how many.
+    pub fn expire(&mut self, now_ms: u64) -> usize {
+        let ttl_ms = self.ttl_ms;
+        let before = self.sessions.len();
+        self.sessions
+            .retain(|_, s| now_ms.saturating_sub(s.last_seen_ms) < ttl_ms);
+        before - self.sessions.len()
+    }
+
+    /// Records the result of request `seq` of `client`, only kept if it's
+    /// the last one, a skipped request applied late doesn't replace it.
+    pub fn set_result(&mut self, client: u64, seq: u64, result: Vec<u8>) {
+        if let Some(session) = self.sessions.get_mut(&client) {
+            if session.last_seq == seq {
+                session.last_result = result;
+            }
+        }
+    }
+
+    /// Puts back the session of `client` as it was before a request that
+    /// couldn't be applied, so a retry of it is applied again. `None` if the
+    /// client had no session.
+    pub fn revert(&mut self, client: u64, previous: Option<Session>) {
+        match previous {
+            Some(session) => self.sessions.insert(client, session),
+            None => self.sessions.remove(&client),
+        };
+    }
+
+    pub fn get(&self, client: u64) -> Option<&Session> {
+        self.sessions.get(&client)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// The sessions to save in a snapshot, sorted by clerk id so replicas
+    /// save the same bytes.
+    pub fn to_vec(&self) -> Vec<(u64, Session)> {
+        let mut sessions: Vec<_> = self.sessions.iter().map(|(&c, s)| (c, s.clone())).collect();
+        sessions.sort_unstable_by_key(|&(c, _)| c);
+        sessions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let mut s = Sessions::new(Duration::from_millis(100));
+        assert_eq!(s.check(1, 1, 0), Dedup::Apply);
+        assert_eq!(s.check(1, 1, 10), Dedup::Duplicate);
+        assert_eq!(s.check(1, 2, 20), Dedup::Apply);
+        assert_eq!(s.check(2, 1, 50), Dedup::Apply);
+        assert_eq!(s.check(3, 5, 50), Dedup::Expired);
+        assert_eq!(s.len(), 2);
+
+        // clerk 1 was last seen at 20.
+        assert_eq!(s.check(2, 2, 120), Dedup::Apply);
+        assert_eq!(s.get(1), None);
+        assert_eq!(s.check(1, 3, 120), Dedup::Expired);
+        assert_eq!(s.expire(220), 1);
+        assert!(s.is_empty());
+
+        let mut s = Sessions::new(Duration::from_millis(100));
+        s.check(2, 1, 0);
+        s.check(1, 1, 0);
+        s.check(1, 4, 0);
+        s.set_result(1, 4, vec![4]);
+        assert_eq!(s.get(1).unwrap().last_result, vec![4]);
+        let previous = s.get(2).cloned();
+        assert_eq!(s.check(2, 2, 0), Dedup::Apply);
+        s.revert(2, previous);
+        assert_eq!(s.get(2).unwrap().last_seq, 1);
+        let saved = s.to_vec();
+        assert_eq!(saved[0].0, 1);
+        let restored = Sessions::restore(Duration::from_millis(100), saved);
+        assert_eq!(restored, s);
+
+        // pipelined requests committed out of order.
+        let mut s = Sessions::new(Duration::from_millis(100));
+        s.check(1, 1, 0);
+        assert_eq!(s.check(1, 4, 0), Dedup::Apply);
+        assert_eq!(s.get(1).unwrap().skipped, vec![2, 3]);
+        assert_eq!(s.check(1, 3, 0), Dedup::Apply);
+        assert_eq!(s.check(1, 3, 0), Dedup::Duplicate);
+        assert_eq!(s.check(1, 100, 0), Dedup::Apply);
+        assert_eq!(s.get(1).unwrap().skipped, (36..100).collect::<Vec<_>>());
+        assert_eq!(s.check(1, 2, 0), Dedup::Duplicate);
+        assert_eq!(s.check(1, 36, 0), Dedup::Apply);
+
+        // a skipped request applied late keeps the result of the last one.
+        let mut s = Sessions::new(Duration::from_millis(100));
+        s.check(1, 1, 0);
+        s.check(1, 3, 0);
+        s.set_result(1, 3, vec![3]);
+        assert_eq!(s.check(1, 2, 0), Dedup::Apply);
+        s.set_result(1, 2, vec![2]);
+        assert_eq!(s.check(1, 3, 0), Dedup::Duplicate);
+        assert_eq!(s.get(1).unwrap().last_result, vec![3]);
+    }
+}
@@ -0,0 +1,123 @@
+//! When a `KvServer` snapshots.
+//!
+//! Besides the size of the raft state, `maxraftstate` of the original lab, a
+//! server may snapshot every so many applied ops or every so often. Feed a
+//! `SnapshotTrigger` from the apply loop and snapshot whenever it says so.
+
+use std::time::{Duration, Instant};
+
+/// Limits past which a snapshot is due, none of them set by default. A
+/// snapshot is due once any of them is reached.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotPolicy {
+    /// The raft state grew this big, in bytes.
+    pub max_raft_state: Option<usize>,
+    /// This many ops were applied since the last snapshot.
+    pub every_ops: Option<u64>,
+    /// This long passed since the last snapshot.
+    pub every: Option<Duration>,
+}
+
+impl SnapshotPolicy {
+    /// Whether no snapshot is ever due.
+    pub fn is_never(&self) -> bool {
+        *self == SnapshotPolicy::default()
+    }
+}
+
+/// A `maxraftstate`.
+impl From<Option<usize>> for SnapshotPolicy {
+    fn from(max_raft_state: Option<usize>) -> SnapshotPolicy {
+        SnapshotPolicy {
+            max_raft_state,
+            ..SnapshotPolicy::default()
+        }
+    }
+}
+
+/// Tells when a snapshot is due by a `SnapshotPolicy`.
+#[derive(Debug)]
+pub struct SnapshotTrigger {
+    policy: SnapshotPolicy,
+    // applied since the last snapshot.
+    ops: u64,
+    last: Instant,
+}
+
+impl SnapshotTrigger {
+    pub fn new(policy: SnapshotPolicy) -> SnapshotTrigger {
+        SnapshotTrigger {
+            policy,
+            ops: 0,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn policy(&self) -> &SnapshotPolicy {
+        &self.policy
+    }
+
+    /// Snapshots by `policy` from now on, counting from the last snapshot.
+    pub fn set_policy(&mut self, policy: SnapshotPolicy) {
+        self.policy = policy;
+    }
+
+    /// An op was applied and the raft state is `raft_state_size` bytes now.
+    /// Returns whether a snapshot is due.
+    pub fn applied(&mut self, raft_state_size: usize) -> bool {
+        self.ops += 1;
+        self.due(raft_state_size)
+    }
+
+    /// Whether a snapshot is due with a raft state of `raft_state_size`
+    /// bytes.
+    pub fn due(&self, raft_state_size: usize) -> bool {
+        let p = &self.policy;
+        matches!(p.max_raft_state, Some(max) if raft_state_size >= max)
+            || matches!(p.every_ops, Some(n) if self.ops >= n)
+            || matches!(p.every, Some(every) if self.last.elapsed() >= every)
+    }
+
+    /// A snapshot was taken, or installed from the leader.
+    pub fn snapshotted(&mut self) {
+        self.ops = 0;
+        self.last = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot_trigger() {
+        let never = SnapshotPolicy::from(None);
+        assert!(never.is_never());
+        let mut t = SnapshotTrigger::new(never);
+        assert!(!t.applied(usize::MAX));
+
+        let mut t = SnapshotTrigger::new(Some(100).into());
+        assert!(!t.applied(99));
+        assert!(t.applied(100));
+
+        let mut t = SnapshotTrigger::new(SnapshotPolicy {
+            every_ops: Some(2),
+            every: Some(Duration::from_millis(20)),
+            ..SnapshotPolicy::default()
+        });
+        assert!(!t.applied(0));
+        assert!(t.applied(0));
+        t.snapshotted();
+        assert!(!t.applied(0));
+        thread::sleep(Duration::from_millis(20));
+        assert!(t.due(0));
+        t.snapshotted();
+        assert!(!t.due(0));
+
+        t.set_policy(Some(10).into());
+        assert!(t.due(10));
+        assert_eq!(t.policy().max_raft_state, Some(10));
+    }
+}
@@ -0,0 +1,430 @@
+//! Replicated state machines over raft.
+//!
+//! A service built on raft keeps its state in a [`StateMachine`] and drives
+//! it from the apply loop through a [`Replica`], which takes care of what
+//! every such service needs: applying each client request once, tracking
+//! the applied index, and deciding when to snapshot. `KvServer` is one such
+//! service, a lock or counter service could be another.
+//!
+//! An op that panics when applied must not take the apply loop down with
+//! it. `Replica::apply` catches the panic and reports the entry poisoned,
+//! the request isn't recorded as applied, so a retry fails the same way.
+//! Applying is deterministic, so every replica poisons the same entries and
+//! is left with the same state.
+//!
+//! Snapshots of a replica are stamped with `SNAPSHOT_VERSION`, see
+//! `raft::version`, and encoded as
+//!
+//! ```text
+//! | applied index: u64 LE | policy | sessions: u64 LE | session* | state |
+//! ```
+//!
+//! where the snapshot policy is
+//!
+//! ```text
+//! | max raft state: u64 LE | every ops: u64 LE | every ms: u64 LE |
+//! ```
+//!
+//! with 0 for a limit that isn't set, and
+//!
+//! a session is
+//!
+//! ```text
+//! | client: u64 LE | last seq: u64 LE | last seen: u64 LE | result len: u64 LE | result |
+//! | skipped: u64 LE | skipped seq: u64 LE* |
+//! ```
+
+use std::any::Any;
+use std::convert::TryInto;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+
+use crate::kvraft::errors::{Error, Result};
+use crate::kvraft::session::{Dedup, Session, Sessions};
+use crate::kvraft::snapshot::{SnapshotPolicy, SnapshotTrigger};
+use crate::raft::version;
+
+/// The format version of snapshots, bumped whenever their encoding changes.
+/// Snapshots of other versions are rejected.
+///
+/// - 0, unstamped: sessions without skipped requests.
+/// - 1: sessions with the requests skipped by pipelined clerks.
+const SNAPSHOT_VERSION: u16 = 1;
+
+/// The state of a service, changed only by applying ops in log order.
+///
+/// Ops and their results are encoded by the service, e.g. with labcodec.
+/// Applying must be deterministic, so replicas that applied the same ops
+/// have the same state.
+pub trait StateMachine: Send + 'static {
+    /// Applies an op and returns its result.
+    fn apply(&mut self, op: &[u8]) -> Vec<u8>;
+    /// Encodes the state.
+    fn snapshot(&self) -> Vec<u8>;
+    /// Replaces the state with one encoded by `snapshot`.
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()>;
+}
+
+/// What became of a request applied by `Replica::apply`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Applied {
+    /// The op was applied, with this result.
+    Done(Vec<u8>),
+    /// The request was applied before, the op was not applied again. Has
+    /// its result if it's the last request of the client, the one a client
+    /// retries.
+    Duplicate(Option<Vec<u8>>),
+    /// The session of the client expired, the op was not applied.
+    SessionExpired,
+    /// Applying the op panicked, with this message. The changes it made to
+    /// the state before stay.
+    Poisoned(String),
+}
+
+/// A state machine with the applied index, duplicate detection and
+/// snapshot policy of a replica.
+pub struct Replica<S> {
+    state: S,
+    sessions: Sessions,
+    session_ttl: Duration,
+    snapshots: SnapshotTrigger,
+    // taken or restored.
+    snapshot_count: u64,
+    // entries whose op panicked since the replica started.
+    poisoned: Vec<u64>,
+    applied_index: u64,
+}
+
+impl<S: StateMachine> Replica<S> {
+    /// Expires clients idle for `session_ttl`, see `kvraft::session`.
+    pub fn new(state: S, session_ttl: Duration, snapshot_policy: SnapshotPolicy) -> Replica<S> {
+        Replica {
+            state,
+            sessions: Sessions::new(session_ttl),
+            session_ttl,
+            snapshots: SnapshotTrigger::new(snapshot_policy),
+            snapshot_count: 0,
+            poisoned: vec![],
+            applied_index: 0,
+        }
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    /// The index of the last entry applied.
+    pub fn applied_index(&self) -> u64 {
+        self.applied_index
+    }
+
+    /// The number of snapshots taken or restored.
+    pub fn snapshot_count(&self) -> u64 {
+        self.snapshot_count
+    }
+
+    /// The indexes of the entries whose op panicked since the replica
+    /// started.
+    pub fn poisoned(&self) -> &[u64] {
+        &self.poisoned
+    }
+
+    /// Applies the entry at `index`: request `seq` of `client`, stamped
+    /// `now_ms` by the leader, unless it was applied before.
+    ///
+    /// Entries must come in log order, an entry at or below the applied
+    /// index, e.g. one covered by a snapshot restored meanwhile, is skipped
+    /// as a duplicate.
+    pub fn apply(&mut self, index: u64, client: u64, seq: u64, now_ms: u64, op: &[u8]) -> Applied {
+        if index <= self.applied_index {
+            return Applied::Duplicate(None);
+        }
+        self.applied_index = index;
+        let previous = self.sessions.get(client).cloned();
+        match self.sessions.check(client, seq, now_ms) {
+            Dedup::Apply => {
+                let state = &mut self.state;
+                match panic::catch_unwind(AssertUnwindSafe(|| state.apply(op))) {
+                    Ok(result) => {
+                        self.sessions.set_result(client, seq, result.clone());
+                        Applied::Done(result)
+                    }
+                    Err(payload) => {
+                        self.sessions.revert(client, previous);
+                        self.poisoned.push(index);
+                        Applied::Poisoned(panic_message(&*payload))
+                    }
+                }
+            }
+            Dedup::Duplicate => {
+                let last = self.sessions.get(client).filter(|s| s.last_seq == seq);
+                Applied::Duplicate(last.map(|s| s.last_result.clone()))
+            }
+            Dedup::Expired => Applied::SessionExpired,
+        }
+    }
+
+    /// Marks the entry at `index` applied without applying anything, e.g.
+    /// a read or an entry of another kind.
+    pub fn skip(&mut self, index: u64) {
+        self.applied_index = self.applied_index.max(index);
+    }
+
+    pub fn snapshot_policy(&self) -> &SnapshotPolicy {
+        self.snapshots.policy()
+    }
+
+    /// Snapshots by `policy` from now on. Change it by applying an entry,
+    /// so every replica changes it at the same point. It's saved in
+    /// snapshots, so it survives restarts.
+    pub fn set_snapshot_policy(&mut self, policy: SnapshotPolicy) {
+        self.snapshots.set_policy(policy);
+    }
+
+    /// Whether a snapshot is due, after an entry was applied and the raft
+    /// state is `raft_state_size` bytes.
+    pub fn snapshot_due(&mut self, raft_state_size: usize) -> bool {
+        self.snapshots.applied(raft_state_size)
+    }
+
+    /// Encodes a snapshot of everything applied so far.
+    pub fn snapshot(&mut self) -> Vec<u8> {
+        self.snapshots.snapshotted();
+        self.snapshot_count += 1;
+        let sessions = self.sessions.to_vec();
+        let state = self.state.snapshot();
+        let mut data = Vec::with_capacity(40 + sessions.len() * 32 + state.len());
+        data.extend_from_slice(&self.applied_index.to_le_bytes());
+        put_policy(&mut data, self.snapshots.policy());
+        data.extend_from_slice(&(sessions.len() as u64).to_le_bytes());
+        for (client, s) in sessions {
+            data.extend_from_slice(&client.to_le_bytes());
+            data.extend_from_slice(&s.last_seq.to_le_bytes());
+            data.extend_from_slice(&s.last_seen_ms.to_le_bytes());
+            data.extend_from_slice(&(s.last_result.len() as u64).to_le_bytes());
+            data.extend_from_slice(&s.last_result);
+            data.extend_from_slice(&(s.skipped.len() as u64).to_le_bytes());
+            for seq in &s.skipped {
+                data.extend_from_slice(&seq.to_le_bytes());
+            }
+        }
+        data.extend_from_slice(&state);
+        version::stamp(SNAPSHOT_VERSION, &data)
+    }
+
+    /// Restores a snapshot encoded by `snapshot`, unless it's older than
+    /// what's applied. Empty data, nothing snapshotted yet, is ignored.
+    pub fn restore(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let (v, mut rest) = version::split(data);
+        if v != SNAPSHOT_VERSION {
+            return Err(Error::Corruption(format!(
+                "snapshot format version {}, expect {}",
+                v, SNAPSHOT_VERSION
+            )));
+        }
+        let applied_index = take_u64(&mut rest)?;
+        if applied_index <= self.applied_index {
+            return Ok(());
+        }
+        let policy = take_policy(&mut rest)?;
+        let n = take_u64(&mut rest)?;
+        let mut sessions = vec![];
+        for _ in 0..n {
+            let client = take_u64(&mut rest)?;
+            let last_seq = take_u64(&mut rest)?;
+            let last_seen_ms = take_u64(&mut rest)?;
+            let len = take_u64(&mut rest)?;
+            let last_result = take(&mut rest, len)?.to_vec();
+            let skipped = (0..take_u64(&mut rest)?)
+                .map(|_| take_u64(&mut rest))
+                .collect::<Result<_>>()?;
+            sessions.push((
+                client,
+                Session {
+                    last_seq,
+                    last_seen_ms,
+                    last_result,
+                    skipped,
+                },
+            ));
+        }
+        self.state.restore(rest)?;
+        self.sessions = Sessions::restore(self.session_ttl, sessions);
+        self.snapshots.set_policy(policy);
+        self.applied_index = applied_index;
+        self.snapshots.snapshotted();
+        self.snapshot_count += 1;
+        Ok(())
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+fn take<'a>(data: &mut &'a [u8], len: u64) -> Result<&'a [u8]> {
+    if (data.len() as u64) < len {
+        return Err(Error::Corruption("truncated snapshot".to_owned()));
+    }
+    let (taken, rest) = data.split_at(len as usize);
+    *data = rest;
+    Ok(taken)
+}
+
+fn take_u64(data: &mut &[u8]) -> Result<u64> {
+    let n = take(data, 8)?;
+    Ok(u64::from_le_bytes(n.try_into().unwrap()))
+}
+
+fn put_policy(data: &mut Vec<u8>, policy: &SnapshotPolicy) {
+    let every_ms = policy.every.map(|d| d.as_millis() as u64);
+    for limit in &[
+        policy.max_raft_state.map(|n| n as u64),
+        policy.every_ops,
+        every_ms,
+    ] {
+        data.extend_from_slice(&limit.unwrap_or(0).to_le_bytes());
+    }
+}
+
+fn take_policy(data: &mut &[u8]) -> Result<SnapshotPolicy> {
+    let mut limit = || Ok(Some(take_u64(data)?).filter(|&n| n > 0));
+    Ok(SnapshotPolicy {
+        max_raft_state: limit()?.map(|n| n as usize),
+        every_ops: limit()?,
+        every: limit()?.map(Duration::from_millis),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // adds the ops to a counter.
+    #[derive(Default)]
+    struct Counter(u64);
+
+    impl StateMachine for Counter {
+        fn apply(&mut self, op: &[u8]) -> Vec<u8> {
+            assert!(op[0] != 0, "adding 0");
+            self.0 += u64::from(op[0]);
+            self.0.to_le_bytes().to_vec()
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            self.0.to_le_bytes().to_vec()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+            let mut snapshot = snapshot;
+            self.0 = take_u64(&mut snapshot)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_replica() {
+        let ttl = Duration::from_secs(1);
+        let policy = SnapshotPolicy {
+            every_ops: Some(2),
+            ..SnapshotPolicy::default()
+        };
+        let mut r = Replica::new(Counter::default(), ttl, SnapshotPolicy::default());
+        r.set_snapshot_policy(policy.clone());
+        assert_eq!(
+            r.apply(1, 7, 1, 0, &[2]),
+            Applied::Done(2u64.to_le_bytes().to_vec())
+        );
+        assert!(!r.snapshot_due(0));
+        assert_eq!(
+            r.apply(2, 7, 1, 0, &[2]),
+            Applied::Duplicate(Some(2u64.to_le_bytes().to_vec()))
+        );
+        assert!(r.snapshot_due(0));
+        assert_eq!(r.apply(2, 7, 2, 0, &[2]), Applied::Duplicate(None));
+        assert_eq!(r.apply(3, 8, 2, 0, &[2]), Applied::SessionExpired);
+        r.skip(4);
+        assert_eq!(r.applied_index(), 4);
+        let snapshot = r.snapshot();
+        assert!(!r.snapshot_due(0));
+        assert_eq!(r.snapshot_count(), 1);
+
+        let mut restored = Replica::new(Counter::default(), ttl, SnapshotPolicy::default());
+        restored.restore(&[]).unwrap();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.applied_index(), 4);
+        assert_eq!(restored.snapshot_count(), 1);
+        assert_eq!(restored.snapshot_policy(), &policy);
+        assert_eq!(restored.state().0, 2);
+        assert_eq!(restored.sessions(), r.sessions());
+        // entries covered by the snapshot aren't applied again, and a retry
+        // gets the result the snapshot kept.
+        assert_eq!(restored.apply(3, 7, 2, 0, &[2]), Applied::Duplicate(None));
+        assert_eq!(
+            restored.apply(5, 7, 1, 0, &[2]),
+            Applied::Duplicate(Some(2u64.to_le_bytes().to_vec()))
+        );
+        assert_eq!(
+            restored.apply(6, 7, 2, 0, &[2]),
+            Applied::Done(4u64.to_le_bytes().to_vec())
+        );
+
+        match restored.restore(&snapshot[..12]) {
+            Ok(()) => (),
+            Err(e) => panic!("an older snapshot must be ignored, got {:?}", e),
+        }
+        let mut fresh = Replica::new(Counter::default(), ttl, SnapshotPolicy::default());
+        assert_eq!(
+            fresh.restore(&snapshot[..20]),
+            Err(Error::Corruption("truncated snapshot".to_owned()))
+        );
+        assert_eq!(
+            fresh.restore(&snapshot[4..]),
+            Err(Error::Corruption(
+                "snapshot format version 0, expect 1".to_owned()
+            ))
+        );
+
+        // a poisoned op doesn't count as applied, its retry panics again.
+        for index in 1..3 {
+            assert_eq!(
+                fresh.apply(index, 7, 1, 0, &[0]),
+                Applied::Poisoned("adding 0".to_owned())
+            );
+        }
+        assert_eq!(fresh.poisoned(), &[1, 2]);
+        assert_eq!(
+            fresh.apply(3, 7, 1, 0, &[2]),
+            Applied::Done(2u64.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_replica_out_of_order() {
+        let ttl = Duration::from_secs(1);
+        let mut r = Replica::new(Counter::default(), ttl, SnapshotPolicy::default());
+        let result = |n: u64| n.to_le_bytes().to_vec();
+        assert_eq!(r.apply(1, 7, 1, 0, &[1]), Applied::Done(result(1)));
+        assert_eq!(r.apply(2, 7, 3, 0, &[3]), Applied::Done(result(4)));
+        assert_eq!(r.apply(3, 7, 2, 0, &[2]), Applied::Done(result(6)));
+        // a retry of 3 gets its own result, not that of 2.
+        assert_eq!(
+            r.apply(4, 7, 3, 0, &[3]),
+            Applied::Duplicate(Some(result(4)))
+        );
+        assert_eq!(r.apply(5, 7, 2, 0, &[2]), Applied::Duplicate(None));
+    }
+}
@@ -9,7 +9,7 @@ use std::time::{Duration, Instant};
 use futures::channel::oneshot;
 use futures::executor::block_on;
 use futures::future;
-use futures::{Future, FutureExt};
+use futures::{Future, FutureExt, StreamExt};
 use futures_timer::Delay;
 use rand::{seq::SliceRandom, Rng};
 
@@ -17,8 +17,18 @@ use linearizability::check_operations_timeout;
 use linearizability::model::Operation;
 use linearizability::models::{KvInput, KvModel, KvOutput, Op};
 
-use crate::kvraft::client::Clerk;
+use crate::kvraft::backoff::BackoffPolicy;
+use crate::kvraft::cancel::CancelToken;
+use crate::kvraft::client::{Clerk, ReadConsistency, ScanIter, ScanPage};
 use crate::kvraft::config::Config;
+use crate::kvraft::errors::Error;
+use crate::kvraft::fence::Fence;
+use crate::kvraft::leader;
+use crate::kvraft::limits::Limits;
+use crate::kvraft::script::{Event, NetworkScript};
+use crate::kvraft::snapshot::SnapshotPolicy;
+use crate::proto::kvraftpb::{self, PutAppendRequest};
+use crate::raft::persister::Faults;
 
 /// The tester generously allows solutions to complete elections in one second
 /// (much more than the paper's range of timeouts).
@@ -43,6 +53,11 @@ fn append(cfg: &Config, ck: &Clerk, key: &str, value: &str) {
     cfg.op();
 }
 
+fn delete(cfg: &Config, ck: &Clerk, key: &str) {
+    ck.delete(key.to_owned());
+    cfg.op();
+}
+
 fn check(cfg: &Config, ck: &Clerk, key: &str, value: &str) {
     let v = get(cfg, ck, key);
     if v != value {
@@ -50,6 +65,22 @@ fn check(cfg: &Config, ck: &Clerk, key: &str, value: &str) {
     }
 }
 
+fn scan(cfg: &Config, ck: &Clerk, start: &str, end: &str, limit: u32) -> ScanPage {
+    let page = ck.scan(start.to_owned(), end.to_owned(), limit);
+    cfg.op();
+    page
+}
+
+fn page(kvs: &[(&str, &str)], next: Option<&str>) -> ScanPage {
+    ScanPage {
+        kvs: kvs
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect(),
+        next: next.map(str::to_owned),
+    }
+}
+
 // spawn ncli clients and wait until they are all done
 fn spawn_clients_and_wait<Func, Fact>(
     cfg: Arc<Config>,
@@ -610,195 +641,2033 @@ fn test_unreliable_one_key_3a() {
     cfg.end();
 }
 
-// Submit a request in the minority partition and check that the requests
-// doesn't go through until the partition heals. The leader in the original
-// network ends up in the minority partition.
 #[test]
-fn test_one_partition_3a() {
-    let nservers = 5;
-    let cfg = Config::new(nservers, false, None);
+fn test_delete_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
 
-    let all = cfg.all();
-    let ck = cfg.make_client(&all);
+    cfg.begin("Test: delete (3A)");
 
-    put(&cfg, &ck, "1", "13");
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "a", "x");
+    append(&cfg, &ck, "b", "y");
+    delete(&cfg, &ck, "a");
+    check(&cfg, &ck, "a", "");
+    check(&cfg, &ck, "b", "y");
 
-    cfg.begin("Test: progress in majority (3A)");
+    // deleting a missing key does nothing, and the key can be written again.
+    delete(&cfg, &ck, "a");
+    append(&cfg, &ck, "a", "z");
+    check(&cfg, &ck, "a", "z");
 
-    let (p1, p2) = cfg.make_partition();
-    cfg.partition(&p1, &p2);
+    cfg.end();
+}
 
-    // connect ckp1 to p1
-    let ckp1 = cfg.make_client(&p1);
-    // connect ckp2a to p2
-    let ckp2a = cfg.make_client(&p2);
-    let ckp2a_name = ckp2a.name.clone();
-    // connect ckp2b to p2
-    let ckp2b = cfg.make_client(&p2);
-    let ckp2b_name = ckp2b.name.clone();
+#[test]
+fn test_scan_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
 
-    put(&cfg, &ckp1, "1", "14");
-    check(&cfg, &ckp1, "1", "14");
+    cfg.begin("Test: range scans (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    for k in &["d", "a", "c", "e", "b"] {
+        put(&cfg, &ck, k, &k.to_uppercase());
+    }
+    delete(&cfg, &ck, "c");
+
+    let first = scan(&cfg, &ck, "b", "e", 2);
+    assert_eq!(first, page(&[("b", "B"), ("d", "D")], None));
+    let first = scan(&cfg, &ck, "", "", 2);
+    assert_eq!(first, page(&[("a", "A"), ("b", "B")], Some("d")));
+    let second = scan(&cfg, &ck, "d", "", 2);
+    assert_eq!(second, page(&[("d", "D"), ("e", "E")], None));
+    assert_eq!(scan(&cfg, &ck, "", "", 0).kvs.len(), 4);
+    assert_eq!(scan(&cfg, &ck, "f", "", 0), page(&[], None));
 
     cfg.end();
+}
 
-    let (done0_tx, done0_rx) = oneshot::channel::<&'static str>();
-    let (done1_tx, done1_rx) = oneshot::channel::<&'static str>();
+#[test]
+fn test_scan_range_3a() {
+    const NSERVERS: usize = 5;
+    const NKEYS: usize = 450;
+    let cfg = Config::new(NSERVERS, false, None);
 
-    cfg.begin("Test: no progress in minority (3A)");
-    cfg.net.spawn(future::lazy(move |_| {
-        ckp2a.put("1".to_owned(), "15".to_owned());
-        done0_tx
-            .send("put")
-            .map_err(|e| {
-                warn!("done0 send failed: {:?}", e);
-            })
-            .unwrap();
-    }));
-    let done0_rx = done0_rx.map(|op| {
-        cfg.op();
-        op
-    });
+    cfg.begin("Test: scan iterators over pages, leader changes (3A)");
 
-    cfg.net.spawn(future::lazy(move |_| {
-        // different clerk in p2
-        ckp2b.get("1".to_owned());
-        done1_tx
-            .send("get")
-            .map_err(|e| {
-                warn!("done0 send failed: {:?}", e);
-            })
-            .unwrap();
-    }));
-    let done1_rx = done1_rx.map(|op| {
-        cfg.op();
-        op
-    });
+    let ck = cfg.make_client(&cfg.all());
+    let key = |i: usize| format!("k{:03}", i);
+    ck.put_many((0..NKEYS).map(|i| (key(i), i.to_string())).collect());
+    cfg.op();
 
-    let timeout = Delay::new(Duration::from_secs(1));
+    let mut seen = 0;
+    for (i, (k, v)) in ck.scan_range(..).enumerate() {
+        if i == 150 {
+            // the next pages come from the next leader.
+            let leader = cfg.leader().unwrap();
+            cfg.shutdown_server(leader);
+            thread::sleep(RAFT_ELECTION_TIMEOUT);
+            cfg.start_server(leader);
+            cfg.connect_all();
+        }
+        assert_eq!((k, v), (key(i), i.to_string()));
+        seen += 1;
+    }
+    assert_eq!(seen, NKEYS);
 
-    let dones = block_on(
-        future::select(timeout, future::select(done0_rx, done1_rx)).map(|res| match res {
-            future::Either::Left((_, dones)) => dones,
-            future::Either::Right((future::Either::Left((op, _)), _)) => {
-                panic!("{} in minority completed", op.unwrap())
-            }
-            future::Either::Right((future::Either::Right((op, _)), _)) => {
-                panic!("{} in minority completed", op.unwrap())
-            }
-        }),
+    let keys = |r: ScanIter| r.map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(
+        keys(ck.scan_range(key(10)..key(13))),
+        vec![key(10), key(11), key(12)]
     );
-
-    check(&cfg, &ckp1, "1", "14");
-    put(&cfg, &ckp1, "1", "16");
-    check(&cfg, &ckp1, "1", "16");
+    assert_eq!(
+        keys(ck.scan_range(key(10)..=key(12))),
+        vec![key(10), key(11), key(12)]
+    );
+    assert_eq!(keys(ck.scan_range(key(448)..)), vec![key(448), key(449)]);
+    assert_eq!(ck.scan_range(key(5)..key(5)).count(), 0);
+    assert_eq!(ck.scan_range(..String::new()).count(), 0);
 
     cfg.end();
+}
 
-    cfg.begin("Test: completion after heal (3A)");
-
-    cfg.connect_all();
-    cfg.connect_client_by_name(&ckp2a_name, &all);
-    cfg.connect_client_by_name(&ckp2b_name, &all);
+#[test]
+fn test_write_batch_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, true, None);
 
-    thread::sleep(RAFT_ELECTION_TIMEOUT);
+    cfg.begin("Test: atomic write batches, unreliable net (3A)");
 
-    let timeout = Delay::new(Duration::from_secs(3));
-    let (timeout, next) = block_on(async {
-        future::select(timeout, dones)
-            .map(|res| match res {
-                future::Either::Left(_) => panic!("put/get did not complete"),
-                future::Either::Right((future::Either::Left((op, next)), timeout)) => {
-                    info!("{} completes", op.unwrap());
-                    (timeout, future::Either::Left(next))
-                }
-                future::Either::Right((future::Either::Right((op, next)), timeout)) => {
-                    info!("{} completes", op.unwrap());
-                    (timeout, future::Either::Right(next))
-                }
-            })
-            .await
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "a", "x");
+    ck.batch()
+        .append("a".to_owned(), "y".to_owned())
+        .put("b".to_owned(), "z".to_owned())
+        .delete("a".to_owned())
+        .append("a".to_owned(), "w".to_owned())
+        .commit();
+    cfg.op();
+    // retried batches must not be applied twice.
+    check(&cfg, &ck, "a", "w");
+    check(&cfg, &ck, "b", "z");
+
+    // a reader sees all of a batch or none of it.
+    let done = Arc::new(AtomicUsize::new(0));
+    let done_ = done.clone();
+    let cfg = Arc::new(cfg);
+    let cfg_ = cfg.clone();
+    let reader = thread::spawn(move || {
+        let ck = cfg_.make_client(&cfg_.all());
+        while done_.load(Ordering::Relaxed) == 0 {
+            let page = scan(&cfg_, &ck, "c0", "c9", 0);
+            let values: Vec<_> = page.kvs.iter().map(|(_, v)| v).collect();
+            assert!(
+                values.windows(2).all(|w| w[0] == w[1]),
+                "partial batch {:?}",
+                page.kvs
+            );
+        }
+        cfg_.delete_client(&ck);
     });
+    for i in 0..10 {
+        let mut batch = ck.batch();
+        for k in 0..5 {
+            batch.put(format!("c{}", k), i.to_string());
+        }
+        batch.commit();
+        cfg.op();
+    }
+    done.store(1, Ordering::Relaxed);
+    reader.join().unwrap();
 
-    block_on(async {
-        future::select(timeout, next)
-            .map(|res| match res {
-                future::Either::Left(_) => panic!("put/get did not complete"),
-                future::Either::Right((op, _)) => info!("{} completes", op.unwrap()),
-            })
-            .await
+    cfg.end();
+}
+
+#[test]
+fn test_watch_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Arc::new(Config::new(NSERVERS, false, None));
+
+    cfg.begin("Test: watch, leader changes (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "other", "x");
+    let (tx, rx) = mpsc::channel();
+    let cfg_ = cfg.clone();
+    let watcher = thread::spawn(move || {
+        let ck = cfg_.make_client(&cfg_.all());
+        let mut watch = ck.watch("w".to_owned(), 0);
+        let mut changes = vec![];
+        while changes.len() < 10 {
+            changes.extend(watch.wait_changes());
+        }
+        cfg_.delete_client(&ck);
+        tx.send(changes).unwrap();
     });
 
-    check(&cfg, &ck, "1", "15");
+    for i in 0..10 {
+        if i == 5 {
+            // the watch goes on at the next leader.
+            let leader = cfg.leader().unwrap_or(0);
+            cfg.shutdown_server(leader);
+            thread::sleep(RAFT_ELECTION_TIMEOUT);
+            cfg.start_server(leader);
+            cfg.connect_all();
+        }
+        if i % 3 == 2 {
+            delete(&cfg, &ck, "w1");
+        } else {
+            put(&cfg, &ck, &format!("w{}", i % 2), &i.to_string());
+        }
+        put(&cfg, &ck, "other", &i.to_string());
+    }
+    let changes = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    watcher.join().unwrap();
+
+    assert!(changes.windows(2).all(|w| w[0].revision < w[1].revision));
+    for (i, change) in changes.iter().enumerate() {
+        if i % 3 == 2 {
+            assert_eq!((&*change.key, change.deleted), ("w1", true));
+        } else {
+            assert_eq!(change.key, format!("w{}", i % 2));
+            assert_eq!(change.value, i.to_string());
+        }
+    }
 
     cfg.end();
 }
 
 #[test]
-fn test_many_partitions_one_client_3a() {
-    // Test: partitions, one client (3A) ...
-    generic_test("3A", 1, false, false, true, None)
-}
+fn test_watch_stream_3a() {
+    const NSERVERS: usize = 5;
+    const NWRITES: usize = 20;
+    let cfg = Arc::new(Config::new(NSERVERS, true, None));
 
-#[test]
-fn test_many_partitions_many_clients_3a() {
-    // Test: partitions, many clients (3A) ...
-    generic_test("3A", 5, false, false, true, None)
-}
+    cfg.begin("Test: watch streams, leader changes, unreliable net (3A)");
 
-#[test]
-fn test_persist_one_client_3a() {
-    // Test: restarts, one client (3A) ...
-    generic_test("3A", 1, false, true, false, None)
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "w", "0");
+    let (tx, rx) = mpsc::channel();
+    let cfg_ = cfg.clone();
+    let watcher = thread::spawn(move || {
+        let ck = cfg_.make_client(&cfg_.all());
+        let changes = ck.watch_stream("w".to_owned(), 0).take(NWRITES + 1);
+        tx.send(block_on(changes.collect::<Vec<_>>())).unwrap();
+        cfg_.delete_client(&ck);
+    });
+
+    for i in 1..=NWRITES {
+        if i % 7 == 0 {
+            let leader = cfg.leader().unwrap_or(0);
+            cfg.shutdown_server(leader);
+            thread::sleep(RAFT_ELECTION_TIMEOUT);
+            cfg.start_server(leader);
+            cfg.connect_all();
+        }
+        append(&cfg, &ck, "w", &i.to_string());
+        put(&cfg, &ck, "x", &i.to_string());
+    }
+    let changes = rx.recv_timeout(Duration::from_secs(20)).unwrap();
+    watcher.join().unwrap();
+
+    // every append once, in order.
+    assert!(changes.windows(2).all(|w| w[0].revision < w[1].revision));
+    let mut expected = String::new();
+    for (i, change) in changes.iter().enumerate() {
+        expected.push_str(&i.to_string());
+        assert_eq!(change.key, "w");
+        assert_eq!(change.value, expected);
+    }
+
+    cfg.end();
 }
 
 #[test]
-fn test_persist_concurrent_3a() {
-    // Test: restarts, many clients (3A) ...
-    generic_test("3A", 5, false, true, false, None)
+fn test_follower_reads_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: reads served by followers (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "0");
+    let leader = cfg.leader().unwrap();
+    let followers: Vec<_> = cfg.all().into_iter().filter(|&i| i != leader).collect();
+    let reader = cfg.make_client(&followers);
+    for i in 1..20 {
+        // a read that starts after a write completed must see it, even if
+        // the follower hasn't applied it yet.
+        put(&cfg, &ck, "k", &i.to_string());
+        check(&cfg, &reader, "k", &i.to_string());
+    }
+
+    cfg.end();
 }
 
 #[test]
-fn test_persist_concurrent_unreliable_3a() {
-    // Test: unreliable net, restarts, many clients (3A) ...
-    generic_test("3A", 5, true, true, false, None)
+fn test_stale_reads_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: stale reads (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    let mut last_applied = [0; NSERVERS];
+    for i in 0..10 {
+        put(&cfg, &ck, "k", &i.to_string());
+        for (s, last_applied) in last_applied.iter_mut().enumerate() {
+            // a server only ever moves forward, and catches up eventually.
+            let reader = cfg.make_client(&[s]);
+            let start = Instant::now();
+            loop {
+                let (v, applied) = reader.get_stale("k".to_owned());
+                cfg.op();
+                assert!(applied >= *last_applied, "applied index went back");
+                *last_applied = applied;
+                if v == i.to_string() {
+                    break;
+                }
+                assert!(start.elapsed() < RAFT_ELECTION_TIMEOUT, "never caught up");
+                thread::sleep(Duration::from_millis(10));
+            }
+            cfg.delete_client(&reader);
+        }
+    }
+
+    cfg.end();
 }
 
 #[test]
-fn test_persist_partition_3a() {
-    // Test: restarts, partitions, many clients (3A) ...
-    generic_test("3A", 5, false, true, true, None)
+fn test_incr_3a() {
+    const NSERVERS: usize = 5;
+    const NCLIENTS: usize = 5;
+    const NINCRS: usize = 20;
+    let cfg = Arc::new(Config::new(NSERVERS, true, None));
+
+    cfg.begin("Test: concurrent increments, unreliable net (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    assert_eq!(ck.incr("n".to_owned(), -1), -1);
+    cfg.op();
+
+    let values = Arc::new(Mutex::new(vec![]));
+    let values_ = values.clone();
+    block_on(spawn_clients_and_wait(cfg.clone(), NCLIENTS, move || {
+        let values = values_.clone();
+        move |_, ck: &Clerk| {
+            for _ in 0..NINCRS {
+                let v = ck.incr("n".to_owned(), 2);
+                values.lock().unwrap().push(v);
+            }
+        }
+    }));
+
+    // every increment is applied once, so every one of them saw a value of
+    // its own.
+    let mut values = values.lock().unwrap().clone();
+    values.sort_unstable();
+    let expected: Vec<_> = (0..(NCLIENTS * NINCRS) as i64).map(|i| 1 + 2 * i).collect();
+    assert_eq!(values, expected);
+    check(
+        &cfg,
+        &ck,
+        "n",
+        &(2 * (NCLIENTS * NINCRS) as i64 - 1).to_string(),
+    );
+
+    cfg.end();
 }
 
 #[test]
-fn test_persist_partition_unreliable_3a() {
-    // Test: unreliable net, restarts, partitions, many clients (3A) ...
-    generic_test("3A", 5, true, true, true, None)
+fn test_get_at_revision_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: reads at earlier revisions (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    // a watch tells the revisions of the writes.
+    let mut watch = ck.watch("k".to_owned(), 0);
+    put(&cfg, &ck, "k", "a");
+    append(&cfg, &ck, "k", "b");
+    delete(&cfg, &ck, "k");
+    put(&cfg, &ck, "k", "c");
+    let mut changes = vec![];
+    while changes.len() < 4 {
+        changes.extend(watch.wait_changes());
+    }
+    let revs: Vec<_> = changes.iter().map(|c| c.revision).collect();
+
+    assert_eq!(ck.get_at("k".to_owned(), revs[0]), Ok("a".to_owned()));
+    assert_eq!(ck.get_at("k".to_owned(), revs[1]), Ok("ab".to_owned()));
+    assert_eq!(ck.get_at("k".to_owned(), revs[2]), Ok(String::new()));
+    assert_eq!(ck.get_at("k".to_owned(), revs[3] - 1), Ok(String::new()));
+    assert_eq!(ck.get_at("k".to_owned(), 0), Ok("c".to_owned()));
+
+    ck.compact(revs[2]);
+    assert_eq!(
+        ck.get_at("k".to_owned(), revs[1]),
+        Err(Error::Compacted(revs[2]))
+    );
+    assert_eq!(ck.get_at("k".to_owned(), revs[2]), Ok(String::new()));
+    check(&cfg, &ck, "k", "c");
+
+    cfg.end();
 }
 
 #[test]
-fn test_persist_partition_unreliable_linearizable_3a() {
-    // Test: unreliable net, restarts, partitions, linearizability checks (3A) ...
-    generic_test_linearizability("3A", 15, 7, true, true, true, None)
+fn test_export_import_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: export and import into a fresh service (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    for i in 0..20 {
+        put(&cfg, &ck, &format!("k{:02}", i), &format!("v{}", i));
+    }
+    delete(&cfg, &ck, "k05");
+
+    let mut export = ck.export();
+    let first = export.next().unwrap();
+    let revision = export.revision();
+    // writes after the export started aren't exported.
+    put(&cfg, &ck, "k00", "x");
+    put(&cfg, &ck, "k99", "x");
+    let mut chunks = vec![first];
+    chunks.extend(&mut export);
+    assert_eq!(export.revision(), revision);
+    let kvs: Vec<_> = chunks.iter().flatten().cloned().collect();
+    let want: Vec<_> = (0..20)
+        .filter(|&i| i != 5)
+        .map(|i| (format!("k{:02}", i), format!("v{}", i)))
+        .collect();
+    assert_eq!(kvs, want);
+
+    let fresh = Config::new(NSERVERS, false, None);
+    let fresh_ck = fresh.make_client(&fresh.all());
+    for chunk in chunks {
+        fresh_ck.import(chunk, revision);
+    }
+    check(&fresh, &fresh_ck, "k00", "v0");
+    check(&fresh, &fresh_ck, "k05", "");
+    check(&fresh, &fresh_ck, "k19", "v19");
+    check(&fresh, &fresh_ck, "k99", "");
+    // revisions go on from the exported one.
+    let mut watch = fresh_ck.watch("k".to_owned(), revision);
+    put(&fresh, &fresh_ck, "k00", "y");
+    let mut changes = vec![];
+    while changes.is_empty() {
+        changes = watch.wait_changes();
+    }
+    assert!(changes[0].revision > revision);
+    fresh.end();
+
+    cfg.end();
 }
 
-// if one server falls behind, then rejoins, does it
-// recover by using the InstallSnapshot RPC?
-// also checks that majority discards committed log entries
-// even if minority doesn't respond.
 #[test]
-fn test_snapshot_rpc_3b() {
-    let nservers = 3;
-    let maxraftstate = 1000;
-    let cfg = Config::new(nservers, false, Some(maxraftstate));
+fn test_leader_hint_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "a");
 
-    let all = cfg.all();
-    let ck = cfg.make_client(&all);
+    cfg.begin("Test: followers point to the leader (3A)");
 
-    cfg.begin("Test: InstallSnapshot RPC (3B)");
+    // heartbeats have told every follower about the leader.
+    thread::sleep(RAFT_ELECTION_TIMEOUT);
+    let leader = cfg.leader().unwrap();
+    let args = PutAppendRequest {
+        key: "k".to_owned(),
+        value: "b".to_owned(),
+        op: kvraftpb::Op::Put as i32,
+        ..Default::default()
+    };
+    for i in cfg.all() {
+        // a client of server i only.
+        let cki = cfg.make_client(&[i]);
+        let replies = block_on(future::join_all(
+            cki.servers.iter().map(|s| s.put_append(&args)),
+        ));
+        let replied: Vec<_> = replies.into_iter().filter_map(|r| r.ok()).collect();
+        assert_eq!(replied.len(), 1);
+        let reply = &replied[0];
+        assert_eq!(leader::from_hint(reply.server), Some(i));
+        if i == leader {
+            assert!(!reply.wrong_leader);
+        } else {
+            assert!(reply.wrong_leader);
+            assert_eq!(leader::from_hint(reply.leader_hint), Some(leader));
+        }
+    }
+    check(&cfg, &ck, "k", "b");
 
-    put(&cfg, &ck, "a", "A");
-    check(&cfg, &ck, "a", "A");
+    cfg.end();
+}
+
+#[test]
+fn test_request_deadline_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "a");
+
+    cfg.begin("Test: requests give up by their deadline (3A)");
+
+    let (p1, p2) = cfg.make_partition();
+    cfg.partition(&p1, &p2);
+    cfg.connect_client(&ck, &p1);
+    // a client of the old leader only, which can't commit anything.
+    let old_leader = *p2.last().unwrap();
+    let ckp2 = cfg.make_client(&[old_leader]);
+
+    let args = PutAppendRequest {
+        key: "k".to_owned(),
+        value: "b".to_owned(),
+        op: kvraftpb::Op::Put as i32,
+        timeout_ms: 500,
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let replies = block_on(future::join_all(
+        ckp2.servers
+            .iter()
+            .map(|s| s.put_append(&args).map(|r| (r, start.elapsed()))),
+    ));
+    // only the old leader is connected.
+    let replied: Vec<_> = replies
+        .into_iter()
+        .filter_map(|(r, elapsed)| Some((r.ok()?, elapsed)))
+        .collect();
+    assert_eq!(replied.len(), 1);
+    let (reply, elapsed) = &replied[0];
+    assert_eq!(reply.err, Error::Timeout.to_string());
+    if *elapsed > Duration::from_millis(500) + RAFT_ELECTION_TIMEOUT {
+        panic!("a request with a 500ms deadline took {:?}", elapsed);
+    }
+
+    put(&cfg, &ck, "k", "c");
+    cfg.connect_all();
+    check(&cfg, &ckp2, "k", "c");
+
+    cfg.end();
+}
+
+#[test]
+fn test_speed_disjoint_keys_3a() {
+    const NSERVERS: usize = 3;
+    const NCLIENTS: usize = 10;
+    const NOPS: usize = 100;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: ops on disjoint keys keep up (3A)");
+
+    let cfg = Arc::new(cfg);
+    let cfg_ = cfg.clone();
+    let start = Instant::now();
+    block_on(spawn_clients_and_wait(cfg.clone(), NCLIENTS, move || {
+        let cfg1 = cfg_.clone();
+        move |me, myck| {
+            for n in 0..NOPS {
+                put(&cfg1, myck, &format!("{}-{}", me, n % 10), &n.to_string());
+            }
+            check(&cfg1, myck, &format!("{}-9", me), &(NOPS - 1).to_string());
+        }
+    }));
+    let elapsed = start.elapsed();
+    // half a heartbeat interval per op on average.
+    if elapsed > Duration::from_millis(50) * NOPS as u32 {
+        panic!(
+            "{} ops of {} clients on their own keys took {:?}",
+            NOPS, NCLIENTS, elapsed
+        );
+    }
+
+    cfg.end();
+}
+
+#[test]
+fn test_size_limits_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: keys and values past the size limits (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    let limits = Limits::default();
+    let long_key = "k".repeat(limits.max_key + 1);
+    let big_value = "v".repeat(limits.max_value + 1);
+    assert_eq!(
+        ck.try_put(long_key.clone(), "v".to_owned()),
+        Err(Error::KeyTooLarge {
+            size: limits.max_key + 1,
+            max: limits.max_key,
+        })
+    );
+    assert_eq!(
+        ck.try_put("k".to_owned(), big_value.clone()),
+        Err(Error::ValueTooLarge {
+            size: limits.max_value + 1,
+            max: limits.max_value,
+        })
+    );
+    assert_eq!(
+        ck.try_put("k".to_owned(), "v".repeat(limits.max_value)),
+        Ok(())
+    );
+    // the value would grow past the limit.
+    assert_eq!(
+        ck.try_append("k".to_owned(), "v".to_owned()),
+        Err(Error::ValueTooLarge {
+            size: limits.max_value + 1,
+            max: limits.max_value,
+        })
+    );
+
+    // servers reject writes past the limits too.
+    let args = PutAppendRequest {
+        key: "k2".to_owned(),
+        value: big_value,
+        op: kvraftpb::Op::Put as i32,
+        ..Default::default()
+    };
+    let replies = block_on(future::join_all(
+        ck.servers.iter().map(|s| s.put_append(&args)),
+    ));
+    let leader_reply = replies
+        .into_iter()
+        .filter_map(|r| r.ok())
+        .find(|r| !r.wrong_leader)
+        .unwrap();
+    let want = Error::ValueTooLarge {
+        size: limits.max_value + 1,
+        max: limits.max_value,
+    };
+    assert_eq!(leader_reply.err, want.to_string());
+    check(&cfg, &ck, "k2", "");
+    assert!(cfg.log_size() < 2 * limits.max_value + 4096);
+
+    cfg.end();
+}
+
+#[test]
+fn test_custom_size_limits_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+    let limits = Limits {
+        max_key: 8,
+        max_value: 16,
+    };
+
+    // restart the servers with the smaller limits.
+    cfg.set_limits(limits);
+    for i in cfg.all() {
+        cfg.shutdown_server(i);
+        cfg.start_server(i);
+    }
+    cfg.connect_all();
+
+    cfg.begin("Test: keys and values past custom size limits (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    assert_eq!(ck.limits(), limits);
+    assert_eq!(
+        ck.try_put("k".repeat(9), "v".to_owned()),
+        Err(Error::KeyTooLarge { size: 9, max: 8 })
+    );
+    assert_eq!(
+        ck.try_put("k".to_owned(), "v".repeat(17)),
+        Err(Error::ValueTooLarge { size: 17, max: 16 })
+    );
+    assert_eq!(ck.try_put("k".to_owned(), "v".repeat(16)), Ok(()));
+    assert_eq!(
+        ck.try_append("k".to_owned(), "v".to_owned()),
+        Err(Error::ValueTooLarge { size: 17, max: 16 })
+    );
+    check(&cfg, &ck, "k", &"v".repeat(16));
+
+    // writes to a namespace are checked the same.
+    let a = ck.namespace("a".to_owned());
+    assert_eq!(
+        a.try_put("k".repeat(9), "v".to_owned()),
+        Err(Error::KeyTooLarge { size: 9, max: 8 })
+    );
+    assert_eq!(
+        a.try_append("k".to_owned(), "v".repeat(17)),
+        Err(Error::ValueTooLarge { size: 17, max: 16 })
+    );
+    assert_eq!(a.get("k".to_owned()), "");
+    assert_eq!(
+        a.try_delete("k".repeat(9)),
+        Err(Error::KeyTooLarge { size: 9, max: 8 })
+    );
+
+    cfg.end();
+}
+
+#[test]
+fn test_namespaces_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: keys in namespaces (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    let a = ck.namespace("a".to_owned());
+    let b = ck.namespace("b".to_owned());
+    put(&cfg, &ck, "k", "default");
+    a.put("k".to_owned(), "in a".to_owned());
+    a.append("k".to_owned(), "!".to_owned());
+    a.put("l".to_owned(), "x".to_owned());
+    b.put("k".to_owned(), "in b".to_owned());
+    check(&cfg, &ck, "k", "default");
+    assert_eq!(a.get("k".to_owned()), "in a!");
+    assert_eq!(b.get("k".to_owned()), "in b");
+    assert_eq!(a.stats(), (2, 8));
+
+    assert_eq!(a.delete_all(), 2);
+    assert_eq!(a.get("k".to_owned()), "");
+    assert_eq!(a.get("l".to_owned()), "");
+    assert_eq!(a.stats(), (0, 0));
+    assert_eq!(b.get("k".to_owned()), "in b");
+    b.delete("k".to_owned());
+    assert_eq!(b.stats(), (0, 0));
+    check(&cfg, &ck, "k", "default");
+
+    cfg.end();
+}
+
+#[test]
+fn test_rolling_restart_3a() {
+    const NSERVERS: usize = 5;
+    const NCLIENTS: usize = 3;
+    let cfg = Arc::new(Config::new(NSERVERS, false, None));
+
+    cfg.begin("Test: rolling restart with draining servers (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "");
+
+    let done = Arc::new(AtomicUsize::new(0));
+    let slowest = Arc::new(Mutex::new(Duration::default()));
+    let (cfg_, done_, slowest_) = (cfg.clone(), done.clone(), slowest.clone());
+    let clients = thread::spawn(move || {
+        block_on(spawn_clients_and_wait(cfg_.clone(), NCLIENTS, move || {
+            let (cfg1, done1, slowest1) = (cfg_.clone(), done_.clone(), slowest_.clone());
+            move |me, myck| {
+                let mut n = 0;
+                while done1.load(Ordering::Relaxed) == 0 {
+                    let start = Instant::now();
+                    append(&cfg1, myck, "k", &format!("x {} {} y", me, n));
+                    let mut slowest = slowest1.lock().unwrap();
+                    *slowest = (*slowest).max(start.elapsed());
+                    n += 1;
+                }
+                debug!("client {} appended {} times", me, n);
+            }
+        }))
+    });
+
+    for i in 0..NSERVERS {
+        thread::sleep(Duration::from_millis(200));
+        cfg.drain_server(i, RAFT_ELECTION_TIMEOUT);
+        cfg.start_server(i);
+        cfg.connect_all();
+    }
+    done.store(1, Ordering::Relaxed);
+    clients.join().unwrap();
+
+    // clients never waited for an election, leadership was handed over.
+    let slowest = *slowest.lock().unwrap();
+    if slowest > RAFT_ELECTION_TIMEOUT {
+        panic!("an append took {:?} during a rolling restart", slowest);
+    }
+    let v = get(&cfg, &ck, "k");
+    for me in 0..NCLIENTS {
+        assert!(v.contains(&format!("x {} 0 y", me)));
+    }
+
+    cfg.end();
+}
+
+#[test]
+fn test_append_and_get_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, true, None);
+
+    cfg.begin("Test: appends returning the value, unreliable (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "a");
+    // retries over the unreliable network must get the value of the first
+    // try.
+    let mut want = "a".to_owned();
+    for i in 0..20 {
+        let arg = (i % 10).to_string();
+        want.push_str(&arg);
+        assert_eq!(ck.append_and_get("k".to_owned(), arg), want);
+        cfg.op();
+    }
+    assert_eq!(ck.append_and_get("new".to_owned(), "x".to_owned()), "x");
+    check(&cfg, &ck, "k", &want);
+
+    cfg.end();
+}
+
+#[test]
+fn test_fence_tokens_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: fencing tokens of lock keys grow (3A)");
+
+    let ck1 = cfg.make_client(&cfg.all());
+    let ck2 = cfg.make_client(&cfg.all());
+    let mut fence = Fence::new();
+    let t1 = ck1.put_fenced("lock/a".to_owned(), "ck1".to_owned());
+    assert!(fence.admit(t1));
+
+    // the leader changes, ck2 takes the lock over.
+    let (p1, p2) = cfg.make_partition();
+    cfg.partition(&p1, &p2);
+    cfg.connect_client(&ck2, &p1);
+    let t2 = ck2.put_fenced("lock/a".to_owned(), "ck2".to_owned());
+    assert!(t2 > t1);
+    assert!(fence.admit(t2));
+    // ck1 still holds t1, the resource turns it away.
+    assert!(!fence.admit(t1));
+
+    cfg.connect_all();
+    cfg.connect_client(&ck1, &cfg.all());
+    let t3 = ck1.put_fenced("lock/b".to_owned(), "ck1".to_owned());
+    assert!(t3 > t2);
+    check(&cfg, &ck1, "lock/a", "ck2");
+
+    cfg.end();
+}
+
+#[test]
+fn test_secondary_index_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: secondary indexes follow writes (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    let pairs = |kvs: &[(&str, &str)]| -> Vec<(String, String)> {
+        kvs.iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect()
+    };
+    put(&cfg, &ck, "user/1", "ann");
+    put(&cfg, &ck, "user/2", "bob");
+    put(&cfg, &ck, "team/1", "ann");
+    ck.create_index("by-name".to_owned(), "user/".to_owned());
+    assert_eq!(
+        ck.scan_index("by-name".to_owned(), "ann".to_owned(), "b".to_owned()),
+        pairs(&[("user/1", "ann")])
+    );
+
+    put(&cfg, &ck, "user/3", "ann");
+    append(&cfg, &ck, "user/2", "by");
+    delete(&cfg, &ck, "user/1");
+    let mut batch = ck.batch();
+    batch
+        .put("user/4".to_owned(), "al".to_owned())
+        .put("user/5".to_owned(), "cy".to_owned());
+    batch.commit();
+    assert_eq!(
+        ck.scan_index("by-name".to_owned(), "".to_owned(), "".to_owned()),
+        pairs(&[
+            ("user/4", "al"),
+            ("user/3", "ann"),
+            ("user/2", "bobby"),
+            ("user/5", "cy"),
+        ])
+    );
+
+    cfg.end();
+}
+
+#[test]
+fn test_read_your_writes_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: session reads see their own writes (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "0");
+    // a server that lags behind can't serve the session.
+    let (p1, p2) = cfg.make_partition();
+    let lagging = p1[0];
+    let mut majority: Vec<_> = p1[1..].to_vec();
+    majority.extend(&p2);
+    cfg.partition(&majority, &[lagging]);
+    for i in 1..10 {
+        let v = i.to_string();
+        put(&cfg, &ck, "k", &v);
+        // the clerk may try the lagging server, it must not get an older
+        // value from it.
+        cfg.connect_client(&ck, &cfg.all());
+        assert_eq!(ck.get_session("k".to_owned()), v);
+        cfg.op();
+        cfg.connect_client(&ck, &majority);
+    }
+
+    cfg.end();
+}
+
+#[test]
+fn test_audit_log_3a() {
+    const NSERVERS: usize = 3;
+    const NCLIENTS: usize = 3;
+    const NWRITES: usize = 10;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: servers agree on the ops they applied (3A)");
+
+    let cks: Vec<_> = (0..NCLIENTS).map(|_| cfg.make_client(&cfg.all())).collect();
+    for i in 0..NWRITES {
+        for (c, ck) in cks.iter().enumerate() {
+            append(&cfg, ck, &format!("k{}", c), &i.to_string());
+        }
+    }
+    check(&cfg, &cks[0], "k0", "0123456789");
+
+    // followers may still be applying the last entries.
+    thread::sleep(RAFT_ELECTION_TIMEOUT);
+    let logs: Vec<_> = cfg
+        .all()
+        .into_iter()
+        .map(|i| {
+            let mut log = cfg.audit_log(i).unwrap();
+            log.retain(|r| r.op != "Get");
+            log
+        })
+        .collect();
+    for log in &logs {
+        assert_eq!(log, &logs[0], "servers applied different ops");
+    }
+    assert_eq!(logs[0].len(), NCLIENTS * NWRITES);
+    let mut requests: Vec<_> = logs[0].iter().map(|r| (r.client, r.seq)).collect();
+    requests.sort_unstable();
+    requests.dedup();
+    assert_eq!(requests.len(), logs[0].len(), "an op was applied twice");
+    assert!(logs[0].windows(2).all(|w| w[0].index < w[1].index));
+    assert!(logs[0].iter().all(|r| r.op == "Append"));
+
+    cfg.end();
+}
+
+#[test]
+fn test_async_clerks_3a() {
+    const NSERVERS: usize = 3;
+    const NCLERKS: usize = 200;
+    const NAPPENDS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: one thread drives many clerks (3A)");
+
+    let cks: Vec<_> = (0..NCLERKS).map(|_| cfg.make_client(&cfg.all())).collect();
+    // every clerk waits for its write before the next one.
+    let clerks = cks.iter().enumerate().map(|(i, ck)| async move {
+        let key = format!("k{}", i);
+        ck.put_async(key.clone(), String::new()).await;
+        for j in 0..NAPPENDS {
+            ck.append_async(key.clone(), j.to_string()).await;
+        }
+        ck.get_async(key).await
+    });
+    let t0 = Instant::now();
+    let values = block_on(future::join_all(clerks));
+    for v in values {
+        assert_eq!(v, "012");
+    }
+    // one at a time, they would take a heartbeat interval or so each.
+    assert!(
+        t0.elapsed() < Duration::from_secs(10),
+        "clerks didn't run concurrently"
+    );
+
+    let ck = &cks[0];
+    block_on(ck.delete_async("k0".to_owned()));
+    check(&cfg, ck, "k0", "");
+
+    cfg.end();
+}
+
+#[test]
+fn test_coalesced_gets_3a() {
+    const NSERVERS: usize = 3;
+    const NTHREADS: usize = 16;
+    const NGETS: usize = 50;
+    const NAPPENDS: usize = 20;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: concurrent gets of a hot key share RPCs (3A)");
+
+    let ck = Arc::new(cfg.make_client(&cfg.all()));
+    put(&cfg, &ck, "hot", "");
+    let writer = cfg.make_client(&cfg.all());
+    let rpcs0 = cfg.net.total_count();
+    let readers: Vec<_> = (0..NTHREADS)
+        .map(|_| {
+            let ck = ck.clone();
+            thread::spawn(move || {
+                // a reader never sees the value go back.
+                let mut last = 0;
+                for _ in 0..NGETS {
+                    let v = ck.get("hot".to_owned());
+                    assert!(v.len() >= last, "read {:?} after {} appends", v, last);
+                    last = v.len();
+                }
+            })
+        })
+        .collect();
+    for _ in 0..NAPPENDS {
+        append(&cfg, &writer, "hot", "x");
+    }
+    for r in readers {
+        r.join().unwrap();
+    }
+    let rpcs = cfg.net.total_count() - rpcs0;
+    // a done append is seen by the gets after it.
+    check(&cfg, &ck, "hot", &"x".repeat(NAPPENDS));
+    assert!(
+        rpcs < NTHREADS * NGETS / 2,
+        "{} gets of a key took {} RPCs",
+        NTHREADS * NGETS,
+        rpcs
+    );
+
+    cfg.end();
+}
+
+#[test]
+fn test_pipelined_clerk_3a() {
+    const NSERVERS: usize = 3;
+    const NKEYS: usize = 50;
+    const NAPPENDS: usize = 5;
+    let cfg = Config::new(NSERVERS, true, None);
+
+    cfg.begin("Test: one clerk with many writes in flight (3A)");
+
+    let builder = Clerk::builder().pipeline_depth(16);
+    let ck = cfg.make_client_with(&cfg.all(), builder);
+    put(&cfg, &ck, "k", "");
+    let t0 = Instant::now();
+    let writes = (0..NKEYS).map(|i| {
+        let ck = &ck;
+        async move {
+            let key = format!("k{}", i % 10);
+            ck.append_async(key, format!("{},", i)).await;
+        }
+    });
+    let appends = (0..NAPPENDS).map(|j| ck.append_async("k".to_owned(), j.to_string()));
+    block_on(future::join(
+        future::join_all(writes),
+        future::join_all(appends),
+    ));
+    let elapsed = t0.elapsed();
+
+    // every write was applied once, those to a key in the order started.
+    check(&cfg, &ck, "k", "01234");
+    for k in 0..10 {
+        let expected: String = (k..NKEYS).step_by(10).map(|i| format!("{},", i)).collect();
+        check(&cfg, &ck, &format!("k{}", k), &expected);
+    }
+    assert!(
+        elapsed < Duration::from_secs(10),
+        "{} writes took {:?}",
+        NKEYS + NAPPENDS,
+        elapsed
+    );
+
+    cfg.end();
+}
+
+#[test]
+fn test_clerk_backoff_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: clerks back off while there's no leader (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "a");
+    // server 0 alone can't elect a leader, it only hears from the clerk.
+    cfg.shutdown_server(1);
+    cfg.shutdown_server(2);
+    let rpcs0 = cfg.net.count("0");
+    let done = Arc::new(AtomicUsize::new(0));
+    let t = {
+        let done = done.clone();
+        thread::spawn(move || {
+            ck.append("k".to_owned(), "b".to_owned());
+            done.store(1, Ordering::SeqCst);
+            ck
+        })
+    };
+    thread::sleep(Duration::from_secs(5));
+    let rpcs = cfg.net.count("0") - rpcs0;
+    // the delays grow to 500ms, 250ms on average. retrying every 100ms
+    // would take 50.
+    assert!(rpcs < 40, "{} RPCs in 5 seconds without a leader", rpcs);
+    assert_eq!(done.load(Ordering::SeqCst), 0);
+
+    cfg.start_server(1);
+    cfg.start_server(2);
+    cfg.connect_all();
+    let ck = t.join().unwrap();
+    check(&cfg, &ck, "k", "ab");
+
+    cfg.end();
+}
+
+#[test]
+fn test_clerk_builder_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: clerks with their own retry policy (3A)");
+
+    let ms = Duration::from_millis;
+    let builder = Clerk::builder()
+        .rpc_timeout(ms(100))
+        .backoff(BackoffPolicy {
+            base: ms(1),
+            cap: ms(10),
+        })
+        .retry_limit(3);
+    let ck = cfg.make_client_with(&cfg.all(), builder);
+    put(&cfg, &ck, "k", "a");
+
+    // no server can be reached.
+    cfg.connect_client(&ck, &[]);
+    let t0 = Instant::now();
+    let r = ck.try_put("k".to_owned(), "b".to_owned());
+    assert!(
+        matches!(r, Err(Error::Unavailable { rounds: 3, .. })),
+        "{:?}",
+        r
+    );
+    // 3 rounds of RPC timeouts with short delays.
+    let elapsed = t0.elapsed();
+    assert!(elapsed < ms(1000), "gave up after {:?}", elapsed);
+
+    cfg.connect_client(&ck, &cfg.all());
+    ck.try_append("k".to_owned(), "c".to_owned()).unwrap();
+    check(&cfg, &ck, "k", "ac");
+
+    cfg.end();
+}
+
+#[test]
+fn test_clerk_retry_time_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: clerks give up on an unavailable service (3A)");
+
+    let retry_time = Duration::from_millis(1000);
+    let builder = Clerk::builder().retry_time(retry_time);
+    let ck = cfg.make_client_with(&cfg.all(), builder);
+    put(&cfg, &ck, "k", "a");
+
+    // the whole service is down.
+    cfg.connect_client(&ck, &[]);
+    let t0 = Instant::now();
+    match ck.try_append("k".to_owned(), "b".to_owned()) {
+        Err(Error::Unavailable {
+            tried, last_errors, ..
+        }) => {
+            assert_eq!(tried, NSERVERS);
+            assert_eq!(last_errors.len(), NSERVERS);
+        }
+        r => panic!("expected Unavailable, got {:?}", r),
+    }
+    let elapsed = t0.elapsed();
+    assert!(elapsed >= retry_time, "gave up after {:?}", elapsed);
+    assert!(elapsed < 4 * retry_time, "gave up after {:?}", elapsed);
+
+    // the budget is per call.
+    cfg.connect_client(&ck, &cfg.all());
+    ck.try_append("k".to_owned(), "c".to_owned()).unwrap();
+    check(&cfg, &ck, "k", "ac");
+
+    cfg.end();
+}
+
+#[test]
+fn test_clerk_resume_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: restarted clerks resume their session (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    append(&cfg, &ck, "k", "a");
+    // the clerk saves its session, sends an append and crashes before it
+    // learns the append was applied.
+    let saved = ck.session();
+    append(&cfg, &ck, "k", "b");
+    assert_eq!(ck.session().client_id, saved.client_id);
+    assert!(ck.session().next_seq > saved.next_seq);
+    cfg.delete_client(&ck);
+    drop(ck);
+
+    // the restarted clerk sends the append again, with the same sequence
+    // number.
+    let ck = cfg.make_client_with(&cfg.all(), Clerk::builder().resume(saved));
+    assert_eq!(ck.session(), saved);
+    append(&cfg, &ck, "k", "b");
+    check(&cfg, &ck, "k", "ab");
+    append(&cfg, &ck, "k", "c");
+    check(&cfg, &ck, "k", "abc");
+
+    // a new clerk is someone else.
+    let other = cfg.make_client(&cfg.all());
+    assert_ne!(other.session().client_id, saved.client_id);
+    append(&cfg, &other, "k", "b");
+    check(&cfg, &other, "k", "abcb");
+
+    cfg.end();
+}
+
+#[test]
+fn test_clerk_servers_change_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: clerks follow servers added and removed (3A)");
+
+    let ck0 = cfg.make_client(&cfg.all());
+    put(&cfg, &ck0, "k", "");
+    let leader = cfg.leader().unwrap();
+    let others: Vec<_> = cfg.all().into_iter().filter(|&i| i != leader).collect();
+
+    // the clerk only knows a follower, which can't write.
+    let ck = cfg.make_client_of(&others[..1]);
+    let r = ck
+        .with_timeout(Duration::from_secs(1))
+        .append("k".to_owned(), "a".to_owned());
+    assert_eq!(r, Err(Error::Timeout));
+    let at = cfg.add_client_server(&ck, leader);
+    append(&cfg, &ck, "k", "b");
+
+    // the leader leaves, the clerk goes on with the others.
+    for &i in &others[1..] {
+        cfg.add_client_server(&ck, i);
+    }
+    ck.remove_server(at);
+    cfg.shutdown_server(leader);
+    append(&cfg, &ck, "k", "c");
+    let v = ck.get("k".to_owned());
+    assert!(v == "bc" || v == "abc", "got {:?}", v);
+
+    cfg.end();
+}
+
+#[test]
+fn test_clerk_history_3a() {
+    const NSERVERS: usize = 5;
+    const NCLIENTS: usize = 5;
+    const NOPS: usize = 20;
+    let cfg = {
+        let cfg = Config::new(NSERVERS, true, None);
+        cfg.begin("Test: clerks record a linearizable history, unreliable (3A)");
+        Arc::new(cfg)
+    };
+
+    // no timing in the test body, the clerks record their calls.
+    let cfg_ = cfg.clone();
+    block_on(spawn_clients_and_wait(cfg.clone(), NCLIENTS, move || {
+        let cfg = cfg_.clone();
+        move |me, ck: &Clerk| {
+            let mut rng = rand::thread_rng();
+            for n in 0..NOPS {
+                let key = *["a", "b"].choose(&mut rng).unwrap();
+                let value = format!("x {} {} y", me, n);
+                match rng.gen_range(0, 4) {
+                    0 => {
+                        get(&cfg, ck, key);
+                    }
+                    1 => put(&cfg, ck, key, &value),
+                    2 => append(&cfg, ck, key, &value),
+                    _ => delete(&cfg, ck, key),
+                }
+            }
+        }
+    }));
+    cfg.check_history(LINEARIZABILITY_CHECK_TIMEOUT);
+
+    cfg.end();
+}
+
+#[test]
+fn test_seeded_network_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::with_seed(NSERVERS, true, None, 42);
+    assert_eq!(cfg.net.seed(), 42);
+
+    cfg.begin("Test: unreliable net drawn from a seed (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    for i in 0..10 {
+        append(&cfg, &ck, "k", &i.to_string());
+    }
+    check(&cfg, &ck, "k", "0123456789");
+
+    cfg.end();
+}
+
+#[test]
+fn test_unreliable_reordering_3a() {
+    const NSERVERS: usize = 5;
+    const NCLIENTS: usize = 5;
+    const UPTO: usize = 10;
+    let cfg = {
+        let cfg = Config::with_long_reordering(NSERVERS, None);
+        cfg.begin("Test: concurrent appends, long reordering (3A)");
+        Arc::new(cfg)
+    };
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "");
+    let cfg_ = cfg.clone();
+    block_on(spawn_clients_and_wait(cfg.clone(), NCLIENTS, move || {
+        let cfg = cfg_.clone();
+        move |me, ck: &Clerk| {
+            for n in 0..UPTO {
+                append(&cfg, ck, "k", &format!("x {} {} y", me, n));
+                get(&cfg, ck, "k");
+            }
+        }
+    }));
+    check_concurrent_appends(get(&cfg, &ck, "k"), &[UPTO; NCLIENTS]);
+    cfg.check_history(LINEARIZABILITY_CHECK_TIMEOUT);
+
+    cfg.end();
+}
+
+#[test]
+fn test_duplicate_rpcs_3a() {
+    const NSERVERS: usize = 3;
+    const NCLIENTS: usize = 5;
+    const UPTO: usize = 10;
+    let cfg = {
+        let cfg = Config::new(NSERVERS, false, None);
+        cfg.begin("Test: appends once with duplicated RPCs (3A)");
+        Arc::new(cfg)
+    };
+    // raft RPCs are duplicated too.
+    cfg.net.set_duplicate_rate(0.3);
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "");
+    let cfg_ = cfg.clone();
+    block_on(spawn_clients_and_wait(cfg.clone(), NCLIENTS, move || {
+        let cfg = cfg_.clone();
+        move |me, ck: &Clerk| {
+            for n in 0..UPTO {
+                append(&cfg, ck, "k", &format!("x {} {} y", me, n));
+            }
+        }
+    }));
+    check_concurrent_appends(get(&cfg, &ck, "k"), &[UPTO; NCLIENTS]);
+
+    cfg.end();
+}
+
+#[test]
+fn test_overloaded_servers_3a() {
+    const NSERVERS: usize = 3;
+    const NCLIENTS: usize = 5;
+    const UPTO: usize = 5;
+    let cfg = {
+        let cfg = Config::new(NSERVERS, false, None);
+        cfg.begin("Test: appends to overloaded servers (3A)");
+        Arc::new(cfg)
+    };
+
+    // restart the servers with room for two calls each.
+    cfg.limit_servers(2, labrpc::Overflow::Reject);
+    for i in cfg.all() {
+        cfg.shutdown_server(i);
+        cfg.start_server(i);
+    }
+    cfg.connect_all();
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "");
+    let cfg_ = cfg.clone();
+    block_on(spawn_clients_and_wait(cfg.clone(), NCLIENTS, move || {
+        let cfg = cfg_.clone();
+        move |me, ck: &Clerk| {
+            for n in 0..UPTO {
+                append(&cfg, ck, "k", &format!("x {} {} y", me, n));
+            }
+        }
+    }));
+    check_concurrent_appends(get(&cfg, &ck, "k"), &[UPTO; NCLIENTS]);
+
+    cfg.end();
+}
+
+#[test]
+fn test_network_script_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Arc::new(Config::new(NSERVERS, false, None));
+
+    cfg.begin("Test: progress under a fault schedule (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "");
+
+    let secs = Duration::from_secs;
+    let script = NetworkScript::new()
+        .at(secs(0), Event::Partition(vec![0, 1], vec![2, 3, 4]))
+        .at(secs(2), Event::Heal)
+        .at(
+            secs(2),
+            Event::DropRate {
+                from: 2,
+                to: 3,
+                rate: 0.3,
+            },
+        )
+        .at(secs(3), Event::Crash(4))
+        .at(secs(4), Event::Restart(4));
+    let run = cfg.run_script(script.clone());
+    let start = Instant::now();
+    let mut n = 0;
+    while start.elapsed() < script.duration() + secs(1) {
+        append(&cfg, &ck, "k", "x");
+        n += 1;
+    }
+    run.wait();
+    check(&cfg, &ck, "k", &"x".repeat(n));
+
+    cfg.end();
+}
+
+#[test]
+fn test_failed_calls_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: progress when the leader fails some calls (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "a");
+
+    let leader = cfg.leader().unwrap();
+    let failed = cfg.fail_calls(leader, "kv.put_append", 3);
+    append(&cfg, &ck, "k", "b");
+    check(&cfg, &ck, "k", "ab");
+    assert_eq!(failed.load(Ordering::SeqCst), 3);
+
+    cfg.net.clear_interceptors();
+    cfg.end();
+}
+
+#[test]
+fn test_one_way_partition_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: progress when the leader can't send (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "a");
+
+    // the leader still hears the others, and answers nobody.
+    let leader = cfg.leader().unwrap();
+    let others: Vec<_> = cfg.all().into_iter().filter(|&i| i != leader).collect();
+    cfg.partition_one_way(&[leader], &others);
+    append(&cfg, &ck, "k", "b");
+    check(&cfg, &ck, "k", "ab");
+
+    cfg.connect_all();
+    append(&cfg, &ck, "k", "c");
+    check(&cfg, &ck, "k", "abc");
+
+    cfg.end();
+}
+
+#[test]
+fn test_clerk_metrics_3a() {
+    const NSERVERS: usize = 5;
+    const NOPS: u64 = 10;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: clerks measure their calls (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    for i in 0..NOPS {
+        put(&cfg, &ck, "k", &i.to_string());
+        check(&cfg, &ck, "k", &i.to_string());
+    }
+    let metrics = ck.metrics();
+    assert_eq!(metrics.latency["Put"].count(), NOPS);
+    assert_eq!(metrics.latency["Get"].count(), NOPS);
+    assert_eq!(metrics.calls(), 2 * NOPS);
+
+    // the leader the clerk knows is replaced while it's cut off, and is a
+    // follower when the clerk writes again.
+    let leader = cfg.leader().unwrap();
+    let others: Vec<_> = cfg.all().into_iter().filter(|&i| i != leader).collect();
+    cfg.partition(&others, &[leader]);
+    let ck2 = cfg.make_client(&others);
+    put(&cfg, &ck2, "k", "x");
+    cfg.connect_all();
+    thread::sleep(RAFT_ELECTION_TIMEOUT);
+    put(&cfg, &ck, "k", "y");
+
+    let after = ck.metrics();
+    assert_eq!(after.latency["Put"].count(), NOPS + 1);
+    assert!(after.redirects >= 1, "{:?}", after);
+    assert!(after.retries >= after.redirects, "{:?}", after);
+    assert_eq!(ck2.metrics().latency["Put"].count(), 1);
+
+    cfg.end();
+}
+
+#[test]
+fn test_hedged_reads_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: hedged gets get past a partitioned leader (3A)");
+
+    let builder = Clerk::builder()
+        .rpc_timeout(Duration::from_secs(3))
+        .hedge_after(Duration::from_millis(50));
+    let ck = cfg.make_client_with(&cfg.all(), builder);
+    put(&cfg, &ck, "k", "a");
+    check(&cfg, &ck, "k", "a");
+
+    // the leader the clerk knows can't reach anyone but the clerk.
+    let leader = cfg.leader().unwrap();
+    let others: Vec<_> = cfg.all().into_iter().filter(|&i| i != leader).collect();
+    cfg.partition(&others, &[leader]);
+    let ck2 = cfg.make_client(&others);
+    put(&cfg, &ck2, "k", "b");
+
+    for _ in 0..5 {
+        let t0 = Instant::now();
+        check(&cfg, &ck, "k", "b");
+        let elapsed = t0.elapsed();
+        assert!(
+            elapsed < Duration::from_millis(1500),
+            "get waited {:?} for the partitioned leader",
+            elapsed
+        );
+    }
+
+    cfg.end();
+}
+
+#[test]
+fn test_clerk_deadline_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: clerk calls give up at their deadline (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "a");
+    let timeout = Duration::from_millis(500);
+    assert_eq!(ck.with_timeout(timeout).get("k".to_owned()).unwrap(), "a");
+
+    // no server can be reached.
+    cfg.connect_client(&ck, &[]);
+    let t0 = Instant::now();
+    let r = ck.with_timeout(timeout).put("k".to_owned(), "b".to_owned());
+    assert_eq!(r, Err(Error::Timeout));
+    assert_eq!(
+        ck.with_timeout(timeout).get("k".to_owned()),
+        Err(Error::Timeout)
+    );
+    let elapsed = t0.elapsed();
+    assert!(elapsed < 3 * timeout, "calls took {:?} to give up", elapsed);
+    let past = ck.with_deadline(Instant::now());
+    assert_eq!(past.delete("k".to_owned()), Err(Error::Timeout));
+
+    // the clerk goes on after giving up, none of the writes reached a
+    // server.
+    cfg.connect_client(&ck, &cfg.all());
+    ck.with_timeout(Duration::from_secs(10))
+        .append("k".to_owned(), "c".to_owned())
+        .unwrap();
+    check(&cfg, &ck, "k", "ac");
+
+    cfg.end();
+}
+
+#[test]
+fn test_cancel_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: cancelled calls are applied once at most (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "a");
+
+    // the clerk only reaches the leader, cut off from the others.
+    let leader = cfg.leader().unwrap();
+    let others: Vec<_> = cfg.all().into_iter().filter(|&i| i != leader).collect();
+    cfg.partition(&others, &[leader]);
+    let ck_old = Arc::new(cfg.make_client(&[leader]));
+
+    let token = CancelToken::new();
+    let (ck, t) = (ck_old.clone(), token.clone());
+    let call = thread::spawn(move || ck.with_cancel(&t).append("k".to_owned(), "b".to_owned()));
+    thread::sleep(Duration::from_millis(500));
+    let t0 = Instant::now();
+    token.cancel();
+    assert_eq!(call.join().unwrap(), Err(Error::Cancelled));
+    assert!(t0.elapsed() < Duration::from_millis(500));
+    let r = ck_old.with_cancel(&token).get("k".to_owned());
+    assert_eq!(r, Err(Error::Cancelled));
+
+    cfg.connect_all();
+    cfg.connect_client(&ck_old, &cfg.all());
+    append(&cfg, &ck_old, "k", "c");
+    let v = ck_old.get("k".to_owned());
+    assert!(v == "ac" || v == "abc", "got {:?}", v);
+
+    thread::sleep(RAFT_ELECTION_TIMEOUT);
+    let leader = cfg.leader().unwrap();
+    let mut requests: Vec<_> = cfg
+        .audit_log(leader)
+        .unwrap()
+        .into_iter()
+        .filter(|r| r.op != "Get")
+        .map(|r| (r.client, r.seq))
+        .collect();
+    let n = requests.len();
+    requests.sort_unstable();
+    requests.dedup();
+    assert_eq!(requests.len(), n, "an op was applied twice");
+
+    cfg.end();
+}
+
+#[test]
+fn test_put_many_3a() {
+    const NSERVERS: usize = 3;
+    const NKEYS: usize = 100;
+    let cfg = Config::new(NSERVERS, true, None);
+
+    cfg.begin("Test: bulk puts in one request (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    let pairs = |value: &str| -> Vec<_> {
+        (0..NKEYS)
+            .map(|i| (format!("k{:03}", i), value.to_owned()))
+            .collect()
+    };
+    ck.put_many(pairs("a"));
+    // the network drops replies, retries must not append twice.
+    ck.append_many(pairs("b"));
+    ck.put_many(vec![]);
+    for i in (0..NKEYS).step_by(10) {
+        check(&cfg, &ck, &format!("k{:03}", i), "ab");
+    }
+
+    // each was a single op.
+    thread::sleep(RAFT_ELECTION_TIMEOUT);
+    let leader = cfg.leader().unwrap();
+    let writes = cfg
+        .audit_log(leader)
+        .unwrap()
+        .into_iter()
+        .filter(|r| r.op != "Get")
+        .count();
+    assert_eq!(writes, 2);
+
+    cfg.end();
+}
+
+#[test]
+fn test_delete_if_and_range_3a() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, true, None);
+
+    cfg.begin("Test: conditional and range deletes (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "lock", "a");
+    assert!(!ck.delete_if("lock".to_owned(), "b".to_owned()));
+    check(&cfg, &ck, "lock", "a");
+    assert!(ck.delete_if("lock".to_owned(), "a".to_owned()));
+    check(&cfg, &ck, "lock", "");
+    assert!(!ck.delete_if("lock".to_owned(), "a".to_owned()));
+    assert!(!ck.delete_if("lock".to_owned(), "".to_owned()));
+
+    for i in 0..20 {
+        put(&cfg, &ck, &format!("tmp/{:02}", i), "x");
+    }
+    put(&cfg, &ck, "tmq", "y");
+    // the network drops replies, retries must not remove more.
+    assert_eq!(ck.delete_range("tmp/05".to_owned(), "tmp/10".to_owned()), 5);
+    assert_eq!(ck.delete_range("tmp/".to_owned(), "tmp0".to_owned()), 15);
+    assert_eq!(ck.delete_range("tmp/".to_owned(), "tmp0".to_owned()), 0);
+    check(&cfg, &ck, "tmp/00", "");
+    check(&cfg, &ck, "tmq", "y");
+    assert_eq!(ck.delete_range("".to_owned(), "".to_owned()), 1);
+    check(&cfg, &ck, "tmq", "");
+
+    cfg.end();
+}
+
+// A leader cut off from the majority must stop serving Gets before the
+// majority elects another leader and writes, with leases or without.
+fn generic_test_lease_reads(leases: bool) {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+    if !leases {
+        cfg.set_leases(false);
+        for i in cfg.all() {
+            cfg.shutdown_server(i);
+            cfg.start_server(i);
+        }
+        cfg.connect_all();
+    }
+
+    let title = if leases { "with" } else { "without" };
+    cfg.begin(&format!(
+        "Test: Gets are linearizable {} leases (3A)",
+        title
+    ));
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "1");
+    let leader = cfg.leader().unwrap();
+    let others: Vec<_> = cfg.all().into_iter().filter(|&i| i != leader).collect();
+    let (minority, majority) = (vec![leader, others[0]], others[1..].to_vec());
+    cfg.partition(&majority, &minority);
+
+    let ck_old = cfg.make_client(&minority);
+    let ck_new = cfg.make_client(&majority);
+    put(&cfg, &ck_new, "k", "2");
+
+    // the write is done, the old leader can't serve a Get of the value
+    // before it any more.
+    let (tx, rx) = mpsc::channel();
+    let ck_old = Arc::new(ck_old);
+    let ck = ck_old.clone();
+    let t = thread::spawn(move || tx.send(ck.get("k".to_owned())).unwrap());
+    if let Ok(v) = rx.recv_timeout(Duration::from_secs(1)) {
+        panic!("Get in the minority partition returned {:?}", v);
+    }
+
+    cfg.connect_all();
+    cfg.connect_client(&ck_old, &cfg.all());
+    let v = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(v, "2");
+    t.join().unwrap();
+
+    cfg.end();
+}
+
+#[test]
+fn test_lease_reads_3a() {
+    generic_test_lease_reads(true);
+}
+
+#[test]
+fn test_no_lease_reads_3a() {
+    generic_test_lease_reads(false);
+}
+
+#[test]
+fn test_read_consistency_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: Gets as fresh as their clerk asks (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "k", "1");
+    for consistency in &[ReadConsistency::Linearizable, ReadConsistency::LeaseRead] {
+        assert_eq!(ck.get_with("k".to_owned(), *consistency), "1");
+    }
+
+    let leader = cfg.leader().unwrap();
+    let others: Vec<_> = cfg.all().into_iter().filter(|&i| i != leader).collect();
+    let (minority, majority) = (vec![leader, others[0]], others[1..].to_vec());
+    cfg.partition(&majority, &minority);
+    let ck_old = cfg.make_client(&minority);
+    let ck_new = cfg.make_client(&majority);
+    put(&cfg, &ck_new, "k", "2");
+
+    // a stale read is served in the minority, with what it applied.
+    let t0 = Instant::now();
+    let v = ck_old.get_with("k".to_owned(), ReadConsistency::Stale);
+    assert!(v == "1" || v.is_empty(), "stale read got {:?}", v);
+    assert!(t0.elapsed() < Duration::from_secs(1));
+
+    // a linearizable one isn't.
+    let (tx, rx) = mpsc::channel();
+    let ck_old = Arc::new(ck_old);
+    let ck = ck_old.clone();
+    let t = thread::spawn(move || {
+        let v = ck.get_with("k".to_owned(), ReadConsistency::Linearizable);
+        tx.send(v).unwrap();
+    });
+    if let Ok(v) = rx.recv_timeout(Duration::from_secs(1)) {
+        panic!("linearizable Get in the minority returned {:?}", v);
+    }
+
+    cfg.connect_all();
+    cfg.connect_client(&ck_old, &cfg.all());
+    let v = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(v, "2");
+    t.join().unwrap();
+
+    cfg.end();
+}
+
+// Submit a request in the minority partition and check that the requests
+// doesn't go through until the partition heals. The leader in the original
+// network ends up in the minority partition.
+#[test]
+fn test_one_partition_3a() {
+    let nservers = 5;
+    let cfg = Config::new(nservers, false, None);
+
+    let all = cfg.all();
+    let ck = cfg.make_client(&all);
+
+    put(&cfg, &ck, "1", "13");
+
+    cfg.begin("Test: progress in majority (3A)");
+
+    let (p1, p2) = cfg.make_partition();
+    cfg.partition(&p1, &p2);
+
+    // connect ckp1 to p1
+    let ckp1 = cfg.make_client(&p1);
+    // connect ckp2a to p2
+    let ckp2a = cfg.make_client(&p2);
+    let ckp2a_name = ckp2a.name.clone();
+    // connect ckp2b to p2
+    let ckp2b = cfg.make_client(&p2);
+    let ckp2b_name = ckp2b.name.clone();
+
+    put(&cfg, &ckp1, "1", "14");
+    check(&cfg, &ckp1, "1", "14");
+
+    cfg.end();
+
+    let (done0_tx, done0_rx) = oneshot::channel::<&'static str>();
+    let (done1_tx, done1_rx) = oneshot::channel::<&'static str>();
+
+    cfg.begin("Test: no progress in minority (3A)");
+    cfg.net.spawn(future::lazy(move |_| {
+        ckp2a.put("1".to_owned(), "15".to_owned());
+        done0_tx
+            .send("put")
+            .map_err(|e| {
+                warn!("done0 send failed: {:?}", e);
+            })
+            .unwrap();
+    }));
+    let done0_rx = done0_rx.map(|op| {
+        cfg.op();
+        op
+    });
+
+    cfg.net.spawn(future::lazy(move |_| {
+        // different clerk in p2
+        ckp2b.get("1".to_owned());
+        done1_tx
+            .send("get")
+            .map_err(|e| {
+                warn!("done0 send failed: {:?}", e);
+            })
+            .unwrap();
+    }));
+    let done1_rx = done1_rx.map(|op| {
+        cfg.op();
+        op
+    });
+
+    let timeout = Delay::new(Duration::from_secs(1));
+
+    let dones = block_on(
+        future::select(timeout, future::select(done0_rx, done1_rx)).map(|res| match res {
+            future::Either::Left((_, dones)) => dones,
+            future::Either::Right((future::Either::Left((op, _)), _)) => {
+                panic!("{} in minority completed", op.unwrap())
+            }
+            future::Either::Right((future::Either::Right((op, _)), _)) => {
+                panic!("{} in minority completed", op.unwrap())
+            }
+        }),
+    );
+
+    check(&cfg, &ckp1, "1", "14");
+    put(&cfg, &ckp1, "1", "16");
+    check(&cfg, &ckp1, "1", "16");
+
+    cfg.end();
+
+    cfg.begin("Test: completion after heal (3A)");
+
+    cfg.connect_all();
+    cfg.connect_client_by_name(&ckp2a_name, &all);
+    cfg.connect_client_by_name(&ckp2b_name, &all);
+
+    thread::sleep(RAFT_ELECTION_TIMEOUT);
+
+    let timeout = Delay::new(Duration::from_secs(3));
+    let (timeout, next) = block_on(async {
+        future::select(timeout, dones)
+            .map(|res| match res {
+                future::Either::Left(_) => panic!("put/get did not complete"),
+                future::Either::Right((future::Either::Left((op, next)), timeout)) => {
+                    info!("{} completes", op.unwrap());
+                    (timeout, future::Either::Left(next))
+                }
+                future::Either::Right((future::Either::Right((op, next)), timeout)) => {
+                    info!("{} completes", op.unwrap());
+                    (timeout, future::Either::Right(next))
+                }
+            })
+            .await
+    });
+
+    block_on(async {
+        future::select(timeout, next)
+            .map(|res| match res {
+                future::Either::Left(_) => panic!("put/get did not complete"),
+                future::Either::Right((op, _)) => info!("{} completes", op.unwrap()),
+            })
+            .await
+    });
+
+    check(&cfg, &ck, "1", "15");
+
+    cfg.end();
+}
+
+#[test]
+fn test_many_partitions_one_client_3a() {
+    // Test: partitions, one client (3A) ...
+    generic_test("3A", 1, false, false, true, None)
+}
+
+#[test]
+fn test_many_partitions_many_clients_3a() {
+    // Test: partitions, many clients (3A) ...
+    generic_test("3A", 5, false, false, true, None)
+}
+
+#[test]
+fn test_persist_one_client_3a() {
+    // Test: restarts, one client (3A) ...
+    generic_test("3A", 1, false, true, false, None)
+}
+
+#[test]
+fn test_persist_concurrent_3a() {
+    // Test: restarts, many clients (3A) ...
+    generic_test("3A", 5, false, true, false, None)
+}
+
+#[test]
+fn test_persist_concurrent_unreliable_3a() {
+    // Test: unreliable net, restarts, many clients (3A) ...
+    generic_test("3A", 5, true, true, false, None)
+}
+
+#[test]
+fn test_persist_partition_3a() {
+    // Test: restarts, partitions, many clients (3A) ...
+    generic_test("3A", 5, false, true, true, None)
+}
+
+#[test]
+fn test_persist_partition_unreliable_3a() {
+    // Test: unreliable net, restarts, partitions, many clients (3A) ...
+    generic_test("3A", 5, true, true, true, None)
+}
+
+#[test]
+fn test_persist_partition_unreliable_linearizable_3a() {
+    // Test: unreliable net, restarts, partitions, linearizability checks (3A) ...
+    generic_test_linearizability("3A", 15, 7, true, true, true, None)
+}
+
+// servers whose saves fail must not acknowledge what they failed to save,
+// the appends they acknowledged have to survive a restart of everyone.
+#[test]
+fn test_persist_faults_3a() {
+    const NSERVERS: usize = 5;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: failed and torn saves, restarts (3A)");
+
+    let ck = cfg.make_client(&cfg.all());
+    let mut rng = rand::thread_rng();
+    let mut acked = String::new();
+    put(&cfg, &ck, "k", &acked);
+    for round in 0..5 {
+        for i in 0..NSERVERS {
+            cfg.set_persist_faults(
+                i,
+                Faults {
+                    fail_nth: Some(rng.gen_range(1, 10)),
+                    torn: round % 2 == 1,
+                    delay: Duration::from_millis(rng.gen_range(0, 5)),
+                },
+            );
+        }
+        for j in 0..10 {
+            let v = format!("x {} {} y", round, j);
+            append(&cfg, &ck, "k", &v);
+            acked += &v;
+        }
+        let failed: u64 = (0..NSERVERS).map(|i| cfg.failed_saves(i)).sum();
+        debug!("round {}: {} saves failed", round, failed);
+
+        for i in 0..NSERVERS {
+            cfg.shutdown_server(i);
+        }
+        for i in 0..NSERVERS {
+            cfg.start_server(i);
+        }
+        cfg.connect_all();
+        check(&cfg, &ck, "k", &acked);
+    }
+
+    cfg.end();
+}
+
+// if one server falls behind, then rejoins, does it
+// recover by using the InstallSnapshot RPC?
+// also checks that majority discards committed log entries
+// even if minority doesn't respond.
+#[test]
+fn test_snapshot_rpc_3b() {
+    let nservers = 3;
+    let maxraftstate = 1000;
+    let cfg = Config::new(nservers, false, Some(maxraftstate));
+
+    let all = cfg.all();
+    let ck = cfg.make_client(&all);
+
+    cfg.begin("Test: InstallSnapshot RPC (3B)");
+
+    put(&cfg, &ck, "a", "A");
+    check(&cfg, &ck, "a", "A");
 
     // a bunch of puts into the majority partition.
     cfg.partition(&[0, 1], &[2]);
@@ -846,6 +2715,81 @@ fn test_snapshot_rpc_3b() {
     cfg.end();
 }
 
+// a lagging server catches up on a snapshot sent over a network that splits
+// large messages, and loses some of the pieces.
+#[test]
+fn test_snapshot_fragments_3b() {
+    let nservers = 3;
+    let maxraftstate = 1000;
+    let cfg = Config::new(nservers, false, Some(maxraftstate));
+    cfg.net.set_max_message_size(Some(256));
+    cfg.net.set_fragmentation(Some(0.02));
+
+    let ck = cfg.make_client(&cfg.all());
+
+    cfg.begin("Test: InstallSnapshot RPC over a fragmenting network (3B)");
+
+    cfg.partition(&[0, 1], &[2]);
+    {
+        let ck1 = cfg.make_client(&[0, 1]);
+        for i in 0..50 {
+            put(&cfg, &ck1, &format!("{}", i), &format!("{}", i));
+        }
+    }
+
+    // 2 has to catch up on a snapshot larger than a message.
+    cfg.partition(&[0, 2], &[1]);
+    {
+        let ck1 = cfg.make_client(&[0, 2]);
+        put(&cfg, &ck1, "c", "C");
+        check(&cfg, &ck1, "1", "1");
+        check(&cfg, &ck1, "49", "49");
+    }
+
+    cfg.connect_all();
+    check(&cfg, &ck, "c", "C");
+
+    cfg.check_timeout();
+    cfg.end();
+}
+
+// the majority goes on while a lagging server never gets the snapshot it
+// needs, and the lagging server catches up once it does.
+#[test]
+fn test_snapshots_lost_3b() {
+    let nservers = 3;
+    let maxraftstate = 1000;
+    let cfg = Config::new(nservers, false, Some(maxraftstate));
+    let ck = cfg.make_client(&cfg.all());
+
+    cfg.begin("Test: progress while InstallSnapshot RPCs are lost (3B)");
+
+    cfg.net.drop_method("raft.install_snapshot", 1.0);
+    cfg.partition(&[0, 1], &[2]);
+    {
+        let ck1 = cfg.make_client(&[0, 1]);
+        for i in 0..50 {
+            put(&cfg, &ck1, &format!("{}", i), &format!("{}", i));
+        }
+    }
+    cfg.connect_all();
+    put(&cfg, &ck, "a", "A");
+    check(&cfg, &ck, "49", "49");
+
+    // now 2 is needed.
+    cfg.net.drop_method("raft.install_snapshot", 0.0);
+    cfg.partition(&[0, 2], &[1]);
+    {
+        let ck1 = cfg.make_client(&[0, 2]);
+        put(&cfg, &ck1, "b", "B");
+        check(&cfg, &ck1, "a", "A");
+        check(&cfg, &ck1, "1", "1");
+    }
+
+    cfg.check_timeout();
+    cfg.end();
+}
+
 // are the snapshots not too huge? 500 bytes is a generous bound for the
 // operations we're doing here.
 #[test]
@@ -889,6 +2833,38 @@ fn test_snapshot_size_3b() {
     cfg.end();
 }
 
+#[test]
+fn test_snapshot_prefix_compression_3b() {
+    const NSERVERS: usize = 3;
+    const NKEYS: usize = 100;
+    let maxraftstate = 1000;
+    let cfg = Config::new(NSERVERS, false, Some(maxraftstate));
+
+    cfg.begin("Test: snapshots compress shared key prefixes (3B)");
+
+    let ck = cfg.make_client(&cfg.all());
+    let key = |i| format!("customers/region-eu/account-{:04}", i);
+    for i in 0..NKEYS {
+        put(&cfg, &ck, &key(i), "1");
+    }
+    for i in 0..NKEYS {
+        check(&cfg, &ck, &key(i), "1");
+    }
+
+    // written in full, the keys alone would take this much.
+    let naive = NKEYS * key(0).len();
+    let size = cfg.snapshot_size();
+    assert!(size > 0, "no snapshot taken");
+    assert!(
+        size < naive / 2,
+        "snapshot too large ({} bytes, the keys are {} bytes)",
+        size,
+        naive
+    );
+
+    cfg.end();
+}
+
 #[test]
 fn test_snapshot_recover_3b() {
     // Test: restarts, snapshots, one client (3B) ...
@@ -924,3 +2900,168 @@ fn test_snapshot_unreliable_recover_concurrent_partition_linearizable_3b() {
     // Test: unreliable net, restarts, partitions, snapshots, linearizability checks (3B) ...
     generic_test_linearizability("3B", 15, 7, true, true, true, Some(1000))
 }
+
+#[test]
+fn test_ttl_3b() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::new(NSERVERS, false, Some(1000));
+
+    cfg.begin("Test: keys expire, restarts, snapshots (3B)");
+
+    let ck = cfg.make_client(&cfg.all());
+    let ttl = Duration::from_secs(2);
+    ck.put_with_ttl("short".to_owned(), "x".to_owned(), ttl);
+    ck.put_with_ttl("long".to_owned(), "y".to_owned(), ttl * 100);
+    ck.put_with_ttl("rewritten".to_owned(), "z".to_owned(), ttl);
+    put(&cfg, &ck, "rewritten", "w");
+    check(&cfg, &ck, "short", "x");
+
+    // snapshot on the way.
+    let start = Instant::now();
+    while start.elapsed() < ttl + Duration::from_millis(500) {
+        put(&cfg, &ck, "filler", &"f".repeat(100));
+    }
+    check(&cfg, &ck, "short", "");
+    check(&cfg, &ck, "long", "y");
+    check(&cfg, &ck, "rewritten", "w");
+
+    // every replica agrees after a restart.
+    for i in 0..NSERVERS {
+        cfg.shutdown_server(i);
+    }
+    for i in 0..NSERVERS {
+        cfg.start_server(i);
+    }
+    cfg.connect_all();
+    check(&cfg, &ck, "short", "");
+    check(&cfg, &ck, "long", "y");
+
+    cfg.end();
+}
+
+// snapshots taken every so many ops, however small the state is.
+#[test]
+fn test_snapshot_every_ops_3b() {
+    const NSERVERS: usize = 3;
+    let cfg = Config::with_snapshot_policy(
+        NSERVERS,
+        false,
+        SnapshotPolicy {
+            every_ops: Some(10),
+            ..SnapshotPolicy::default()
+        },
+    );
+
+    cfg.begin("Test: snapshots every 10 ops (3B)");
+
+    let ck = cfg.make_client(&cfg.all());
+    put(&cfg, &ck, "x", "0");
+    let log_size = cfg.log_size();
+    for i in 0..100 {
+        append(&cfg, &ck, "x", &(i % 10).to_string());
+    }
+    assert!(cfg.snapshot_size() > 0, "no snapshot taken");
+    // the log of 10 ops is about as big as the first one.
+    assert!(
+        cfg.log_size() < 20 * log_size,
+        "logs were not trimmed ({} bytes for 10 ops, {} bytes for 1)",
+        cfg.log_size(),
+        log_size
+    );
+
+    for i in 0..NSERVERS {
+        cfg.shutdown_server(i);
+        cfg.start_server(i);
+    }
+    cfg.connect_all();
+    check(&cfg, &ck, "x", &format!("0{}", "0123456789".repeat(10)));
+
+    cfg.end();
+}
+
+#[test]
+fn test_stats_3b() {
+    const NSERVERS: usize = 3;
+    const NKEYS: u64 = 20;
+    let maxraftstate = 1000;
+    let cfg = Config::new(NSERVERS, false, Some(maxraftstate));
+
+    cfg.begin("Test: servers report their stats (3B)");
+
+    let ck = cfg.make_client(&cfg.all());
+    for i in 0..NKEYS {
+        put(&cfg, &ck, &format!("k{:02}", i), "0123456789");
+    }
+    for _ in 0..100 {
+        put(&cfg, &ck, "k00", "0123456789");
+    }
+    check(&cfg, &ck, "k00", "0123456789");
+
+    // followers may still be applying the last entries.
+    thread::sleep(RAFT_ELECTION_TIMEOUT);
+    let stats: Vec<_> = cfg
+        .all()
+        .into_iter()
+        .map(|i| cfg.kv_stats(i).unwrap())
+        .collect();
+    for s in &stats {
+        assert_eq!(s.keys, NKEYS);
+        assert_eq!(s.state_bytes, NKEYS * 13);
+        assert_eq!(s.applied_index, stats[0].applied_index);
+        assert!(s.snapshots > 0, "no snapshot taken");
+        assert_eq!(s.pending, 0);
+        assert_eq!(s.sessions, 1);
+    }
+
+    cfg.end();
+}
+
+#[test]
+fn test_set_snapshot_policy_3b() {
+    const NSERVERS: usize = 3;
+    let maxraftstate = 1000;
+    let cfg = Config::new(NSERVERS, false, None);
+
+    cfg.begin("Test: snapshot threshold changed on live servers (3B)");
+
+    let ck = cfg.make_client(&cfg.all());
+    for i in 0..50 {
+        put(&cfg, &ck, "x", &i.to_string());
+    }
+    assert_eq!(cfg.snapshot_size(), 0);
+    let untrimmed = cfg.log_size();
+    assert!(untrimmed > maxraftstate);
+
+    ck.set_snapshot_policy(Some(maxraftstate).into());
+    for i in 0..50 {
+        put(&cfg, &ck, "x", &i.to_string());
+    }
+    assert!(cfg.snapshot_size() > 0, "no snapshot taken");
+    if cfg.log_size() > 2 * maxraftstate {
+        panic!(
+            "logs were not trimmed ({} > 2*{})",
+            cfg.log_size(),
+            maxraftstate
+        );
+    }
+
+    // the policy survives restarts, though the servers start with none.
+    for i in 0..NSERVERS {
+        cfg.shutdown_server(i);
+        cfg.start_server(i);
+    }
+    cfg.connect_all();
+    for i in 0..100 {
+        put(&cfg, &ck, "x", &i.to_string());
+    }
+    if cfg.log_size() > 2 * maxraftstate {
+        panic!(
+            "logs were not trimmed after a restart ({} > 2*{})",
+            cfg.log_size(),
+            maxraftstate
+        );
+    }
+    check(&cfg, &ck, "x", "99");
+
+    cfg.end();
+}
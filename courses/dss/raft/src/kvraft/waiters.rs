@@ -0,0 +1,155 @@
+//! RPC handlers waiting for their ops to be applied.
+//!
+//! A handler proposes an op with `raft::Node::start`, registers for the index
+//! and term it got and awaits the receiver. The apply loop replies when it
+//! applies the entry at that index: with the result if the entry is from the
+//! same term, so it's the op of the handler, or with `Error::NoLeader` if a
+//! later leader overwrote it.
+//!
+//! Every request carries a deadline. An op not applied by then is abandoned:
+//! `expire`, called every so often, drops its registration and replies
+//! `Error::Timeout`, so a handler doesn't wait longer than its client does.
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use futures::channel::oneshot;
+
+use crate::kvraft::errors::{Error, Result};
+
+struct Waiter<T> {
+    term: u64,
+    deadline: Instant,
+    reply: oneshot::Sender<Result<T>>,
+}
+
+/// Handlers by the index of their entry.
+pub struct Waiters<T> {
+    waiters: BTreeMap<u64, Waiter<T>>,
+}
+
+impl<T> Default for Waiters<T> {
+    fn default() -> Waiters<T> {
+        Waiters {
+            waiters: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> Waiters<T> {
+    pub fn new() -> Waiters<T> {
+        Waiters::default()
+    }
+
+    /// Registers for the entry at `index` proposed in `term`, until
+    /// `deadline`. A handler registered for the index before, in an older
+    /// term, is replied `Error::NoLeader`.
+    pub fn register(
+        &mut self,
+        index: u64,
+        term: u64,
+        deadline: Instant,
+    ) -> oneshot::Receiver<Result<T>> {
+        let (reply, rx) = oneshot::channel();
+        let waiter = Waiter {
+            term,
+            deadline,
+            reply,
+        };
+        if let Some(old) = self.waiters.insert(index, waiter) {
+            let _ = old.reply.send(Err(Error::NoLeader));
+        }
+        rx
+    }
+
+    /// The entry at `index` of `term` was applied with `result`. Replies to
+    /// its handler, and `Error::NoLeader` to the handlers of entries up to
+    /// it that were overwritten.
+    pub fn applied(&mut self, index: u64, term: u64, result: T) {
+        let own = self.waiters.remove(&index);
+        let later = self.waiters.split_off(&index);
+        // the handler may have given up already, so sending may fail.
+        for (_, waiter) in std::mem::replace(&mut self.waiters, later) {
+            let _ = waiter.reply.send(Err(Error::NoLeader));
+        }
+        if let Some(waiter) = own {
+            let reply = if waiter.term == term {
+                Ok(result)
+            } else {
+                Err(Error::NoLeader)
+            };
+            let _ = waiter.reply.send(reply);
+        }
+    }
+
+    /// Abandons the handlers whose deadline passed at `now`, replying
+    /// `Error::Timeout`. Returns how many.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let expired: Vec<_> = self
+            .waiters
+            .iter()
+            .filter(|(_, w)| w.deadline <= now)
+            .map(|(&i, _)| i)
+            .collect();
+        for i in &expired {
+            let waiter = self.waiters.remove(i).unwrap();
+            let _ = waiter.reply.send(Err(Error::Timeout));
+        }
+        expired.len()
+    }
+
+    /// The earliest deadline of the handlers, when to `expire` next.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.waiters.values().map(|w| w.deadline).min()
+    }
+
+    /// Replies `err` to every handler, e.g. `Error::NoLeader` once the
+    /// server lost leadership.
+    pub fn fail_all(&mut self, err: Error) {
+        for (_, waiter) in std::mem::take(&mut self.waiters) {
+            let _ = waiter.reply.send(Err(err.clone()));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_waiters() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        let mut w = Waiters::new();
+        let mut a = w.register(1, 1, later);
+        let mut b = w.register(2, 1, later);
+        let mut c = w.register(3, 1, now);
+        let mut d = w.register(4, 1, later);
+        assert_eq!(w.next_deadline(), Some(now));
+
+        assert_eq!(w.expire(now), 1);
+        assert_eq!(c.try_recv().unwrap(), Some(Err(Error::Timeout)));
+        // entry 1 wasn't applied, e.g. it's covered by a snapshot.
+        w.applied(2, 1, "b");
+        assert_eq!(a.try_recv().unwrap(), Some(Err(Error::NoLeader)));
+        assert_eq!(b.try_recv().unwrap(), Some(Ok("b")));
+        assert_eq!(d.try_recv().unwrap(), None);
+        assert_eq!(w.len(), 1);
+
+        let mut e = w.register(4, 2, later);
+        assert_eq!(d.try_recv().unwrap(), Some(Err(Error::NoLeader)));
+        w.fail_all(Error::NoLeader);
+        assert_eq!(e.try_recv().unwrap(), Some(Err(Error::NoLeader)));
+        assert!(w.is_empty());
+    }
+}
@@ -4,6 +4,10 @@ pub mod raftpb {
     labrpc::service! {
         service raft {
             rpc request_vote(RequestVoteArgs) returns (RequestVoteReply);
+            rpc append_entries(AppendEntriesArgs) returns (AppendEntriesReply);
+            rpc install_snapshot(InstallSnapshotArgs) returns (InstallSnapshotReply);
+            rpc read_index(ReadIndexArgs) returns (ReadIndexReply);
+            rpc timeout_now(TimeoutNowArgs) returns (TimeoutNowReply);
 
             // Your code here if more rpc desired.
             // rpc xxx(yyy) returns (zzz)
@@ -21,6 +25,20 @@ pub mod kvraftpb {
         service kv {
             rpc get(GetRequest) returns (GetReply);
             rpc put_append(PutAppendRequest) returns (PutAppendReply);
+            rpc scan(ScanRequest) returns (ScanReply);
+            rpc write_batch(WriteBatchRequest) returns (WriteBatchReply);
+            rpc watch(WatchRequest) returns (WatchReply);
+            rpc incr(IncrRequest) returns (IncrReply);
+            rpc compact(CompactRequest) returns (CompactReply);
+            rpc export(ExportRequest) returns (ExportReply);
+            rpc import(ImportRequest) returns (ImportReply);
+            rpc delete_namespace(DeleteNamespaceRequest) returns (DeleteNamespaceReply);
+            rpc namespace_stats(NamespaceStatsRequest) returns (NamespaceStatsReply);
+            rpc stats(StatsRequest) returns (StatsReply);
+            rpc set_snapshot_policy(SetSnapshotPolicyRequest) returns (SetSnapshotPolicyReply);
+            rpc create_index(CreateIndexRequest) returns (CreateIndexReply);
+            rpc scan_index(ScanIndexRequest) returns (ScanIndexReply);
+            rpc audit(AuditRequest) returns (AuditReply);
 
             // Your code here if more rpc desired.
             // rpc xxx(yyy) returns (zzz)
@@ -0,0 +1,132 @@
+//! Delivery of applied entries to the service and other consumers.
+//!
+//! The service passes its `apply_ch` to `Raft::new`. Other consumers, e.g.
+//! metrics or audit logs, can `Node::subscribe` to get a copy of every
+//! `ApplyMsg` sent afterwards. Subscribers acknowledge what they have
+//! consumed, and the log must not be compacted past the slowest of them.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::stream::Stream;
+
+use super::ApplyMsg;
+
+/// A stream of the `ApplyMsg`s of a peer, created by `Node::subscribe`.
+pub struct Subscription {
+    rx: UnboundedReceiver<ApplyMsg>,
+    acked: Arc<AtomicU64>,
+}
+
+impl Subscription {
+    /// Acknowledges that all messages up to `index` have been consumed.
+    pub fn ack(&self, index: u64) {
+        self.acked.fetch_max(index, Ordering::Release);
+    }
+}
+
+impl Stream for Subscription {
+    type Item = ApplyMsg;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ApplyMsg>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+struct Subscriber {
+    tx: UnboundedSender<ApplyMsg>,
+    acked: Arc<AtomicU64>,
+}
+
+/// Fans out applied entries to `apply_ch` and all subscribers.
+pub struct Appliers {
+    apply_ch: UnboundedSender<ApplyMsg>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Appliers {
+    pub fn new(apply_ch: UnboundedSender<ApplyMsg>) -> Appliers {
+        Appliers {
+            apply_ch,
+            subscribers: vec![],
+        }
+    }
+
+    /// Adds a subscriber that has consumed everything up to `applied_index`.
+    pub fn subscribe(&mut self, applied_index: u64) -> Subscription {
+        let (tx, rx) = unbounded();
+        let acked = Arc::new(AtomicU64::new(applied_index));
+        self.subscribers.push(Subscriber {
+            tx,
+            acked: acked.clone(),
+        });
+        Subscription { rx, acked }
+    }
+
+    /// Sends `msg` to `apply_ch` and all subscribers, dropping subscribers
+    /// that have gone away. Returns false if `apply_ch` is closed.
+    pub fn apply(&mut self, msg: ApplyMsg) -> bool {
+        self.subscribers
+            .retain(|s| s.tx.unbounded_send(msg.clone()).is_ok());
+        self.apply_ch.unbounded_send(msg).is_ok()
+    }
+
+    /// The highest index acknowledged by all subscribers, if there are any.
+    /// Entries after it must be kept.
+    pub fn min_acked(&self) -> Option<u64> {
+        self.subscribers
+            .iter()
+            .map(|s| s.acked.load(Ordering::Acquire))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::stream::StreamExt;
+
+    use super::*;
+
+    fn msg(index: u64) -> ApplyMsg {
+        ApplyMsg {
+            command_valid: true,
+            command: vec![],
+            command_index: index,
+        }
+    }
+
+    #[test]
+    fn test_appliers() {
+        let (tx, mut apply_ch) = unbounded();
+        let mut appliers = Appliers::new(tx);
+        assert_eq!(appliers.min_acked(), None);
+
+        let mut sub1 = appliers.subscribe(0);
+        assert!(appliers.apply(msg(1)));
+        let sub2 = appliers.subscribe(1);
+        assert!(appliers.apply(msg(2)));
+
+        let got: Vec<_> = block_on((&mut apply_ch).take(2).collect());
+        assert_eq!(got.len(), 2);
+        assert_eq!(block_on(sub1.next()).unwrap().command_index, 1);
+        assert_eq!(block_on(sub1.next()).unwrap().command_index, 2);
+        assert_eq!(appliers.min_acked(), Some(0));
+
+        sub1.ack(2);
+        assert_eq!(appliers.min_acked(), Some(1));
+        sub2.ack(2);
+        assert_eq!(appliers.min_acked(), Some(2));
+
+        drop(sub1);
+        drop(sub2);
+        assert!(appliers.apply(msg(3)));
+        assert_eq!(appliers.min_acked(), None);
+
+        drop(apply_ch);
+        assert!(!appliers.apply(msg(4)));
+    }
+}
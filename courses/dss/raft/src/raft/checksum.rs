@@ -0,0 +1,108 @@
+//! CRC32 checksums of log entries, snapshots and persisted state.
+//!
+//! Messages carry the checksum of their payload in a field, see
+//! [`crc32`] and [`verify`], and log entries their own, see [`stamp_entries`]
+//! and [`verify_entries`]. Blobs handed to the persister are sealed with a
+//! trailing checksum instead, see [`seal`] and [`unseal`].
+
+use super::errors::*;
+use crate::proto::raftpb::LogEntry;
+
+const CHECKSUM_LEN: usize = 4;
+
+/// The CRC32 checksum of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// Checks that `data` matches `checksum`.
+pub fn verify(data: &[u8], checksum: u32) -> Result<()> {
+    let actual = crc32(data);
+    if actual != checksum {
+        return Err(Error::Corruption(format!(
+            "checksum mismatch, expect {:#010x}, got {:#010x}",
+            checksum, actual
+        )));
+    }
+    Ok(())
+}
+
+/// Sets the checksum of every entry to that of its data.
+pub fn stamp_entries(entries: &mut [LogEntry]) {
+    for e in entries {
+        e.checksum = crc32(&e.data);
+    }
+}
+
+/// Checks that every entry matches its checksum.
+pub fn verify_entries(entries: &[LogEntry]) -> Result<()> {
+    entries.iter().try_for_each(|e| verify(&e.data, e.checksum))
+}
+
+/// Appends the checksum of `data` to it.
+pub fn seal(mut data: Vec<u8>) -> Vec<u8> {
+    let checksum = crc32(&data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+    data
+}
+
+/// Verifies data sealed by [`seal`] and returns it without the checksum.
+pub fn unseal(data: &[u8]) -> Result<&[u8]> {
+    if data.len() < CHECKSUM_LEN {
+        return Err(Error::Corruption(format!(
+            "sealed data is too short, got {} bytes",
+            data.len()
+        )));
+    }
+    let (data, checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+    let mut buf = [0; CHECKSUM_LEN];
+    buf.copy_from_slice(checksum);
+    verify(data, u32::from_le_bytes(buf))?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let sealed = seal(b"raft state".to_vec());
+        assert_eq!(unseal(&sealed).unwrap(), b"raft state");
+        assert_eq!(unseal(&seal(vec![])).unwrap(), b"");
+
+        let mut torn = sealed.clone();
+        torn.truncate(sealed.len() - 1);
+        unseal(&torn).unwrap_err();
+        let mut flipped = sealed;
+        flipped[0] ^= 1;
+        match unseal(&flipped) {
+            Err(Error::Corruption(_)) => (),
+            res => panic!("expect corruption, got {:?}", res),
+        }
+        unseal(&[]).unwrap_err();
+    }
+
+    #[test]
+    fn test_entries() {
+        let mut entries = vec![
+            LogEntry {
+                term: 1,
+                data: b"a".to_vec(),
+                ..Default::default()
+            },
+            LogEntry {
+                term: 2,
+                data: b"b".to_vec(),
+                ..Default::default()
+            },
+        ];
+        stamp_entries(&mut entries);
+        verify_entries(&entries).unwrap();
+        entries[1].data[0] ^= 1;
+        match verify_entries(&entries) {
+            Err(Error::Corruption(_)) => (),
+            res => panic!("expect corruption, got {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Optional compression of log entry payloads.
+//!
+//! Compression is only available when the `snappy` feature is enabled.
+//! The [`Compression`] used for the entries of an AppendEntries travels
+//! along with them, so peers built with and without the feature can still
+//! talk to each other:
+//!
+//! - every AppendEntries reply advertises the `preferred` compression of
+//!   the follower,
+//! - `Raft::send_append_entries` compresses the entries with what the
+//!   follower advertised last, if the leader supports it, none until then,
+//! - the event loop decompresses them before `handle_append_entries`.
+//!
+//! The same helpers can be used in `Raft::persist` before handing the state
+//! to the persister.
+
+use super::errors::*;
+pub use crate::proto::raftpb::Compression;
+use crate::proto::raftpb::LogEntry;
+
+/// The compression this peer uses for outgoing payloads.
+pub fn preferred() -> Compression {
+    if cfg!(feature = "snappy") {
+        Compression::Snappy
+    } else {
+        Compression::None
+    }
+}
+
+/// Whether this peer is able to decode payloads compressed with `kind`.
+pub fn supported(kind: Compression) -> bool {
+    match kind {
+        Compression::None => true,
+        Compression::Snappy => cfg!(feature = "snappy"),
+    }
+}
+
+/// Compresses `data` with `kind`, which must be [`supported`].
+pub fn compress(kind: Compression, data: &[u8]) -> Result<Vec<u8>> {
+    match kind {
+        Compression::None => Ok(data.to_vec()),
+        #[cfg(feature = "snappy")]
+        Compression::Snappy => snap::raw::Encoder::new()
+            .compress_vec(data)
+            .map_err(|e| Error::Compress(e.to_string())),
+        #[cfg(not(feature = "snappy"))]
+        Compression::Snappy => Err(Error::Compress(
+            "snappy is not enabled, rebuild with the `snappy` feature".to_owned(),
+        )),
+    }
+}
+
+/// Decompresses `data` that was compressed with `kind`.
+pub fn decompress(kind: Compression, data: &[u8]) -> Result<Vec<u8>> {
+    match kind {
+        Compression::None => Ok(data.to_vec()),
+        #[cfg(feature = "snappy")]
+        Compression::Snappy => snap::raw::Decoder::new()
+            .decompress_vec(data)
+            .map_err(|e| Error::Decompress(e.to_string())),
+        #[cfg(not(feature = "snappy"))]
+        Compression::Snappy => Err(Error::Decompress(
+            "snappy is not enabled, rebuild with the `snappy` feature".to_owned(),
+        )),
+    }
+}
+
+/// Compresses the data of `entries` with `kind`, all of them or, on an
+/// error, none.
+pub fn compress_entries(kind: Compression, entries: &mut [LogEntry]) -> Result<()> {
+    if kind == Compression::None {
+        return Ok(());
+    }
+    let data = entries
+        .iter()
+        .map(|e| compress(kind, &e.data))
+        .collect::<Result<Vec<_>>>()?;
+    for (e, data) in entries.iter_mut().zip(data) {
+        e.data = data;
+    }
+    Ok(())
+}
+
+/// Decompresses the data of `entries`, compressed with `kind`, a
+/// `Compression` as an `i32` as it's received.
+pub fn decompress_entries(kind: i32, entries: &mut [LogEntry]) -> Result<()> {
+    let kind = Compression::from_i32(kind)
+        .ok_or_else(|| Error::Decompress(format!("unknown compression {}", kind)))?;
+    if kind == Compression::None {
+        return Ok(());
+    }
+    let data = entries
+        .iter()
+        .map(|e| decompress(kind, &e.data))
+        .collect::<Result<Vec<_>>>()?;
+    for (e, data) in entries.iter_mut().zip(data) {
+        e.data = data;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let data = b"hello hello hello hello hello".to_vec();
+        let kind = preferred();
+        assert!(supported(kind));
+        let compressed = compress(kind, &data).unwrap();
+        assert_eq!(decompress(kind, &compressed).unwrap(), data);
+        assert_eq!(decompress(Compression::None, &data).unwrap(), data);
+    }
+
+    #[test]
+    fn test_entries() {
+        let entries: Vec<_> = (0..3)
+            .map(|i| LogEntry {
+                term: i,
+                data: vec![i as u8; 100],
+                ..Default::default()
+            })
+            .collect();
+        let mut sent = entries.clone();
+        compress_entries(preferred(), &mut sent).unwrap();
+        decompress_entries(preferred() as i32, &mut sent).unwrap();
+        assert_eq!(sent, entries);
+        decompress_entries(-1, &mut sent).unwrap_err();
+    }
+
+    #[cfg(not(feature = "snappy"))]
+    #[test]
+    fn test_unsupported() {
+        assert!(!supported(Compression::Snappy));
+        compress(Compression::Snappy, b"data").unwrap_err();
+        decompress(Compression::Snappy, b"data").unwrap_err();
+        let mut entries = vec![LogEntry {
+            term: 1,
+            data: b"data".to_vec(),
+            ..Default::default()
+        }];
+        compress_entries(Compression::Snappy, &mut entries).unwrap_err();
+        assert_eq!(entries[0].data, b"data");
+    }
+}
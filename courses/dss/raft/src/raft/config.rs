@@ -13,10 +13,25 @@ use crate::proto::raftpb::*;
 use crate::raft;
 use crate::raft::persister::*;
 
-static ID: AtomicUsize = AtomicUsize::new(0);
+/// Allocates endnames that are unique within a single `Config`.
+///
+/// Every config owns its own generator, so tests running concurrently don't
+/// interleave ids, and a test sees the same endnames on every run.
+pub struct IdGen {
+    next: AtomicUsize,
+}
+
+impl IdGen {
+    /// Creates a generator whose first id is `seed`.
+    pub fn new(seed: usize) -> IdGen {
+        IdGen {
+            next: AtomicUsize::new(seed),
+        }
+    }
 
-fn uniqstring() -> String {
-    format!("{}", ID.fetch_add(1, Ordering::Relaxed))
+    pub fn uniqstring(&self) -> String {
+        format!("{}", self.next.fetch_add(1, Ordering::Relaxed))
+    }
 }
 
 /// A log entry.
@@ -71,9 +86,18 @@ pub struct Config {
     saved: Box<[Arc<SimplePersister>]>,
     // the port file names each sends to
     endnames: Box<[Box<[String]>]>,
+    // generator of the port file names
+    ids: IdGen,
 
     pub storage: Arc<Mutex<Storage>>,
 
+    // interval between two ticks of the raft event loops,
+    // applies to servers started afterwards.
+    pub tick_interval: Duration,
+    // idle ticks after which leaders quiesce, see `Raft::set_quiesce_after`.
+    // applies to servers started afterwards.
+    pub quiesce_after: Option<u64>,
+
     // time at which make_config() was called
     start: Instant,
 
@@ -89,9 +113,25 @@ pub struct Config {
 
 impl Config {
     pub fn new(n: usize, unreliable: bool) -> Config {
+        Config::new_with_seed(n, unreliable, 0)
+    }
+
+    /// Like `new`, but endnames are allocated starting from `seed`.
+    pub fn new_with_seed(n: usize, unreliable: bool, seed: usize) -> Config {
+        Config::with_network(n, unreliable, seed, labrpc::Network::new())
+    }
+
+    /// Like `new`, but the network and the ticks of the peers run on
+    /// `clock`, e.g. a `labrpc::SimClock` the test advances, and the network
+    /// draws its faults from `seed`, so a run can be replayed.
+    pub fn with_clock<C: labrpc::Clock>(n: usize, unreliable: bool, seed: u64, clock: C) -> Config {
+        let net = labrpc::Network::with_clock(seed, clock);
+        Config::with_network(n, unreliable, 0, net)
+    }
+
+    fn with_network(n: usize, unreliable: bool, seed: usize, net: labrpc::Network) -> Config {
         init_logger();
 
-        let net = labrpc::Network::new();
         net.set_reliable(!unreliable);
         net.set_long_delays(true);
         let storage = Storage {
@@ -112,7 +152,10 @@ impl Config {
             connected: vec![true; n].into_boxed_slice(),
             saved: saved.into_boxed_slice(),
             endnames: endnames.into_boxed_slice(),
+            ids: IdGen::new(seed),
             storage: Arc::new(Mutex::new(storage)),
+            tick_interval: raft::DEFAULT_TICK_INTERVAL,
+            quiesce_after: None,
 
             start: Instant::now(),
             t0: Instant::now(),
@@ -363,7 +406,7 @@ impl Config {
         // so that old crashed instance's ClientEnds can't send.
         self.endnames[i] = vec![String::new(); self.n].into_boxed_slice();
         for j in 0..self.n {
-            self.endnames[i][j] = uniqstring();
+            self.endnames[i][j] = self.ids.uniqstring();
         }
 
         // a fresh set of ClientEnds.
@@ -414,8 +457,9 @@ impl Config {
         });
         self.net.spawn_poller(apply);
 
-        let rf = raft::Raft::new(clients, i, Box::new(self.saved[i].clone()), tx);
-        let node = raft::Node::new(rf);
+        let mut rf = raft::Raft::new(clients, i, Box::new(self.saved[i].clone()), tx);
+        rf.set_quiesce_after(self.quiesce_after);
+        let node = raft::Node::with_clock(rf, self.tick_interval, self.net.clock());
         self.rafts.lock().unwrap()[i] = Some(node.clone());
 
         let mut builder = labrpc::ServerBuilder::new(format!("{}", i));
@@ -434,8 +478,7 @@ impl Config {
         // continues to update the Persister.
         // but copy old persister's content so that we always
         // pass Make() the last persisted state.
-        let p = SimplePersister::new();
-        p.save_raft_state(self.saved[i].raft_state());
+        let p = self.saved[i].fork();
         self.saved[i] = Arc::new(p);
 
         if let Some(rf) = self.rafts.lock().unwrap()[i].take() {
@@ -487,6 +530,9 @@ impl Config {
 
 impl Drop for Config {
     fn drop(&mut self) {
+        if thread::panicking() {
+            println!("network seed: {}", self.net.seed());
+        }
         if let Ok(rafts) = self.rafts.try_lock() {
             for r in rafts.iter() {
                 if let Some(rf) = r {
@@ -0,0 +1,81 @@
+//! Diagnostics of leader elections.
+//!
+//! A candidate records why each peer granted or refused its vote in an
+//! [`ElectionReport`], which is exposed by `Node::status`. Refusals are
+//! carried back in the `RequestVoteReply` as a [`VoteDenial`].
+
+pub use crate::proto::raftpb::VoteDenial;
+
+/// How the votes of an election went, from the view of the candidate.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ElectionReport {
+    /// The term of the election.
+    pub term: u64,
+    /// Peers that granted their vote, including the candidate itself.
+    pub granted: Vec<usize>,
+    /// Peers that refused their vote and why.
+    pub denied: Vec<(usize, VoteDenial)>,
+}
+
+impl ElectionReport {
+    /// Starts a report for an election of `me` in `term`.
+    pub fn new(me: usize, term: u64) -> ElectionReport {
+        ElectionReport {
+            term,
+            granted: vec![me],
+            denied: vec![],
+        }
+    }
+
+    /// `peer` granted its vote.
+    pub fn grant(&mut self, peer: usize) {
+        if !self.granted.contains(&peer) {
+            self.granted.push(peer);
+        }
+    }
+
+    /// `peer` refused its vote for `reason`.
+    pub fn deny(&mut self, peer: usize, reason: VoteDenial) {
+        debug!(
+            "election of term {}: peer {} denied its vote: {:?}",
+            self.term, peer, reason
+        );
+        if self.denied.iter().all(|(p, _)| *p != peer) {
+            self.denied.push((peer, reason));
+        }
+    }
+
+    /// Whether the candidate has votes from a majority of `peers` peers.
+    pub fn won(&self, peers: usize) -> bool {
+        self.granted.len() > peers / 2
+    }
+
+    /// Whether the election can't be won any more.
+    pub fn lost(&self, peers: usize) -> bool {
+        peers - self.denied.len() <= peers / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_election_report() {
+        let mut report = ElectionReport::new(0, 3);
+        assert!(!report.won(5));
+        report.grant(1);
+        report.grant(1);
+        report.deny(2, VoteDenial::StaleTerm);
+        report.deny(3, VoteDenial::LogNotUpToDate);
+        assert!(!report.won(5));
+        assert!(!report.lost(5));
+        report.deny(4, VoteDenial::AlreadyVoted);
+        assert!(report.lost(5));
+        assert_eq!(report.granted, vec![0, 1]);
+        assert_eq!(report.denied.len(), 3);
+
+        report.grant(2);
+        assert!(report.won(5));
+    }
+}
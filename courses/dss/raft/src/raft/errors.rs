@@ -6,6 +6,25 @@ pub enum Error {
     Decode(labcodec::DecodeError),
     Rpc(labrpc::Error),
     NotLeader,
+    /// Another entry was committed at the index of a proposal.
+    Superseded {
+        index: u64,
+        term: u64,
+    },
+    Killed,
+    /// Persisted or received data doesn't match its checksum.
+    Corruption(String),
+    /// The persister failed to save the state.
+    Persist(String),
+    /// Data couldn't be compressed or decompressed, see `raft::compress`.
+    Compress(String),
+    Decompress(String),
+    /// The entry at the index has been compacted into a snapshot.
+    Compacted(u64),
+    /// The entry at the index isn't in the log yet.
+    Unavailable(u64),
+    /// Persisted data has a format version this build can't load.
+    Version(u16),
 }
 
 impl fmt::Display for Error {
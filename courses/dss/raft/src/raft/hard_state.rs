@@ -0,0 +1,171 @@
+//! Encoding of the persisted raft state.
+//!
+//! The term and vote of a peer (its [`HardState`]) are kept in their own
+//! sealed record in front of the log:
+//!
+//! ```text
+//! | hard state len: u32 LE | sealed hard state | sealed log |
+//! ```
+//!
+//! So a vote can be persisted without encoding the log again, by keeping the
+//! sealed log of the previous persist around, and a corrupt log doesn't take
+//! the term and vote with it. A peer that forgot its vote may vote twice in
+//! the same term.
+//!
+//! The whole state is stamped with [`VERSION`], see [`migrations`].
+//!
+//! A term and vote saved apart from the log with
+//! `Persister::save_hard_state` are put back in by [`merge`].
+
+use super::checksum;
+use super::errors::*;
+use super::version::{self, Migrations};
+pub use crate::proto::raftpb::HardState;
+
+const LEN_BYTES: usize = 4;
+
+/// The format version of the raft state.
+///
+/// Bump it when changing how the log is encoded, and add a migration from
+/// the previous version to [`migrations`].
+pub const VERSION: u16 = 1;
+
+/// How raft state saved by older versions is loaded by [`decode`].
+pub fn migrations() -> Migrations {
+    // version 0 is the same format without a stamp.
+    Migrations::new(VERSION).add(0, |data| Ok(data.to_vec()))
+}
+
+/// Seals an encoded log, so it can be reused by [`encode_with_sealed_log`].
+pub fn seal_log(log: Vec<u8>) -> Vec<u8> {
+    checksum::seal(log)
+}
+
+/// Encodes `hard_state` and the encoded `log`.
+pub fn encode(hard_state: &HardState, log: Vec<u8>) -> Vec<u8> {
+    encode_with_sealed_log(hard_state, &seal_log(log))
+}
+
+/// Encodes `hard_state` and a log sealed by [`seal_log`].
+pub fn encode_with_sealed_log(hard_state: &HardState, sealed_log: &[u8]) -> Vec<u8> {
+    let mut hs = vec![];
+    labcodec::encode(hard_state, &mut hs).unwrap();
+    let hs = checksum::seal(hs);
+    let mut data = Vec::with_capacity(LEN_BYTES + hs.len() + sealed_log.len());
+    data.extend_from_slice(&(hs.len() as u32).to_le_bytes());
+    data.extend_from_slice(&hs);
+    data.extend_from_slice(sealed_log);
+    version::stamp(VERSION, &data)
+}
+
+/// Decodes data encoded by [`encode`], or by an older version.
+///
+/// Fails only if the hard state is unreadable, the log is returned as an
+/// inner result so the term and vote survive a corrupt log.
+pub fn decode(data: &[u8]) -> Result<(HardState, Result<Vec<u8>>)> {
+    let (hard_state, sealed_log) = decode_sealed(data)?;
+    Ok((
+        hard_state,
+        checksum::unseal(&sealed_log).map(<[u8]>::to_vec),
+    ))
+}
+
+/// Combines encoded raft state with a hard state saved apart from it,
+/// keeping the newer of the two hard states. Terms only grow and a peer
+/// votes at most once in a term, so the newer one is the greater.
+///
+/// Empty raft state is taken as an empty log.
+pub fn merge(data: &[u8], hard_state: &HardState) -> Result<Vec<u8>> {
+    if data.is_empty() {
+        return Ok(encode(hard_state, vec![]));
+    }
+    let (hs, sealed_log) = decode_sealed(data)?;
+    if (hard_state.term, hard_state.voted_for) > (hs.term, hs.voted_for) {
+        Ok(encode_with_sealed_log(hard_state, &sealed_log))
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+/// Decodes the hard state and returns it with the sealed log.
+fn decode_sealed(data: &[u8]) -> Result<(HardState, Vec<u8>)> {
+    let data = migrations().load(data)?;
+    if data.len() < LEN_BYTES {
+        return Err(Error::Corruption(format!(
+            "raft state is too short, got {} bytes",
+            data.len()
+        )));
+    }
+    let (len, rest) = data.split_at(LEN_BYTES);
+    let mut buf = [0; LEN_BYTES];
+    buf.copy_from_slice(len);
+    let len = u32::from_le_bytes(buf) as usize;
+    if rest.len() < len {
+        return Err(Error::Corruption(format!(
+            "hard state is truncated, expect {} bytes, got {}",
+            len,
+            rest.len()
+        )));
+    }
+    let (hs, log) = rest.split_at(len);
+    let hard_state = labcodec::decode(checksum::unseal(hs)?).map_err(Error::Decode)?;
+    Ok((hard_state, log.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hard_state_codec() {
+        let hs = HardState {
+            term: 3,
+            voted_for: 2,
+        };
+        let data = encode(&hs, b"log".to_vec());
+        let (got, log) = decode(&data).unwrap();
+        assert_eq!(got, hs);
+        assert_eq!(log.unwrap(), b"log");
+
+        let sealed = seal_log(b"log".to_vec());
+        assert_eq!(encode_with_sealed_log(&hs, &sealed), data);
+
+        // A corrupt log keeps the hard state.
+        let mut torn = data.clone();
+        torn.pop();
+        let (got, log) = decode(&torn).unwrap();
+        assert_eq!(got, hs);
+        log.unwrap_err();
+
+        let mut flipped = data.clone();
+        // past the version stamp.
+        flipped[4 + LEN_BYTES] ^= 1;
+        decode(&flipped).unwrap_err();
+        decode(&[1, 0]).unwrap_err();
+
+        // Unstamped state of version 0 still loads.
+        let (_, unstamped) = version::split(&data);
+        let (got, log) = decode(unstamped).unwrap();
+        assert_eq!(got, hs);
+        assert_eq!(log.unwrap(), b"log");
+        let newer = version::stamp(VERSION + 1, unstamped);
+        assert_eq!(decode(&newer), Err(Error::Version(VERSION + 1)));
+    }
+
+    #[test]
+    fn test_merge() {
+        let hs = |term, voted_for| HardState { term, voted_for };
+        let data = encode(&hs(3, 2), b"log".to_vec());
+        assert_eq!(merge(&data, &hs(3, 2)).unwrap(), data);
+        assert_eq!(merge(&data, &hs(2, 1)).unwrap(), data);
+        assert_eq!(merge(&data, &hs(3, 0)).unwrap(), data);
+        for newer in &[hs(4, 0), hs(4, 1)] {
+            let (got, log) = decode(&merge(&data, newer).unwrap()).unwrap();
+            assert_eq!(&got, newer);
+            assert_eq!(log.unwrap(), b"log");
+        }
+        let (got, log) = decode(&merge(&[], &hs(1, 1)).unwrap()).unwrap();
+        assert_eq!(got, hs(1, 1));
+        assert!(log.unwrap().is_empty());
+    }
+}
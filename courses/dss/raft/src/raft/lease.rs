@@ -0,0 +1,130 @@
+//! Leader leases.
+//!
+//! A read index costs the leader a round of heartbeats per read. With a
+//! lease, the leader skips it: followers that acked a heartbeat won't elect
+//! another leader for at least an election timeout after they got it, so
+//! for a little less than that after sending it, counted from when it was
+//! sent, a leader acked by a majority is sure it's still the leader and may
+//! serve reads at its commit index on its own.
+//!
+//! That only holds if the lease is shorter than the minimum election
+//! timeout, with a margin for clocks running at different rates, and if the
+//! leader committed an entry of its term, so its commit index is current.
+//!
+//! Times are those of the clock the peer ticks on, see `Node::with_clock`,
+//! so a lease expires in the same time as the election timeouts it counts
+//! on, simulated or not.
+
+use std::time::Duration;
+
+/// A read the leader may serve without a round of heartbeats, until
+/// `until` on the clock of the peer, once it applied up to `read_index`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LeaseRead {
+    pub read_index: u64,
+    pub until: Duration,
+}
+
+/// The lease of a leader. Disabled unless it has a duration.
+#[derive(Debug, Default)]
+pub struct Lease {
+    duration: Option<Duration>,
+    // when the latest heartbeat acked by each peer was sent, `None` for
+    // this peer.
+    acked: Vec<Option<Duration>>,
+    me: usize,
+    // the commit index, once an entry of the term is committed.
+    commit_index: Option<u64>,
+}
+
+impl Lease {
+    /// A lease of `duration` from the heartbeats acked by a majority of
+    /// `peers`, peer `me` included. Never valid if `None`.
+    pub fn new(duration: Option<Duration>, peers: usize, me: usize) -> Lease {
+        Lease {
+            duration,
+            acked: vec![None; peers],
+            me,
+            commit_index: None,
+        }
+    }
+
+    /// Forgets the acks and the commit index, on becoming leader and on
+    /// losing leadership.
+    pub fn reset(&mut self) {
+        self.acked.iter_mut().for_each(|a| *a = None);
+        self.commit_index = None;
+    }
+
+    /// `peer` acked an AppendEntries sent at `sent_at`, in the term of the
+    /// lease.
+    pub fn acked(&mut self, peer: usize, sent_at: Duration) {
+        if peer != self.me {
+            let ack = &mut self.acked[peer];
+            *ack = (*ack).max(Some(sent_at));
+        }
+    }
+
+    /// The commit index advanced to `index`, covering an entry of the term
+    /// of the lease.
+    pub fn committed(&mut self, index: u64) {
+        self.commit_index = Some(index);
+    }
+
+    /// The read the lease allows at `now`, `None` if it's disabled, hasn't
+    /// been acked by a majority or has expired.
+    pub fn read(&self, now: Duration) -> Option<LeaseRead> {
+        let duration = self.duration?;
+        let read_index = self.commit_index?;
+        // others acking, with this peer, make a majority.
+        let needed = self.acked.len() / 2;
+        let until = if needed == 0 {
+            now + duration
+        } else {
+            let mut acked: Vec<_> = self.acked.iter().flatten().collect();
+            acked.sort_unstable_by(|a, b| b.cmp(a));
+            **acked.get(needed - 1)? + duration
+        };
+        if now < until {
+            Some(LeaseRead { read_index, until })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease() {
+        let t0 = Duration::from_secs(1);
+        let ms = Duration::from_millis;
+        let mut disabled = Lease::new(None, 3, 0);
+        disabled.acked(0, t0);
+        disabled.committed(1);
+        assert_eq!(disabled.read(t0), None);
+
+        // 5 peers, 2 others must ack.
+        let mut lease = Lease::new(Some(ms(100)), 5, 0);
+        lease.acked(1, t0);
+        lease.acked(0, t0 + ms(90));
+        lease.acked(2, t0 + ms(50));
+        assert_eq!(lease.read(t0), None, "no entry of the term committed");
+        lease.committed(7);
+        let read = lease.read(t0 + ms(10)).unwrap();
+        assert_eq!(read.read_index, 7);
+        assert_eq!(read.until, t0 + ms(100));
+        assert_eq!(lease.read(t0 + ms(100)), None);
+        lease.acked(3, t0 + ms(80));
+        lease.acked(3, t0 + ms(60));
+        assert_eq!(lease.read(t0 + ms(100)).unwrap().until, t0 + ms(150));
+
+        lease.reset();
+        assert_eq!(lease.read(t0), None);
+        let mut single = Lease::new(Some(ms(100)), 1, 0);
+        single.committed(1);
+        assert!(single.read(t0).is_some());
+    }
+}
@@ -0,0 +1,126 @@
+//! Counters and latency histograms of a raft peer, see `Node::metrics`.
+
+use std::time::Duration;
+
+/// Number of buckets of a [`Histogram`].
+const BUCKETS: usize = 24;
+
+/// A latency histogram with exponential buckets.
+///
+/// Bucket `i` counts observations below `2^i` microseconds, the last one
+/// counts everything else.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            sum: Duration::default(),
+            max: Duration::default(),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&mut self, d: Duration) {
+        let micros = d.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += d;
+        self.max = self.max.max(d);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::default();
+        }
+        self.sum / self.count as u32
+    }
+
+    /// Adds the observations of `other`.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (n, m) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *n += m;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+
+    /// An upper bound of the `q` quantile, `0 <= q <= 1`.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let target = (q * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target && seen > 0 {
+                if i == BUCKETS - 1 {
+                    return self.max;
+                }
+                return Duration::from_micros(1 << i).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Metrics of a raft peer.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    pub elections_started: u64,
+    pub elections_won: u64,
+    pub append_entries_sent: u64,
+    pub snapshots_installed: u64,
+    /// From `Node::start` to applying the proposed entry.
+    pub commit_latency: Histogram,
+    /// Time spent saving the raft state.
+    pub persist_latency: Histogram,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::default();
+        assert_eq!(h.mean(), Duration::default());
+        assert_eq!(h.quantile(0.99), Duration::default());
+
+        for ms in 1..=100 {
+            h.observe(Duration::from_millis(ms));
+        }
+        assert_eq!(h.count(), 100);
+        assert_eq!(h.max(), Duration::from_millis(100));
+        assert_eq!(h.mean(), Duration::from_micros(50_500));
+        let p50 = h.quantile(0.5);
+        assert!(p50 >= Duration::from_millis(50), "{:?}", p50);
+        assert!(p50 <= Duration::from_millis(100), "{:?}", p50);
+        assert_eq!(h.quantile(1.0), Duration::from_millis(100));
+
+        h.observe(Duration::from_secs(3600));
+        assert_eq!(h.quantile(1.0), Duration::from_secs(3600));
+
+        let mut merged = Histogram::default();
+        merged.observe(Duration::from_millis(1));
+        merged.merge(&h);
+        assert_eq!(merged.count(), 102);
+        assert_eq!(merged.max(), Duration::from_secs(3600));
+    }
+}
@@ -1,19 +1,57 @@
 use std::sync::mpsc::{sync_channel, Receiver};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use futures::channel::mpsc::UnboundedSender;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+use futures::executor::block_on;
+use futures::future::{Future, FutureExt};
+use futures::select;
+use futures::stream::StreamExt;
 
+pub mod apply;
+pub mod checksum;
+pub mod compress;
 #[cfg(test)]
 pub mod config;
+pub mod diagnostics;
 pub mod errors;
+pub mod hard_state;
+pub mod lease;
+pub mod metrics;
 pub mod persister;
+pub mod progress;
+pub mod proposal;
+pub mod quiesce;
+pub mod stickiness;
+pub mod storage;
 #[cfg(test)]
 mod tests;
+pub mod trace;
+pub mod version;
 
+use self::apply::*;
+use self::diagnostics::*;
 use self::errors::*;
-use self::persister::*;
+use self::lease::*;
+use self::metrics::*;
+use self::persister::Persister;
+use self::progress::*;
+use self::proposal::*;
+use self::quiesce::*;
+use self::stickiness::*;
+use self::storage::*;
+use self::trace::*;
 use crate::proto::raftpb::*;
 
+/// Consecutive failed saves after which a leader steps down.
+pub const MAX_PERSIST_FAILURES: u32 = 3;
+
+/// The default interval between two ticks of the event loop.
+pub const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone)]
 pub struct ApplyMsg {
     pub command_valid: bool,
     pub command: Vec<u8>,
@@ -25,6 +63,8 @@ pub struct ApplyMsg {
 pub struct State {
     pub term: u64,
     pub is_leader: bool,
+    /// The peer this one believes leads the term, itself included, if any.
+    pub leader: Option<usize>,
 }
 
 impl State {
@@ -36,6 +76,24 @@ impl State {
     pub fn is_leader(&self) -> bool {
         self.is_leader
     }
+    /// The peer this one believes leads the term, if any.
+    pub fn leader(&self) -> Option<usize> {
+        self.leader
+    }
+}
+
+/// State of a raft peer, with diagnostics.
+#[derive(Default, Clone, Debug)]
+pub struct Status {
+    pub state: State,
+    /// The number of elections this peer has started.
+    pub elections: u64,
+    /// The latest election this peer has started, if any.
+    pub last_election: Option<ElectionReport>,
+    /// How far each peer is behind, only known by the leader.
+    pub lags: Vec<Option<Lag>>,
+    /// The read the lease of the leader allows, see `Node::lease_read`.
+    pub lease: Option<LeaseRead>,
 }
 
 // A single Raft peer.
@@ -46,7 +104,49 @@ pub struct Raft {
     persister: Box<dyn Persister>,
     // this peer's index into peers[]
     me: usize,
+    // the term, the role and the leader of the term once known, e.g. from
+    // its AppendEntries.
     state: Arc<State>,
+    // proposals waiting to be applied, resolved by `Raft::apply`.
+    proposals: Proposals,
+    // elections started so far, and the votes of the latest one.
+    // start a new `ElectionReport` on every election, and record every
+    // RequestVote reply in it.
+    elections: u64,
+    last_election: Option<ElectionReport>,
+    // replication progress of each peer, only maintained by the leader.
+    // reset it with `Progress::new` when becoming leader, and `ack` it on
+    // every successful AppendEntries reply.
+    progress: Vec<Option<Progress>>,
+    // consecutive failed saves of the persister.
+    persist_failures: u32,
+    // allocates the trace_id of outgoing requests.
+    trace_ids: TraceIds,
+    // apply_ch and other consumers of applied entries, send every
+    // ApplyMsg through `Raft::apply`.
+    appliers: Appliers,
+    // whether the leader may stop sending heartbeats, `tick` it while
+    // leading and `wake` it on proposals and role changes.
+    quiesce: Quiesce,
+    // whether a leader heard from recently is still considered alive,
+    // `tick` it and tell it about messages from the leader. don't start
+    // elections or grant votes while `leader_alive`.
+    stickiness: Stickiness,
+    // the lease of the leader. `reset` it on becoming leader and stepping
+    // down, tell it the commit index once an entry of the term is committed,
+    // and note when every AppendEntries was sent, by `clock`, to tell it
+    // when the peer acks it.
+    lease: Lease,
+    // the clock the peer ticks on, see `Node::with_clock`.
+    clock: Arc<dyn labrpc::Clock>,
+    // counters and latencies, shared with `Node::metrics`. latencies are
+    // observed for you, bump the counters where the events happen.
+    metrics: Arc<Mutex<Metrics>>,
+    // the log, only access it through the `Storage` trait.
+    log: Box<dyn Storage>,
+    // the compression each peer advertised in its last AppendEntries reply,
+    // `send_append_entries` compresses the entries sent to it with it.
+    compression: Vec<Compression>,
     // Your data here (2A, 2B, 2C).
     // Look at the paper's Figure 2 for a description of what
     // state a Raft server must maintain.
@@ -67,31 +167,134 @@ impl Raft {
         persister: Box<dyn Persister>,
         apply_ch: UnboundedSender<ApplyMsg>,
     ) -> Raft {
-        let raft_state = persister.raft_state();
+        // starting over with a corrupted state could vote twice in a term.
+        let raft_state = persister
+            .raft_state()
+            .unwrap_or_else(|e| panic!("peer {} failed to read raft state: {}", me, e));
 
         // Your initialization code here (2A, 2B, 2C).
+        let compression = vec![Compression::None; peers.len()];
         let mut rf = Raft {
             peers,
             persister,
             me,
             state: Arc::default(),
+            proposals: Proposals::default(),
+            elections: 0,
+            last_election: None,
+            progress: vec![],
+            persist_failures: 0,
+            trace_ids: TraceIds::new(me),
+            appliers: Appliers::new(apply_ch),
+            quiesce: Quiesce::default(),
+            stickiness: Stickiness::default(),
+            lease: Lease::default(),
+            clock: Arc::new(labrpc::RealClock::new()),
+            metrics: Arc::default(),
+            log: Box::new(MemStorage::new()),
+            compression,
         };
 
         // initialize from state persisted before a crash
         rf.restore(&raft_state);
 
-        crate::your_code_here((rf, apply_ch))
+        crate::your_code_here(rf)
+    }
+
+    /// lets the leader stop sending heartbeats after `idle_ticks` ticks
+    /// without proposals, never if `None`. disabled by default.
+    pub fn set_quiesce_after(&mut self, idle_ticks: Option<u64>) {
+        self.quiesce = Quiesce::new(idle_ticks);
+    }
+
+    /// sticks to a leader heard from within the last `grace` ticks, even if
+    /// the election timeout fires, see `stickiness`. disabled by default.
+    pub fn set_leader_stickiness(&mut self, grace: Option<u64>) {
+        self.stickiness = Stickiness::new(grace);
+    }
+
+    /// lets the leader serve reads on its own for `duration` after a
+    /// majority acked a heartbeat, see `lease`. disabled by default.
+    /// `duration` must be shorter than the minimum election timeout.
+    pub fn set_lease(&mut self, duration: Option<Duration>) {
+        self.lease = Lease::new(duration, self.peers.len(), self.me);
+    }
+
+    /// replaces the in-memory log with `storage`, before any entry is
+    /// appended.
+    pub fn set_storage(&mut self, storage: Box<dyn Storage>) {
+        self.log = storage;
     }
 
     /// save Raft's persistent state to stable storage,
     /// where it can later be retrieved after a crash and restart.
     /// see paper's Figure 2 for a description of what should be persistent.
-    fn persist(&mut self) {
+    ///
+    /// never acknowledge anything that depends on the state if this fails.
+    fn persist(&mut self) -> Result<()> {
         // Your code here (2C).
         // Example:
-        // labcodec::encode(&self.xxx, &mut data).unwrap();
-        // labcodec::encode(&self.yyy, &mut data).unwrap();
-        // self.persister.save_raft_state(data);
+        // labcodec::encode(&self.xxx, &mut log).unwrap();
+        // labcodec::encode(&self.yyy, &mut log).unwrap();
+        // let hs = HardState { term: self.term, voted_for: .. };
+        // self.save_raft_state(hard_state::encode(&hs, log))
+        //
+        // if only the term or vote changed, `self.save_hard_state(&hs)`
+        // saves just them.
+        Ok(())
+    }
+
+    /// saves `data` with the persister, a leader that keeps failing to
+    /// save steps down instead of acknowledging unpersisted entries.
+    fn save_raft_state(&mut self, data: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let res = self.persister.save_raft_state(data);
+        self.check_saved(start, res)
+    }
+
+    /// saves only the term and vote, a much smaller write than the whole
+    /// raft state. `raft_state()` returns them merged into the state saved
+    /// by `save_raft_state`.
+    fn save_hard_state(&mut self, hs: &HardState) -> Result<()> {
+        let start = Instant::now();
+        let res = self.persister.save_hard_state(hs.term, hs.voted_for);
+        self.check_saved(start, res)
+    }
+
+    /// records a save started at `start`, see `save_raft_state`.
+    fn check_saved(&mut self, start: Instant, res: persister::Result<()>) -> Result<()> {
+        self.metrics
+            .lock()
+            .unwrap()
+            .persist_latency
+            .observe(start.elapsed());
+        match res {
+            Ok(()) => {
+                self.persist_failures = 0;
+                Ok(())
+            }
+            Err(e) => {
+                self.persist_failures += 1;
+                error!(
+                    "peer {} failed to persist ({} in a row): {}",
+                    self.me, self.persist_failures, e
+                );
+                if self.persist_failures >= MAX_PERSIST_FAILURES && self.state.is_leader() {
+                    self.step_down();
+                }
+                Err(Error::Persist(e.to_string()))
+            }
+        }
+    }
+
+    /// turns this peer into a follower of the current term.
+    fn step_down(&mut self) {
+        self.state = Arc::new(State {
+            term: self.state.term(),
+            is_leader: false,
+            leader: None,
+        });
+        // Your code here (2A).
     }
 
     /// restore previously persisted state.
@@ -102,12 +305,15 @@ impl Raft {
         }
         // Your code here (2C).
         // Example:
-        // match labcodec::decode(data) {
+        // let (hs, log) = hard_state::decode(data).unwrap_or_else(|e| panic!("{:?}", e));
+        // self.term = hs.term;
+        // match log.and_then(|log| labcodec::decode(&log).map_err(Error::Decode)) {
         //     Ok(o) => {
         //         self.xxx = o.xxx;
         //         self.yyy = o.yyy;
         //     }
         //     Err(e) => {
+        //         // the term and vote are still known.
         //         panic!("{:?}", e);
         //     }
         // }
@@ -138,6 +344,8 @@ impl Raft {
         // Your code here if you want the rpc becomes async.
         // Example:
         // ```
+        // args.trace_id = self.trace_ids.next_id().0;
+        // debug!("{} [{}] -> {} {:?}", self.me, TraceId(args.trace_id), server, args);
         // let peer = &self.peers[server];
         // let peer_clone = peer.clone();
         // let (tx, rx) = channel();
@@ -151,16 +359,38 @@ impl Raft {
         crate::your_code_here((server, args, tx, rx))
     }
 
-    fn start<M>(&self, command: &M) -> Result<(u64, u64)>
-    where
-        M: labcodec::Message,
-    {
+    /// sends an AppendEntries RPC to `server` like `send_request_vote`,
+    /// with the entries checksummed and compressed the way `server`
+    /// advertised.
+    fn send_append_entries(
+        &self,
+        server: usize,
+        mut args: AppendEntriesArgs,
+    ) -> Receiver<Result<AppendEntriesReply>> {
+        checksum::stamp_entries(&mut args.entries);
+        let kind = self.compression[server];
+        match compress::compress_entries(kind, &mut args.entries) {
+            Ok(()) => args.compression = kind as i32,
+            Err(e) => warn!(
+                "{} -> {} send entries uncompressed: {:?}",
+                self.me, server, e
+            ),
+        }
+        // Your code here if you want the rpc becomes async, see
+        // `send_request_vote`.
+        let (tx, rx) = sync_channel::<Result<AppendEntriesReply>>(1);
+        crate::your_code_here((server, args, tx, rx))
+    }
+
+    fn start(&mut self, command: Vec<u8>) -> Result<(u64, u64)> {
+        if self.persist_failures >= MAX_PERSIST_FAILURES {
+            return Err(Error::NotLeader);
+        }
         let index = 0;
         let term = 0;
         let is_leader = true;
-        let mut buf = vec![];
-        labcodec::encode(command, &mut buf).map_err(Error::Encode)?;
-        // Your code here (2B).
+        // Your code here (2B), append `command` to the log.
+        let _ = command;
 
         if is_leader {
             Ok((index, term))
@@ -168,15 +398,304 @@ impl Raft {
             Err(Error::NotLeader)
         }
     }
+
+    /// advances the logical clock of this peer by one tick.
+    /// the event loop calls it every `tick_interval`, so election
+    /// timeouts and heartbeat intervals should be counted in ticks.
+    fn tick(&mut self) {
+        self.stickiness.tick();
+        self.warn_lagging_peers();
+        // Your code here (2A, 2B).
+        // skip empty heartbeats while `self.quiesce.is_quiet()`, and set
+        // `quiesce` in the last one sent before going quiet.
+    }
+
+    /// The index of the last entry in the log.
+    fn last_index(&self) -> u64 {
+        self.log.last_index()
+    }
+
+    /// logs a warning for every peer that persistently lags behind.
+    fn warn_lagging_peers(&mut self) {
+        let last_index = self.last_index();
+        for (peer, pr) in self.progress.iter_mut().enumerate() {
+            if let Some(pr) = pr {
+                if pr.should_warn(last_index) {
+                    warn!(
+                        "peer {} lags behind leader {}: {:?}",
+                        peer,
+                        self.me,
+                        pr.lag(last_index)
+                    );
+                }
+            }
+        }
+    }
+
+    /// The index of the last entry sent to apply_ch.
+    fn applied_index(&self) -> u64 {
+        // Your code here (2B).
+        0
+    }
+
+    /// sends an applied entry of `term` to apply_ch and the subscribers.
+    fn apply(&mut self, msg: ApplyMsg, term: u64) {
+        if let Some(latency) = self.proposals.applied(msg.command_index, term) {
+            self.metrics.lock().unwrap().commit_latency.observe(latency);
+        }
+        if !self.appliers.apply(msg) {
+            debug!("{} apply_ch is closed", self.me);
+        }
+    }
+
+    /// The highest index that may be compacted into a snapshot without
+    /// losing entries a consumer of applied entries still needs.
+    fn compactable_index(&self) -> u64 {
+        let applied = self.applied_index();
+        self.appliers
+            .min_acked()
+            .map_or(applied, |acked| acked.min(applied))
+    }
+
+    /// handles an incoming RequestVote RPC.
+    fn handle_request_vote(&mut self, args: RequestVoteArgs) -> RequestVoteReply {
+        // Your code here (2A, 2B).
+        crate::your_code_here(args)
+    }
+
+    /// handles an incoming AppendEntries RPC.
+    ///
+    /// `last_log_index` and `applied_index` of the reply are filled in by
+    /// the event loop.
+    fn handle_append_entries(&mut self, args: AppendEntriesArgs) -> AppendEntriesReply {
+        // Your code here (2A, 2B).
+        crate::your_code_here(args)
+    }
+
+    /// handles an incoming InstallSnapshot RPC whose data matches its
+    /// checksum.
+    ///
+    /// a snapshot sent in chunks can be staged with
+    /// `persister.stage_snapshot` and saved with the truncated log by
+    /// `persister.promote_snapshot` once the last chunk arrived, so a crash
+    /// mid-install never leaves a snapshot that disagrees with the log.
+    fn handle_install_snapshot(&mut self, args: InstallSnapshotArgs) -> InstallSnapshotReply {
+        // Your code here (3B).
+        crate::your_code_here(args)
+    }
+
+    /// finds a read index for `Node::read_index`: once the service applied
+    /// up to it, it may serve a linearizable read from its own state.
+    ///
+    /// the leader notes its commit index, confirms it's still the leader
+    /// with a round of heartbeats and replies once a majority answered, see
+    /// Section 8 of the paper. a follower sends a ReadIndex RPC to the
+    /// leader and replies with its answer if `forward` is set, otherwise
+    /// fails with `Error::NotLeader`. `forward` isn't set for ReadIndex RPCs,
+    /// so they're never forwarded again.
+    fn handle_read_index(&mut self, forward: bool, reply: oneshot::Sender<Result<u64>>) {
+        // Your code here (3A).
+        crate::your_code_here((forward, reply))
+    }
+
+    /// moves leadership away for `Node::transfer_leadership`.
+    ///
+    /// the leader stops accepting proposals, brings the most up to date
+    /// follower up to its last entry and sends it a TimeoutNow RPC, see
+    /// Section 3.10 of the Raft dissertation. reply once a new leader is
+    /// heard from, or `Error::NotLeader` if this peer isn't the leader.
+    /// give up and accept proposals again if that takes more than an
+    /// election timeout.
+    fn handle_transfer_leadership(&mut self, reply: oneshot::Sender<Result<()>>) {
+        // Your code here (3A).
+        crate::your_code_here(reply)
+    }
+
+    /// handles an incoming TimeoutNow RPC: start an election right away,
+    /// with `leadership_transfer` set in the RequestVotes so voters ignore
+    /// leader stickiness.
+    fn handle_timeout_now(&mut self, args: TimeoutNowArgs) -> TimeoutNowReply {
+        // Your code here (3A).
+        crate::your_code_here(args)
+    }
+
+    /// handles a reply to an AppendEntries RPC sent to `peer`.
+    fn handle_append_entries_reply(&mut self, peer: usize, reply: AppendEntriesReply) {
+        let last_index = self.last_index();
+        if let Some(Some(pr)) = self.progress.get_mut(peer) {
+            pr.hint(reply.last_log_index, reply.applied_index);
+            debug!(
+                "{} [{}] <- {} {:?}, progress {:?}",
+                self.me,
+                TraceId(reply.trace_id),
+                peer,
+                reply,
+                pr.lag(last_index)
+            );
+        }
+        if let Some(kind) = Compression::from_i32(reply.compression) {
+            if compress::supported(kind) {
+                self.compression[peer] = kind;
+            }
+        }
+        // Your code here (2A, 2B).
+    }
+
+    fn status(&self) -> Status {
+        Status {
+            state: (*self.state).clone(),
+            elections: self.elections,
+            last_election: self.last_election.clone(),
+            lags: self
+                .progress
+                .iter()
+                .map(|pr| pr.as_ref().map(|pr| pr.lag(self.last_index())))
+                .collect(),
+            lease: self.lease.read(self.clock.now()),
+        }
+    }
+
+    /// appends `command` to the log for `Node::propose`.
+    fn propose(&mut self, command: Vec<u8>) -> Result<Proposal> {
+        self.quiesce.wake();
+        let trace_id = self.trace_ids.next_id();
+        let res = self.start(command);
+        debug!("{} [{}] propose: {:?}", self.me, trace_id, res);
+        let proposals = &mut self.proposals;
+        res.map(|(index, term)| proposals.register(index, term))
+    }
+
+    /// adds a consumer of applied entries for `Node::subscribe`.
+    fn subscribe(&mut self) -> Subscription {
+        let applied = self.applied_index();
+        self.appliers.subscribe(applied)
+    }
+
+    /// handles one event delivered to the event loop.
+    fn step(&mut self, event: Event) {
+        match event {
+            Event::RequestVote { args, reply } => {
+                let trace_id = TraceId(args.trace_id);
+                debug!("{} [{}] <- {:?}", self.me, trace_id, args);
+                let mut resp = if self.stickiness.deny_vote(args.leadership_transfer) {
+                    RequestVoteReply {
+                        denial: VoteDenial::LeaderSticky as i32,
+                        ..Default::default()
+                    }
+                } else {
+                    self.handle_request_vote(args)
+                };
+                resp.trace_id = trace_id.0;
+                let _ = reply.send(resp);
+            }
+            Event::AppendEntries { mut args, reply } => {
+                let trace_id = TraceId(args.trace_id);
+                debug!("{} [{}] <- {:?}", self.me, trace_id, args);
+                let res = compress::decompress_entries(args.compression, &mut args.entries)
+                    .and_then(|()| checksum::verify_entries(&args.entries));
+                let mut resp = match res {
+                    Ok(()) => self.handle_append_entries(args),
+                    Err(e) => {
+                        warn!("{} [{}] drop entries: {:?}", self.me, trace_id, e);
+                        AppendEntriesReply {
+                            corrupted: true,
+                            ..Default::default()
+                        }
+                    }
+                };
+                resp.trace_id = trace_id.0;
+                resp.last_log_index = self.last_index();
+                resp.applied_index = self.applied_index();
+                resp.compression = compress::preferred() as i32;
+                let _ = reply.send(resp);
+            }
+            Event::InstallSnapshot { args, reply } => {
+                let trace_id = TraceId(args.trace_id);
+                debug!(
+                    "{} [{}] <- InstallSnapshot of {} bytes",
+                    self.me,
+                    trace_id,
+                    args.data.len()
+                );
+                let mut resp = match checksum::verify(&args.data, args.checksum) {
+                    Ok(()) => self.handle_install_snapshot(args),
+                    Err(e) => {
+                        warn!("{} [{}] drop snapshot: {:?}", self.me, trace_id, e);
+                        InstallSnapshotReply {
+                            corrupted: true,
+                            ..Default::default()
+                        }
+                    }
+                };
+                resp.trace_id = trace_id.0;
+                let _ = reply.send(resp);
+            }
+            Event::AppendEntriesReply { peer, reply } => {
+                self.handle_append_entries_reply(peer, reply);
+            }
+            Event::SetLease { duration } => self.set_lease(duration),
+            Event::ReadIndex { forward, reply } => {
+                self.handle_read_index(forward, reply);
+            }
+            Event::TransferLeadership { reply } => {
+                self.handle_transfer_leadership(reply);
+            }
+            Event::TimeoutNow { args, reply } => {
+                let trace_id = TraceId(args.trace_id);
+                debug!("{} [{}] <- {:?}", self.me, trace_id, args);
+                let mut resp = self.handle_timeout_now(args);
+                resp.trace_id = trace_id.0;
+                let _ = reply.send(resp);
+            }
+        }
+    }
 }
 
 impl Raft {
     /// Only for suppressing deadcode warnings.
     #[doc(hidden)]
     pub fn __suppress_deadcode(&mut self) {
-        let _ = self.start(&0);
+        let _ = self.start(vec![]);
         let _ = self.send_request_vote(0, Default::default());
-        self.persist();
+        let _ = self.send_append_entries(0, Default::default());
+        let _ = self.persist();
+        let _ = self.save_raft_state(vec![]);
+        let _ = self.save_hard_state(&HardState::default());
+        let _ = self.compactable_index();
+        self.apply(
+            ApplyMsg {
+                command_valid: false,
+                command: vec![],
+                command_index: 0,
+            },
+            0,
+        );
+        self.step(Event::AppendEntriesReply {
+            peer: 0,
+            reply: Default::default(),
+        });
+        // sent by the RPC handlers of `Node`, not reachable in the lib
+        // until a service registers them.
+        let (reply, _) = oneshot::channel();
+        self.step(Event::RequestVote {
+            args: Default::default(),
+            reply,
+        });
+        let (reply, _) = oneshot::channel();
+        self.step(Event::AppendEntries {
+            args: Default::default(),
+            reply,
+        });
+        let (reply, _) = oneshot::channel();
+        self.step(Event::InstallSnapshot {
+            args: Default::default(),
+            reply,
+        });
+        let (reply, _) = oneshot::channel();
+        self.step(Event::TimeoutNow {
+            args: Default::default(),
+            reply,
+        });
         let _ = &self.state;
         let _ = &self.me;
         let _ = &self.persister;
@@ -184,30 +703,148 @@ impl Raft {
     }
 }
 
-// Choose concurrency paradigm.
-//
-// You can either drive the raft state machine by the rpc framework,
-//
-// ```rust
-// struct Node { raft: Arc<Mutex<Raft>> }
-// ```
-//
-// or spawn a new thread runs the raft state machine and communicate via
-// a channel.
+/// An input of the raft event loop.
+///
+/// Everything that touches the state of a peer goes through the event loop,
+/// so the state is only ever mutated by a single thread and role transitions
+/// can't race with timers. Only `Node::propose` and `Node::subscribe`, which
+/// must answer right away, take the lock of the state themselves.
+enum Event {
+    /// A RequestVote RPC arrived.
+    RequestVote {
+        args: RequestVoteArgs,
+        reply: oneshot::Sender<RequestVoteReply>,
+    },
+    /// An AppendEntries RPC arrived.
+    AppendEntries {
+        args: AppendEntriesArgs,
+        reply: oneshot::Sender<AppendEntriesReply>,
+    },
+    /// An InstallSnapshot RPC arrived.
+    InstallSnapshot {
+        args: InstallSnapshotArgs,
+        reply: oneshot::Sender<InstallSnapshotReply>,
+    },
+    /// `Node::read_index` or a ReadIndex RPC wants a read index.
+    ReadIndex {
+        forward: bool,
+        reply: oneshot::Sender<Result<u64>>,
+    },
+    /// `Node::transfer_leadership` wants leadership moved away.
+    TransferLeadership { reply: oneshot::Sender<Result<()>> },
+    /// A TimeoutNow RPC arrived.
+    TimeoutNow {
+        args: TimeoutNowArgs,
+        reply: oneshot::Sender<TimeoutNowReply>,
+    },
+    /// `Node::set_lease` changes the lease.
+    SetLease { duration: Option<Duration> },
+    /// `peer` replied to an AppendEntries RPC.
+    AppendEntriesReply {
+        peer: usize,
+        reply: AppendEntriesReply,
+    },
+    // Your code here if more events desired, e.g. RPC replies.
+}
+
+/// Drives a Raft peer: `tick()` every `tick_interval` and `step()` on every
+/// event, until `shutdown` fires or all senders are gone.
+fn run(
+    raft: Arc<Mutex<Raft>>,
+    tick_interval: Duration,
+    clock: Arc<dyn labrpc::Clock>,
+    mut events: UnboundedReceiver<Event>,
+    shutdown: oneshot::Receiver<()>,
+    status: Arc<Mutex<Status>>,
+) {
+    block_on(async {
+        let mut shutdown = shutdown.fuse();
+        let mut ticker = clock.sleep(tick_interval).fuse();
+        loop {
+            select! {
+                event = events.next() => match event {
+                    Some(event) => raft.lock().unwrap().step(event),
+                    None => break,
+                },
+                _ = ticker => {
+                    raft.lock().unwrap().tick();
+                    ticker = clock.sleep(tick_interval).fuse();
+                }
+                _ = shutdown => break,
+            }
+            let raft = raft.lock().unwrap();
+            *status.lock().unwrap() = raft.status();
+        }
+    });
+}
+
+// The raft state machine is driven by a single event loop thread, see `run`.
 //
-// ```rust
-// struct Node { sender: Sender<Msg> }
-// ```
+// RPC handlers send an `Event` to the loop and wait for its answer, timeouts
+// are counted in ticks. `Node::start` appends under the lock of the state,
+// it must not wait for the loop. To talk to other peers, spawn the RPC
+// on `self.peers[i]` and send the reply back to the loop as another `Event`.
 #[derive(Clone)]
 pub struct Node {
-    // Your code here.
+    raft: Arc<Mutex<Raft>>,
+    events: UnboundedSender<Event>,
+    status: Arc<Mutex<Status>>,
+    metrics: Arc<Mutex<Metrics>>,
+    shutdown: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    clock: Arc<dyn labrpc::Clock>,
 }
 
 impl Node {
     /// Create a new raft service.
     pub fn new(raft: Raft) -> Node {
-        // Your code here.
-        crate::your_code_here(raft)
+        Node::with_tick_interval(raft, DEFAULT_TICK_INTERVAL)
+    }
+
+    /// Create a new raft service whose event loop ticks every `tick_interval`.
+    pub fn with_tick_interval(raft: Raft, tick_interval: Duration) -> Node {
+        Node::with_clock(raft, tick_interval, Arc::new(labrpc::RealClock::new()))
+    }
+
+    /// Like `with_tick_interval`, but ticks on `clock`, e.g. the clock of a
+    /// network on simulated time, see `labrpc::Network::clock`.
+    pub fn with_clock(
+        mut raft: Raft,
+        tick_interval: Duration,
+        clock: Arc<dyn labrpc::Clock>,
+    ) -> Node {
+        raft.clock = clock.clone();
+        let (events, rx) = unbounded();
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let status = Arc::new(Mutex::new(raft.status()));
+        let loop_status = status.clone();
+        let metrics = raft.metrics.clone();
+        let name = format!("raft-{}", raft.me);
+        let raft = Arc::new(Mutex::new(raft));
+        let loop_raft = raft.clone();
+        let loop_clock = clock.clone();
+        let handle = thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                run(
+                    loop_raft,
+                    tick_interval,
+                    loop_clock,
+                    rx,
+                    shutdown_rx,
+                    loop_status,
+                )
+            })
+            .unwrap();
+        Node {
+            raft,
+            events,
+            status,
+            metrics,
+            shutdown: Arc::new(Mutex::new(Some(shutdown))),
+            handle: Arc::new(Mutex::new(Some(handle))),
+            clock,
+        }
     }
 
     /// the service using Raft (e.g. a k/v server) wants to start
@@ -226,34 +863,136 @@ impl Node {
     where
         M: labcodec::Message,
     {
-        // Your code here.
-        // Example:
-        // self.raft.start(command)
-        crate::your_code_here(command)
+        self.propose(command).map(|p| (p.index, p.term))
+    }
+
+    /// Like `start`, but also returns a [`Proposal`] future that resolves
+    /// once the entry at the returned index is applied, or with
+    /// [`Error::Superseded`] if a different entry was committed there.
+    ///
+    /// Services can await it instead of watching `apply_ch` for their
+    /// index to find out whether they lost leadership.
+    ///
+    /// This method must return without blocking on the raft.
+    pub fn propose<M>(&self, command: &M) -> Result<Proposal>
+    where
+        M: labcodec::Message,
+    {
+        let mut buf = vec![];
+        labcodec::encode(command, &mut buf).map_err(Error::Encode)?;
+        // the loop is gone once killed, see `kill`.
+        if self.events.is_closed() {
+            return Err(Error::Killed);
+        }
+        let mut raft = self.raft.lock().unwrap();
+        let proposal = raft.propose(buf);
+        *self.status.lock().unwrap() = raft.status();
+        proposal
+    }
+
+    /// Resolves to a read index: once the service applied up to it, a
+    /// linearizable read may be served from its own state, without putting
+    /// the read into the log. Followers ask the leader for it, so reads can
+    /// be spread over all peers. Fails with [`Error::NotLeader`] if no
+    /// leader could be reached.
+    ///
+    /// This method must return without blocking on the raft.
+    pub fn read_index(&self) -> impl Future<Output = Result<u64>> {
+        let (reply, rx) = oneshot::channel();
+        let event = Event::ReadIndex {
+            forward: true,
+            reply,
+        };
+        let sent = self.events.unbounded_send(event).is_ok();
+        async move {
+            if !sent {
+                return Err(Error::Killed);
+            }
+            rx.await.unwrap_or(Err(Error::Killed))
+        }
+    }
+
+    /// Hands leadership to another peer, e.g. before shutting this one
+    /// down, so clients don't wait for an election timeout. Resolves once
+    /// another peer took over. Fails with [`Error::NotLeader`] if this peer
+    /// isn't the leader, or no other peer took over in time.
+    ///
+    /// This method must return without blocking on the raft.
+    pub fn transfer_leadership(&self) -> impl Future<Output = Result<()>> {
+        let (reply, rx) = oneshot::channel();
+        let sent = self
+            .events
+            .unbounded_send(Event::TransferLeadership { reply })
+            .is_ok();
+        async move {
+            if !sent {
+                return Err(Error::Killed);
+            }
+            rx.await.unwrap_or(Err(Error::Killed))
+        }
+    }
+
+    /// The read index of a linearizable read this peer may serve on its
+    /// own right now, without a round of heartbeats: `Some` while it's the
+    /// leader with a valid lease. Read at it like at a `read_index`.
+    pub fn lease_read(&self) -> Option<u64> {
+        let lease = self.status.lock().unwrap().lease?;
+        Some(lease.read_index).filter(|_| self.clock.now() < lease.until)
+    }
+
+    /// Changes the lease of the leader, see `Raft::set_lease`.
+    pub fn set_lease(&self, duration: Option<Duration>) {
+        let _ = self.events.unbounded_send(Event::SetLease { duration });
     }
 
     /// The current term of this peer.
     pub fn term(&self) -> u64 {
-        // Your code here.
-        // Example:
-        // self.raft.term
-        crate::your_code_here(())
+        self.status.lock().unwrap().state.term()
     }
 
     /// Whether this peer believes it is the leader.
     pub fn is_leader(&self) -> bool {
-        // Your code here.
-        // Example:
-        // self.raft.leader_id == self.id
-        crate::your_code_here(())
+        self.status.lock().unwrap().state.is_leader()
     }
 
     /// The current state of this peer.
     pub fn get_state(&self) -> State {
-        State {
-            term: self.term(),
-            is_leader: self.is_leader(),
+        self.status.lock().unwrap().state.clone()
+    }
+
+    /// The current state of this peer with election diagnostics.
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Counters and latencies of this peer.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Subscribes to the `ApplyMsg`s sent to apply_ch from now on.
+    ///
+    /// Acknowledge consumed messages with `Subscription::ack`, the log is
+    /// never compacted past the slowest subscriber. Returns `None` if the
+    /// peer has been killed.
+    pub fn subscribe(&self) -> Option<Subscription> {
+        if self.events.is_closed() {
+            return None;
         }
+        Some(self.raft.lock().unwrap().subscribe())
+    }
+
+    /// Peers that are more than `threshold` entries behind this peer,
+    /// empty if this peer isn't the leader.
+    pub fn lagging_peers(&self, threshold: u64) -> Vec<(usize, Lag)> {
+        let status = self.status.lock().unwrap();
+        status
+            .lags
+            .iter()
+            .enumerate()
+            .filter_map(|(peer, lag)| lag.map(|lag| (peer, lag)))
+            .filter(|(_, lag)| lag.entries > threshold)
+            .collect()
     }
 
     /// the tester calls kill() when a Raft instance won't be
@@ -265,6 +1004,15 @@ impl Node {
     /// a VIRTUAL crash in tester, so take care of background
     /// threads you generated with this Raft Node.
     pub fn kill(&self) {
+        if let Some(shutdown) = self.shutdown.lock().unwrap().take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            // The loop itself may kill the node, it must not join itself.
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
         // Your code here, if desired.
     }
 }
@@ -275,7 +1023,57 @@ impl RaftService for Node {
     //
     // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
     async fn request_vote(&self, args: RequestVoteArgs) -> labrpc::Result<RequestVoteReply> {
-        // Your code here (2A, 2B).
-        crate::your_code_here(args)
+        let (reply, rx) = oneshot::channel();
+        self.events
+            .unbounded_send(Event::RequestVote { args, reply })
+            .map_err(|_| labrpc::Error::Stopped)?;
+        rx.await.map_err(labrpc::Error::Recv)
+    }
+
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn append_entries(&self, args: AppendEntriesArgs) -> labrpc::Result<AppendEntriesReply> {
+        let (reply, rx) = oneshot::channel();
+        self.events
+            .unbounded_send(Event::AppendEntries { args, reply })
+            .map_err(|_| labrpc::Error::Stopped)?;
+        rx.await.map_err(labrpc::Error::Recv)
+    }
+
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn install_snapshot(
+        &self,
+        args: InstallSnapshotArgs,
+    ) -> labrpc::Result<InstallSnapshotReply> {
+        let (reply, rx) = oneshot::channel();
+        self.events
+            .unbounded_send(Event::InstallSnapshot { args, reply })
+            .map_err(|_| labrpc::Error::Stopped)?;
+        rx.await.map_err(labrpc::Error::Recv)
+    }
+
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn read_index(&self, args: ReadIndexArgs) -> labrpc::Result<ReadIndexReply> {
+        let (reply, rx) = oneshot::channel();
+        self.events
+            .unbounded_send(Event::ReadIndex {
+                forward: false,
+                reply,
+            })
+            .map_err(|_| labrpc::Error::Stopped)?;
+        let res = rx.await.map_err(labrpc::Error::Recv)?;
+        Ok(ReadIndexReply {
+            trace_id: args.trace_id,
+            not_leader: res.is_err(),
+            read_index: res.unwrap_or(0),
+        })
+    }
+
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn timeout_now(&self, args: TimeoutNowArgs) -> labrpc::Result<TimeoutNowReply> {
+        let (reply, rx) = oneshot::channel();
+        self.events
+            .unbounded_send(Event::TimeoutNow { args, reply })
+            .map_err(|_| labrpc::Error::Stopped)?;
+        rx.await.map_err(labrpc::Error::Recv)
     }
 }
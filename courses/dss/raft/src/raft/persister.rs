@@ -5,105 +5,742 @@
 //! so, while you can modify this code to help you debug, please
 //! test with the original before submitting.
 
+#[cfg(any(feature = "sled-persister", feature = "rocksdb-persister"))]
+mod db;
+#[cfg(feature = "encryption")]
+mod encrypted;
+mod faulty;
+mod file;
+#[cfg(feature = "mmap-persister")]
+mod mmap;
+mod record;
+mod wal;
+
+use std::io::{Cursor, Read, Write};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{error, fmt, io, result};
+
+use bytes::Bytes;
+use futures::future::{self, BoxFuture, FutureExt};
+
+use super::compress::Compression;
+use super::hard_state::{self, HardState};
+use super::metrics::Histogram;
+
+#[cfg(feature = "rocksdb-persister")]
+pub use self::db::RocksDbPersister;
+#[cfg(feature = "sled-persister")]
+pub use self::db::SledPersister;
+#[cfg(feature = "encryption")]
+pub use self::encrypted::{EncryptedPersister, KEY_LEN};
+pub use self::faulty::{Faults, FaultyPersister};
+pub use self::file::FilePersister;
+#[cfg(feature = "mmap-persister")]
+pub use self::mmap::{Mapped, MmapPersister};
+pub use self::wal::WalPersister;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The persisted data fails its checks, e.g. a checksum mismatch.
+    Corruption(String),
+    /// The persisted data is compressed in a way this build can't read.
+    Decompress(String),
+    /// Encrypting failed, or the persisted data is encrypted with an
+    /// unknown key.
+    Encrypt(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
 
+/// What a persister has saved so far, see `Persister::stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub saves: u64,
+    /// Bytes handed to the backend, including record headers.
+    pub bytes_written: u64,
+    pub fsyncs: u64,
+    pub save_latency: Histogram,
+}
+
+impl Stats {
+    /// Records a save of `bytes` bytes that took `fsyncs` fsyncs and
+    /// `latency` in total.
+    pub fn record(&mut self, bytes: u64, fsyncs: u64, latency: Duration) {
+        self.saves += 1;
+        self.bytes_written += bytes;
+        self.fsyncs += fsyncs;
+        self.save_latency.observe(latency);
+    }
+
+    pub fn merge(&mut self, other: &Stats) {
+        self.saves += other.saves;
+        self.bytes_written += other.bytes_written;
+        self.fsyncs += other.fsyncs;
+        self.save_latency.merge(&other.save_latency);
+    }
+}
+
+/// Saving may fail, e.g. when the state lives on a disk. The state saved
+/// before a failed save is kept.
+///
+/// The `_async` variants of saves let callers running on an executor wait
+/// for a slow backend without blocking it. Their futures are independent of
+/// `self`, and saves take effect in the order they're issued, whether
+/// they're awaited or not. By default they save before returning.
+///
+/// Reading fails with `Error::Corruption` instead of returning data that
+/// doesn't match what was saved. Nothing saved reads as empty. Reads return
+/// `Bytes`, so persisters that keep the state in memory can share it
+/// instead of copying it.
 pub trait Persister: Send + 'static {
-    fn raft_state(&self) -> Vec<u8>;
-    fn save_raft_state(&self, state: Vec<u8>);
-    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>);
-    fn snapshot(&self) -> Vec<u8>;
+    fn raft_state(&self) -> Result<Bytes>;
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()>;
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()>;
+    fn snapshot(&self) -> Result<Bytes>;
+
+    /// Stats of the saves so far. Persisters that don't keep any return
+    /// empty stats.
+    fn stats(&self) -> Stats {
+        Stats::default()
+    }
+
+    /// Saves only the term and vote of the raft state, `voted_for` as in
+    /// `HardState`. Persisters may keep them apart from the rest of the
+    /// raft state, a much smaller write than `save_raft_state`, and put
+    /// them back in when reading with `hard_state::merge`. By default the
+    /// whole raft state is saved again.
+    fn save_hard_state(&self, term: u64, voted_for: u64) -> Result<()> {
+        let state = self.raft_state()?;
+        let state = merge_hard_state(state, &encode_hard_state(term, voted_for))?;
+        self.save_raft_state(state.to_vec())
+    }
+
+    fn save_raft_state_async(&self, state: Vec<u8>) -> BoxFuture<'static, Result<()>> {
+        future::ready(self.save_raft_state(state)).boxed()
+    }
+
+    fn save_state_and_snapshot_async(
+        &self,
+        state: Vec<u8>,
+        snapshot: Vec<u8>,
+    ) -> BoxFuture<'static, Result<()>> {
+        future::ready(self.save_state_and_snapshot(state, snapshot)).boxed()
+    }
+
+    /// Saves `snapshot` while the caller goes on and calls `done` with the
+    /// result, maybe from another thread, once it's durable. Unlike the
+    /// other saves it may take effect after saves issued later, though never
+    /// after a later snapshot. The raft state is kept as it is, so the
+    /// caller must keep the log entries the snapshot covers until `done` is
+    /// called, and save the truncated raft state then. By default the
+    /// snapshot is saved before returning.
+    fn save_snapshot_in_background(&self, snapshot: Bytes, done: SnapshotSaved) {
+        let res = self
+            .raft_state()
+            .and_then(|state| self.save_state_and_snapshot(state.to_vec(), snapshot.to_vec()));
+        done(res)
+    }
+
+    /// Streams the snapshot, for snapshots too large to read at once.
+    /// Corruption fails a read with `io::ErrorKind::InvalidData`.
+    fn snapshot_reader(&self) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(Cursor::new(self.snapshot()?)))
+    }
+
+    /// Streams a new snapshot, for snapshots too large to build at once.
+    /// The snapshot is saved by `SnapshotWriter::commit`.
+    fn snapshot_writer(&self) -> Result<Box<dyn SnapshotWriter + '_>> {
+        Ok(Box::new(BufferedSnapshotWriter {
+            persister: self,
+            snapshot: vec![],
+        }))
+    }
+
+    /// Stages `data` at `offset` of an incoming snapshot, e.g. a chunk of an
+    /// InstallSnapshot RPC, and returns the number of bytes staged. Offset 0
+    /// starts a new snapshot and a chunk may be staged again, but a chunk
+    /// past the staged bytes fails. `snapshot` doesn't return the staged
+    /// snapshot until it's promoted.
+    fn stage_snapshot(&self, _offset: u64, _data: &[u8]) -> Result<u64> {
+        Err(unsupported("staging snapshots"))
+    }
+
+    /// Saves the staged snapshot together with `state`, the raft state
+    /// truncated to it, like `save_state_and_snapshot`: a crash leaves
+    /// either the old or the new state and snapshot, never a mix. Fails if
+    /// nothing is staged.
+    fn promote_snapshot(&self, _state: Vec<u8>) -> Result<()> {
+        Err(unsupported("staging snapshots"))
+    }
+
+    /// Drops the staged snapshot, if any.
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Called when a snapshot saved by `Persister::save_snapshot_in_background`
+/// is durable, or failed to save.
+pub type SnapshotSaved = Box<dyn FnOnce(Result<()>) + Send>;
+
+const HARD_STATE_LEN: usize = 16;
+
+/// Encodes a term and vote kept apart from the raft state, see
+/// `Persister::save_hard_state`.
+fn encode_hard_state(term: u64, voted_for: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HARD_STATE_LEN);
+    data.extend_from_slice(&term.to_le_bytes());
+    data.extend_from_slice(&voted_for.to_le_bytes());
+    data
+}
+
+/// Puts a term and vote encoded by `encode_hard_state` back into the raft
+/// state, nothing if `hard_state` is empty.
+fn merge_hard_state(state: Bytes, hard_state: &[u8]) -> Result<Bytes> {
+    if hard_state.is_empty() {
+        return Ok(state);
+    }
+    if hard_state.len() != HARD_STATE_LEN {
+        return Err(Error::Corruption(format!(
+            "hard state should be {} bytes, got {}",
+            HARD_STATE_LEN,
+            hard_state.len()
+        )));
+    }
+    let mut word = [0; 8];
+    word.copy_from_slice(&hard_state[..8]);
+    let term = u64::from_le_bytes(word);
+    word.copy_from_slice(&hard_state[8..]);
+    let voted_for = u64::from_le_bytes(word);
+    hard_state::merge(&state, &HardState { term, voted_for })
+        .map(Bytes::from)
+        .map_err(|e| Error::Corruption(e.to_string()))
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} is not supported", what),
+    ))
+}
+
+fn check_stage_offset(offset: u64, staged: u64) -> Result<()> {
+    if offset > staged {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "snapshot chunk at {} leaves a gap after {} staged bytes",
+                offset, staged
+            ),
+        )));
+    }
+    Ok(())
+}
+
+fn nothing_staged() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "no snapshot is staged",
+    ))
+}
+
+/// A snapshot staged in memory, for persisters that don't stage on disk.
+/// A crash discards it.
+#[derive(Default)]
+struct Staged(Mutex<Option<Vec<u8>>>);
+
+impl Staged {
+    fn stage(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        let mut staged = self.0.lock().unwrap();
+        if offset == 0 {
+            *staged = Some(vec![]);
+        }
+        check_stage_offset(offset, staged.as_ref().map_or(0, |s| s.len() as u64))?;
+        // staged if offset is 0 or less than what's staged.
+        let snapshot = staged.as_mut().unwrap();
+        snapshot.truncate(offset as usize);
+        snapshot.extend_from_slice(data);
+        Ok(snapshot.len() as u64)
+    }
+
+    /// Takes the staged snapshot, for promoting it.
+    fn take(&self) -> Result<Vec<u8>> {
+        self.0.lock().unwrap().take().ok_or_else(nothing_staged)
+    }
+
+    fn discard(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+pub trait SnapshotWriter: Write {
+    /// Saves the written snapshot together with `state`, like
+    /// `Persister::save_state_and_snapshot`. A writer dropped before it's
+    /// committed saves nothing.
+    fn commit(self: Box<Self>, state: Vec<u8>) -> Result<()>;
+}
+
+/// Buffers the snapshot for persisters that don't stream.
+struct BufferedSnapshotWriter<'a, P: ?Sized> {
+    persister: &'a P,
+    snapshot: Vec<u8>,
+}
+
+impl<P: ?Sized + Persister> Write for BufferedSnapshotWriter<'_, P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.snapshot.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<P: ?Sized + Persister> SnapshotWriter for BufferedSnapshotWriter<'_, P> {
+    fn commit(self: Box<Self>, state: Vec<u8>) -> Result<()> {
+        self.persister.save_state_and_snapshot(state, self.snapshot)
+    }
 }
 
 impl<T: ?Sized + Persister> Persister for Box<T> {
-    fn raft_state(&self) -> Vec<u8> {
+    fn raft_state(&self) -> Result<Bytes> {
         (**self).raft_state()
     }
-    fn save_raft_state(&self, state: Vec<u8>) {
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()> {
         (**self).save_raft_state(state)
     }
-    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()> {
         (**self).save_state_and_snapshot(state, snapshot)
     }
-    fn snapshot(&self) -> Vec<u8> {
+    fn snapshot(&self) -> Result<Bytes> {
         (**self).snapshot()
     }
+    fn stats(&self) -> Stats {
+        (**self).stats()
+    }
+    fn save_hard_state(&self, term: u64, voted_for: u64) -> Result<()> {
+        (**self).save_hard_state(term, voted_for)
+    }
+    fn save_raft_state_async(&self, state: Vec<u8>) -> BoxFuture<'static, Result<()>> {
+        (**self).save_raft_state_async(state)
+    }
+    fn save_state_and_snapshot_async(
+        &self,
+        state: Vec<u8>,
+        snapshot: Vec<u8>,
+    ) -> BoxFuture<'static, Result<()>> {
+        (**self).save_state_and_snapshot_async(state, snapshot)
+    }
+    fn save_snapshot_in_background(&self, snapshot: Bytes, done: SnapshotSaved) {
+        (**self).save_snapshot_in_background(snapshot, done)
+    }
+    fn snapshot_reader(&self) -> Result<Box<dyn Read + '_>> {
+        (**self).snapshot_reader()
+    }
+    fn snapshot_writer(&self) -> Result<Box<dyn SnapshotWriter + '_>> {
+        (**self).snapshot_writer()
+    }
+    fn stage_snapshot(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        (**self).stage_snapshot(offset, data)
+    }
+    fn promote_snapshot(&self, state: Vec<u8>) -> Result<()> {
+        (**self).promote_snapshot(state)
+    }
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        (**self).discard_staged_snapshot()
+    }
 }
 
 impl<T: ?Sized + Sync + Persister> Persister for Arc<T> {
-    fn raft_state(&self) -> Vec<u8> {
+    fn raft_state(&self) -> Result<Bytes> {
         (**self).raft_state()
     }
-    fn save_raft_state(&self, state: Vec<u8>) {
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()> {
         (**self).save_raft_state(state)
     }
-    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()> {
         (**self).save_state_and_snapshot(state, snapshot)
     }
-    fn snapshot(&self) -> Vec<u8> {
+    fn snapshot(&self) -> Result<Bytes> {
         (**self).snapshot()
     }
+    fn stats(&self) -> Stats {
+        (**self).stats()
+    }
+    fn save_hard_state(&self, term: u64, voted_for: u64) -> Result<()> {
+        (**self).save_hard_state(term, voted_for)
+    }
+    fn save_raft_state_async(&self, state: Vec<u8>) -> BoxFuture<'static, Result<()>> {
+        (**self).save_raft_state_async(state)
+    }
+    fn save_state_and_snapshot_async(
+        &self,
+        state: Vec<u8>,
+        snapshot: Vec<u8>,
+    ) -> BoxFuture<'static, Result<()>> {
+        (**self).save_state_and_snapshot_async(state, snapshot)
+    }
+    fn save_snapshot_in_background(&self, snapshot: Bytes, done: SnapshotSaved) {
+        (**self).save_snapshot_in_background(snapshot, done)
+    }
+    fn snapshot_reader(&self) -> Result<Box<dyn Read + '_>> {
+        (**self).snapshot_reader()
+    }
+    fn snapshot_writer(&self) -> Result<Box<dyn SnapshotWriter + '_>> {
+        (**self).snapshot_writer()
+    }
+    fn stage_snapshot(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        (**self).stage_snapshot(offset, data)
+    }
+    fn promote_snapshot(&self, state: Vec<u8>) -> Result<()> {
+        (**self).promote_snapshot(state)
+    }
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        (**self).discard_staged_snapshot()
+    }
 }
 
-#[derive(Default)]
+/// Keeps the state in memory, in the same checksummed records as
+/// `FilePersister` keeps on disk.
+///
+/// Reads share the saved records instead of copying them, and so do forks.
 pub struct SimplePersister {
     states: Mutex<(
-        Vec<u8>, // raft state
-        Vec<u8>, // snapshot
+        Bytes, // raft state record
+        Bytes, // snapshot record
+        Bytes, // hard state record
     )>,
+    compression: Compression,
+    stats: Mutex<Stats>,
+    staged: Staged,
 }
 
 impl SimplePersister {
     pub fn new() -> SimplePersister {
         SimplePersister {
             states: Mutex::default(),
+            compression: Compression::None,
+            stats: Mutex::default(),
+            staged: Staged::default(),
         }
     }
+
+    /// A new persister holding what this one has saved, with fresh stats.
+    /// Like a crash, it drops the staged snapshot.
+    pub fn fork(&self) -> SimplePersister {
+        SimplePersister {
+            states: Mutex::new(self.states.lock().unwrap().clone()),
+            compression: self.compression,
+            stats: Mutex::default(),
+            staged: Staged::default(),
+        }
+    }
+
+    /// Compresses what is saved from now on with `kind`, see
+    /// `compress::supported`.
+    pub fn with_compression(mut self, kind: Compression) -> SimplePersister {
+        self.compression = kind;
+        self
+    }
+}
+
+impl Default for SimplePersister {
+    fn default() -> SimplePersister {
+        SimplePersister::new()
+    }
 }
 
 impl Persister for SimplePersister {
-    fn raft_state(&self) -> Vec<u8> {
-        self.states.lock().unwrap().0.clone()
+    fn raft_state(&self) -> Result<Bytes> {
+        let states = self.states.lock().unwrap();
+        merge_hard_state(
+            record::decode_bytes(&states.0)?,
+            &record::decode(&states.2)?,
+        )
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let state = record::encode_with(self.compression, &state);
+        let bytes = state.len() as u64;
+        self.states.lock().unwrap().0 = state.into();
+        self.stats.lock().unwrap().record(bytes, 0, start.elapsed());
+        Ok(())
     }
 
-    fn save_raft_state(&self, state: Vec<u8>) {
-        self.states.lock().unwrap().0 = state;
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let state = record::encode_with(self.compression, &state);
+        let snapshot = record::encode_with(self.compression, &snapshot);
+        let bytes = (state.len() + snapshot.len()) as u64;
+        let mut states = self.states.lock().unwrap();
+        states.0 = state.into();
+        states.1 = snapshot.into();
+        self.stats.lock().unwrap().record(bytes, 0, start.elapsed());
+        Ok(())
     }
 
-    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
-        self.states.lock().unwrap().0 = state;
-        self.states.lock().unwrap().1 = snapshot;
+    fn snapshot(&self) -> Result<Bytes> {
+        record::decode_bytes(&self.states.lock().unwrap().1)
     }
 
-    fn snapshot(&self) -> Vec<u8> {
-        self.states.lock().unwrap().1.clone()
+    fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    fn save_hard_state(&self, term: u64, voted_for: u64) -> Result<()> {
+        let start = Instant::now();
+        let hard_state = record::encode(&encode_hard_state(term, voted_for));
+        let bytes = hard_state.len() as u64;
+        self.states.lock().unwrap().2 = hard_state.into();
+        self.stats.lock().unwrap().record(bytes, 0, start.elapsed());
+        Ok(())
+    }
+
+    fn stage_snapshot(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        self.staged.stage(offset, data)
+    }
+
+    fn promote_snapshot(&self, state: Vec<u8>) -> Result<()> {
+        self.save_state_and_snapshot(state, self.staged.take()?)
+    }
+
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        self.staged.discard();
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::mpsc;
+
+    use futures::executor::block_on;
+
+    use super::super::compress;
     use super::*;
 
     #[test]
     fn test_object_safety() {
         let sp = SimplePersister::new();
-        sp.save_raft_state(vec![111]);
+        sp.save_raft_state(vec![111]).unwrap();
         let obj: Box<dyn Persister + Sync> = Box::new(sp);
-        assert_eq!(obj.raft_state(), vec![111]);
-        obj.save_state_and_snapshot(vec![222], vec![123]);
-        assert_eq!(obj.raft_state(), vec![222]);
-        assert_eq!(obj.snapshot(), vec![123]);
+        assert_eq!(obj.raft_state().unwrap(), vec![111]);
+        obj.save_state_and_snapshot(vec![222], vec![123]).unwrap();
+        assert_eq!(obj.raft_state().unwrap(), vec![222]);
+        assert_eq!(obj.snapshot().unwrap(), vec![123]);
 
         let cloneable_obj: Arc<dyn Persister> = Arc::new(obj);
-        assert_eq!(cloneable_obj.raft_state(), vec![222]);
-        assert_eq!(cloneable_obj.snapshot(), vec![123]);
+        assert_eq!(cloneable_obj.raft_state().unwrap(), vec![222]);
+        assert_eq!(cloneable_obj.snapshot().unwrap(), vec![123]);
 
         let cloneable_obj_ = cloneable_obj.clone();
-        cloneable_obj.save_raft_state(vec![233]);
-        assert_eq!(cloneable_obj_.raft_state(), vec![233]);
-        assert_eq!(cloneable_obj_.snapshot(), vec![123]);
+        cloneable_obj.save_raft_state(vec![233]).unwrap();
+        assert_eq!(cloneable_obj_.raft_state().unwrap(), vec![233]);
+        assert_eq!(cloneable_obj_.snapshot().unwrap(), vec![123]);
 
         let sp = SimplePersister::new();
         let obj: Arc<dyn Persister + Sync> = Arc::new(sp);
         let _box_obj: Box<dyn Persister> = Box::new(obj);
     }
+
+    #[test]
+    fn test_save_async() {
+        let sp: Box<dyn Persister> = Box::new(SimplePersister::new());
+        let saved = sp.save_state_and_snapshot_async(vec![1], vec![2]);
+        let saved_again = sp.save_raft_state_async(vec![3]);
+        block_on(saved_again).unwrap();
+        block_on(saved).unwrap();
+        assert_eq!(sp.raft_state().unwrap(), vec![3]);
+        assert_eq!(sp.snapshot().unwrap(), vec![2]);
+    }
+
+    /// Saves a snapshot of `p` in the background and waits for it. The raft
+    /// state must be kept.
+    pub(super) fn check_save_in_background<P: Persister>(p: &P) {
+        let state = p.raft_state().unwrap();
+        let (tx, rx) = mpsc::channel();
+        p.save_snapshot_in_background(
+            Bytes::from(vec![7; 100]),
+            Box::new(move |res| tx.send(res).unwrap()),
+        );
+        rx.recv().unwrap().unwrap();
+        assert_eq!(p.raft_state().unwrap(), state);
+        assert_eq!(p.snapshot().unwrap(), vec![7; 100]);
+    }
+
+    #[test]
+    fn test_save_in_background() {
+        let sp = SimplePersister::new();
+        sp.save_state_and_snapshot(vec![1], vec![2]).unwrap();
+        check_save_in_background(&sp);
+    }
+
+    #[test]
+    fn test_snapshot_stream() {
+        let sp = SimplePersister::new();
+        sp.save_state_and_snapshot(vec![1], vec![2]).unwrap();
+        let mut w = sp.snapshot_writer().unwrap();
+        w.write_all(&[3; 10]).unwrap();
+        drop(w);
+        assert_eq!(sp.snapshot().unwrap(), vec![2]);
+
+        let mut w = sp.snapshot_writer().unwrap();
+        w.write_all(&[3; 10]).unwrap();
+        w.write_all(&[4; 10]).unwrap();
+        w.commit(vec![5]).unwrap();
+        assert_eq!(sp.raft_state().unwrap(), vec![5]);
+        let mut snapshot = vec![];
+        sp.snapshot_reader()
+            .unwrap()
+            .read_to_end(&mut snapshot)
+            .unwrap();
+        assert_eq!(snapshot, [[3; 10], [4; 10]].concat());
+    }
+
+    /// Stages a snapshot in chunks and promotes it, `p` holds state 1 and
+    /// snapshot 2 to begin with.
+    pub(super) fn check_staged_snapshot<P: Persister>(p: &P) {
+        assert!(p.promote_snapshot(vec![3]).is_err());
+        assert!(p.stage_snapshot(4, &[4; 4]).is_err());
+        assert_eq!(p.stage_snapshot(0, &[4; 4]).unwrap(), 4);
+        assert_eq!(p.stage_snapshot(4, &[5; 4]).unwrap(), 8);
+        // a chunk sent again.
+        assert_eq!(p.stage_snapshot(4, &[5; 4]).unwrap(), 8);
+        assert!(p.stage_snapshot(9, &[6; 4]).is_err());
+        assert_eq!(p.snapshot().unwrap(), vec![2]);
+
+        p.promote_snapshot(vec![3]).unwrap();
+        assert_eq!(p.raft_state().unwrap(), vec![3]);
+        assert_eq!(p.snapshot().unwrap(), [[4; 4], [5; 4]].concat());
+        assert!(p.promote_snapshot(vec![4]).is_err());
+
+        p.stage_snapshot(0, &[6; 4]).unwrap();
+        p.discard_staged_snapshot().unwrap();
+        assert!(p.promote_snapshot(vec![4]).is_err());
+        assert_eq!(p.raft_state().unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn test_staged_snapshot() {
+        let sp = SimplePersister::new();
+        sp.save_state_and_snapshot(vec![1], vec![2]).unwrap();
+        check_staged_snapshot(&sp);
+
+        // a crash drops the staged snapshot.
+        sp.stage_snapshot(0, &[7; 4]).unwrap();
+        assert!(sp.fork().promote_snapshot(vec![8]).is_err());
+    }
+
+    /// Saves terms and votes apart from the raft state.
+    pub(super) fn check_hard_state<P: Persister>(p: &P) {
+        let hs = |term, voted_for| HardState { term, voted_for };
+        let load = |p: &P| {
+            let (hs, log) = hard_state::decode(&p.raft_state().unwrap()).unwrap();
+            (hs, log.unwrap())
+        };
+        p.save_raft_state(hard_state::encode(&hs(1, 0), b"log 1".to_vec()))
+            .unwrap();
+        p.save_hard_state(1, 2).unwrap();
+        assert_eq!(load(p), (hs(1, 2), b"log 1".to_vec()));
+
+        // Raft state saved with an older vote doesn't take it back.
+        p.save_raft_state(hard_state::encode(&hs(1, 0), b"log 2".to_vec()))
+            .unwrap();
+        assert_eq!(load(p), (hs(1, 2), b"log 2".to_vec()));
+        p.save_raft_state(hard_state::encode(&hs(2, 0), b"log 3".to_vec()))
+            .unwrap();
+        assert_eq!(load(p), (hs(2, 0), b"log 3".to_vec()));
+    }
+
+    #[test]
+    fn test_hard_state() {
+        let sp = SimplePersister::new();
+        sp.save_hard_state(1, 1).unwrap();
+        let (hs, log) = hard_state::decode(&sp.raft_state().unwrap()).unwrap();
+        assert_eq!((hs.term, hs.voted_for), (1, 1));
+        assert!(log.unwrap().is_empty());
+        check_hard_state(&sp);
+        assert_eq!(sp.fork().raft_state().unwrap(), sp.raft_state().unwrap());
+        assert_eq!(sp.stats().saves, 5);
+    }
+
+    #[test]
+    fn test_shared_reads() {
+        let sp = SimplePersister::new();
+        sp.save_state_and_snapshot(vec![1; 100], vec![2; 100])
+            .unwrap();
+        let fork = sp.fork();
+        let state = sp.raft_state().unwrap();
+        assert_eq!(state, vec![1; 100]);
+        assert_eq!(state.as_ptr(), fork.raft_state().unwrap().as_ptr());
+        assert_eq!(
+            sp.snapshot().unwrap().as_ptr(),
+            fork.snapshot().unwrap().as_ptr()
+        );
+
+        // Saving doesn't change what was read before.
+        sp.save_raft_state(vec![3; 100]).unwrap();
+        assert_eq!(state, vec![1; 100]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let sp = SimplePersister::new();
+        sp.save_raft_state(vec![1; 10]).unwrap();
+        sp.save_state_and_snapshot(vec![2; 10], vec![3; 20])
+            .unwrap();
+        let stats = sp.stats();
+        assert_eq!(stats.saves, 2);
+        assert_eq!(stats.save_latency.count(), 2);
+        assert_eq!(stats.bytes_written, 10 + 10 + 20 + 3 * 12);
+
+        let forked = sp.fork();
+        assert_eq!(forked.stats(), Stats::default());
+        assert_eq!(forked.snapshot().unwrap(), vec![3; 20]);
+
+        let obj: Arc<dyn Persister + Sync> = Arc::new(sp);
+        let mut merged = obj.stats();
+        merged.merge(&stats);
+        assert_eq!(merged.saves, 4);
+    }
+
+    #[test]
+    fn test_compression() {
+        let sp = SimplePersister::new();
+        sp.save_raft_state(vec![1; 100]).unwrap();
+        let old = sp.states.lock().unwrap().clone();
+
+        // Uncompressed state saved before still loads.
+        let sp = SimplePersister::new().with_compression(compress::preferred());
+        *sp.states.lock().unwrap() = old;
+        assert_eq!(sp.raft_state().unwrap(), vec![1; 100]);
+        sp.save_state_and_snapshot(vec![2; 100], vec![3; 100])
+            .unwrap();
+        assert_eq!(sp.raft_state().unwrap(), vec![2; 100]);
+        assert_eq!(sp.snapshot().unwrap(), vec![3; 100]);
+    }
 }
@@ -0,0 +1,265 @@
+//! Persisters backed by embedded databases, so a kvraft server can run as a
+//! durable service outside of the tester. Enable them with the
+//! `sled-persister` and `rocksdb-persister` features.
+//!
+//! Both keep the raft state, the snapshot and the term and vote saved by
+//! `save_hard_state` as checksummed records under three keys, and write
+//! both keys of `save_state_and_snapshot` in one atomic batch. Snapshots
+//! are staged in memory.
+
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use super::{encode_hard_state, merge_hard_state, record, Error, Persister, Result, Staged, Stats};
+use crate::raft::compress::Compression;
+
+const RAFT_STATE_KEY: &[u8] = b"raft_state";
+const SNAPSHOT_KEY: &[u8] = b"snapshot";
+const HARD_STATE_KEY: &[u8] = b"hard_state";
+
+fn db_error<E: ToString>(e: E) -> Error {
+    Error::Io(io::Error::other(e.to_string()))
+}
+
+#[cfg(feature = "sled-persister")]
+pub struct SledPersister {
+    db: sled::Db,
+    compression: Compression,
+    stats: Mutex<Stats>,
+    staged: Staged,
+}
+
+#[cfg(feature = "sled-persister")]
+impl SledPersister {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SledPersister> {
+        Ok(SledPersister {
+            db: sled::open(path).map_err(db_error)?,
+            compression: Compression::None,
+            stats: Mutex::default(),
+            staged: Staged::default(),
+        })
+    }
+
+    /// Compresses what is saved from now on with `kind`.
+    pub fn with_compression(mut self, kind: Compression) -> SledPersister {
+        self.compression = kind;
+        self
+    }
+
+    fn read(&self, key: &[u8]) -> Result<Vec<u8>> {
+        match self.db.get(key).map_err(db_error)? {
+            Some(value) => record::decode(&value),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Writes `data` of each key as records in one batch.
+    fn write(&self, data: &[(&[u8], &[u8])]) -> Result<()> {
+        let start = Instant::now();
+        let mut batch = sled::Batch::default();
+        let mut bytes = 0;
+        for (key, data) in data {
+            let value = record::encode_with(self.compression, data);
+            bytes += value.len() as u64;
+            batch.insert(*key, value);
+        }
+        self.db.apply_batch(batch).map_err(db_error)?;
+        self.db.flush().map_err(db_error)?;
+        self.stats.lock().unwrap().record(bytes, 1, start.elapsed());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sled-persister")]
+impl Persister for SledPersister {
+    fn raft_state(&self) -> Result<Bytes> {
+        merge_hard_state(
+            self.read(RAFT_STATE_KEY)?.into(),
+            &self.read(HARD_STATE_KEY)?,
+        )
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()> {
+        self.write(&[(RAFT_STATE_KEY, &state)])
+    }
+
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()> {
+        self.write(&[(RAFT_STATE_KEY, &state), (SNAPSHOT_KEY, &snapshot)])
+    }
+
+    fn snapshot(&self) -> Result<Bytes> {
+        self.read(SNAPSHOT_KEY).map(Bytes::from)
+    }
+
+    fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    fn save_hard_state(&self, term: u64, voted_for: u64) -> Result<()> {
+        self.write(&[(HARD_STATE_KEY, &encode_hard_state(term, voted_for))])
+    }
+
+    fn stage_snapshot(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        self.staged.stage(offset, data)
+    }
+
+    fn promote_snapshot(&self, state: Vec<u8>) -> Result<()> {
+        self.save_state_and_snapshot(state, self.staged.take()?)
+    }
+
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        self.staged.discard();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rocksdb-persister")]
+pub struct RocksDbPersister {
+    db: rocksdb::DB,
+    compression: Compression,
+    stats: Mutex<Stats>,
+    staged: Staged,
+}
+
+#[cfg(feature = "rocksdb-persister")]
+impl RocksDbPersister {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RocksDbPersister> {
+        Ok(RocksDbPersister {
+            db: rocksdb::DB::open_default(path).map_err(db_error)?,
+            compression: Compression::None,
+            stats: Mutex::default(),
+            staged: Staged::default(),
+        })
+    }
+
+    /// Compresses what is saved from now on with `kind`.
+    pub fn with_compression(mut self, kind: Compression) -> RocksDbPersister {
+        self.compression = kind;
+        self
+    }
+
+    fn read(&self, key: &[u8]) -> Result<Vec<u8>> {
+        match self.db.get(key).map_err(db_error)? {
+            Some(value) => record::decode(&value),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Writes `data` of each key as records in one synced batch.
+    fn write(&self, data: &[(&[u8], &[u8])]) -> Result<()> {
+        let start = Instant::now();
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut bytes = 0;
+        for (key, data) in data {
+            let value = record::encode_with(self.compression, data);
+            bytes += value.len() as u64;
+            batch.put(key, value);
+        }
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        self.db.write_opt(batch, &opts).map_err(db_error)?;
+        self.stats.lock().unwrap().record(bytes, 1, start.elapsed());
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rocksdb-persister")]
+impl Persister for RocksDbPersister {
+    fn raft_state(&self) -> Result<Bytes> {
+        merge_hard_state(
+            self.read(RAFT_STATE_KEY)?.into(),
+            &self.read(HARD_STATE_KEY)?,
+        )
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()> {
+        self.write(&[(RAFT_STATE_KEY, &state)])
+    }
+
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()> {
+        self.write(&[(RAFT_STATE_KEY, &state), (SNAPSHOT_KEY, &snapshot)])
+    }
+
+    fn snapshot(&self) -> Result<Bytes> {
+        self.read(SNAPSHOT_KEY).map(Bytes::from)
+    }
+
+    fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    fn save_hard_state(&self, term: u64, voted_for: u64) -> Result<()> {
+        self.write(&[(HARD_STATE_KEY, &encode_hard_state(term, voted_for))])
+    }
+
+    fn stage_snapshot(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        self.staged.stage(offset, data)
+    }
+
+    fn promote_snapshot(&self, state: Vec<u8>) -> Result<()> {
+        self.save_state_and_snapshot(state, self.staged.take()?)
+    }
+
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        self.staged.discard();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("raft-{}-persister-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Saves, reopens with `open` and checks everything survived.
+    fn check_durable<P: Persister, F: Fn(&Path) -> P>(dir: &Path, open: F) {
+        let p = open(dir);
+        assert!(p.raft_state().unwrap().is_empty());
+        assert!(p.snapshot().unwrap().is_empty());
+        p.save_raft_state(vec![1, 2, 3]).unwrap();
+        p.save_state_and_snapshot(vec![4], vec![5, 6]).unwrap();
+        p.save_raft_state(vec![7]).unwrap();
+        drop(p);
+
+        let p = open(dir);
+        assert_eq!(p.raft_state().unwrap(), vec![7]);
+        assert_eq!(p.snapshot().unwrap(), vec![5, 6]);
+        assert_eq!(p.stats().saves, 0);
+
+        p.save_state_and_snapshot(vec![1], vec![2]).unwrap();
+        super::super::tests::check_staged_snapshot(&p);
+        super::super::tests::check_hard_state(&p);
+    }
+
+    #[cfg(feature = "sled-persister")]
+    #[test]
+    fn test_sled_persister() {
+        let dir = temp_dir("sled");
+        check_durable(&dir, |dir| SledPersister::open(dir).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "rocksdb-persister")]
+    #[test]
+    fn test_rocksdb_persister() {
+        let dir = temp_dir("rocksdb");
+        check_durable(&dir, |dir| RocksDbPersister::open(dir).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+//! Encryption at rest, enable it with the `encryption` feature.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use bytes::Bytes;
+use rand::Rng;
+
+use super::{Error, Persister, Result, SnapshotSaved, Staged, Stats};
+
+pub const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+struct Keys {
+    // encrypts new saves.
+    current: u32,
+    ciphers: HashMap<u32, Aes256Gcm>,
+}
+
+/// Encrypts the raft state and snapshots saved with another persister with
+/// AES-256-GCM.
+///
+/// Saved data is `| key id: u32 LE | nonce | ciphertext |`, so keys can be
+/// rotated: after `rotate`, new saves use the new key while data saved
+/// with older keys still loads as long as they're kept. `reencrypt` saves
+/// everything again with the current key, after which older keys can be
+/// removed with `remove_key`.
+///
+/// Snapshots are staged in memory and encrypted when they're promoted.
+pub struct EncryptedPersister<P> {
+    inner: P,
+    keys: Mutex<Keys>,
+    staged: Staged,
+}
+
+impl<P: Persister> EncryptedPersister<P> {
+    pub fn new(inner: P, key_id: u32, key: &[u8; KEY_LEN]) -> EncryptedPersister<P> {
+        let mut ciphers = HashMap::new();
+        ciphers.insert(key_id, cipher(key));
+        EncryptedPersister {
+            inner,
+            keys: Mutex::new(Keys {
+                current: key_id,
+                ciphers,
+            }),
+            staged: Staged::default(),
+        }
+    }
+
+    /// Adds a key that data may have been saved with.
+    pub fn add_key(&self, key_id: u32, key: &[u8; KEY_LEN]) {
+        self.keys
+            .lock()
+            .unwrap()
+            .ciphers
+            .insert(key_id, cipher(key));
+    }
+
+    /// Encrypts saves from now on with the given key, older keys are kept
+    /// for reading.
+    pub fn rotate(&self, key_id: u32, key: &[u8; KEY_LEN]) {
+        let mut keys = self.keys.lock().unwrap();
+        keys.ciphers.insert(key_id, cipher(key));
+        keys.current = key_id;
+    }
+
+    /// Forgets a key that isn't the current one. Data saved with it doesn't
+    /// load anymore.
+    pub fn remove_key(&self, key_id: u32) {
+        let mut keys = self.keys.lock().unwrap();
+        if key_id != keys.current {
+            keys.ciphers.remove(&key_id);
+        }
+    }
+
+    /// Saves the raft state and snapshot again with the current key.
+    pub fn reencrypt(&self) -> Result<()> {
+        let state = self.raft_state()?;
+        let snapshot = self.snapshot()?;
+        self.save_state_and_snapshot(state.to_vec(), snapshot.to_vec())
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let keys = self.keys.lock().unwrap();
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        let ciphertext = keys.ciphers[&keys.current]
+            .encrypt(GenericArray::from_slice(&nonce), data)
+            .map_err(|_| Error::Encrypt("failed to encrypt".to_owned()))?;
+
+        let mut sealed = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&keys.current.to_le_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn decrypt(&self, sealed: Bytes) -> Result<Bytes> {
+        // nothing saved.
+        if sealed.is_empty() {
+            return Ok(sealed);
+        }
+        if sealed.len() < KEY_ID_LEN + NONCE_LEN {
+            return Err(Error::Corruption(format!(
+                "encrypted data is too short, got {} bytes",
+                sealed.len()
+            )));
+        }
+        let mut key_id = [0; KEY_ID_LEN];
+        key_id.copy_from_slice(&sealed[..KEY_ID_LEN]);
+        let key_id = u32::from_le_bytes(key_id);
+        let (nonce, ciphertext) = sealed[KEY_ID_LEN..].split_at(NONCE_LEN);
+
+        let keys = self.keys.lock().unwrap();
+        let cipher = keys
+            .ciphers
+            .get(&key_id)
+            .ok_or_else(|| Error::Encrypt(format!("unknown key {}", key_id)))?;
+        cipher
+            .decrypt(GenericArray::from_slice(nonce), ciphertext)
+            .map(Bytes::from)
+            .map_err(|_| {
+                Error::Corruption(format!(
+                    "data encrypted with key {} fails to verify",
+                    key_id
+                ))
+            })
+    }
+}
+
+fn cipher(key: &[u8; KEY_LEN]) -> Aes256Gcm {
+    Aes256Gcm::new(GenericArray::from_slice(key))
+}
+
+impl<P: Persister> Persister for EncryptedPersister<P> {
+    fn raft_state(&self) -> Result<Bytes> {
+        self.decrypt(self.inner.raft_state()?)
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()> {
+        self.inner.save_raft_state(self.encrypt(&state)?)
+    }
+
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()> {
+        self.inner
+            .save_state_and_snapshot(self.encrypt(&state)?, self.encrypt(&snapshot)?)
+    }
+
+    fn snapshot(&self) -> Result<Bytes> {
+        self.decrypt(self.inner.snapshot()?)
+    }
+
+    fn stats(&self) -> Stats {
+        self.inner.stats()
+    }
+
+    fn save_snapshot_in_background(&self, snapshot: Bytes, done: SnapshotSaved) {
+        match self.encrypt(&snapshot) {
+            Ok(sealed) => self.inner.save_snapshot_in_background(sealed.into(), done),
+            Err(e) => done(Err(e)),
+        }
+    }
+
+    fn stage_snapshot(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        self.staged.stage(offset, data)
+    }
+
+    fn promote_snapshot(&self, state: Vec<u8>) -> Result<()> {
+        self.save_state_and_snapshot(state, self.staged.take()?)
+    }
+
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        self.staged.discard();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SimplePersister;
+    use super::*;
+
+    #[test]
+    fn test_encrypted_persister() {
+        let p = EncryptedPersister::new(SimplePersister::new(), 1, &[1; KEY_LEN]);
+        assert!(p.raft_state().unwrap().is_empty());
+        p.save_state_and_snapshot(b"state".to_vec(), b"snapshot".to_vec())
+            .unwrap();
+        assert_eq!(p.raft_state().unwrap(), &b"state"[..]);
+        assert_eq!(p.snapshot().unwrap(), &b"snapshot"[..]);
+        let sealed = p.inner().raft_state().unwrap();
+        assert!(!sealed.windows(5).any(|w| w == b"state"));
+
+        // Old data loads after a rotation, until the old key is removed.
+        p.rotate(2, &[2; KEY_LEN]);
+        p.save_raft_state(b"state 2".to_vec()).unwrap();
+        assert_eq!(p.raft_state().unwrap(), &b"state 2"[..]);
+        assert_eq!(p.snapshot().unwrap(), &b"snapshot"[..]);
+        p.reencrypt().unwrap();
+        p.remove_key(1);
+        assert_eq!(p.snapshot().unwrap(), &b"snapshot"[..]);
+
+        // Wrong keys and tampering.
+        let other = EncryptedPersister::new(SimplePersister::new(), 2, &[3; KEY_LEN]);
+        other.inner().save_raft_state(sealed.to_vec()).unwrap();
+        match other.raft_state() {
+            Err(Error::Encrypt(_)) => (),
+            res => panic!("expect unknown key, got {:?}", res),
+        }
+        let mut tampered = p.inner().raft_state().unwrap().to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        other.inner().save_raft_state(tampered).unwrap();
+        other.add_key(2, &[2; KEY_LEN]);
+        match other.raft_state() {
+            Err(Error::Corruption(_)) => (),
+            res => panic!("expect corruption, got {:?}", res),
+        }
+    }
+}
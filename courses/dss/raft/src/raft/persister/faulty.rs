@@ -0,0 +1,206 @@
+//! Fault injection for crash tests.
+
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use super::{Error, Persister, Result, SnapshotSaved, Stats};
+
+/// Faults injected by a `FaultyPersister`.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// Fails the nth save after the faults are set, counting from 1.
+    pub fail_nth: Option<u64>,
+    /// A failed save is torn: the first half of the data still reaches the
+    /// inner persister.
+    pub torn: bool,
+    /// Every save waits this long before it reaches the inner persister.
+    pub delay: Duration,
+}
+
+struct State {
+    faults: Faults,
+    // saves since the faults were set.
+    saves: u64,
+    failed: u64,
+}
+
+/// Forwards to another persister, failing or delaying saves as told by
+/// `set_faults`.
+///
+/// A failed save returns an error, so the data it was given must not be
+/// treated as durable. Unless it's torn, the inner persister keeps the
+/// state saved before it. Promoting a staged snapshot counts as a save,
+/// but it's never torn.
+pub struct FaultyPersister<P> {
+    inner: P,
+    state: Mutex<State>,
+}
+
+impl<P: Persister> FaultyPersister<P> {
+    pub fn new(inner: P) -> FaultyPersister<P> {
+        FaultyPersister {
+            inner,
+            state: Mutex::new(State {
+                faults: Faults::default(),
+                saves: 0,
+                failed: 0,
+            }),
+        }
+    }
+
+    /// Replaces the faults, saves are counted from now on.
+    pub fn set_faults(&self, faults: Faults) {
+        let mut state = self.state.lock().unwrap();
+        state.faults = faults;
+        state.saves = 0;
+    }
+
+    /// Number of saves failed so far.
+    pub fn failed_saves(&self) -> u64 {
+        self.state.lock().unwrap().failed
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Waits out the delay of a save, returns `Some(torn)` if it fails.
+    fn inject(&self) -> Option<bool> {
+        let (delay, fail) = {
+            let mut state = self.state.lock().unwrap();
+            state.saves += 1;
+            let mut fail = None;
+            if state.faults.fail_nth == Some(state.saves) {
+                state.failed += 1;
+                fail = Some(state.faults.torn);
+            }
+            (state.faults.delay, fail)
+        };
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+        fail
+    }
+}
+
+fn tear(data: &[u8]) -> Vec<u8> {
+    data[..data.len() / 2].to_vec()
+}
+
+fn injected() -> Error {
+    Error::Io(io::Error::other("injected fault"))
+}
+
+impl<P: Persister> Persister for FaultyPersister<P> {
+    fn raft_state(&self) -> Result<Bytes> {
+        self.inner.raft_state()
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()> {
+        match self.inject() {
+            None => self.inner.save_raft_state(state),
+            Some(torn) => {
+                if torn {
+                    let _ = self.inner.save_raft_state(tear(&state));
+                }
+                Err(injected())
+            }
+        }
+    }
+
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()> {
+        match self.inject() {
+            None => self.inner.save_state_and_snapshot(state, snapshot),
+            Some(torn) => {
+                if torn {
+                    let _ = self
+                        .inner
+                        .save_state_and_snapshot(tear(&state), tear(&snapshot));
+                }
+                Err(injected())
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Result<Bytes> {
+        self.inner.snapshot()
+    }
+
+    fn stats(&self) -> Stats {
+        self.inner.stats()
+    }
+
+    fn save_hard_state(&self, term: u64, voted_for: u64) -> Result<()> {
+        match self.inject() {
+            None => self.inner.save_hard_state(term, voted_for),
+            Some(_) => Err(injected()),
+        }
+    }
+
+    fn save_snapshot_in_background(&self, snapshot: Bytes, done: SnapshotSaved) {
+        match self.inject() {
+            None => self.inner.save_snapshot_in_background(snapshot, done),
+            Some(torn) => {
+                if torn {
+                    let snapshot = snapshot.slice(..snapshot.len() / 2);
+                    self.inner
+                        .save_snapshot_in_background(snapshot, Box::new(|_| ()));
+                }
+                done(Err(injected()))
+            }
+        }
+    }
+
+    fn stage_snapshot(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        self.inner.stage_snapshot(offset, data)
+    }
+
+    fn promote_snapshot(&self, state: Vec<u8>) -> Result<()> {
+        match self.inject() {
+            None => self.inner.promote_snapshot(state),
+            Some(_) => Err(injected()),
+        }
+    }
+
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        self.inner.discard_staged_snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SimplePersister;
+    use super::*;
+
+    #[test]
+    fn test_faulty_persister() {
+        let p = FaultyPersister::new(SimplePersister::new());
+        p.save_raft_state(b"state 1".to_vec()).unwrap();
+
+        p.set_faults(Faults {
+            fail_nth: Some(2),
+            ..Faults::default()
+        });
+        p.save_raft_state(b"state 2".to_vec()).unwrap();
+        assert!(p.save_raft_state(b"state 3".to_vec()).is_err());
+        assert_eq!(p.raft_state().unwrap(), &b"state 2"[..]);
+        p.save_raft_state(b"state 4".to_vec()).unwrap();
+        assert_eq!(p.failed_saves(), 1);
+
+        p.set_faults(Faults {
+            fail_nth: Some(1),
+            torn: true,
+            delay: Duration::from_millis(1),
+        });
+        assert!(p
+            .save_state_and_snapshot(b"state 5!".to_vec(), b"snapshot".to_vec())
+            .is_err());
+        assert_eq!(p.raft_state().unwrap(), &b"stat"[..]);
+        assert_eq!(p.snapshot().unwrap(), &b"snap"[..]);
+        assert_eq!(p.failed_saves(), 2);
+    }
+}
@@ -0,0 +1,743 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use super::{
+    check_stage_offset, encode_hard_state, merge_hard_state, nothing_staged, record, Persister,
+    Result, SnapshotSaved, SnapshotWriter, Stats,
+};
+use crate::raft::compress::Compression;
+
+pub(super) const RAFT_STATE_FILE: &str = "raft_state";
+pub(super) const SNAPSHOT_FILE: &str = "snapshot";
+const HARD_STATE_FILE: &str = "hard_state";
+const STAGED_SNAPSHOT_FILE: &str = "snapshot.staged";
+const RETAINED_DIR: &str = "snapshots";
+const CURRENT_FILE: &str = "current";
+const GENERATION_PREFIX: &str = "state.";
+// of the directories and the `current` file in `save_generation`.
+const GENERATION_FSYNCS: u64 = 3;
+
+/// A persister that keeps the raft state and the snapshot in two files of
+/// a generation directory, and a term and vote saved by `save_hard_state`
+/// in a file of its own.
+///
+/// Files are replaced by writing a temporary file and renaming it over the
+/// old one, so a crash leaves either the old or the new content of a file.
+/// The raft state and the snapshot saved together go into a new generation
+/// directory instead, which is made current by renaming the `current` file
+/// naming it, so a crash leaves either the old or the new pair, never a
+/// mix. Generations not current are removed when the persister is opened.
+/// Files hold checksummed records, so corruption on disk is reported when
+/// reading.
+///
+/// A snapshot writer streams to its own temporary file and only takes the
+/// lock to commit, so it doesn't block saving the raft state meanwhile. A
+/// staged snapshot is kept raw in its own file until it's promoted, it's
+/// copied into a record then. Snapshots saved in the background are written
+/// by a thread of their own the same way.
+///
+/// Clones share the directory and the lock, so they can be used from
+/// several threads.
+///
+/// Retained snapshots are hard links to the snapshot and raft state files
+/// saved together, `{id}.snapshot` and `{id}.raft_state` in the
+/// `snapshots` directory.
+///
+/// A directory written before generations has its files in the directory
+/// itself, they're used until the first generation is saved.
+#[derive(Clone)]
+pub struct FilePersister {
+    dir: PathBuf,
+    // serializes writers, so they don't share temporary files, and holds
+    // the current generation, `None` for the files in `dir` itself.
+    lock: Arc<Mutex<Option<u64>>>,
+    // numbers the temporary files of snapshot writers.
+    next_stream: Arc<AtomicU64>,
+    // snapshots saved in the background with a smaller stream number are
+    // older than the saved one, so they're dropped. Only set with the lock.
+    stale_streams: Arc<AtomicU64>,
+    compression: Compression,
+    stats: Arc<Mutex<Stats>>,
+    // number of snapshots to retain, see `with_snapshot_retention`.
+    retain: usize,
+    next_retained: Arc<AtomicU64>,
+}
+
+impl FilePersister {
+    /// Opens a persister in `dir`, creating the directory if needed.
+    /// Anything persisted there before is kept.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<FilePersister> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let next_retained = retained_ids(&dir.join(RETAINED_DIR))?
+            .last()
+            .map_or(0, |id| id + 1);
+        let current = read_current(&dir)?;
+        remove_stale_generations(&dir, current)?;
+        Ok(FilePersister {
+            dir,
+            lock: Arc::new(Mutex::new(current)),
+            next_stream: Arc::default(),
+            stale_streams: Arc::default(),
+            compression: Compression::None,
+            stats: Arc::default(),
+            retain: 0,
+            next_retained: Arc::new(AtomicU64::new(next_retained)),
+        })
+    }
+
+    /// Compresses what is saved from now on with `kind`, except for
+    /// streamed snapshots. Files saved uncompressed before still load.
+    pub fn with_compression(mut self, kind: Compression) -> FilePersister {
+        self.compression = kind;
+        self
+    }
+
+    /// The directory of this persister.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Keeps the last `n` snapshots saved from now on, each with the raft
+    /// state saved along with it, so they can be rolled back to with
+    /// `roll_back`. By default none are kept.
+    pub fn with_snapshot_retention(mut self, n: usize) -> FilePersister {
+        self.retain = n;
+        self
+    }
+
+    /// The ids of the retained snapshots, oldest first.
+    pub fn retained_snapshots(&self) -> io::Result<Vec<u64>> {
+        retained_ids(&self.dir.join(RETAINED_DIR))
+    }
+
+    /// Makes the retained snapshot `id` and the raft state saved along with
+    /// it current again, atomically like `save_state_and_snapshot`. The
+    /// retained snapshots saved after it are kept.
+    pub fn roll_back(&self, id: u64) -> Result<()> {
+        let mut current = self.lock.lock().unwrap();
+        let retained = self.dir.join(RETAINED_DIR);
+        let mut tmps = vec![];
+        for name in &[SNAPSHOT_FILE, RAFT_STATE_FILE] {
+            let record = fs::read(retained.join(retained_name(id, name)))?;
+            // don't roll back to something that doesn't load.
+            record::decode(&record)?;
+            tmps.push(self.write_tmp(name, &record)?);
+        }
+        self.save_generation(&mut current, &tmps[0], &tmps[1])?;
+        self.supersede_background_snapshots();
+        Ok(())
+    }
+
+    /// The term and vote saved by `save_hard_state`, encoded.
+    pub(super) fn read_hard_state(&self) -> Result<Vec<u8>> {
+        decode_file(open_file(&self.dir.join(HARD_STATE_FILE))?)
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        decode_file(self.open(name)?)
+    }
+
+    /// Opens the file `name` of the current generation, `None` if it
+    /// wasn't saved yet. The file stays readable once open, even if a
+    /// newer generation replaces it.
+    pub(super) fn open(&self, name: &str) -> io::Result<Option<File>> {
+        let current = self.lock.lock().unwrap();
+        open_file(&self.generation_dir(*current).join(name))
+    }
+
+    /// The path of the file `name` of the current generation.
+    #[cfg(test)]
+    pub(super) fn path(&self, name: &str) -> PathBuf {
+        self.generation_dir(*self.lock.lock().unwrap()).join(name)
+    }
+
+    fn generation_dir(&self, generation: Option<u64>) -> PathBuf {
+        match generation {
+            Some(id) => self.dir.join(generation_name(id)),
+            None => self.dir.clone(),
+        }
+    }
+
+    /// Writes `data` to a temporary file, to be renamed by `commit`.
+    /// Returns the file and its size.
+    fn stage(&self, name: &str, data: &[u8]) -> io::Result<(PathBuf, u64)> {
+        let record = record::encode_with(self.compression, data);
+        Ok((self.write_tmp(name, &record)?, record.len() as u64))
+    }
+
+    fn write_tmp(&self, name: &str, record: &[u8]) -> io::Result<PathBuf> {
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let mut f = File::create(&tmp)?;
+        f.write_all(record)?;
+        f.sync_all()?;
+        Ok(tmp)
+    }
+
+    /// Renames `tmp` over the file `name` in `dir`.
+    fn commit(&self, tmp: &Path, dir: &Path, name: &str) -> io::Result<()> {
+        fs::rename(tmp, dir.join(name))?;
+        sync_dir(dir)
+    }
+
+    /// Makes the snapshot and the raft state in the temporary files
+    /// `snapshot` and `state` current together, in a new generation.
+    /// Called with the lock held.
+    fn save_generation(
+        &self,
+        current: &mut Option<u64>,
+        snapshot: &Path,
+        state: &Path,
+    ) -> io::Result<()> {
+        let generation = self.write_generation(*current, snapshot, state)?;
+        self.point_to(generation)?;
+        // the old one is unreachable now, the save is done already so a
+        // failure to remove it is only logged, `new` removes it then.
+        let old = current.replace(generation);
+        if let Err(e) = self.remove_generation(old) {
+            warn!("failed to remove old generation in {:?}: {}", self.dir, e);
+        }
+        Ok(())
+    }
+
+    /// Moves `snapshot` and `state` into the directory of the generation
+    /// after `current`, the first step of `save_generation`.
+    fn write_generation(
+        &self,
+        current: Option<u64>,
+        snapshot: &Path,
+        state: &Path,
+    ) -> io::Result<u64> {
+        let generation = current.map_or(0, |id| id + 1);
+        let dir = self.generation_dir(Some(generation));
+        match fs::remove_dir_all(&dir) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            res => res?,
+        }
+        fs::create_dir(&dir)?;
+        fs::rename(snapshot, dir.join(SNAPSHOT_FILE))?;
+        fs::rename(state, dir.join(RAFT_STATE_FILE))?;
+        sync_dir(&dir)?;
+        Ok(generation)
+    }
+
+    /// Makes `generation` current, the second step of `save_generation`.
+    fn point_to(&self, generation: u64) -> io::Result<()> {
+        let record = record::encode(generation.to_string().as_bytes());
+        let tmp = self.write_tmp(CURRENT_FILE, &record)?;
+        self.commit(&tmp, &self.dir, CURRENT_FILE)
+    }
+
+    fn remove_generation(&self, generation: Option<u64>) -> io::Result<()> {
+        if generation.is_some() {
+            return fs::remove_dir_all(self.generation_dir(generation));
+        }
+        for name in &[SNAPSHOT_FILE, RAFT_STATE_FILE] {
+            match fs::remove_file(self.dir.join(name)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                res => res?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the snapshots still being saved in the background, as a newer
+    /// snapshot has just been saved. Called with the lock held.
+    fn supersede_background_snapshots(&self) {
+        let next = self.next_stream.load(Ordering::Relaxed);
+        self.stale_streams.store(next, Ordering::Relaxed);
+    }
+
+    /// Saves `snapshot` alone, the stream `id` of a background save.
+    fn save_snapshot(&self, id: u64, snapshot: &[u8]) -> Result<()> {
+        let start = Instant::now();
+        let name = format!("{}.{}", SNAPSHOT_FILE, id);
+        let record = record::encode_with(self.compression, snapshot);
+        let tmp = match self.write_tmp(&name, &record) {
+            Ok(tmp) => tmp,
+            Err(e) => {
+                let _ = fs::remove_file(self.dir.join(format!("{}.tmp", name)));
+                return Err(e.into());
+            }
+        };
+
+        let current = self.lock.lock().unwrap();
+        if id < self.stale_streams.load(Ordering::Relaxed) {
+            fs::remove_file(&tmp)?;
+            return Ok(());
+        }
+        self.stale_streams.store(id + 1, Ordering::Relaxed);
+        let dir = self.generation_dir(*current);
+        self.commit(&tmp, &dir, SNAPSHOT_FILE)?;
+        let fsyncs = 2 + self.retain_snapshot(&dir);
+        self.stats
+            .lock()
+            .unwrap()
+            .record(record.len() as u64, fsyncs, start.elapsed());
+        Ok(())
+    }
+
+    /// Retains the snapshot and raft state just saved, and drops the
+    /// retained snapshots past the retention. Returns the number of
+    /// fsyncs. Called with the lock held.
+    ///
+    /// The save is done already, so a failure is only logged.
+    fn retain_snapshot(&self, dir: &Path) -> u64 {
+        if self.retain == 0 {
+            return 0;
+        }
+        match self.try_retain_snapshot(dir) {
+            Ok(()) => 1,
+            Err(e) => {
+                warn!("failed to retain snapshot in {:?}: {}", self.dir, e);
+                0
+            }
+        }
+    }
+
+    fn try_retain_snapshot(&self, dir: &Path) -> io::Result<()> {
+        let retained = self.dir.join(RETAINED_DIR);
+        fs::create_dir_all(&retained)?;
+        let id = self.next_retained.fetch_add(1, Ordering::Relaxed);
+        for name in &[SNAPSHOT_FILE, RAFT_STATE_FILE] {
+            // saves rename new files over the current ones, so the link
+            // keeps what's saved now.
+            let (from, to) = (dir.join(name), retained.join(retained_name(id, name)));
+            if fs::hard_link(&from, &to).is_err() {
+                fs::copy(&from, &to)?;
+            }
+        }
+        let ids = retained_ids(&retained)?;
+        for id in &ids[..ids.len().saturating_sub(self.retain)] {
+            for name in &[SNAPSHOT_FILE, RAFT_STATE_FILE] {
+                match fs::remove_file(retained.join(retained_name(*id, name))) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    res => res?,
+                }
+            }
+        }
+        sync_dir(&retained)
+    }
+}
+
+fn retained_name(id: u64, name: &str) -> String {
+    format!("{:020}.{}", id, name)
+}
+
+fn generation_name(id: u64) -> String {
+    format!("{}{:020}", GENERATION_PREFIX, id)
+}
+
+/// The generation the `current` file in `dir` names, `None` if there's
+/// none.
+fn read_current(dir: &Path) -> io::Result<Option<u64>> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let data = match decode_file(open_file(&dir.join(CURRENT_FILE))?) {
+        Ok(data) if data.is_empty() => return Ok(None),
+        Ok(data) => data,
+        Err(e) => return Err(invalid(e.to_string())),
+    };
+    let id = String::from_utf8(data)
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| invalid("malformed current generation".to_owned()))?;
+    Ok(Some(id))
+}
+
+/// Removes what a crash amid `save_generation` left behind: generations
+/// other than `current`, and the files of the directory itself once there
+/// are generations.
+fn remove_stale_generations(dir: &Path, current: Option<u64>) -> io::Result<()> {
+    let current = current.map(generation_name);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(GENERATION_PREFIX) && Some(&*name) != current.as_deref() {
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+    if current.is_none() {
+        return Ok(());
+    }
+    for name in &[SNAPSHOT_FILE, RAFT_STATE_FILE] {
+        match fs::remove_file(dir.join(name)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+fn open_file(path: &Path) -> io::Result<Option<File>> {
+    match File::open(path) {
+        Ok(f) => Ok(Some(f)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn decode_file(f: Option<File>) -> Result<Vec<u8>> {
+    let mut f = match f {
+        Some(f) => f,
+        None => return Ok(vec![]),
+    };
+    let mut record = vec![];
+    f.read_to_end(&mut record)?;
+    record::decode(&record)
+}
+
+/// The ids of the snapshots retained in `dir`, sorted.
+fn retained_ids(dir: &Path) -> io::Result<Vec<u64>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut ids = vec![];
+    for entry in entries {
+        let name = entry?.file_name();
+        let id = name
+            .to_str()
+            .and_then(|name| name.split('.').next())
+            .and_then(|id| id.parse().ok());
+        if let Some(id) = id {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+/// Makes renames in the directory durable.
+pub(super) fn sync_dir(dir: &Path) -> io::Result<()> {
+    // Directories can't be opened as files on some platforms, there's
+    // nothing to sync then.
+    match File::open(dir) {
+        Ok(dir) => dir.sync_all().or(Ok(())),
+        Err(_) => Ok(()),
+    }
+}
+
+impl Persister for FilePersister {
+    fn raft_state(&self) -> Result<Bytes> {
+        merge_hard_state(self.read(RAFT_STATE_FILE)?.into(), &self.read_hard_state()?)
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let current = self.lock.lock().unwrap();
+        let (tmp, bytes) = self.stage(RAFT_STATE_FILE, &state)?;
+        self.commit(&tmp, &self.generation_dir(*current), RAFT_STATE_FILE)?;
+        self.stats.lock().unwrap().record(bytes, 2, start.elapsed());
+        Ok(())
+    }
+
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let mut current = self.lock.lock().unwrap();
+        let (state_tmp, state_bytes) = self.stage(RAFT_STATE_FILE, &state)?;
+        let (snapshot_tmp, snapshot_bytes) = self.stage(SNAPSHOT_FILE, &snapshot)?;
+        self.save_generation(&mut current, &snapshot_tmp, &state_tmp)?;
+        self.supersede_background_snapshots();
+        let fsyncs = GENERATION_FSYNCS + 2 + self.retain_snapshot(&self.generation_dir(*current));
+        self.stats
+            .lock()
+            .unwrap()
+            .record(state_bytes + snapshot_bytes, fsyncs, start.elapsed());
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Bytes> {
+        self.read(SNAPSHOT_FILE).map(Bytes::from)
+    }
+
+    fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    fn save_hard_state(&self, term: u64, voted_for: u64) -> Result<()> {
+        let start = Instant::now();
+        let _guard = self.lock.lock().unwrap();
+        let (tmp, bytes) = self.stage(HARD_STATE_FILE, &encode_hard_state(term, voted_for))?;
+        self.commit(&tmp, &self.dir, HARD_STATE_FILE)?;
+        self.stats.lock().unwrap().record(bytes, 2, start.elapsed());
+        Ok(())
+    }
+
+    fn save_snapshot_in_background(&self, snapshot: Bytes, done: SnapshotSaved) {
+        let id = self.next_stream.fetch_add(1, Ordering::Relaxed);
+        let files = self.clone();
+        thread::spawn(move || done(files.save_snapshot(id, &snapshot)));
+    }
+
+    fn snapshot_reader(&self) -> Result<Box<dyn Read + '_>> {
+        match self.open(SNAPSHOT_FILE)? {
+            Some(f) => Ok(Box::new(record::Reader::new(BufReader::new(f))?)),
+            None => Ok(Box::new(io::empty())),
+        }
+    }
+
+    fn snapshot_writer(&self) -> Result<Box<dyn SnapshotWriter + '_>> {
+        let id = self.next_stream.fetch_add(1, Ordering::Relaxed);
+        let tmp = self.dir.join(format!("{}.{}.tmp", SNAPSHOT_FILE, id));
+        let writer = record::Writer::new(BufWriter::new(File::create(&tmp)?))?;
+        Ok(Box::new(FileSnapshotWriter {
+            persister: self,
+            tmp,
+            writer: Some(writer),
+        }))
+    }
+
+    fn stage_snapshot(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.dir.join(STAGED_SNAPSHOT_FILE))?;
+        if offset > 0 {
+            check_stage_offset(offset, f.metadata()?.len())?;
+        }
+        f.set_len(offset)?;
+        f.seek(SeekFrom::Start(offset))?;
+        f.write_all(data)?;
+        // staged chunks survive a restart, so they're not sent again.
+        f.sync_all()?;
+        if offset == 0 {
+            sync_dir(&self.dir)?;
+        }
+        Ok(offset + data.len() as u64)
+    }
+
+    fn promote_snapshot(&self, state: Vec<u8>) -> Result<()> {
+        let path = self.dir.join(STAGED_SNAPSHOT_FILE);
+        let staged = match File::open(&path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(nothing_staged()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut w = self.snapshot_writer()?;
+        io::copy(&mut BufReader::new(staged), &mut w)?;
+        w.commit(state)?;
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        match fs::remove_file(self.dir.join(STAGED_SNAPSHOT_FILE)) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+struct FileSnapshotWriter<'a> {
+    persister: &'a FilePersister,
+    tmp: PathBuf,
+    // taken by `commit`.
+    writer: Option<record::Writer<BufWriter<File>>>,
+}
+
+impl Write for FileSnapshotWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.as_mut().unwrap().flush()
+    }
+}
+
+impl SnapshotWriter for FileSnapshotWriter<'_> {
+    fn commit(mut self: Box<Self>, state: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let mut f = self.writer.take().unwrap().finish()?;
+        f.flush()?;
+        f.get_ref().sync_all()?;
+        let snapshot_bytes = f.get_ref().metadata()?.len();
+
+        let p = self.persister;
+        let mut current = p.lock.lock().unwrap();
+        let (state_tmp, state_bytes) = p.stage(RAFT_STATE_FILE, &state)?;
+        p.save_generation(&mut current, &self.tmp, &state_tmp)?;
+        p.supersede_background_snapshots();
+        let fsyncs = GENERATION_FSYNCS + 2 + p.retain_snapshot(&p.generation_dir(*current));
+        p.stats
+            .lock()
+            .unwrap()
+            .record(state_bytes + snapshot_bytes, fsyncs, start.elapsed());
+        Ok(())
+    }
+}
+
+impl Drop for FileSnapshotWriter<'_> {
+    fn drop(&mut self) {
+        // gone already if committed.
+        let _ = fs::remove_file(&self.tmp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::super::Error;
+    use super::*;
+
+    #[test]
+    fn test_file_persister() {
+        let dir = env::temp_dir().join(format!("raft-file-persister-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let p = FilePersister::new(&dir).unwrap();
+        assert!(p.raft_state().unwrap().is_empty());
+        assert!(p.snapshot().unwrap().is_empty());
+        p.save_raft_state(vec![1, 2, 3]).unwrap();
+        p.save_state_and_snapshot(vec![4], vec![5, 6]).unwrap();
+        drop(p);
+
+        // Survives a restart.
+        let p = FilePersister::new(&dir).unwrap();
+        assert_eq!(p.raft_state().unwrap(), vec![4]);
+        assert_eq!(p.snapshot().unwrap(), vec![5, 6]);
+        p.save_raft_state(vec![7]).unwrap();
+        assert_eq!(p.raft_state().unwrap(), vec![7]);
+        assert_eq!(p.snapshot().unwrap(), vec![5, 6]);
+
+        // A corrupted file isn't handed out.
+        let path = p.path(SNAPSHOT_FILE);
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&path, data).unwrap();
+        match p.snapshot() {
+            Err(Error::Corruption(_)) => (),
+            res => panic!("expect corruption, got {:?}", res),
+        }
+
+        // Streamed snapshots.
+        let mut w = p.snapshot_writer().unwrap();
+        w.write_all(&[8; 100]).unwrap();
+        drop(w);
+        let mut w = p.snapshot_writer().unwrap();
+        w.write_all(&[9; 100]).unwrap();
+        p.save_raft_state(vec![10]).unwrap();
+        w.commit(vec![11]).unwrap();
+        assert_eq!(p.raft_state().unwrap(), vec![11]);
+        let mut snapshot = vec![];
+        p.snapshot_reader()
+            .unwrap()
+            .read_to_end(&mut snapshot)
+            .unwrap();
+        assert_eq!(snapshot, vec![9; 100]);
+        let mut files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec![CURRENT_FILE.to_owned(), generation_name(1)]);
+
+        let stats = p.stats();
+        assert_eq!(stats.saves, 3);
+        assert_eq!(stats.fsyncs, 2 + 2 + 5);
+
+        // Staged snapshots, also across a restart.
+        p.save_state_and_snapshot(vec![1], vec![2]).unwrap();
+        super::super::tests::check_staged_snapshot(&p);
+        p.stage_snapshot(0, &[12; 4]).unwrap();
+        drop(p);
+        let p = FilePersister::new(&dir).unwrap();
+        assert_eq!(p.stage_snapshot(4, &[13; 4]).unwrap(), 8);
+        p.promote_snapshot(vec![14]).unwrap();
+        assert_eq!(p.snapshot().unwrap(), [[12; 4], [13; 4]].concat());
+        assert!(!dir.join(STAGED_SNAPSHOT_FILE).exists());
+        assert!(p.retained_snapshots().unwrap().is_empty());
+
+        // Retained snapshots.
+        let p = p.with_snapshot_retention(2);
+        for i in 0..3 {
+            p.save_state_and_snapshot(vec![i], vec![i + 100]).unwrap();
+        }
+        let mut w = p.snapshot_writer().unwrap();
+        w.write_all(&[103]).unwrap();
+        w.commit(vec![3]).unwrap();
+        p.save_raft_state(vec![4]).unwrap();
+        assert_eq!(p.retained_snapshots().unwrap(), vec![2, 3]);
+        assert!(p.roll_back(1).is_err());
+        p.roll_back(2).unwrap();
+        assert_eq!(p.raft_state().unwrap(), vec![2]);
+        assert_eq!(p.snapshot().unwrap(), vec![102]);
+        drop(p);
+        let p = FilePersister::new(&dir).unwrap().with_snapshot_retention(2);
+        p.roll_back(3).unwrap();
+        assert_eq!(p.snapshot().unwrap(), vec![103]);
+        p.save_state_and_snapshot(vec![5], vec![105]).unwrap();
+        assert_eq!(p.retained_snapshots().unwrap(), vec![3, 4]);
+
+        // Snapshots saved in the background, older ones don't win.
+        super::super::tests::check_save_in_background(&p);
+        assert_eq!(p.retained_snapshots().unwrap(), vec![4, 5]);
+        let stale = p.next_stream.fetch_add(1, Ordering::Relaxed);
+        p.save_state_and_snapshot(vec![6], vec![106]).unwrap();
+        p.save_snapshot(stale, &[8]).unwrap();
+        assert_eq!(p.snapshot().unwrap(), vec![106]);
+
+        super::super::tests::check_hard_state(&p);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_persister_crash() {
+        let dir = env::temp_dir().join(format!("raft-file-persister-crash-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        // Files of a directory written before generations.
+        let p = FilePersister::new(&dir).unwrap();
+        p.save_raft_state(vec![1]).unwrap();
+        assert_eq!(p.path(RAFT_STATE_FILE), dir.join(RAFT_STATE_FILE));
+        let generation = |p: &FilePersister, state: u8, snapshot: u8| {
+            let current = p.lock.lock().unwrap();
+            let (state, _) = p.stage(RAFT_STATE_FILE, &[state]).unwrap();
+            let (snapshot, _) = p.stage(SNAPSHOT_FILE, &[snapshot]).unwrap();
+            p.write_generation(*current, &snapshot, &state).unwrap()
+        };
+
+        // A crash before the new generation is made current keeps the old
+        // raft state, with the old snapshot.
+        generation(&p, 2, 3);
+        drop(p);
+        let p = FilePersister::new(&dir).unwrap();
+        assert_eq!(p.raft_state().unwrap(), vec![1]);
+        assert!(p.snapshot().unwrap().is_empty());
+        assert!(!dir.join(generation_name(0)).exists());
+
+        p.save_state_and_snapshot(vec![4], vec![5]).unwrap();
+        assert!(!dir.join(RAFT_STATE_FILE).exists());
+        generation(&p, 6, 7);
+        drop(p);
+        let p = FilePersister::new(&dir).unwrap();
+        assert_eq!(p.raft_state().unwrap(), vec![4]);
+        assert_eq!(p.snapshot().unwrap(), vec![5]);
+
+        // A crash after, before the old one is removed, has both new.
+        p.point_to(generation(&p, 8, 9)).unwrap();
+        drop(p);
+        let p = FilePersister::new(&dir).unwrap();
+        assert_eq!(p.raft_state().unwrap(), vec![8]);
+        assert_eq!(p.snapshot().unwrap(), vec![9]);
+        assert!(!dir.join(generation_name(0)).exists());
+        assert!(dir.join(generation_name(1)).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+//! A persister that maps its files instead of reading them, enable it with
+//! the `mmap-persister` feature.
+
+use std::io::{self, Cursor, Read};
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use memmap2::Mmap;
+
+use super::file::{RAFT_STATE_FILE, SNAPSHOT_FILE};
+use super::{
+    merge_hard_state, record, FilePersister, Persister, Result, SnapshotSaved, SnapshotWriter,
+    Stats,
+};
+use crate::raft::compress::Compression;
+
+/// Saves like `FilePersister`, but maps the raft state and the snapshot
+/// into memory to read them, so a large log is checked against its
+/// checksum in place instead of being copied out of the file first.
+///
+/// `map_raft_state` and `map_snapshot` hand out the mapping itself.
+/// `raft_state` and `snapshot` still return copies. Compressed files are
+/// decoded into memory. The mapped raft state doesn't have the term and
+/// vote saved by `save_hard_state` merged in, `raft_state` does.
+pub struct MmapPersister {
+    files: FilePersister,
+}
+
+/// The raft state or snapshot mapped by a `MmapPersister`.
+pub struct Mapped(MappedData);
+
+enum MappedData {
+    Empty,
+    Map(Mmap, Range<usize>),
+    Decoded(Vec<u8>),
+}
+
+impl Deref for Mapped {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.0 {
+            MappedData::Empty => &[],
+            MappedData::Map(ref map, ref range) => &map[range.clone()],
+            MappedData::Decoded(ref data) => data,
+        }
+    }
+}
+
+impl AsRef<[u8]> for Mapped {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl MmapPersister {
+    /// Opens a persister in `dir`, like `FilePersister::new`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<MmapPersister> {
+        Ok(MmapPersister {
+            files: FilePersister::new(dir)?,
+        })
+    }
+
+    /// Compresses what is saved from now on with `kind`. Compressed files
+    /// can't be used in place.
+    pub fn with_compression(mut self, kind: Compression) -> MmapPersister {
+        self.files = self.files.with_compression(kind);
+        self
+    }
+
+    /// The directory of this persister.
+    pub fn dir(&self) -> &Path {
+        self.files.dir()
+    }
+
+    pub fn map_raft_state(&self) -> Result<Mapped> {
+        self.map(RAFT_STATE_FILE)
+    }
+
+    pub fn map_snapshot(&self) -> Result<Mapped> {
+        self.map(SNAPSHOT_FILE)
+    }
+
+    fn map(&self, name: &str) -> Result<Mapped> {
+        let f = match self.files.open(name)? {
+            Some(f) => f,
+            None => return Ok(Mapped(MappedData::Empty)),
+        };
+        // Empty files can't be mapped on some platforms.
+        if f.metadata()?.len() == 0 {
+            return Ok(Mapped(MappedData::Empty));
+        }
+        // SAFETY: `FilePersister` replaces files by renaming new ones over
+        // them and never writes a saved file in place, so the mapped file
+        // doesn't change while it's mapped.
+        let map = unsafe { Mmap::map(&f)? };
+        match record::data_range(&map)? {
+            Some(range) => Ok(Mapped(MappedData::Map(map, range))),
+            None => Ok(Mapped(MappedData::Decoded(record::decode(&map)?))),
+        }
+    }
+}
+
+impl Persister for MmapPersister {
+    fn raft_state(&self) -> Result<Bytes> {
+        merge_hard_state(
+            Bytes::copy_from_slice(&self.map_raft_state()?),
+            &self.files.read_hard_state()?,
+        )
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()> {
+        self.files.save_raft_state(state)
+    }
+
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()> {
+        self.files.save_state_and_snapshot(state, snapshot)
+    }
+
+    fn snapshot(&self) -> Result<Bytes> {
+        Ok(Bytes::copy_from_slice(&self.map_snapshot()?))
+    }
+
+    fn stats(&self) -> Stats {
+        self.files.stats()
+    }
+
+    fn save_hard_state(&self, term: u64, voted_for: u64) -> Result<()> {
+        self.files.save_hard_state(term, voted_for)
+    }
+
+    fn save_raft_state_async(&self, state: Vec<u8>) -> BoxFuture<'static, Result<()>> {
+        self.files.save_raft_state_async(state)
+    }
+
+    fn save_state_and_snapshot_async(
+        &self,
+        state: Vec<u8>,
+        snapshot: Vec<u8>,
+    ) -> BoxFuture<'static, Result<()>> {
+        self.files.save_state_and_snapshot_async(state, snapshot)
+    }
+
+    fn save_snapshot_in_background(&self, snapshot: Bytes, done: SnapshotSaved) {
+        self.files.save_snapshot_in_background(snapshot, done)
+    }
+
+    fn snapshot_reader(&self) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(Cursor::new(self.map_snapshot()?)))
+    }
+
+    fn snapshot_writer(&self) -> Result<Box<dyn SnapshotWriter + '_>> {
+        self.files.snapshot_writer()
+    }
+
+    fn stage_snapshot(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        self.files.stage_snapshot(offset, data)
+    }
+
+    fn promote_snapshot(&self, state: Vec<u8>) -> Result<()> {
+        self.files.promote_snapshot(state)
+    }
+
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        self.files.discard_staged_snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::super::Error;
+    use super::*;
+
+    #[test]
+    fn test_mmap_persister() {
+        let dir = env::temp_dir().join(format!("raft-mmap-persister-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let p = MmapPersister::new(&dir).unwrap();
+        assert!(p.map_raft_state().unwrap().is_empty());
+        assert!(p.snapshot().unwrap().is_empty());
+        p.save_raft_state(vec![]).unwrap();
+        assert!(p.map_raft_state().unwrap().is_empty());
+        p.save_state_and_snapshot(vec![1; 4096], vec![2; 100])
+            .unwrap();
+
+        // A mapping outlives the file it maps being replaced.
+        let state = p.map_raft_state().unwrap();
+        p.save_raft_state(vec![3; 10]).unwrap();
+        assert_eq!(&*state, &[1; 4096][..]);
+        assert_eq!(p.raft_state().unwrap(), vec![3; 10]);
+        let mut snapshot = vec![];
+        p.snapshot_reader()
+            .unwrap()
+            .read_to_end(&mut snapshot)
+            .unwrap();
+        assert_eq!(snapshot, vec![2; 100]);
+
+        super::super::tests::check_hard_state(&p);
+
+        let p = p.with_compression(Compression::Snappy);
+        p.save_raft_state(vec![4; 10]).unwrap();
+        assert_eq!(&*p.map_raft_state().unwrap(), &[4; 10][..]);
+
+        let path = p.files.path(SNAPSHOT_FILE);
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&path, data).unwrap();
+        match p.map_snapshot() {
+            Err(Error::Corruption(_)) => (),
+            Err(e) => panic!("expect corruption, got {:?}", e),
+            Ok(_) => panic!("expect corruption"),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,385 @@
+//! The format persisters save the raft state and the snapshot in:
+//!
+//! | magic: "RFTP" | len: u32 LE | crc32: u32 LE | data |
+//!
+//! or, if the data is compressed,
+//!
+//! | magic: "RFTC" | len: u32 LE | crc32: u32 LE | codec: u8 | data |
+//!
+//! where the codec is a `Compression`, and the length and checksum are of
+//! the compressed data. Uncompressed records stay readable whichever
+//! compression a persister is set to.
+//!
+//! A record that is shorter than its header says, or whose data doesn't
+//! match its checksum, was torn or corrupted after it was saved.
+//!
+//! [`Writer`] and [`Reader`] stream records too large to hold in memory.
+//! Streamed records aren't compressed.
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use bytes::Bytes;
+
+use super::{Error, Result};
+use crate::raft::compress::{self, Compression};
+
+const MAGIC: &[u8; 4] = b"RFTP";
+const COMPRESSED_MAGIC: &[u8; 4] = b"RFTC";
+const HEADER_LEN: usize = 12;
+const CODEC_LEN: usize = 1;
+
+/// Wraps `data` in a record.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + data.len());
+    record.extend_from_slice(&header(MAGIC, data));
+    record.extend_from_slice(data);
+    record
+}
+
+/// Wraps `data` compressed with `kind` in a record, or uncompressed if
+/// `kind` isn't supported by this build.
+pub fn encode_with(kind: Compression, data: &[u8]) -> Vec<u8> {
+    if kind == Compression::None || !compress::supported(kind) {
+        return encode(data);
+    }
+    let data = match compress::compress(kind, data) {
+        Ok(compressed) => compressed,
+        Err(_) => return encode(data),
+    };
+    let mut record = Vec::with_capacity(HEADER_LEN + CODEC_LEN + data.len());
+    record.extend_from_slice(&header(COMPRESSED_MAGIC, &data));
+    record.push(kind as i32 as u8);
+    record.extend_from_slice(&data);
+    record
+}
+
+/// Checks a record made by `encode` or `encode_with` and returns its data.
+/// An empty record means nothing was saved and has empty data.
+pub fn decode(record: &[u8]) -> Result<Vec<u8>> {
+    if record.is_empty() {
+        return Ok(vec![]);
+    }
+    let (kind, data_at) = split(record)?;
+    decompress(kind, &record[data_at..])
+}
+
+/// Like `decode`, but shares the data with `record` instead of copying it,
+/// unless it's compressed.
+pub fn decode_bytes(record: &Bytes) -> Result<Bytes> {
+    match data_range(record)? {
+        Some(range) => Ok(record.slice(range)),
+        None => decode(record).map(Bytes::from),
+    }
+}
+
+/// Checks a record like `decode` and returns where its data is, so it can
+/// be used in place, or `None` if the data is compressed and has to be
+/// decoded.
+pub fn data_range(record: &[u8]) -> Result<Option<Range<usize>>> {
+    if record.is_empty() {
+        return Ok(Some(0..0));
+    }
+    match split(record)? {
+        (Compression::None, data_at) => Ok(Some(data_at..record.len())),
+        _ => Ok(None),
+    }
+}
+
+/// Checks a record and returns its compression and where its data starts.
+fn split(record: &[u8]) -> Result<(Compression, usize)> {
+    let (kind, data_at) = match record.get(..4) {
+        Some(magic) if magic == MAGIC && record.len() >= HEADER_LEN => {
+            (Compression::None, HEADER_LEN)
+        }
+        Some(magic) if magic == COMPRESSED_MAGIC && record.len() >= HEADER_LEN + CODEC_LEN => {
+            (codec(record[HEADER_LEN])?, HEADER_LEN + CODEC_LEN)
+        }
+        _ => return Err(bad_header(record)),
+    };
+    check(&record[..HEADER_LEN], &record[data_at..])?;
+    Ok((kind, data_at))
+}
+
+fn header(magic: &[u8; 4], data: &[u8]) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(magic);
+    header[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    header[8..].copy_from_slice(&crc32fast::hash(data).to_le_bytes());
+    header
+}
+
+/// The length and checksum in a header.
+fn parse_header(header: &[u8]) -> (u32, u32) {
+    let mut word = [0; 4];
+    word.copy_from_slice(&header[4..8]);
+    let len = u32::from_le_bytes(word);
+    word.copy_from_slice(&header[8..12]);
+    (len, u32::from_le_bytes(word))
+}
+
+/// Checks `data` against its header.
+fn check(header: &[u8], data: &[u8]) -> Result<()> {
+    let (len, checksum) = parse_header(header);
+    if data.len() != len as usize {
+        return Err(Error::Corruption(format!(
+            "record length mismatch, expect {}, got {}",
+            len,
+            data.len()
+        )));
+    }
+    let actual = crc32fast::hash(data);
+    if actual != checksum {
+        return Err(Error::Corruption(format!(
+            "record checksum mismatch, expect {:#010x}, got {:#010x}",
+            checksum, actual
+        )));
+    }
+    Ok(())
+}
+
+fn codec(byte: u8) -> Result<Compression> {
+    match Compression::from_i32(i32::from(byte)) {
+        Some(kind) if kind != Compression::None => Ok(kind),
+        _ => Err(Error::Corruption(format!("unknown record codec {}", byte))),
+    }
+}
+
+fn decompress(kind: Compression, data: &[u8]) -> Result<Vec<u8>> {
+    compress::decompress(kind, data).map_err(|e| Error::Decompress(e.to_string()))
+}
+
+fn bad_header(record: &[u8]) -> Error {
+    Error::Corruption(format!(
+        "bad record header {:?}",
+        &record[..record.len().min(HEADER_LEN)]
+    ))
+}
+
+fn corrupted(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Writes the data written to it as a record. The header is filled in by
+/// `finish`, a record that isn't finished doesn't decode.
+pub struct Writer<W: Write + Seek> {
+    inner: W,
+    len: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write + Seek> Writer<W> {
+    pub fn new(mut inner: W) -> io::Result<Writer<W>> {
+        inner.write_all(&[0; HEADER_LEN])?;
+        Ok(Writer {
+            inner,
+            len: 0,
+            hasher: crc32fast::Hasher::new(),
+        })
+    }
+
+    /// Writes the header and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.len > u64::from(u32::MAX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("record of {} bytes is too large", self.len),
+            ));
+        }
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(MAGIC);
+        header[4..8].copy_from_slice(&(self.len as u32).to_le_bytes());
+        header[8..].copy_from_slice(&self.hasher.finalize().to_le_bytes());
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&header)?;
+        self.inner.seek(SeekFrom::End(0))?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the data of a record. The checksum is verified once all data is
+/// read, a corrupted record fails the last read with `InvalidData`.
+///
+/// A compressed record is read and checked at once by `new`.
+pub struct Reader<R: Read> {
+    inner: R,
+    remaining: u64,
+    checksum: u32,
+    hasher: crc32fast::Hasher,
+    decompressed: Option<Cursor<Vec<u8>>>,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the header. An empty `inner` reads as empty data, like in
+    /// `decode`.
+    pub fn new(mut inner: R) -> Result<Reader<R>> {
+        let mut buf = [0; HEADER_LEN];
+        let mut n = 0;
+        while n < HEADER_LEN {
+            match inner.read(&mut buf[n..])? {
+                0 => break,
+                m => n += m,
+            }
+        }
+        let mut decompressed = None;
+        let (remaining, checksum) = match n {
+            0 => (0, crc32fast::hash(&[])),
+            HEADER_LEN if &buf[..4] == MAGIC => parse_header(&buf),
+            HEADER_LEN if &buf[..4] == COMPRESSED_MAGIC => {
+                let mut rest = vec![];
+                inner.read_to_end(&mut rest)?;
+                if rest.is_empty() {
+                    return Err(bad_header(&buf));
+                }
+                let data = &rest[CODEC_LEN..];
+                check(&buf, data)?;
+                decompressed = Some(Cursor::new(decompress(codec(rest[0])?, data)?));
+                (0, crc32fast::hash(&[]))
+            }
+            _ => return Err(bad_header(&buf[..n])),
+        };
+        Ok(Reader {
+            inner,
+            remaining: u64::from(remaining),
+            checksum,
+            hasher: crc32fast::Hasher::new(),
+            decompressed,
+        })
+    }
+
+    fn verify(&mut self) -> io::Result<()> {
+        let mut trailing = [0; 1];
+        if self.inner.read(&mut trailing)? != 0 {
+            return Err(corrupted("record has trailing data".to_owned()));
+        }
+        let actual = self.hasher.clone().finalize();
+        if actual != self.checksum {
+            return Err(corrupted(format!(
+                "record checksum mismatch, expect {:#010x}, got {:#010x}",
+                self.checksum, actual
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(ref mut decompressed) = self.decompressed {
+            return decompressed.read(buf);
+        }
+        if self.remaining == 0 {
+            self.verify()?;
+            return Ok(0);
+        }
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 && max > 0 {
+            return Err(corrupted(format!(
+                "record is {} bytes shorter than its header says",
+                self.remaining
+            )));
+        }
+        self.hasher.update(&buf[..n]);
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn assert_corrupted(record: &[u8]) {
+        match decode(record) {
+            Err(Error::Corruption(_)) => (),
+            res => panic!("expect corruption, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let record = encode(b"raft state");
+        assert_eq!(decode(&record).unwrap(), b"raft state");
+        assert_eq!(decode(&encode(&[])).unwrap(), b"");
+        assert_eq!(decode(&[]).unwrap(), b"");
+        assert_eq!(data_range(&record).unwrap(), Some(HEADER_LEN..record.len()));
+        assert_eq!(data_range(&[]).unwrap(), Some(0..0));
+
+        assert_corrupted(&record[..record.len() - 1]);
+        assert!(data_range(&record[..record.len() - 1]).is_err());
+        assert_corrupted(&record[..5]);
+        let mut flipped = record.clone();
+        flipped[HEADER_LEN] ^= 1;
+        assert_corrupted(&flipped);
+        let mut labcodec = record;
+        labcodec[0] = 0x08;
+        assert_corrupted(&labcodec);
+    }
+
+    fn read_all(record: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut r = Reader::new(Cursor::new(record)).map_err(|e| corrupted(e.to_string()))?;
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut w = Writer::new(Cursor::new(vec![])).unwrap();
+        w.write_all(b"raft ").unwrap();
+        w.write_all(b"snapshot").unwrap();
+        let record = w.finish().unwrap().into_inner();
+        assert_eq!(record, encode(b"raft snapshot"));
+        assert_eq!(read_all(record.clone()).unwrap(), b"raft snapshot");
+        assert_eq!(read_all(vec![]).unwrap(), b"");
+        assert_eq!(read_all(encode(&[])).unwrap(), b"");
+
+        let mut flipped = record.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        read_all(flipped).unwrap_err();
+        read_all(record[..record.len() - 1].to_vec()).unwrap_err();
+        read_all(record[..3].to_vec()).unwrap_err();
+        let mut trailing = record;
+        trailing.push(0);
+        read_all(trailing).unwrap_err();
+    }
+
+    #[test]
+    fn test_compression() {
+        let data = b"raft raft raft raft raft raft raft raft".to_vec();
+        let record = encode_with(compress::preferred(), &data);
+        assert_eq!(decode(&record).unwrap(), data);
+        assert_eq!(read_all(record.clone()).unwrap(), data);
+        if compress::preferred() != Compression::None {
+            assert!(record.len() < encode(&data).len());
+        }
+        assert_eq!(encode_with(Compression::None, &data), encode(&data));
+
+        let mut flipped = record;
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_corrupted(&flipped);
+
+        let mut compressed = header(COMPRESSED_MAGIC, b"xx").to_vec();
+        compressed.push(Compression::Snappy as i32 as u8);
+        compressed.extend_from_slice(b"xx");
+        decode(&compressed).unwrap_err();
+        compressed[HEADER_LEN] = 7;
+        assert_corrupted(&compressed);
+    }
+}
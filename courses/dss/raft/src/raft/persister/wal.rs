@@ -0,0 +1,505 @@
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+
+use super::file::sync_dir;
+use super::{Error, FilePersister, Persister, Result, SnapshotSaved, SnapshotWriter, Stats};
+use crate::raft::compress::Compression;
+
+const WAL_DIR: &str = "wal";
+const SEGMENT_EXT: &str = "wal";
+// length and crc32 of a record.
+const RECORD_HEADER_LEN: u64 = 8;
+
+/// A persister that keeps the log in a write-ahead log of segment files, so
+/// appending entries doesn't rewrite everything persisted before.
+///
+/// Entries are appended with [`WalPersister::append`] and read back with
+/// [`WalPersister::entries`] on recovery. The rest of the raft state, i.e.
+/// the term and vote, and the snapshot are saved through the `Persister`
+/// methods as usual. After a snapshot, [`WalPersister::compact`] removes the
+/// segments it covers.
+///
+/// A segment is named by the index of its first entry and holds records of
+/// `| len: u32 LE | crc32: u32 LE | entry |`. A new segment is started once
+/// the current one reaches `segment_size` bytes. A torn record at the tail
+/// is dropped when the persister is opened.
+pub struct WalPersister {
+    files: FilePersister,
+    wal: Mutex<Wal>,
+}
+
+struct Segment {
+    first_index: u64,
+    path: PathBuf,
+    // start offset of each record.
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+impl Segment {
+    fn next_index(&self) -> u64 {
+        self.first_index + self.offsets.len() as u64
+    }
+}
+
+struct Wal {
+    dir: PathBuf,
+    segment_size: u64,
+    segments: Vec<Segment>,
+    // of appends.
+    stats: Stats,
+}
+
+impl WalPersister {
+    /// Opens a persister in `dir` whose segments are about `segment_size`
+    /// bytes, recovering anything persisted there before.
+    pub fn open<P: Into<PathBuf>>(dir: P, segment_size: u64) -> io::Result<WalPersister> {
+        let dir = dir.into();
+        let files = FilePersister::new(&dir)?;
+        let wal = Wal::open(dir.join(WAL_DIR), segment_size)?;
+        Ok(WalPersister {
+            files,
+            wal: Mutex::new(wal),
+        })
+    }
+
+    /// Compresses the raft state and snapshots saved from now on with
+    /// `kind`, see `FilePersister::with_compression`. Entries aren't
+    /// compressed.
+    pub fn with_compression(mut self, kind: Compression) -> WalPersister {
+        self.files = self.files.with_compression(kind);
+        self
+    }
+
+    /// The index of the first entry in the log, 0 if the log is empty.
+    ///
+    /// `compact` only removes whole segments, so it may be lower than the
+    /// compacted index.
+    pub fn first_index(&self) -> u64 {
+        let wal = self.wal.lock().unwrap();
+        wal.segments.first().map_or(0, |s| s.first_index)
+    }
+
+    /// The index of the last entry in the log, 0 if the log is empty.
+    pub fn last_index(&self) -> u64 {
+        let wal = self.wal.lock().unwrap();
+        wal.segments.last().map_or(0, |s| s.next_index() - 1)
+    }
+
+    /// Appends `entries` starting at `index`, dropping all entries from
+    /// `index` on first. The entries are durable once this returns.
+    ///
+    /// `index` may be anything if the log is empty, otherwise
+    /// `first_index() <= index <= last_index() + 1`.
+    pub fn append(&self, index: u64, entries: &[Vec<u8>]) -> io::Result<()> {
+        self.wal.lock().unwrap().append(index, entries)
+    }
+
+    /// The entries in `[lo, hi)`.
+    pub fn entries(&self, lo: u64, hi: u64) -> Result<Vec<Vec<u8>>> {
+        self.wal.lock().unwrap().entries(lo, hi)
+    }
+
+    /// Removes the segments that only hold entries up to `index`.
+    pub fn compact(&self, index: u64) -> io::Result<()> {
+        self.wal.lock().unwrap().compact(index)
+    }
+}
+
+impl Persister for WalPersister {
+    fn raft_state(&self) -> Result<Bytes> {
+        self.files.raft_state()
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) -> Result<()> {
+        self.files.save_raft_state(state)
+    }
+
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) -> Result<()> {
+        self.files.save_state_and_snapshot(state, snapshot)
+    }
+
+    fn snapshot(&self) -> Result<Bytes> {
+        self.files.snapshot()
+    }
+
+    /// The stats of appends and of the raft state and snapshot saves.
+    fn stats(&self) -> Stats {
+        let mut stats = self.files.stats();
+        stats.merge(&self.wal.lock().unwrap().stats);
+        stats
+    }
+
+    fn save_hard_state(&self, term: u64, voted_for: u64) -> Result<()> {
+        self.files.save_hard_state(term, voted_for)
+    }
+
+    fn save_raft_state_async(&self, state: Vec<u8>) -> BoxFuture<'static, Result<()>> {
+        self.files.save_raft_state_async(state)
+    }
+
+    fn save_state_and_snapshot_async(
+        &self,
+        state: Vec<u8>,
+        snapshot: Vec<u8>,
+    ) -> BoxFuture<'static, Result<()>> {
+        self.files.save_state_and_snapshot_async(state, snapshot)
+    }
+
+    fn save_snapshot_in_background(&self, snapshot: Bytes, done: SnapshotSaved) {
+        self.files.save_snapshot_in_background(snapshot, done)
+    }
+
+    fn snapshot_reader(&self) -> Result<Box<dyn Read + '_>> {
+        self.files.snapshot_reader()
+    }
+
+    fn snapshot_writer(&self) -> Result<Box<dyn SnapshotWriter + '_>> {
+        self.files.snapshot_writer()
+    }
+
+    fn stage_snapshot(&self, offset: u64, data: &[u8]) -> Result<u64> {
+        self.files.stage_snapshot(offset, data)
+    }
+
+    fn promote_snapshot(&self, state: Vec<u8>) -> Result<()> {
+        self.files.promote_snapshot(state)
+    }
+
+    fn discard_staged_snapshot(&self) -> Result<()> {
+        self.files.discard_staged_snapshot()
+    }
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+impl Wal {
+    fn open(dir: PathBuf, segment_size: u64) -> io::Result<Wal> {
+        fs::create_dir_all(&dir)?;
+        let mut first_indexes = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new(SEGMENT_EXT)) {
+                continue;
+            }
+            let stem = path.file_stem().and_then(|s| s.to_str());
+            if let Some(first_index) = stem.and_then(|s| s.parse::<u64>().ok()) {
+                first_indexes.push(first_index);
+            }
+        }
+        first_indexes.sort_unstable();
+
+        let mut wal = Wal {
+            dir,
+            segment_size,
+            segments: vec![],
+            stats: Stats::default(),
+        };
+        let mut broken = false;
+        for first_index in first_indexes {
+            let path = wal.segment_path(first_index);
+            let next_index = wal.segments.last().map(Segment::next_index);
+            let contiguous = next_index.unwrap_or(first_index) == first_index;
+            if broken || !contiguous {
+                // Everything after a torn write or a gap is garbage.
+                warn!("drop wal segment {:?}", path);
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let (segment, torn) = Segment::recover(first_index, path)?;
+            broken = torn;
+            wal.segments.push(segment);
+        }
+        Ok(wal)
+    }
+
+    fn segment_path(&self, first_index: u64) -> PathBuf {
+        self.dir
+            .join(format!("{:020}.{}", first_index, SEGMENT_EXT))
+    }
+
+    fn append(&mut self, index: u64, entries: &[Vec<u8>]) -> io::Result<()> {
+        let start = Instant::now();
+        let mut bytes = 0;
+        let mut fsyncs = 1;
+        if let (Some(first), Some(last)) = (self.segments.first(), self.segments.last()) {
+            if index < first.first_index || index > last.next_index() {
+                return Err(invalid_input(format!(
+                    "append at {} out of [{}, {}]",
+                    index,
+                    first.first_index,
+                    last.next_index()
+                )));
+            }
+            self.truncate(index)?;
+            fsyncs += 1;
+        }
+        if self.segments.is_empty() {
+            self.new_segment(index)?;
+            fsyncs += 2;
+        }
+
+        let mut file = self.open_tail()?;
+        for (next_index, entry) in (index..).zip(entries) {
+            if self.segments.last().unwrap().len >= self.segment_size {
+                file.sync_data()?;
+                self.new_segment(next_index)?;
+                fsyncs += 3;
+                file = self.open_tail()?;
+            }
+            let tail = self.segments.last_mut().unwrap();
+            let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + entry.len());
+            record.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            record.extend_from_slice(&crc32fast::hash(entry).to_le_bytes());
+            record.extend_from_slice(entry);
+            file.write_all(&record)?;
+            tail.offsets.push(tail.len);
+            tail.len += record.len() as u64;
+            bytes += record.len() as u64;
+        }
+        file.sync_data()?;
+        self.stats.record(bytes, fsyncs, start.elapsed());
+        Ok(())
+    }
+
+    fn open_tail(&self) -> io::Result<File> {
+        let tail = self.segments.last().unwrap();
+        OpenOptions::new().append(true).open(&tail.path)
+    }
+
+    fn new_segment(&mut self, first_index: u64) -> io::Result<()> {
+        let path = self.segment_path(first_index);
+        File::create(&path)?.sync_all()?;
+        sync_dir(&self.dir)?;
+        self.segments.push(Segment {
+            first_index,
+            path,
+            offsets: vec![],
+            len: 0,
+        });
+        Ok(())
+    }
+
+    /// Drops all entries from `index` on.
+    fn truncate(&mut self, index: u64) -> io::Result<()> {
+        let mut n = self.segments.len();
+        while let Some(tail) = self.segments.last_mut() {
+            if tail.first_index >= index && n > 1 {
+                let tail = self.segments.pop().unwrap();
+                fs::remove_file(&tail.path)?;
+                n -= 1;
+                continue;
+            }
+            if tail.next_index() > index {
+                let k = index.saturating_sub(tail.first_index) as usize;
+                tail.len = tail.offsets[k];
+                tail.offsets.truncate(k);
+                OpenOptions::new()
+                    .write(true)
+                    .open(&tail.path)?
+                    .set_len(tail.len)?;
+                if k == 0 && tail.first_index != index {
+                    // The only segment is empty now, restart it at `index`.
+                    let tail = self.segments.pop().unwrap();
+                    fs::remove_file(&tail.path)?;
+                }
+            }
+            break;
+        }
+        sync_dir(&self.dir)
+    }
+
+    fn entries(&self, lo: u64, hi: u64) -> Result<Vec<Vec<u8>>> {
+        let mut entries = vec![];
+        for segment in &self.segments {
+            if segment.next_index() <= lo || segment.first_index >= hi {
+                continue;
+            }
+            let mut file = File::open(&segment.path)?;
+            let from = lo.max(segment.first_index);
+            let to = hi.min(segment.next_index());
+            file.seek(SeekFrom::Start(
+                segment.offsets[(from - segment.first_index) as usize],
+            ))?;
+            for _ in from..to {
+                let entry = read_record(&mut file)?;
+                entries.push(entry.ok_or_else(|| {
+                    Error::Corruption(format!("corrupted wal record in {:?}", segment.path))
+                })?);
+            }
+        }
+        if entries.len() as u64 != hi.saturating_sub(lo) {
+            return Err(
+                invalid_input(format!("entries [{}, {}) are not in the wal", lo, hi)).into(),
+            );
+        }
+        Ok(entries)
+    }
+
+    fn compact(&mut self, index: u64) -> io::Result<()> {
+        // Always keep the tail, new entries go there.
+        while self.segments.len() > 1 && self.segments[0].next_index() <= index + 1 {
+            let head = self.segments.remove(0);
+            fs::remove_file(&head.path)?;
+        }
+        sync_dir(&self.dir)
+    }
+}
+
+impl Segment {
+    /// Reads a segment, truncating a torn record at its tail. Returns
+    /// whether the segment was torn.
+    fn recover(first_index: u64, path: PathBuf) -> io::Result<(Segment, bool)> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let file_len = file.metadata()?.len();
+        let mut segment = Segment {
+            first_index,
+            path,
+            offsets: vec![],
+            len: 0,
+        };
+        while let Some(entry) = read_record(&mut file)? {
+            segment.offsets.push(segment.len);
+            segment.len += RECORD_HEADER_LEN + entry.len() as u64;
+        }
+        let torn = segment.len != file_len;
+        if torn {
+            warn!(
+                "truncate torn wal segment {:?} from {} to {} bytes",
+                segment.path, file_len, segment.len
+            );
+            file.set_len(segment.len)?;
+            file.sync_all()?;
+        }
+        Ok((segment, torn))
+    }
+}
+
+/// Reads the next record, `None` at the end of the file or at a torn record.
+fn read_record(file: &mut File) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    if !read_full(file, &mut header)? {
+        return Ok(None);
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&header[4..]);
+    let len = u64::from(u32::from_le_bytes(len));
+    // a torn header may claim any length, don't allocate past the file.
+    let left = file
+        .metadata()?
+        .len()
+        .saturating_sub(file.stream_position()?);
+    if len > left {
+        return Ok(None);
+    }
+    let mut entry = vec![0; len as usize];
+    if !read_full(file, &mut entry)? || crc32fast::hash(&entry) != u32::from_le_bytes(crc) {
+        return Ok(None);
+    }
+    Ok(Some(entry))
+}
+
+/// Fills `buf`, returns false if the file ends before.
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..])? {
+            0 => return Ok(false),
+            n => read += n,
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn entries(range: std::ops::Range<u8>) -> Vec<Vec<u8>> {
+        range.map(|i| vec![i; 10]).collect()
+    }
+
+    #[test]
+    fn test_wal_persister() {
+        let dir = env::temp_dir().join(format!("raft-wal-persister-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        // Each segment holds 2 entries of 18 bytes.
+        let p = WalPersister::open(&dir, 36).unwrap();
+        assert_eq!((p.first_index(), p.last_index()), (0, 0));
+        p.append(1, &entries(1..11)).unwrap();
+        assert_eq!((p.first_index(), p.last_index()), (1, 10));
+        assert_eq!(p.entries(3, 6).unwrap(), entries(3..6));
+        p.entries(9, 12).unwrap_err();
+        p.append(12, &entries(12..13)).unwrap_err();
+
+        // Conflicting entries are replaced.
+        p.append(6, &entries(60..62)).unwrap();
+        assert_eq!(p.last_index(), 7);
+        assert_eq!(
+            p.entries(5, 8).unwrap(),
+            vec![vec![5; 10], vec![60; 10], vec![61; 10]]
+        );
+
+        p.compact(4).unwrap();
+        assert_eq!(p.first_index(), 5);
+        p.save_raft_state(vec![1]).unwrap();
+        drop(p);
+
+        // A torn write at the tail is dropped on recovery.
+        let wal_dir = dir.join(WAL_DIR);
+        let mut tails: Vec<_> = fs::read_dir(&wal_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        tails.sort();
+        let mut tail = OpenOptions::new()
+            .append(true)
+            .open(tails.last().unwrap())
+            .unwrap();
+        tail.write_all(&[9, 0, 0, 0, 1, 2]).unwrap();
+        drop(tail);
+
+        let p = WalPersister::open(&dir, 36).unwrap();
+        assert_eq!((p.first_index(), p.last_index()), (5, 7));
+        assert_eq!(
+            p.entries(5, 8).unwrap(),
+            vec![vec![5; 10], vec![60; 10], vec![61; 10]]
+        );
+        assert_eq!(p.raft_state().unwrap(), vec![1]);
+        assert_eq!(p.stats().saves, 0);
+        p.append(8, &entries(8..9)).unwrap();
+        assert_eq!(p.entries(8, 9).unwrap(), entries(8..9));
+        assert_eq!(p.stats().saves, 1);
+        assert_eq!(p.stats().bytes_written, 18);
+        drop(p);
+
+        // So is a torn header claiming more than the file holds.
+        let mut tail = OpenOptions::new()
+            .append(true)
+            .open(tails.last().unwrap())
+            .unwrap();
+        tail.write_all(&[0xff, 0xff, 0xff, 0xff, 1, 2, 3, 4, 5])
+            .unwrap();
+        drop(tail);
+        let p = WalPersister::open(&dir, 36).unwrap();
+        assert_eq!((p.first_index(), p.last_index()), (5, 8));
+        assert_eq!(p.entries(8, 9).unwrap(), entries(8..9));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
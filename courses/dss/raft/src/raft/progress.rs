@@ -0,0 +1,140 @@
+//! Replication progress of followers, tracked by the leader.
+
+use std::time::{Duration, Instant};
+
+/// Entries a follower may fall behind before it's considered lagging.
+pub const LAG_WARN_ENTRIES: u64 = 64;
+/// How long a follower must keep lagging before a warning is logged.
+pub const LAG_WARN_DURATION: Duration = Duration::from_secs(1);
+
+/// How far a follower is behind the leader.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Lag {
+    /// Entries the follower is missing.
+    pub entries: u64,
+    /// Time since the follower was last caught up.
+    pub time: Duration,
+}
+
+/// The leader's view of the log of a follower.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    /// Index of the next entry to send to the follower.
+    pub next_index: u64,
+    /// Index of the highest entry known to be replicated on the follower.
+    pub match_index: u64,
+    /// Index of the highest entry the follower reported as applied.
+    pub applied_index: u64,
+    // the last time the follower had all entries of the leader.
+    caught_up_at: Instant,
+    // whether the current lagging has been reported.
+    warned: bool,
+}
+
+impl Progress {
+    /// Creates the progress of a follower when becoming leader with the
+    /// last log index `last_index`.
+    pub fn new(last_index: u64) -> Progress {
+        Progress {
+            next_index: last_index + 1,
+            match_index: 0,
+            applied_index: 0,
+            caught_up_at: Instant::now(),
+            warned: false,
+        }
+    }
+
+    /// The follower acknowledged entries up to `match_index`, while the last
+    /// index of the leader is `last_index`.
+    pub fn ack(&mut self, match_index: u64, last_index: u64) {
+        if match_index > self.match_index {
+            self.match_index = match_index;
+        }
+        self.next_index = self.match_index + 1;
+        if self.match_index >= last_index {
+            self.caught_up_at = Instant::now();
+            self.warned = false;
+        }
+    }
+
+    /// The follower reported the index of its last entry and of its last
+    /// applied entry in an AppendEntries reply.
+    ///
+    /// Entries past `last_log_index` are missing on the follower, so there's
+    /// no point in probing them one by one.
+    pub fn hint(&mut self, last_log_index: u64, applied_index: u64) {
+        if applied_index > self.applied_index {
+            self.applied_index = applied_index;
+        }
+        if last_log_index < self.next_index {
+            self.next_index = (last_log_index + 1).max(self.match_index + 1);
+        }
+    }
+
+    /// How far the follower is behind a leader whose last index is
+    /// `last_index`.
+    pub fn lag(&self, last_index: u64) -> Lag {
+        let entries = last_index.saturating_sub(self.match_index);
+        let time = if entries == 0 {
+            Duration::default()
+        } else {
+            self.caught_up_at.elapsed()
+        };
+        Lag { entries, time }
+    }
+
+    /// Returns true once per lagging, when the follower has lagged more than
+    /// `LAG_WARN_ENTRIES` for longer than `LAG_WARN_DURATION`.
+    pub fn should_warn(&mut self, last_index: u64) -> bool {
+        let lag = self.lag(last_index);
+        if !self.warned && lag.entries > LAG_WARN_ENTRIES && lag.time > LAG_WARN_DURATION {
+            self.warned = true;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_lag() {
+        let mut pr = Progress::new(10);
+        assert_eq!(pr.next_index, 11);
+        assert_eq!(pr.lag(10).entries, 10);
+
+        pr.ack(10, 10);
+        assert_eq!(pr.lag(10), Lag::default());
+        assert_eq!(pr.next_index, 11);
+
+        // Stale acks never move the progress backwards.
+        pr.ack(5, 200);
+        assert_eq!(pr.match_index, 10);
+        assert_eq!(pr.lag(200).entries, 190);
+        assert!(!pr.should_warn(200));
+
+        pr.caught_up_at -= LAG_WARN_DURATION * 2;
+        assert!(pr.lag(200).time > LAG_WARN_DURATION);
+        assert!(pr.should_warn(200));
+        assert!(!pr.should_warn(200));
+
+        pr.ack(200, 200);
+        assert_eq!(pr.lag(200), Lag::default());
+    }
+
+    #[test]
+    fn test_progress_hint() {
+        let mut pr = Progress::new(100);
+        pr.hint(20, 15);
+        assert_eq!((pr.next_index, pr.applied_index), (21, 15));
+        // Never moves behind what's known to be replicated.
+        pr.ack(10, 100);
+        pr.hint(5, 3);
+        assert_eq!((pr.next_index, pr.applied_index), (11, 15));
+        // A longer follower log doesn't skip entries it may not match.
+        pr.hint(50, 20);
+        assert_eq!((pr.next_index, pr.applied_index), (11, 20));
+    }
+}
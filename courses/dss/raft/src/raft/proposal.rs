@@ -0,0 +1,119 @@
+//! Tracking of in-flight proposals.
+//!
+//! `Node::propose` hands out a [`Proposal`] for every command it appended to
+//! the log. The proposal resolves once the entry at its index is applied: with
+//! `Ok(())` if the applied entry is the proposed one, or with
+//! [`Error::Superseded`] if a different leader committed another entry there.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::channel::oneshot;
+
+use super::errors::*;
+
+/// A command proposed by `Node::propose`.
+#[derive(Debug)]
+pub struct Proposal {
+    /// The index the command will appear at if it's ever committed.
+    pub index: u64,
+    /// The term the command was proposed in.
+    pub term: u64,
+    rx: oneshot::Receiver<Result<()>>,
+}
+
+impl Future for Proposal {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|res| res.unwrap_or(Err(Error::Killed)))
+    }
+}
+
+/// Proposals of a peer that are not applied yet, by index.
+#[derive(Default)]
+pub struct Proposals {
+    pending: BTreeMap<u64, Pending>,
+}
+
+struct Pending {
+    term: u64,
+    proposed_at: Instant,
+    tx: oneshot::Sender<Result<()>>,
+}
+
+impl Proposals {
+    /// Registers a command appended at `index` in `term`.
+    ///
+    /// A previous proposal at the same index can't be committed any more,
+    /// it is resolved as superseded.
+    pub fn register(&mut self, index: u64, term: u64) -> Proposal {
+        let (tx, rx) = oneshot::channel();
+        let pending = Pending {
+            term,
+            proposed_at: Instant::now(),
+            tx,
+        };
+        if let Some(old) = self.pending.insert(index, pending) {
+            let _ = old.tx.send(Err(Error::Superseded {
+                index,
+                term: old.term,
+            }));
+        }
+        Proposal { index, term, rx }
+    }
+
+    /// The entry at `index` with `term` has been applied.
+    ///
+    /// Call it for every entry sent to `apply_ch`, in order. Returns the
+    /// time since the proposal if the applied entry was proposed here.
+    pub fn applied(&mut self, index: u64, term: u64) -> Option<Duration> {
+        // Applying is in order, so everything before `index` is done as well.
+        let rest = self.pending.split_off(&(index + 1));
+        let mut latency = None;
+        for (i, p) in std::mem::replace(&mut self.pending, rest) {
+            let res = if i == index && p.term == term {
+                latency = Some(p.proposed_at.elapsed());
+                Ok(())
+            } else {
+                Err(Error::Superseded {
+                    index: i,
+                    term: p.term,
+                })
+            };
+            let _ = p.tx.send(res);
+        }
+        latency
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_proposals() {
+        let mut proposals = Proposals::default();
+        let p1 = proposals.register(1, 1);
+        let p2 = proposals.register(2, 1);
+        let p3 = proposals.register(3, 1);
+        let p3_ = proposals.register(3, 2);
+        assert_eq!((p3_.index, p3_.term), (3, 2));
+
+        assert!(proposals.applied(1, 1).is_some());
+        assert_eq!(block_on(p1), Ok(()));
+        assert!(proposals.applied(2, 2).is_none());
+        assert_eq!(block_on(p2), Err(Error::Superseded { index: 2, term: 1 }));
+        assert_eq!(block_on(p3), Err(Error::Superseded { index: 3, term: 1 }));
+
+        drop(proposals);
+        assert_eq!(block_on(p3_), Err(Error::Killed));
+    }
+}
@@ -0,0 +1,78 @@
+//! Quiescing of idle clusters.
+//!
+//! A leader that hasn't seen a proposal for a while stops sending empty
+//! heartbeats. Its last AppendEntries before going quiet has `quiesce` set,
+//! and followers that saw it wait [`QUIESCED_TIMEOUT_FACTOR`] times longer
+//! before starting an election. The next proposal wakes everyone up.
+
+/// How much longer quiesced followers wait before starting an election.
+pub const QUIESCED_TIMEOUT_FACTOR: u64 = 10;
+
+/// Tracks whether a leader may quiesce.
+#[derive(Debug, Default)]
+pub struct Quiesce {
+    // idle ticks after which to quiesce, quiescing is disabled if None.
+    after: Option<u64>,
+    idle_ticks: u64,
+}
+
+impl Quiesce {
+    /// Quiesces after `after` ticks without proposals, never if `None`.
+    pub fn new(after: Option<u64>) -> Quiesce {
+        Quiesce {
+            after,
+            idle_ticks: 0,
+        }
+    }
+
+    /// Counts one tick without proposals.
+    pub fn tick(&mut self) {
+        self.idle_ticks = self.idle_ticks.saturating_add(1);
+    }
+
+    /// A proposal arrived, or the peer changed its role.
+    pub fn wake(&mut self) {
+        self.idle_ticks = 0;
+    }
+
+    /// Whether heartbeats should be suppressed.
+    pub fn is_quiet(&self) -> bool {
+        self.after.is_some_and(|after| self.idle_ticks >= after)
+    }
+
+    /// The election timeout of a follower that was told by its leader
+    /// whether it quiesced.
+    pub fn election_timeout(timeout: u64, quiesced: bool) -> u64 {
+        if quiesced {
+            timeout * QUIESCED_TIMEOUT_FACTOR
+        } else {
+            timeout
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiesce() {
+        let mut disabled = Quiesce::new(None);
+        for _ in 0..100 {
+            disabled.tick();
+        }
+        assert!(!disabled.is_quiet());
+
+        let mut q = Quiesce::new(Some(3));
+        q.tick();
+        q.tick();
+        assert!(!q.is_quiet());
+        q.tick();
+        assert!(q.is_quiet());
+        q.wake();
+        assert!(!q.is_quiet());
+
+        assert_eq!(Quiesce::election_timeout(20, false), 20);
+        assert_eq!(Quiesce::election_timeout(20, true), 200);
+    }
+}
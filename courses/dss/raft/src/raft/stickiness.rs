@@ -0,0 +1,82 @@
+//! Leader stickiness.
+//!
+//! A leader on a slow link may miss a few heartbeats while still being alive.
+//! With a stickiness grace, a peer that heard from its leader within the last
+//! `grace` ticks neither starts an election nor grants votes, denying them
+//! with `VoteDenial::LeaderSticky`. Candidates asked by the leader to take
+//! over set `leadership_transfer` in their RequestVote, which bypasses the
+//! grace so intentional moves still work.
+
+/// Tracks how recently a peer heard from its leader.
+#[derive(Debug, Default)]
+pub struct Stickiness {
+    // grace in ticks, stickiness is disabled if None.
+    grace: Option<u64>,
+    // ticks since the last message from the leader, None if never.
+    ticks_since_leader: Option<u64>,
+}
+
+impl Stickiness {
+    /// Sticks to a leader heard from within `grace` ticks, never if `None`.
+    pub fn new(grace: Option<u64>) -> Stickiness {
+        Stickiness {
+            grace,
+            ticks_since_leader: None,
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if let Some(ticks) = self.ticks_since_leader.as_mut() {
+            *ticks = ticks.saturating_add(1);
+        }
+    }
+
+    /// A valid AppendEntries or InstallSnapshot from the leader arrived.
+    pub fn heard_from_leader(&mut self) {
+        self.ticks_since_leader = Some(0);
+    }
+
+    /// The leader is known to be gone, e.g. this peer became candidate.
+    pub fn forget_leader(&mut self) {
+        self.ticks_since_leader = None;
+    }
+
+    /// Whether the leader is still considered alive.
+    pub fn leader_alive(&self) -> bool {
+        match (self.grace, self.ticks_since_leader) {
+            (Some(grace), Some(ticks)) => ticks < grace,
+            _ => false,
+        }
+    }
+
+    /// Whether a vote for a candidate must be denied.
+    pub fn deny_vote(&self, leadership_transfer: bool) -> bool {
+        !leadership_transfer && self.leader_alive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stickiness() {
+        let mut disabled = Stickiness::new(None);
+        disabled.heard_from_leader();
+        assert!(!disabled.deny_vote(false));
+
+        let mut s = Stickiness::new(Some(2));
+        assert!(!s.leader_alive());
+        s.heard_from_leader();
+        assert!(s.deny_vote(false));
+        assert!(!s.deny_vote(true));
+        s.tick();
+        assert!(s.leader_alive());
+        s.tick();
+        assert!(!s.leader_alive());
+
+        s.heard_from_leader();
+        s.forget_leader();
+        assert!(!s.leader_alive());
+    }
+}
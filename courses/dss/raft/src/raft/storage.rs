@@ -0,0 +1,178 @@
+//! Storage of the raft log.
+//!
+//! The algorithm only talks to the log through the [`Storage`] trait, so the
+//! in-memory [`MemStorage`] can be swapped for a disk-backed one.
+//!
+//! Indexes start at 1. Entries up to the snapshot index are compacted, the
+//! term of the snapshot index is still known.
+
+use std::ops::Range;
+
+use super::errors::*;
+pub use crate::proto::raftpb::LogEntry;
+
+/// A snapshot of the state machine and the last entry it includes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub data: Vec<u8>,
+}
+
+pub trait Storage: Send + 'static {
+    /// The index of the first entry that isn't compacted.
+    fn first_index(&self) -> u64;
+
+    /// The index of the last entry, the snapshot index if there's none.
+    fn last_index(&self) -> u64;
+
+    /// The term of the entry at `index`, for `first_index() - 1 <= index <=
+    /// last_index()`.
+    fn term(&self, index: u64) -> Result<u64>;
+
+    /// The entries in `range`.
+    fn entries(&self, range: Range<u64>) -> Result<Vec<LogEntry>>;
+
+    /// Appends `entries` starting at `index`, dropping all entries from
+    /// `index` on first. `first_index() <= index <= last_index() + 1`.
+    fn append(&mut self, index: u64, entries: Vec<LogEntry>) -> Result<()>;
+
+    /// The latest snapshot.
+    fn snapshot(&self) -> Snapshot;
+
+    /// Installs `snapshot`, dropping all entries it includes. Entries after
+    /// it are kept if the log agrees with the snapshot on its last entry.
+    fn apply_snapshot(&mut self, snapshot: Snapshot) -> Result<()>;
+}
+
+/// A [`Storage`] that keeps everything in memory.
+#[derive(Default)]
+pub struct MemStorage {
+    snapshot: Snapshot,
+    // entries after the snapshot.
+    entries: Vec<LogEntry>,
+}
+
+impl MemStorage {
+    pub fn new() -> MemStorage {
+        MemStorage::default()
+    }
+
+    fn check_range(&self, lo: u64, hi: u64) -> Result<()> {
+        if lo < self.first_index() {
+            return Err(Error::Compacted(lo));
+        }
+        if hi > self.last_index() + 1 {
+            return Err(Error::Unavailable(hi - 1));
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MemStorage {
+    fn first_index(&self) -> u64 {
+        self.snapshot.index + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot.index + self.entries.len() as u64
+    }
+
+    fn term(&self, index: u64) -> Result<u64> {
+        if index == self.snapshot.index {
+            return Ok(self.snapshot.term);
+        }
+        self.check_range(index, index + 1)?;
+        Ok(self.entries[(index - self.first_index()) as usize].term)
+    }
+
+    fn entries(&self, range: Range<u64>) -> Result<Vec<LogEntry>> {
+        if range.start >= range.end {
+            return Ok(vec![]);
+        }
+        self.check_range(range.start, range.end)?;
+        let offset = self.first_index();
+        let lo = (range.start - offset) as usize;
+        let hi = (range.end - offset) as usize;
+        Ok(self.entries[lo..hi].to_vec())
+    }
+
+    fn append(&mut self, index: u64, entries: Vec<LogEntry>) -> Result<()> {
+        self.check_range(index, index)?;
+        self.entries.truncate((index - self.first_index()) as usize);
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.snapshot.clone()
+    }
+
+    fn apply_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        if snapshot.index < self.snapshot.index {
+            return Err(Error::Compacted(snapshot.index));
+        }
+        if self.term(snapshot.index).ok() == Some(snapshot.term) {
+            let drop = (snapshot.index + 1 - self.first_index()) as usize;
+            self.entries.drain(..drop);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot = snapshot;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(term: u64) -> LogEntry {
+        LogEntry {
+            term,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mem_storage() {
+        let mut s = MemStorage::new();
+        assert_eq!((s.first_index(), s.last_index()), (1, 0));
+        assert_eq!(s.term(0), Ok(0));
+
+        s.append(1, vec![entry(1), entry(1), entry(2)]).unwrap();
+        assert_eq!(s.last_index(), 3);
+        assert_eq!(s.term(3), Ok(2));
+        assert_eq!(s.entries(2..4).unwrap(), vec![entry(1), entry(2)]);
+        assert_eq!(s.entries(2..5), Err(Error::Unavailable(4)));
+        s.append(5, vec![]).unwrap_err();
+
+        // A conflicting suffix is replaced.
+        s.append(3, vec![entry(3), entry(3)]).unwrap();
+        assert_eq!(s.last_index(), 4);
+        assert_eq!(s.term(3), Ok(3));
+
+        // The log agrees with the snapshot, entries after it are kept.
+        let snap = Snapshot {
+            index: 2,
+            term: 1,
+            data: b"snap".to_vec(),
+        };
+        s.apply_snapshot(snap.clone()).unwrap();
+        assert_eq!((s.first_index(), s.last_index()), (3, 4));
+        assert_eq!(s.term(2), Ok(1));
+        assert_eq!(s.term(1), Err(Error::Compacted(1)));
+        assert_eq!(s.entries(1..3), Err(Error::Compacted(1)));
+        assert_eq!(s.snapshot(), snap);
+
+        // A snapshot from a different history discards the log.
+        s.apply_snapshot(Snapshot {
+            index: 3,
+            term: 5,
+            data: vec![],
+        })
+        .unwrap();
+        assert_eq!((s.first_index(), s.last_index()), (4, 3));
+        s.apply_snapshot(snap).unwrap_err();
+    }
+}
@@ -9,6 +9,7 @@ use std::time::Duration;
 use futures::channel::oneshot;
 use futures::executor::block_on;
 use futures::future;
+use labrpc::{Clock, SimClock};
 use rand::{rngs::ThreadRng, Rng};
 
 use crate::raft::config::{Config, Entry, Storage};
@@ -52,6 +53,47 @@ fn test_initial_election_2a() {
     cfg.end();
 }
 
+#[test]
+fn test_simulated_time_2a() {
+    let servers = 3;
+    let clock = SimClock::new();
+    let mut cfg = Config::with_clock(servers, false, rand::random(), clock.clone());
+
+    cfg.begin("Test (2A): election on simulated time");
+
+    let elections = |cfg: &Config| -> u64 {
+        let rafts = cfg.rafts.lock().unwrap();
+        rafts
+            .iter()
+            .map(|rf| rf.as_ref().unwrap().status().elections)
+            .sum()
+    };
+    let leaders = |cfg: &Config| -> usize {
+        let rafts = cfg.rafts.lock().unwrap();
+        rafts
+            .iter()
+            .filter(|rf| rf.as_ref().unwrap().is_leader())
+            .count()
+    };
+
+    // peers only tick as the clock moves, so no election times out while
+    // it stands still.
+    thread::sleep(2 * RAFT_ELECTION_TIMEOUT);
+    assert_eq!(elections(&cfg), 0, "elections while the clock stood still");
+    cfg.check_no_leader();
+
+    // a tick at a time, so peers handle each one before the next.
+    let deadline = clock.now() + 2 * RAFT_ELECTION_TIMEOUT;
+    while leaders(&cfg) == 0 && clock.now() < deadline {
+        clock.advance(cfg.tick_interval);
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(leaders(&cfg), 1, "expected one leader");
+    assert!(elections(&cfg) > 0);
+
+    cfg.end();
+}
+
 #[test]
 fn test_reelection_2a() {
     let servers = 3;
@@ -0,0 +1,65 @@
+//! Correlation IDs of raft messages.
+//!
+//! Every RequestVote, AppendEntries and InstallSnapshot request should carry
+//! a fresh [`TraceId`] in its `trace_id` field, and the reply carries the same
+//! ID back. Log lines print it as `peer:seq`, so all the lines about one
+//! request can be found across the logs of all peers.
+
+use std::fmt;
+
+const SEQ_BITS: u32 = 48;
+
+/// A correlation ID, unique across the peers of a cluster.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TraceId(pub u64);
+
+impl TraceId {
+    /// The peer that allocated this ID.
+    pub fn peer(self) -> usize {
+        (self.0 >> SEQ_BITS) as usize
+    }
+
+    /// The sequence number of this ID on its peer.
+    pub fn seq(self) -> u64 {
+        self.0 & ((1 << SEQ_BITS) - 1)
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.peer(), self.seq())
+    }
+}
+
+/// Allocates trace IDs of a peer.
+pub struct TraceIds {
+    me: usize,
+    next: u64,
+}
+
+impl TraceIds {
+    pub fn new(me: usize) -> TraceIds {
+        TraceIds { me, next: 1 }
+    }
+
+    pub fn next_id(&mut self) -> TraceId {
+        let seq = self.next;
+        self.next += 1;
+        TraceId(((self.me as u64) << SEQ_BITS) | (seq & ((1 << SEQ_BITS) - 1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_ids() {
+        let mut ids = TraceIds::new(3);
+        let id = ids.next_id();
+        assert_eq!((id.peer(), id.seq()), (3, 1));
+        assert_eq!(ids.next_id().seq(), 2);
+        assert_eq!(format!("{}", id), "3:1");
+        assert_ne!(TraceIds::new(4).next_id(), id);
+    }
+}
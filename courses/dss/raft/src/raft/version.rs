@@ -0,0 +1,125 @@
+//! Format versions of persisted data, so data saved by older builds can
+//! still be loaded after its encoding changes, e.g. when adding entry types
+//! for configuration changes.
+//!
+//! Versioned data is stamped with
+//!
+//! ```text
+//! | 0xfe | 'V' | version: u16 LE | data |
+//! ```
+//!
+//! Data without a stamp is version 0. [`Migrations`] turn data of older
+//! versions into the current one step by step.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use super::errors::*;
+
+const MAGIC: [u8; 2] = [0xfe, b'V'];
+const STAMP_LEN: usize = 4;
+
+/// Prefixes `data` with `version`.
+pub fn stamp(version: u16, data: &[u8]) -> Vec<u8> {
+    let mut stamped = Vec::with_capacity(STAMP_LEN + data.len());
+    stamped.extend_from_slice(&MAGIC);
+    stamped.extend_from_slice(&version.to_le_bytes());
+    stamped.extend_from_slice(data);
+    stamped
+}
+
+/// Splits the version off data stamped by [`stamp`].
+pub fn split(data: &[u8]) -> (u16, &[u8]) {
+    if data.len() < STAMP_LEN || data[..2] != MAGIC {
+        return (0, data);
+    }
+    (u16::from_le_bytes([data[2], data[3]]), &data[STAMP_LEN..])
+}
+
+type Migration = Box<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+/// How to load the versions of one kind of data, e.g. the raft state.
+///
+/// ```ignore
+/// let migrations = Migrations::new(2)
+///     .add(0, |data| Ok(data.to_vec()))
+///     .add(1, |data| upgrade_entries(data));
+/// let data = migrations.load(&persister.raft_state()?)?;
+/// ```
+pub struct Migrations {
+    current: u16,
+    // from the version of the key to the next.
+    steps: HashMap<u16, Migration>,
+}
+
+impl Migrations {
+    pub fn new(current: u16) -> Migrations {
+        Migrations {
+            current,
+            steps: HashMap::new(),
+        }
+    }
+
+    pub fn current(&self) -> u16 {
+        self.current
+    }
+
+    /// Adds how to turn data of version `from` into version `from + 1`.
+    pub fn add<F>(mut self, from: u16, migrate: F) -> Migrations
+    where
+        F: Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.steps.insert(from, Box::new(migrate));
+        self
+    }
+
+    /// Stamps `data` with the current version.
+    pub fn stamp(&self, data: &[u8]) -> Vec<u8> {
+        stamp(self.current, data)
+    }
+
+    /// Splits the version off `data` and migrates it to the current one.
+    /// Fails with `Error::Version` if there's no way to get there.
+    pub fn load<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let (mut version, data) = split(data);
+        if version > self.current {
+            return Err(Error::Version(version));
+        }
+        let mut data = Cow::Borrowed(data);
+        while version < self.current {
+            let migrate = self.steps.get(&version).ok_or(Error::Version(version))?;
+            data = Cow::Owned(migrate(&data)?);
+            version += 1;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp() {
+        assert_eq!(split(&stamp(3, b"data")), (3, &b"data"[..]));
+        assert_eq!(split(b"data"), (0, &b"data"[..]));
+        assert_eq!(split(&[]), (0, &[][..]));
+    }
+
+    #[test]
+    fn test_migrations() {
+        let migrations = Migrations::new(2)
+            .add(0, |data| Ok([data, b"+1"].concat()))
+            .add(1, |data| Ok([data, b"+2"].concat()));
+        assert_eq!(&*migrations.load(b"v0").unwrap(), b"v0+1+2");
+        assert_eq!(&*migrations.load(&stamp(1, b"v1")).unwrap(), b"v1+2");
+        let current = migrations.stamp(b"v2");
+        match migrations.load(&current).unwrap() {
+            Cow::Borrowed(data) => assert_eq!(data, b"v2"),
+            Cow::Owned(_) => panic!("current data is copied"),
+        }
+        assert_eq!(migrations.load(&stamp(3, b"v3")), Err(Error::Version(3)));
+        let gap = Migrations::new(2).add(1, |data| Ok(data.to_vec()));
+        assert_eq!(gap.load(b"v0"), Err(Error::Version(0)));
+    }
+}